@@ -0,0 +1,67 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Byte buffers reused for encoding and decoding packets instead of allocating one per packet.
+// At most `max_bufs` idle buffers are kept, and buffers that grew past `max_buf_len` while in use
+// are freed on return so that one large packet doesn't pin its memory.
+pub struct BufPool {
+    bufs: Mutex<Vec<Vec<u8>>>,
+    max_bufs: usize,
+    max_buf_len: usize,
+    hits: AtomicUsize,
+    misses: AtomicUsize
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PoolStats {
+    // Takes served by an idle buffer
+    pub hits: usize,
+    // Takes that had to allocate
+    pub misses: usize,
+    pub idle: usize
+}
+
+impl BufPool {
+    pub fn new(max_bufs: usize, max_buf_len: usize) -> BufPool {
+        BufPool {
+            bufs: Mutex::new(vec![]),
+            max_bufs,
+            max_buf_len,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0)
+        }
+    }
+
+    // Returns an empty buffer
+    pub fn take(&self) -> Vec<u8> {
+        match self.bufs.lock().unwrap().pop() {
+            Some(buf) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                vec![]
+            }
+        }
+    }
+
+    pub fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() > self.max_buf_len {
+            return;
+        }
+        let mut bufs = self.bufs.lock().unwrap();
+        if bufs.len() < self.max_bufs {
+            buf.clear();
+            bufs.push(buf);
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            idle: self.bufs.lock().unwrap().len()
+        }
+    }
+}
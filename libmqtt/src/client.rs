@@ -0,0 +1,61 @@
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use bufpool::BufPool;
+use connopts::ConnectOptions;
+use ctrlpkt::{ConnAckRetCode, CtrlPkt, LARGE_PUBLISH_LEN};
+use error::{Error, Result};
+use pktstream::PacketStream;
+
+// Blocking helpers for the client side of a connection, shared by the bundled tools
+
+pub fn send<W: Write>(writer: &mut W, pkt: &CtrlPkt) -> Result<()> {
+    if is_large(pkt) {
+        return send_large(writer, pkt);
+    }
+    Ok(writer.write_all(&pkt.serialize()?)?)
+}
+
+// Like send, but encodes into a buffer from `pool` instead of allocating one
+pub fn send_pooled<W: Write>(writer: &mut W, pkt: &CtrlPkt, pool: &BufPool) -> Result<()> {
+    if is_large(pkt) {
+        return send_large(writer, pkt);
+    }
+    let mut buf = pool.take();
+    let res = pkt.serialize_into(&mut buf).and_then(|_| Ok(writer.write_all(&buf)?));
+    pool.put(buf);
+    res
+}
+
+fn is_large(pkt: &CtrlPkt) -> bool {
+    match *pkt {
+        CtrlPkt::Publish { ref payload, .. } => payload.len() >= LARGE_PUBLISH_LEN,
+        _ => false
+    }
+}
+
+// Encoding a large PUBLISH whole would copy its payload. Writing it through a small buffer passes
+// the payload straight on to `writer` instead.
+fn send_large<W: Write>(writer: &mut W, pkt: &CtrlPkt) -> Result<()> {
+    let mut writer = BufWriter::new(writer);
+    pkt.write_to(&mut writer)?;
+    Ok(writer.flush()?)
+}
+
+pub fn recv<R: Read>(pkts: &mut PacketStream<R>) -> Result<CtrlPkt> {
+    pkts.next().unwrap_or_else(|| Err(Error::Io(io::Error::new(ErrorKind::UnexpectedEof,
+        "connection closed by broker"))))
+}
+
+// Sends CONNECT and waits for an accepting CONNACK. Returns the stream for writing and a packet
+// stream over a clone of it for reading.
+pub fn connect<A: ToSocketAddrs>(addr: A, opts: &ConnectOptions)
+    -> Result<(TcpStream, PacketStream<TcpStream>)> {
+    let mut stream = TcpStream::connect(addr)?;
+    let mut pkts = PacketStream::new(stream.try_clone()?);
+    send(&mut stream, &opts.build()?)?;
+    match recv(&mut pkts)? {
+        CtrlPkt::ConnAck { return_code: ConnAckRetCode::Accepted, .. } => Ok((stream, pkts)),
+        CtrlPkt::ConnAck { return_code, .. } => Err(Error::ConnectionRefused(return_code)),
+        pkt => Err(Error::UnexpectedPkt(pkt))
+    }
+}
@@ -0,0 +1,139 @@
+use ctrlpkt::{CtrlPkt, ConnectFlags, ProtocolLv, QosLv};
+use error::{Error, Result};
+
+#[cfg(feature = "v4")]
+const DEFAULT_PROTOCOL_LV: ProtocolLv = ProtocolLv::V311;
+#[cfg(not(feature = "v4"))]
+const DEFAULT_PROTOCOL_LV: ProtocolLv = ProtocolLv::V31;
+
+#[derive(Debug, Clone)]
+pub struct Will {
+    pub topic: String,
+    pub message: Vec<u8>,
+    pub qos_lv: QosLv,
+    pub retain: bool
+}
+
+impl Will {
+    pub fn new(topic: String, message: Vec<u8>) -> Will {
+        Will { topic, message, qos_lv: QosLv::AtMostOnce, retain: false }
+    }
+
+    pub fn set_qos_lv(&mut self, qos_lv: QosLv) -> &mut Will {
+        self.qos_lv = qos_lv;
+        self
+    }
+
+    pub fn set_retain(&mut self, retain: bool) -> &mut Will {
+        self.retain = retain;
+        self
+    }
+
+    // Reads the will out of a decoded Connect packet, or None if the client didn't set one
+    pub fn from_connect(pkt: &CtrlPkt) -> Result<Option<Will>> {
+        match pkt {
+            &CtrlPkt::Connect {
+                connect_flags,
+                will_topic: Some(ref topic),
+                will_message: Some(ref message),
+                ..
+            } if connect_flags.contains(ConnectFlags::WILL_FLAG) => Ok(Some(Will {
+                topic: topic.clone(),
+                message: message.clone(),
+                qos_lv: connect_flags.will_qos_lv()?,
+                retain: connect_flags.contains(ConnectFlags::WILL_RETAIN)
+            })),
+            _ => Ok(None)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    protocol_lv: ProtocolLv,
+    client_id: String,
+    keep_alive: u16,
+    clean_session: bool,
+    will: Option<Will>,
+    username: Option<String>,
+    password: Option<Vec<u8>>
+}
+
+impl ConnectOptions {
+    pub fn new(client_id: String) -> ConnectOptions {
+        ConnectOptions {
+            protocol_lv: DEFAULT_PROTOCOL_LV,
+            client_id,
+            keep_alive: 0,
+            clean_session: true,
+            will: None,
+            username: None,
+            password: None
+        }
+    }
+
+    pub fn set_protocol_lv(&mut self, protocol_lv: ProtocolLv) -> &mut ConnectOptions {
+        self.protocol_lv = protocol_lv;
+        self
+    }
+
+    pub fn set_keep_alive(&mut self, keep_alive: u16) -> &mut ConnectOptions {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn set_clean_session(&mut self, clean_session: bool) -> &mut ConnectOptions {
+        self.clean_session = clean_session;
+        self
+    }
+
+    pub fn set_will(&mut self, will: Will) -> &mut ConnectOptions {
+        self.will = Some(will);
+        self
+    }
+
+    pub fn set_username(&mut self, username: String) -> &mut ConnectOptions {
+        self.username = Some(username);
+        self
+    }
+
+    pub fn set_password(&mut self, password: Vec<u8>) -> &mut ConnectOptions {
+        self.password = Some(password);
+        self
+    }
+
+    pub fn build(&self) -> Result<CtrlPkt> {
+        if self.client_id.len() == 0 &&
+            (!self.protocol_lv.allows_empty_client_id() || !self.clean_session) {
+            return Err(Error::IdRejected);
+        }
+        let mut connect_flags = ConnectFlags::empty();
+        if self.clean_session {
+            connect_flags |= ConnectFlags::CLEAN_SESSION;
+        }
+        if let Some(ref will) = self.will {
+            connect_flags |= ConnectFlags::WILL_FLAG;
+            connect_flags |= ConnectFlags::from_bits_truncate((will.qos_lv as u8) << 3);
+            if will.retain {
+                connect_flags |= ConnectFlags::WILL_RETAIN;
+            }
+        }
+        if self.username.is_some() {
+            connect_flags |= ConnectFlags::USERNAME_FLAG;
+        }
+        if self.password.is_some() {
+            connect_flags |= ConnectFlags::PASSWORD_FLAG;
+        }
+        connect_flags.validate()?;
+        Ok(CtrlPkt::Connect {
+            protocol_lv: self.protocol_lv,
+            connect_flags,
+            keep_alive: self.keep_alive,
+            client_id: self.client_id.clone(),
+            will_topic: self.will.as_ref().map(|will| will.topic.clone()),
+            will_message: self.will.as_ref().map(|will| will.message.clone()),
+            username: self.username.clone(),
+            password: self.password.clone()
+        })
+    }
+}
@@ -1,14 +1,15 @@
-use std::net::TcpStream;
 use std::io::{Read, Write};
 use std::slice::Iter;
 use std::convert::From;
 use std::iter::Iterator;
 use std::u16;
 use error::{Result, Error};
-use uuid::Uuid;
 use self::CtrlPkt::*;
 
 pub const MAX_PAYLOAD_SIZE: usize = 268435455;
+// PUBLISH packets with at least this many bytes after the fixed header have their payload read
+// straight into its own buffer, instead of into the packet buffer and then copied out of it
+pub const LARGE_PUBLISH_LEN: usize = 64 * 1024;
 
 bitflags! {
     pub struct ConnectFlags: u8 {
@@ -21,6 +22,40 @@ bitflags! {
     }
 }
 
+impl ConnectFlags {
+    // Decodes the flags byte of a CONNECT, rejecting the reserved bit and inconsistent flags
+    pub fn from_byte(byte: u8) -> Result<ConnectFlags> {
+        if byte & 0b00000001 != 0 {
+            return Err(Error::InvalidConnectReservedFlag);
+        }
+        let flags = ConnectFlags::from_bits_truncate(byte);
+        flags.validate()?;
+        Ok(flags)
+    }
+
+    pub fn will_qos_lv(&self) -> Result<QosLv> {
+        QosLv::from_int((*self & ConnectFlags::WILL_QOS).bits() >> 3)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.contains(ConnectFlags::WILL_FLAG) {
+            // QoS 3 doesn't exist
+            self.will_qos_lv().map_err(|_| Error::InvalidWillQos)?;
+        } else {
+            if self.contains(ConnectFlags::WILL_RETAIN) {
+                return Err(Error::InvalidWillRetain);
+            }
+            if self.intersects(ConnectFlags::WILL_QOS) {
+                return Err(Error::InvalidWillQos);
+            }
+        }
+        if self.contains(ConnectFlags::PASSWORD_FLAG) && !self.contains(ConnectFlags::USERNAME_FLAG) {
+            return Err(Error::PasswordWithoutUsername);
+        }
+        Ok(())
+    }
+}
+
 bitflags! {
     pub struct ConnAckFlags: u8 {
         const SESSION_PRESENT = 0b00000001;
@@ -62,7 +97,19 @@ impl From<QosLv> for SubAckRetCode {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+impl SubAckRetCode {
+    pub fn from_int(i: u8) -> Result<SubAckRetCode> {
+        match i {
+            0 => Ok(SubAckRetCode::MaxQos0),
+            1 => Ok(SubAckRetCode::MaxQos1),
+            2 => Ok(SubAckRetCode::MaxQos2),
+            0x80 => Ok(SubAckRetCode::Failure),
+            _ => Err(Error::InvalidSubAckRetCode)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ConnAckRetCode {
     Accepted = 0,
     UnacceptableProtocolVer = 1,
@@ -72,6 +119,20 @@ pub enum ConnAckRetCode {
     NotAuthorized = 5
 }
 
+impl ConnAckRetCode {
+    pub fn from_int(i: u8) -> Result<ConnAckRetCode> {
+        match i {
+            0 => Ok(ConnAckRetCode::Accepted),
+            1 => Ok(ConnAckRetCode::UnacceptableProtocolVer),
+            2 => Ok(ConnAckRetCode::IdRejected),
+            3 => Ok(ConnAckRetCode::ServerUnavailable),
+            4 => Ok(ConnAckRetCode::BadUsernameOrPassword),
+            5 => Ok(ConnAckRetCode::NotAuthorized),
+            _ => Err(Error::InvalidConnAckRetCode)
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum CtrlPktType {
     Connect = 1,
@@ -90,7 +151,108 @@ pub enum CtrlPktType {
     Disconnect = 14
 }
 
+impl CtrlPktType {
+    // The flags the spec requires in the low nibble of the fixed header, or None for PUBLISH,
+    // whose flags carry DUP, QoS and RETAIN
+    pub fn reserved_flags(&self) -> Option<u8> {
+        match self {
+            &CtrlPktType::Publish => None,
+            &CtrlPktType::PubRel | &CtrlPktType::Subscribe | &CtrlPktType::Unsubscribe =>
+                Some(0b0010),
+            _ => Some(0)
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CtrlPktType::Connect => "CONNECT",
+            CtrlPktType::ConnAck => "CONNACK",
+            CtrlPktType::Publish => "PUBLISH",
+            CtrlPktType::PubAck => "PUBACK",
+            CtrlPktType::PubRec => "PUBREC",
+            CtrlPktType::PubRel => "PUBREL",
+            CtrlPktType::PubComp => "PUBCOMP",
+            CtrlPktType::Subscribe => "SUBSCRIBE",
+            CtrlPktType::SubAck => "SUBACK",
+            CtrlPktType::Unsubscribe => "UNSUBSCRIBE",
+            CtrlPktType::UnsubAck => "UNSUBACK",
+            CtrlPktType::PingReq => "PINGREQ",
+            CtrlPktType::PingResp => "PINGRESP",
+            CtrlPktType::Disconnect => "DISCONNECT"
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ProtocolLv {
+    #[cfg(feature = "v3")]
+    V31 = 3,
+    #[cfg(feature = "v4")]
+    V311 = 4
+}
+
+impl ProtocolLv {
+    // The protocol levels this build was compiled with
+    pub fn supported() -> Vec<ProtocolLv> {
+        let mut protocol_lvs = vec![];
+        #[cfg(feature = "v3")]
+        protocol_lvs.push(ProtocolLv::V31);
+        #[cfg(feature = "v4")]
+        protocol_lvs.push(ProtocolLv::V311);
+        protocol_lvs
+    }
+
+    pub fn version(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "v3")]
+            ProtocolLv::V31 => "3.1",
+            #[cfg(feature = "v4")]
+            ProtocolLv::V311 => "3.1.1"
+        }
+    }
+
+    pub fn from_protocol(name: &str, lv: u8) -> Result<ProtocolLv> {
+        match (name, lv) {
+            #[cfg(feature = "v3")]
+            ("MQIsdp", 3) => Ok(ProtocolLv::V31),
+            #[cfg(feature = "v4")]
+            ("MQTT", 4) => Ok(ProtocolLv::V311),
+            ("MQIsdp", _) | ("MQTT", _) => Err(Error::UnacceptableProtocolLv),
+            _ => Err(Error::InvalidProtocol)
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "v3")]
+            ProtocolLv::V31 => "MQIsdp",
+            #[cfg(feature = "v4")]
+            ProtocolLv::V311 => "MQTT"
+        }
+    }
+
+    // MQTT 3.1 requires a 1-23 character client id
+    pub fn allows_empty_client_id(&self) -> bool {
+        match *self {
+            #[cfg(feature = "v3")]
+            ProtocolLv::V31 => false,
+            #[cfg(feature = "v4")]
+            ProtocolLv::V311 => true
+        }
+    }
+
+    // MQTT 3.1 CONNACK has no session present flag
+    pub fn has_session_present(&self) -> bool {
+        match *self {
+            #[cfg(feature = "v3")]
+            ProtocolLv::V31 => false,
+            #[cfg(feature = "v4")]
+            ProtocolLv::V311 => true
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum QosLv {
     AtMostOnce = 0,
     AtLeastOnce = 1,
@@ -111,6 +273,7 @@ impl QosLv {
 #[derive(Debug, Clone)]
 pub enum CtrlPkt {
     Connect {
+        protocol_lv: ProtocolLv,
         connect_flags: ConnectFlags,
         keep_alive: u16,
         client_id: String,
@@ -130,43 +293,57 @@ pub enum CtrlPkt {
     },
     PubAck(u16),
     PubRec(u16),
-    PubRel,
-    PubComp,
+    PubRel(u16),
+    PubComp(u16),
     Subscribe { pkt_id: u16, subs: Vec<(String, QosLv)> },
     SubAck { pkt_id: u16, sub_ack_ret_codes: Vec<SubAckRetCode> },
-    Unsubscribe,
-    UnsubAck,
+    Unsubscribe { pkt_id: u16, topic_filters: Vec<String> },
+    UnsubAck(u16),
     PingReq,
     PingResp,
     Disconnect
 }
 
 impl CtrlPkt {
-    pub fn deserialize(stream: &mut TcpStream) -> Result<CtrlPkt> {
+    pub fn deserialize<R: Read>(stream: &mut R) -> Result<CtrlPkt> {
         let (ty, flags) = stream.read_header()?;
+        CtrlPkt::deserialize_body(ty, flags, stream)
+    }
+
+    pub fn deserialize_body<R: Read>(ty: CtrlPktType, flags: u8, stream: &mut R)
+        -> Result<CtrlPkt> {
+        CtrlPkt::deserialize_body_in(ty, flags, stream, &mut vec![])
+    }
+
+    // Like deserialize_body, but reads the packet into `buf` so the caller can reuse it
+    pub fn deserialize_body_in<R: Read>(ty: CtrlPktType, flags: u8, stream: &mut R,
+                                        buf: &mut Vec<u8>) -> Result<CtrlPkt> {
+        match ty.reserved_flags() {
+            Some(reserved_flags) if flags != reserved_flags =>
+                return Err(Error::InvalidFixedHeaderFlags),
+            _ => ()
+        }
         let remaining_len = stream.read_remaining_len()?;
-        let data = stream.read_len(remaining_len)?;
-        let mut iter = data.iter();
+        if let (CtrlPktType::Publish, true) = (ty, remaining_len >= LARGE_PUBLISH_LEN) {
+            return CtrlPkt::deserialize_large_publish(flags, remaining_len, stream, buf);
+        }
+        buf.clear();
+        buf.resize(remaining_len, 0);
+        stream.read_exact(buf)?;
+        let mut iter = buf.iter();
         match ty {
             CtrlPktType::Connect => {
                 let protocol = iter.read_str()?;
-                if protocol != "MQTT" {
-                    return Err(Error::InvalidProtocol);
-                }
-                let protocol_lv = iter.read_protocol_lv()?;
-                if protocol_lv != 4 {
-                    return Err(Error::UnacceptableProtocolLv);
-                }
-                let connect_flags = ConnectFlags::from_bits_truncate(iter.read_u8()?);
+                let protocol_lv = ProtocolLv::from_protocol(&protocol, iter.read_protocol_lv()?)?;
+                let connect_flags = ConnectFlags::from_byte(iter.read_u8()?)?;
                 let keep_alive = iter.read_u16()?;
 
-                let mut client_id = iter.read_str()?;
-                if client_id.len() == 0 {
-                    if !connect_flags.contains(ConnectFlags::CLEAN_SESSION) {
-                        return Err(Error::IdRejected);
-                    }
-                    client_id = Uuid::new_v4().hyphenated().to_string();
-                };
+                // An empty client id is left for the server to assign
+                let client_id = iter.read_str()?;
+                if client_id.len() == 0 && (!protocol_lv.allows_empty_client_id() ||
+                    !connect_flags.contains(ConnectFlags::CLEAN_SESSION)) {
+                    return Err(Error::IdRejected);
+                }
                 let (will_topic, will_message) = if connect_flags.contains(ConnectFlags::WILL_FLAG) {
                     (Some(iter.read_str()?), Some(iter.read_len_data()?))
                 } else {
@@ -182,33 +359,40 @@ impl CtrlPkt {
                 } else {
                     None
                 };
-                Ok(Connect { connect_flags, keep_alive, client_id, will_topic, will_message,
-                    username, password })
+                Ok(Connect { protocol_lv, connect_flags, keep_alive, client_id, will_topic,
+                    will_message, username, password })
             }
             CtrlPktType::Publish => {
-                let flags = PublishFlags::from_bits_truncate(flags);
-                let dup = flags.contains(PublishFlags::DUP);
-                let qos_lv = QosLv::from_int((flags & PublishFlags::QOS_LV).bits() >> 1)?;
-                let retain = flags.contains(PublishFlags::RETAIN);
-                let (topic_name, len) = iter.read_str_get_len()?;
-                let pkt_id = if qos_lv == QosLv::AtLeastOnce || qos_lv == QosLv::ExactlyOnce {
-                    Some(iter.read_u16()?)
-                } else {
-                    None
-                };
-                let payload_len = remaining_len - (len as usize + 2);
+                let (dup, qos_lv, retain) = publish_flags(flags)?;
+                let (topic_name, pkt_id, header_len) = read_publish_header(qos_lv, &mut iter)?;
+                let payload_len = remaining_len.checked_sub(header_len).ok_or(Error::ReadErr)?;
                 let payload = iter.read_len(payload_len)?;
                 Ok(Publish { dup, qos_lv, retain, topic_name, pkt_id, payload })
             }
+            CtrlPktType::ConnAck => {
+                let flags = ConnAckFlags::from_bits_truncate(iter.read_u8()?);
+                let return_code = ConnAckRetCode::from_int(iter.read_u8()?)?;
+                Ok(ConnAck { session_present: flags.contains(ConnAckFlags::SESSION_PRESENT),
+                    return_code })
+            }
             CtrlPktType::PubAck => {
-                let pkt_id = iter.read_u16()?;
+                let pkt_id = iter.read_pkt_id()?;
                 Ok(PubAck(pkt_id))
             }
-            CtrlPktType::Subscribe => {
-                if flags != 0b0010 {
-                    return Err(Error::InvalidFixedHeaderFlags);
+            CtrlPktType::PubRec => Ok(PubRec(iter.read_pkt_id()?)),
+            CtrlPktType::PubRel => Ok(PubRel(iter.read_pkt_id()?)),
+            CtrlPktType::PubComp => Ok(PubComp(iter.read_pkt_id()?)),
+            CtrlPktType::SubAck => {
+                let pkt_id = iter.read_pkt_id()?;
+                let mut sub_ack_ret_codes = vec![];
+                // - 2 because of packet id
+                for _ in 0..remaining_len.saturating_sub(2) {
+                    sub_ack_ret_codes.push(SubAckRetCode::from_int(iter.read_u8()?)?);
                 }
-                let pkt_id = iter.read_u16()?;
+                Ok(SubAck { pkt_id, sub_ack_ret_codes })
+            }
+            CtrlPktType::Subscribe => {
+                let pkt_id = iter.read_pkt_id()?;
                 // - 2 because of packet id
                 // Error if no topic filters are found
                 if remaining_len <= 2 {
@@ -224,55 +408,152 @@ impl CtrlPkt {
                     }
                     let requested_qos = QosLv::from_int(requested_qos_byte & 0b11)?;
                     // + 1 for requested QoS
-                    topic_filters_len += (topic_filter_len as usize) + 1;
+                    topic_filters_len += topic_filter_len + 1;
                     subs.push((topic_filter, requested_qos));
                 }
                 Ok(Subscribe { pkt_id, subs })
             }
+            CtrlPktType::Unsubscribe => {
+                let pkt_id = iter.read_pkt_id()?;
+                if remaining_len <= 2 {
+                    return Err(Error::UnsubscribeMissingTopicFilters);
+                }
+                let mut topic_filters = vec![];
+                let mut topic_filters_len = 0;
+                while remaining_len - 2 - topic_filters_len > 0 {
+                    let (topic_filter, topic_filter_len) = iter.read_str_get_len()?;
+                    topic_filters_len += topic_filter_len;
+                    topic_filters.push(topic_filter);
+                }
+                Ok(Unsubscribe { pkt_id, topic_filters })
+            }
+            CtrlPktType::UnsubAck => Ok(UnsubAck(iter.read_pkt_id()?)),
             CtrlPktType::PingReq => Ok(PingReq),
-            CtrlPktType::Disconnect => Ok(Disconnect),
-            pkt_type => Err(Error::UnimplementedPktType(pkt_type))
+            CtrlPktType::PingResp => Ok(PingResp),
+            CtrlPktType::Disconnect => Ok(Disconnect)
+        }
+    }
+
+    // Reads the topic name and packet id into `buf`, and the payload from the stream into a buffer
+    // of its own, so a multi-megabyte payload is only held once
+    fn deserialize_large_publish<R: Read>(flags: u8, remaining_len: usize, stream: &mut R,
+                                          buf: &mut Vec<u8>) -> Result<CtrlPkt> {
+        let (dup, qos_lv, retain) = publish_flags(flags)?;
+        buf.clear();
+        buf.resize(2, 0);
+        stream.read_exact(buf)?;
+        let topic_name_len = ((buf[0] as usize) << 8) + buf[1] as usize;
+        let header_len = 2 + topic_name_len + if qos_lv == QosLv::AtMostOnce { 0 } else { 2 };
+        if header_len > remaining_len {
+            return Err(Error::ReadErr);
+        }
+        buf.resize(header_len, 0);
+        stream.read_exact(&mut buf[2..])?;
+        let (topic_name, pkt_id, _) = read_publish_header(qos_lv, &mut buf.iter())?;
+        let mut payload = vec![0; remaining_len - header_len];
+        stream.read_exact(&mut payload)?;
+        Ok(Publish { dup, qos_lv, retain, topic_name, pkt_id, payload })
+    }
+
+    pub fn pkt_type(&self) -> CtrlPktType {
+        match *self {
+            Connect { .. } => CtrlPktType::Connect,
+            ConnAck { .. } => CtrlPktType::ConnAck,
+            Publish { .. } => CtrlPktType::Publish,
+            PubAck(_) => CtrlPktType::PubAck,
+            PubRec(_) => CtrlPktType::PubRec,
+            PubRel(_) => CtrlPktType::PubRel,
+            PubComp(_) => CtrlPktType::PubComp,
+            Subscribe { .. } => CtrlPktType::Subscribe,
+            SubAck { .. } => CtrlPktType::SubAck,
+            Unsubscribe { .. } => CtrlPktType::Unsubscribe,
+            UnsubAck(_) => CtrlPktType::UnsubAck,
+            PingReq => CtrlPktType::PingReq,
+            PingResp => CtrlPktType::PingResp,
+            Disconnect => CtrlPktType::Disconnect
         }
     }
 
     pub fn serialize(&self) -> Result<Vec<u8>> {
         let mut buf = vec![];
+        self.serialize_into(&mut buf)?;
+        Ok(buf)
+    }
+
+    // Replaces the contents of `buf` with the encoded packet
+    pub fn serialize_into(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.clear();
+        self.write_to(buf)
+    }
+
+    pub fn write_to<W: Write>(&self, buf: &mut W) -> Result<()> {
         buf.write_header(self)?;
         match self {
+            &Connect {
+                protocol_lv,
+                connect_flags,
+                keep_alive,
+                ref client_id,
+                ref will_topic,
+                ref will_message,
+                ref username,
+                ref password
+            } => {
+                let mut body: Vec<u8> = vec![];
+                body.write_str(protocol_lv.name())?;
+                body.write_u8(protocol_lv as u8)?;
+                body.write_u8(connect_flags.bits())?;
+                body.write_u16(keep_alive)?;
+                body.write_str(client_id)?;
+                if let (&Some(ref will_topic), &Some(ref will_message)) = (will_topic, will_message) {
+                    body.write_str(will_topic)?;
+                    body.write_len_data(will_message)?;
+                }
+                if let &Some(ref username) = username {
+                    body.write_str(username)?;
+                }
+                if let &Some(ref password) = password {
+                    body.write_len_data(password)?;
+                }
+                buf.write_remaining_len(body.len())?;
+                buf.write_all(&body)?;
+                Ok(())
+            }
             &ConnAck { session_present, return_code } => {
                 buf.write_remaining_len(2)?;
                 buf.write_u8(session_present as u8)?;
                 buf.write_u8(return_code as u8)?;
-                Ok(buf)
+                Ok(())
             }
-            &PingResp => {
+            &PingReq | &PingResp | &Disconnect => {
                 buf.write_remaining_len(0)?;
-                Ok(buf)
-            }
-            &Publish { ref topic_name, pkt_id, ref payload, .. } => {
-                let topic_name_len = topic_name.as_bytes().len() + 2;
-                let mut remaining_len = topic_name_len + payload.len();
-                // Add 2 for packet id if it exists
-                if pkt_id.is_some() {
-                    remaining_len += 2;
-                }
-                buf.write_remaining_len(remaining_len)?;
-                buf.write_str(topic_name)?;
-                if pkt_id.is_some() {
-                    buf.write_u16(pkt_id.unwrap())?;
-                }
-                buf.write_all(&payload)?;
-                Ok(buf)
+                Ok(())
             }
+            &Publish { ref topic_name, pkt_id, ref payload, .. } =>
+                write_publish_body(buf, topic_name, pkt_id, payload),
             &PubAck(id) => {
                 buf.write_remaining_len(2)?;
                 buf.write_u16(id)?;
-                Ok(buf)
+                Ok(())
             }
-            &PubRec(id) => {
+            &PubRec(id) | &PubRel(id) | &PubComp(id) => {
                 buf.write_remaining_len(2)?;
                 buf.write_u16(id)?;
-                Ok(buf)
+                Ok(())
+            }
+            &Subscribe { pkt_id, ref subs } => {
+                let mut remaining_len = 2;
+                for &(ref topic_filter, _) in subs {
+                    // + 1 for requested QoS
+                    remaining_len += topic_filter.as_bytes().len() + 2 + 1;
+                }
+                buf.write_remaining_len(remaining_len)?;
+                buf.write_u16(pkt_id)?;
+                for &(ref topic_filter, requested_qos_lv) in subs {
+                    buf.write_str(topic_filter)?;
+                    buf.write_u8(requested_qos_lv as u8)?;
+                }
+                Ok(())
             }
             &SubAck { pkt_id, ref sub_ack_ret_codes } => {
                 buf.write_remaining_len(2 + sub_ack_ret_codes.len())?;
@@ -280,40 +561,122 @@ impl CtrlPkt {
                 for sub_ack in sub_ack_ret_codes {
                     buf.write_u8(*sub_ack as u8)?;
                 }
-                Ok(buf)
+                Ok(())
+            }
+            &Unsubscribe { pkt_id, ref topic_filters } => {
+                let remaining_len = 2 + topic_filters.iter()
+                    .map(|topic_filter| topic_filter.as_bytes().len() + 2)
+                    .sum::<usize>();
+                buf.write_remaining_len(remaining_len)?;
+                buf.write_u16(pkt_id)?;
+                for topic_filter in topic_filters {
+                    buf.write_str(topic_filter)?;
+                }
+                Ok(())
+            }
+            &UnsubAck(id) => {
+                buf.write_remaining_len(2)?;
+                buf.write_u16(id)?;
+                Ok(())
             }
-            pkt => Err(Error::UnimplementedPkt(pkt.clone()))
         }
     }
 }
 
+// A PUBLISH that borrows its topic name and payload, so that one payload can be sent to many
+// clients without copying it into a CtrlPkt for each of them
+#[derive(Debug, Copy, Clone)]
+pub struct PublishRef<'a> {
+    pub dup: bool,
+    pub qos_lv: QosLv,
+    pub retain: bool,
+    pub topic_name: &'a str,
+    pub pkt_id: Option<u16>,
+    pub payload: &'a [u8]
+}
+
+impl<'a> PublishRef<'a> {
+    // Encodes the same bytes as the equivalent CtrlPkt::Publish
+    pub fn write_to<W: Write>(&self, buf: &mut W) -> Result<()> {
+        buf.write_u8(publish_fixed_header(self.dup, self.qos_lv, self.retain))?;
+        write_publish_body(buf, self.topic_name, self.pkt_id, self.payload)
+    }
+}
+
+fn publish_flags(flags: u8) -> Result<(bool, QosLv, bool)> {
+    let flags = PublishFlags::from_bits_truncate(flags);
+    let qos_lv = QosLv::from_int((flags & PublishFlags::QOS_LV).bits() >> 1)?;
+    Ok((flags.contains(PublishFlags::DUP), qos_lv, flags.contains(PublishFlags::RETAIN)))
+}
+
+// Returns the topic name, the packet id, and how many bytes they took up
+fn read_publish_header(qos_lv: QosLv, iter: &mut Iter<u8>) -> Result<(String, Option<u16>, usize)> {
+    let (topic_name, len) = iter.read_str_get_len()?;
+    // The packet id is only present for QoS 1 and 2
+    if qos_lv == QosLv::AtMostOnce {
+        Ok((topic_name, None, len))
+    } else {
+        Ok((topic_name, Some(iter.read_pkt_id()?), len + 2))
+    }
+}
+
+fn publish_fixed_header(dup: bool, qos_lv: QosLv, retain: bool) -> u8 {
+    let mut low_bits = PublishFlags::empty();
+    if retain {
+        low_bits |= PublishFlags::RETAIN;
+    }
+    low_bits |= PublishFlags::from_bits_truncate((qos_lv as u8) << 1);
+    if dup {
+        low_bits |= PublishFlags::DUP;
+    }
+    ((CtrlPktType::Publish as u8) << 4) + PublishFlags::bits(&low_bits)
+}
+
+fn write_publish_body<W: Write>(buf: &mut W, topic_name: &str, pkt_id: Option<u16>,
+                                payload: &[u8]) -> Result<()> {
+    let mut remaining_len = topic_name.as_bytes().len() + 2 + payload.len();
+    // Add 2 for packet id if it exists
+    if pkt_id.is_some() {
+        remaining_len += 2;
+    }
+    buf.write_remaining_len(remaining_len)?;
+    buf.write_str(topic_name)?;
+    if let Some(pkt_id) = pkt_id {
+        buf.write_u16(pkt_id)?;
+    }
+    buf.write_all(payload)?;
+    Ok(())
+}
+
 pub trait MqttWrite: Write {
     fn write_header(&mut self, pkt: &CtrlPkt) -> Result<()>;
     fn write_remaining_len(&mut self, len: usize) -> Result<()>;
     fn write_u8(&mut self, i: u8) -> Result<()>;
     fn write_u16(&mut self, i: u16) -> Result<()>;
     fn write_str(&mut self, s: &str) -> Result<()>;
+    fn write_len_data(&mut self, data: &[u8]) -> Result<()>;
 }
 
-impl MqttWrite for Vec<u8> {
+impl<W: Write> MqttWrite for W {
     fn write_header(&mut self, pkt: &CtrlPkt) -> Result<()> {
         match pkt {
+            &Connect { .. } => {
+                self.write_u8((CtrlPktType::Connect as u8) << 4)
+            }
             &ConnAck { .. } => {
                 self.write_u8((CtrlPktType::ConnAck as u8) << 4)
             }
+            &PingReq => {
+                self.write_u8((CtrlPktType::PingReq as u8) << 4)
+            }
             &PingResp => {
                 self.write_u8((CtrlPktType::PingResp as u8) << 4)
             }
+            &Disconnect => {
+                self.write_u8((CtrlPktType::Disconnect as u8) << 4)
+            }
             &Publish { dup, qos_lv, retain, .. } => {
-                let mut low_bits = PublishFlags::empty();
-                if retain {
-                    low_bits |= PublishFlags::RETAIN;
-                }
-                low_bits |= PublishFlags::from_bits_truncate((qos_lv as u8) << 1);
-                if dup {
-                    low_bits |= PublishFlags::DUP;
-                }
-                self.write_u8(((CtrlPktType::Publish as u8) << 4) + PublishFlags::bits(&low_bits))
+                self.write_u8(publish_fixed_header(dup, qos_lv, retain))
             }
             &PubAck(..) => {
                 self.write_u8((CtrlPktType::PubAck as u8) << 4)
@@ -321,10 +684,24 @@ impl MqttWrite for Vec<u8> {
             &PubRec(..) => {
                 self.write_u8((CtrlPktType::PubRec as u8) << 4)
             }
+            &PubRel(..) => {
+                self.write_u8(((CtrlPktType::PubRel as u8) << 4) + 0b0010)
+            }
+            &PubComp(..) => {
+                self.write_u8((CtrlPktType::PubComp as u8) << 4)
+            }
+            &Subscribe { .. } => {
+                self.write_u8(((CtrlPktType::Subscribe as u8) << 4) + 0b0010)
+            }
             &SubAck { .. } => {
                 self.write_u8((CtrlPktType::SubAck as u8) << 4)
             }
-            pkt => Err(Error::UnimplementedPkt(pkt.clone()))
+            &Unsubscribe { .. } => {
+                self.write_u8(((CtrlPktType::Unsubscribe as u8) << 4) + 0b0010)
+            }
+            &UnsubAck(..) => {
+                self.write_u8((CtrlPktType::UnsubAck as u8) << 4)
+            }
         }
     }
 
@@ -347,7 +724,7 @@ impl MqttWrite for Vec<u8> {
     }
 
     fn write_u16(&mut self, i: u16) -> Result<()> {
-        let msb = ((i & 0xff00) >> 4) as u8;
+        let msb = ((i & 0xff00) >> 8) as u8;
         let lsb = (i & 0x00ff) as u8;
         self.write_u8(msb)?;
         self.write_u8(lsb)
@@ -362,6 +739,14 @@ impl MqttWrite for Vec<u8> {
         self.write_u16(len as u16)?;
         Ok(self.write_all(bytes)?)
     }
+
+    fn write_len_data(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() > (u16::MAX as usize) {
+            return Err(Error::PayloadTooLong);
+        }
+        self.write_u16(data.len() as u16)?;
+        Ok(self.write_all(data)?)
+    }
 }
 
 pub trait MqttRead: Read {
@@ -372,18 +757,18 @@ pub trait MqttRead: Read {
 
 pub trait MqttReadIterator: Iterator {
     fn read_str(&mut self) -> Result<String>;
-    fn read_str_get_len(&mut self) -> Result<(String, u16)>;
+    fn read_str_get_len(&mut self) -> Result<(String, usize)>;
     fn read_protocol_lv(&mut self) -> Result<u8>;
     fn read_len(&mut self, len: usize) -> Result<Vec<u8>>;
     fn read_len_data(&mut self) -> Result<Vec<u8>>;
     fn read_u8(&mut self) -> Result<u8>;
     fn read_u16(&mut self) -> Result<u16>;
+    fn read_pkt_id(&mut self) -> Result<u16>;
 }
 
-impl MqttRead for TcpStream {
+impl<R: Read> MqttRead for R {
     fn read_header(&mut self) -> Result<(CtrlPktType, u8)> {
         let header = try!(self.read_len(1));
-        println!("header: {:#010b}", header[0]);
         let ty = try!(match header[0] >> 4 {
             1 => Ok(CtrlPktType::Connect),
             2 => Ok(CtrlPktType::ConnAck),
@@ -406,19 +791,18 @@ impl MqttRead for TcpStream {
     }
 
     fn read_remaining_len(&mut self) -> Result<usize> {
-        let mut done = false;
         let mut multiplier: usize = 1;
         let mut value: usize = 0;
-        while !done {
+        // At most 4 bytes; a continuation bit on the 4th is malformed
+        for _ in 0..4 {
             let encoded_byte = self.read_len(1)?[0];
             value += ((encoded_byte & 127) as usize) * multiplier;
-            multiplier *= 128;
-            if multiplier > 128 * 128 * 128 {
-                return Err(Error::MalformedRemainingLen);
+            if (encoded_byte & 128) == 0 {
+                return Ok(value);
             }
-            done = (encoded_byte & 128) == 0;
+            multiplier *= 128;
         }
-        Ok(value)
+        Err(Error::MalformedRemainingLen)
     }
 
     fn read_len(&mut self, len: usize) -> Result<Vec<u8>> {
@@ -433,9 +817,10 @@ impl<'a> MqttReadIterator for Iter<'a, u8> {
         Ok(self.read_str_get_len()?.0)
     }
 
-    fn read_str_get_len(&mut self) -> Result<(String, u16)> {
-        let len = self.read_u16()?;
-        let str_buf = self.read_len(len as usize)?;
+    // Also returns the encoded length, including the 2 length bytes
+    fn read_str_get_len(&mut self) -> Result<(String, usize)> {
+        let len = self.read_u16()? as usize;
+        let str_buf = self.read_len(len)?;
         Ok((String::from_utf8(str_buf)?, len + 2))
     }
 
@@ -466,4 +851,12 @@ impl<'a> MqttReadIterator for Iter<'a, u8> {
         let lsb = *self.next().ok_or(Error::ReadErr)?;
         Ok(((msb as u16) << 8) + lsb as u16)
     }
+
+    // The spec requires packet ids to be non-zero
+    fn read_pkt_id(&mut self) -> Result<u16> {
+        match self.read_u16()? {
+            0 => Err(Error::ZeroPktId),
+            pkt_id => Ok(pkt_id)
+        }
+    }
 }
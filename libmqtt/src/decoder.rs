@@ -0,0 +1,66 @@
+use std::io::Cursor;
+use ctrlpkt::CtrlPkt;
+use error::{Error, Result};
+
+// Decodes packets from bytes pushed in pieces of any size, down to one byte at a time, for
+// transports that can't block until a whole packet has arrived the way PacketStream does. Bytes are
+// buffered until the packet they belong to is complete.
+//
+// As with PacketStream, only an UnimplementedPktType error leaves the decoder at the start of the
+// next packet; after any other error its bytes can no longer be trusted.
+pub struct PacketDecoder {
+    buf: Vec<u8>
+}
+
+impl PacketDecoder {
+    pub fn new() -> PacketDecoder {
+        PacketDecoder { buf: vec![] }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    // The next complete packet, or None until more bytes are pushed
+    pub fn next_pkt(&mut self) -> Option<Result<CtrlPkt>> {
+        let pkt_len = match pkt_len(&self.buf) {
+            Ok(Some(pkt_len)) => pkt_len,
+            Ok(None) => return None,
+            Err(e) => return Some(Err(e))
+        };
+        let res = CtrlPkt::deserialize(&mut Cursor::new(&self.buf[..pkt_len]));
+        self.buf.drain(..pkt_len);
+        Some(res)
+    }
+
+    // Bytes pushed that aren't part of a decoded packet yet
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+impl Default for PacketDecoder {
+    fn default() -> PacketDecoder {
+        PacketDecoder::new()
+    }
+}
+
+// The length of the packet at the start of `buf`, fixed header included, or None if `buf` doesn't
+// hold all of it yet
+fn pkt_len(buf: &[u8]) -> Result<Option<usize>> {
+    let (mut multiplier, mut remaining_len) = (1, 0);
+    // The remaining length follows the first byte and takes at most 4 bytes
+    for i in 1..5 {
+        let encoded_byte = match buf.get(i) {
+            Some(&encoded_byte) => encoded_byte,
+            None => return Ok(None)
+        };
+        remaining_len += ((encoded_byte & 127) as usize) * multiplier;
+        if (encoded_byte & 128) == 0 {
+            let pkt_len = i + 1 + remaining_len;
+            return Ok(if buf.len() >= pkt_len { Some(pkt_len) } else { None });
+        }
+        multiplier *= 128;
+    }
+    Err(Error::MalformedRemainingLen)
+}
@@ -1,7 +1,7 @@
 use std::result;
 use std::io;
 use std::string;
-use ctrlpkt::{CtrlPkt, CtrlPktType};
+use ctrlpkt::{ConnAckRetCode, CtrlPkt, CtrlPktType};
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -19,11 +19,22 @@ pub enum Error {
     UnacceptableProtocolLv,
     IdRejected,
     InvalidWillRetain,
+    InvalidWillQos,
+    PasswordWithoutUsername,
+    InvalidConnectReservedFlag,
     InvalidQosLv,
     InvalidFixedHeaderFlags,
     SubscribeMissingTopicFilters,
     SubscribeInvalidRequestedQos,
+    UnsubscribeMissingTopicFilters,
+    ZeroPktId,
     PublishOutOfPktIds,
+    PublishTopicHasWildcards,
+    InvalidTopicFilter,
+    InvalidConnAckRetCode,
+    InvalidSubAckRetCode,
+    ConnectionRefused(ConnAckRetCode),
+    UnexpectedPkt(CtrlPkt),
 
     UnimplementedPkt(CtrlPkt),
     UnimplementedPktType(CtrlPktType),
@@ -1,6 +1,14 @@
 #[macro_use] extern crate bitflags;
 extern crate rand;
-extern crate uuid;
+
+#[cfg(not(any(feature = "v3", feature = "v4")))]
+compile_error!("at least one of the `v3` and `v4` features must be enabled");
+
+pub mod bufpool;
+pub mod client;
+pub mod connopts;
 pub mod ctrlpkt;
+pub mod decoder;
 pub mod error;
 pub mod pktid;
+pub mod pktstream;
@@ -15,8 +15,9 @@ impl PktIdGen {
         if self.in_use.len() == (u16::MAX as usize) {
             return None;
         }
+        // 0 isn't a valid packet id, which leaves u16::MAX of them
         let mut i = rand::random::<u16>();
-        while self.in_use.contains(&i) {
+        while i == 0 || self.in_use.contains(&i) {
             i = rand::random::<u16>();
         }
         self.in_use.insert(i);
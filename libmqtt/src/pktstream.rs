@@ -0,0 +1,76 @@
+use std::io::{ErrorKind, Read};
+use std::mem;
+use std::sync::Arc;
+use bufpool::BufPool;
+use ctrlpkt::{CtrlPkt, MqttRead};
+use error::{Error, Result};
+
+// Yields packets read from `reader` until it is closed at a packet boundary. Iteration stops after
+// the first error since the reader can no longer be assumed to be at the start of a packet, except
+// for packets of an unimplemented type, which are read in full before they're rejected.
+//
+// Packets are read into a buffer that is reused for the next packet, either the stream's own or
+// one borrowed from a pool shared with other streams.
+pub struct PacketStream<R> {
+    reader: R,
+    done: bool,
+    buf: Vec<u8>,
+    pool: Option<Arc<BufPool>>
+}
+
+impl<R: Read> PacketStream<R> {
+    pub fn new(reader: R) -> PacketStream<R> {
+        PacketStream { reader, done: false, buf: vec![], pool: None }
+    }
+
+    // Borrows a buffer from `pool` for each packet, so idle streams don't hold on to one
+    pub fn with_pool(reader: R, pool: Arc<BufPool>) -> PacketStream<R> {
+        PacketStream { reader, done: false, buf: vec![], pool: Some(pool) }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Iterator for PacketStream<R> {
+    type Item = Result<CtrlPkt>;
+
+    fn next(&mut self) -> Option<Result<CtrlPkt>> {
+        if self.done {
+            return None;
+        }
+        let res = match self.reader.read_header() {
+            Err(Error::Io(ref e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                self.done = true;
+                return None;
+            }
+            Err(e) => Err(e),
+            Ok((ty, flags)) => {
+                let mut buf = match self.pool {
+                    Some(ref pool) => pool.take(),
+                    None => mem::replace(&mut self.buf, vec![])
+                };
+                let res = CtrlPkt::deserialize_body_in(ty, flags, &mut self.reader, &mut buf);
+                match self.pool {
+                    Some(ref pool) => pool.put(buf),
+                    None => self.buf = buf
+                }
+                res
+            }
+        };
+        match res {
+            Err(Error::UnimplementedPktType(_)) | Ok(_) => (),
+            Err(_) => self.done = true
+        }
+        Some(res)
+    }
+}
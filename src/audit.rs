@@ -0,0 +1,51 @@
+use std::fmt;
+use std::sync::Arc;
+use uuid;
+
+// Audit mode follows messages from clients through the broker, to track down reports of messages
+// that disappeared. Each message gets an id when it arrives, and every step it takes (queued for
+// or delivered to a subscriber, acknowledged, dropped and why) is recorded as a line starting
+// with `audit <id>`.
+
+// Where audit lines go. The broker prints them to stdout; embedders can collect them instead.
+#[derive(Clone)]
+pub struct AuditSink(Arc<dyn Fn(&str) + Send + Sync>);
+
+impl AuditSink {
+    pub fn new<F: Fn(&str) + Send + Sync + 'static>(sink: F) -> AuditSink {
+        AuditSink(Arc::new(sink))
+    }
+
+    pub fn stdout() -> AuditSink {
+        AuditSink::new(|line| println!("{}", line))
+    }
+}
+
+impl fmt::Debug for AuditSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AuditSink")
+    }
+}
+
+// The audit trail of one message, carried along with it
+#[derive(Clone)]
+pub struct Trace {
+    id: String,
+    sink: AuditSink
+}
+
+impl Trace {
+    pub fn new(sink: &AuditSink) -> Trace {
+        Trace { id: uuid::new_v4(), sink: sink.clone() }
+    }
+
+    pub fn record(&self, event: fmt::Arguments) {
+        (self.sink.0)(&format!("audit {} {}", self.id, event));
+    }
+}
+
+impl fmt::Debug for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Trace({})", self.id)
+    }
+}
@@ -0,0 +1,257 @@
+extern crate libmqtt;
+
+use libmqtt::{bufpool::*, client::{self, recv, send}, connopts::*, ctrlpkt::*, ctrlpkt::CtrlPkt::*,
+    error::*, pktstream::*};
+use std::net::TcpStream;
+use std::sync::{Arc, Barrier};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{env, process, thread};
+
+const USAGE: &str = "\
+Usage: mqtt-bench [options]
+
+Options:
+  -h <host>        broker host (default 127.0.0.1)
+  -p <port>        broker port (default 1883)
+  -c <count>       number of publishing connections (default 10)
+  -n <count>       messages per publisher (default 1000)
+  -r <rate>        messages per second per publisher, 0 for unlimited (default 0)
+  -s <bytes>       payload size, at least 8 (default 64)
+  -q <qos,...>     QoS levels cycled through by each publisher (default 0)
+  -S <count>       number of subscribing connections (default 0)
+  -t <prefix>      topic prefix; publisher i publishes to <prefix>/i (default bench)";
+
+// Subscribers give up once nothing has arrived for this long
+const IDLE_TIMEOUT_SECS: u64 = 5;
+
+struct Opts {
+    host: String,
+    port: u16,
+    publishers: usize,
+    messages: usize,
+    rate: u64,
+    size: usize,
+    qos_mix: Vec<QosLv>,
+    subscribers: usize,
+    topic_prefix: String
+}
+
+impl Opts {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> std::result::Result<Opts, String> {
+        let mut opts = Opts {
+            host: "127.0.0.1".to_string(),
+            port: 1883,
+            publishers: 10,
+            messages: 1000,
+            rate: 0,
+            size: 64,
+            qos_mix: vec![QosLv::AtMostOnce],
+            subscribers: 0,
+            topic_prefix: "bench".to_string()
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} requires a value", arg));
+            let invalid = |_| format!("invalid value for {}", arg);
+            match arg.as_str() {
+                "-h" => opts.host = value()?,
+                "-p" => opts.port = value()?.parse().map_err(invalid)?,
+                "-c" => opts.publishers = value()?.parse().map_err(invalid)?,
+                "-n" => opts.messages = value()?.parse().map_err(invalid)?,
+                "-r" => opts.rate = value()?.parse().map_err(invalid)?,
+                "-s" => opts.size = value()?.parse().map_err(invalid)?,
+                "-q" => {
+                    opts.qos_mix = value()?.split(',')
+                        .map(|s| s.parse().ok().and_then(|i| QosLv::from_int(i).ok()))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or(format!("invalid value for {}", arg))?;
+                }
+                "-S" => opts.subscribers = value()?.parse().map_err(invalid)?,
+                "-t" => opts.topic_prefix = value()?,
+                _ => return Err(format!("unknown option {}", arg))
+            }
+        }
+        // The payload starts with the send timestamp
+        if opts.size < 8 {
+            return Err("payload size must be at least 8 bytes".to_string());
+        }
+        if opts.qos_mix.is_empty() {
+            return Err("QoS mix can't be empty".to_string());
+        }
+        Ok(opts)
+    }
+
+    fn topic(&self, publisher: usize) -> String {
+        format!("{}/{}", self.topic_prefix, publisher)
+    }
+}
+
+fn now_nanos() -> u64 {
+    let d = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64
+}
+
+fn connect(opts: &Opts, client_id: String) -> Result<(TcpStream, PacketStream<TcpStream>)> {
+    let (stream, pkts) = client::connect((opts.host.as_str(), opts.port),
+        &ConnectOptions::new(client_id))?;
+    // Otherwise Nagle's algorithm dominates the measured latency
+    stream.set_nodelay(true)?;
+    Ok((stream, pkts))
+}
+
+// Publishes opts.messages messages and returns the acknowledgement latency of the QoS 1/2 ones
+fn run_publisher(opts: &Opts, n: usize, start: &Barrier) -> Result<Vec<Duration>> {
+    let conn = connect(opts, format!("mqtt-bench-pub-{}-{}", process::id(), n));
+    // Wait even on failure so the other threads aren't stuck at the barrier
+    start.wait();
+    let (mut stream, mut pkts) = conn?;
+    let topic_name = opts.topic(n);
+    // Keeps the publish loop from allocating an encode buffer per message
+    let pool = BufPool::new(1, opts.size + topic_name.len() + 16);
+    let mut latencies = vec![];
+    let began = Instant::now();
+    for i in 0..opts.messages {
+        if opts.rate > 0 {
+            let due = Duration::from_millis(i as u64 * 1000 / opts.rate);
+            let elapsed = began.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+        let qos_lv = opts.qos_mix[i % opts.qos_mix.len()];
+        let pkt_id = (i % 65535) as u16 + 1;
+        let mut payload = vec![0; opts.size];
+        let sent_at = now_nanos();
+        for (b, byte) in payload.iter_mut().zip(0..8) {
+            *b = (sent_at >> (56 - byte * 8)) as u8;
+        }
+        let sent = Instant::now();
+        client::send_pooled(&mut stream, &Publish {
+            dup: false,
+            qos_lv,
+            retain: false,
+            topic_name: topic_name.clone(),
+            pkt_id: if qos_lv == QosLv::AtMostOnce { None } else { Some(pkt_id) },
+            payload
+        }, &pool)?;
+        match qos_lv {
+            QosLv::AtMostOnce => continue,
+            QosLv::AtLeastOnce => match recv(&mut pkts)? {
+                PubAck(id) if id == pkt_id => (),
+                pkt => return Err(Error::UnexpectedPkt(pkt))
+            },
+            QosLv::ExactlyOnce => {
+                match recv(&mut pkts)? {
+                    PubRec(id) if id == pkt_id => send(&mut stream, &PubRel(pkt_id))?,
+                    pkt => return Err(Error::UnexpectedPkt(pkt))
+                }
+                match recv(&mut pkts)? {
+                    PubComp(id) if id == pkt_id => (),
+                    pkt => return Err(Error::UnexpectedPkt(pkt))
+                }
+            }
+        }
+        latencies.push(sent.elapsed());
+    }
+    send(&mut stream, &Disconnect)?;
+    Ok(latencies)
+}
+
+fn subscribe(opts: &Opts, n: usize) -> Result<(TcpStream, PacketStream<TcpStream>)> {
+    let (mut stream, mut pkts) = connect(opts, format!("mqtt-bench-sub-{}-{}", process::id(), n))?;
+    let max_qos_lv = *opts.qos_mix.iter().max_by_key(|qos_lv| **qos_lv as u8).unwrap();
+    let subs = (0..opts.publishers).map(|i| (opts.topic(i), max_qos_lv)).collect();
+    send(&mut stream, &Subscribe { pkt_id: 1, subs })?;
+    match recv(&mut pkts)? {
+        SubAck { pkt_id: 1, .. } => (),
+        pkt => return Err(Error::UnexpectedPkt(pkt))
+    }
+    stream.set_read_timeout(Some(Duration::from_secs(IDLE_TIMEOUT_SECS)))?;
+    Ok((stream, pkts))
+}
+
+// Receives messages from every publisher topic and returns their end-to-end latency
+fn run_subscriber(opts: &Opts, n: usize, ready: &Barrier) -> Result<Vec<Duration>> {
+    let conn = subscribe(opts, n);
+    ready.wait();
+    let (mut stream, mut pkts) = conn?;
+    let expected = opts.publishers * opts.messages;
+    let mut latencies = vec![];
+    while latencies.len() < expected {
+        match pkts.next() {
+            Some(Ok(Publish { qos_lv, pkt_id, payload, .. })) => {
+                let sent_at = payload.iter().take(8).fold(0u64, |acc, b| (acc << 8) | *b as u64);
+                latencies.push(Duration::from_nanos(now_nanos().saturating_sub(sent_at)));
+                match (qos_lv, pkt_id) {
+                    (QosLv::AtLeastOnce, Some(pkt_id)) => send(&mut stream, &PubAck(pkt_id))?,
+                    (QosLv::ExactlyOnce, Some(pkt_id)) => send(&mut stream, &PubRec(pkt_id))?,
+                    _ => ()
+                }
+            }
+            Some(Ok(PubRel(pkt_id))) => send(&mut stream, &PubComp(pkt_id))?,
+            Some(Ok(pkt)) => return Err(Error::UnexpectedPkt(pkt)),
+            // Timed out waiting for the rest; report what arrived
+            Some(Err(_)) | None => break
+        }
+    }
+    let _ = send(&mut stream, &Disconnect);
+    Ok(latencies)
+}
+
+fn print_latencies(label: &str, latencies: &mut Vec<Duration>) {
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort();
+    let percentile = |p: f64| {
+        let d = latencies[((latencies.len() - 1) as f64 * p).round() as usize];
+        d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1_000_000.0
+    };
+    println!("{}: p50 {:.3}ms  p90 {:.3}ms  p99 {:.3}ms  max {:.3}ms", label, percentile(0.5),
+        percentile(0.9), percentile(0.99), percentile(1.0));
+}
+
+fn join_all(handles: Vec<thread::JoinHandle<Result<Vec<Duration>>>>) -> Vec<Duration> {
+    let mut all = vec![];
+    for handle in handles {
+        match handle.join().unwrap() {
+            Ok(latencies) => all.extend(latencies),
+            Err(e) => eprintln!("mqtt-bench: connection failed: {:?}", e)
+        }
+    }
+    all
+}
+
+fn main() {
+    let opts = match Opts::parse(env::args().skip(1)) {
+        Ok(opts) => Arc::new(opts),
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    // Publishers start once every subscriber is subscribed
+    let start = Arc::new(Barrier::new(opts.publishers + opts.subscribers + 1));
+    let subscribers: Vec<_> = (0..opts.subscribers).map(|n| {
+        let (opts, start) = (Arc::clone(&opts), Arc::clone(&start));
+        thread::spawn(move || run_subscriber(&opts, n, &start))
+    }).collect();
+    let publishers: Vec<_> = (0..opts.publishers).map(|n| {
+        let (opts, start) = (Arc::clone(&opts), Arc::clone(&start));
+        thread::spawn(move || run_publisher(&opts, n, &start))
+    }).collect();
+    start.wait();
+    let began = Instant::now();
+    let mut ack_latencies = join_all(publishers);
+    let elapsed = began.elapsed();
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    let sent = opts.publishers * opts.messages;
+    println!("published {} messages of {} bytes in {:.3}s ({:.1} msg/s)", sent, opts.size, secs,
+        sent as f64 / secs);
+    print_latencies("ack latency", &mut ack_latencies);
+    if opts.subscribers > 0 {
+        let mut e2e_latencies = join_all(subscribers);
+        println!("received {}/{} messages across {} subscribers", e2e_latencies.len(),
+            sent * opts.subscribers, opts.subscribers);
+        print_latencies("end-to-end latency", &mut e2e_latencies);
+    }
+}
@@ -0,0 +1,187 @@
+extern crate libmqtt;
+
+use libmqtt::{ctrlpkt::*, error::*, pktstream::*};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Cursor, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{env, process, thread};
+
+const USAGE: &str = "\
+Usage: mqtt-capture record <listen addr> <broker addr> <file prefix>
+       mqtt-capture replay <broker addr> <file> [speed]
+       mqtt-capture dump <file>
+
+record accepts clients on <listen addr>, forwards them to <broker addr> and writes every packet
+of connection n, in both directions, to <file prefix>-<n>.cap.
+replay sends the client packets of a capture to a broker, keeping the recorded timing scaled by
+speed (default 1, 0 sends as fast as possible), and prints what the broker sends back.
+dump prints the packets of a capture.";
+
+// Each record in a capture file is a direction byte, the milliseconds since the connection was
+// accepted as a big-endian u64, the packet length as a big-endian u32, and the raw packet
+const CLIENT_TO_BROKER: u8 = 0;
+const BROKER_TO_CLIENT: u8 = 1;
+
+const REPLY_GRACE_SECS: u64 = 1;
+
+fn as_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
+
+struct Record {
+    direction: u8,
+    offset: Duration,
+    pkt: Vec<u8>
+}
+
+impl Record {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let millis = as_millis(self.offset);
+        let len = self.pkt.len() as u32;
+        let mut header = vec![self.direction];
+        header.extend((0..8).rev().map(|i| (millis >> (i * 8)) as u8));
+        header.extend((0..4).rev().map(|i| (len >> (i * 8)) as u8));
+        w.write_all(&header)?;
+        w.write_all(&self.pkt)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Option<Record>> {
+        let mut header = [0; 13];
+        match r.read_exact(&mut header) {
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            res => res?
+        }
+        let millis = header[1..9].iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+        let len = header[9..13].iter().fold(0u32, |acc, b| (acc << 8) | *b as u32);
+        let mut pkt = vec![0; len as usize];
+        r.read_exact(&mut pkt)?;
+        Ok(Some(Record { direction: header[0], offset: Duration::from_millis(millis), pkt }))
+    }
+
+    fn print(&self) {
+        let arrow = if self.direction == CLIENT_TO_BROKER { "c->b" } else { "b->c" };
+        let offset = as_millis(self.offset);
+        match CtrlPkt::deserialize(&mut Cursor::new(&self.pkt)) {
+            Ok(pkt) => println!("+{}ms {} {:?}", offset, arrow, pkt),
+            Err(e) => println!("+{}ms {} {:?} (undecodable: {:?})", offset, arrow, self.pkt, e)
+        }
+    }
+}
+
+// Reads one packet off the wire without decoding it, so malformed packets are captured as sent
+fn read_raw_pkt<R: Read>(r: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut header = [0];
+    match r.read_exact(&mut header) {
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?
+    }
+    let remaining_len = r.read_remaining_len()?;
+    let mut pkt = header.to_vec();
+    pkt.write_remaining_len(remaining_len)?;
+    pkt.extend(MqttRead::read_len(r, remaining_len)?);
+    Ok(Some(pkt))
+}
+
+fn forward(mut from: TcpStream, mut to: TcpStream, direction: u8, accepted: Instant,
+           out: Arc<Mutex<BufWriter<File>>>) -> Result<()> {
+    while let Some(pkt) = read_raw_pkt(&mut from)? {
+        to.write_all(&pkt)?;
+        let record = Record { direction, offset: accepted.elapsed(), pkt };
+        record.print();
+        let mut out = out.lock().unwrap();
+        record.write_to(&mut *out)?;
+        out.flush()?;
+    }
+    // Propagate the close so the other direction finishes too
+    let _ = to.shutdown(Shutdown::Both);
+    Ok(())
+}
+
+fn record(listen_addr: &str, broker_addr: &str, prefix: &str) -> Result<()> {
+    let listener = TcpListener::bind(listen_addr)?;
+    for (n, client) in listener.incoming().enumerate() {
+        let client = client?;
+        let broker = TcpStream::connect(broker_addr)?;
+        let path = format!("{}-{}.cap", prefix, n);
+        println!("Recording connection {} to {}", n, path);
+        let out = Arc::new(Mutex::new(BufWriter::new(File::create(&path)?)));
+        let accepted = Instant::now();
+        let (client2, broker2, out2) = (client.try_clone()?, broker.try_clone()?, Arc::clone(&out));
+        thread::spawn(move || forward(broker2, client2, BROKER_TO_CLIENT, accepted, out2));
+        thread::spawn(move || forward(client, broker, CLIENT_TO_BROKER, accepted, out));
+    }
+    Ok(())
+}
+
+fn read_records(path: &str) -> Result<Vec<Record>> {
+    let mut r = BufReader::new(File::open(path)?);
+    let mut records = vec![];
+    while let Some(record) = Record::read_from(&mut r)? {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn replay(broker_addr: &str, path: &str, speed: f64) -> Result<()> {
+    let records = read_records(path)?;
+    let mut stream = TcpStream::connect(broker_addr)?;
+    let started = Instant::now();
+    let pkts = PacketStream::new(stream.try_clone()?);
+    let reader = thread::spawn(move || {
+        for pkt in pkts {
+            match pkt {
+                Ok(pkt) => println!("b->c {:?}", pkt),
+                Err(e) => println!("b->c error: {:?}", e)
+            }
+        }
+    });
+    for record in records.iter().filter(|record| record.direction == CLIENT_TO_BROKER) {
+        if speed > 0.0 {
+            let due = Duration::from_millis((as_millis(record.offset) as f64 / speed) as u64);
+            let elapsed = started.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+        record.print();
+        stream.write_all(&record.pkt)?;
+    }
+    // Give the broker a moment to answer the last packets before hanging up
+    thread::sleep(Duration::from_secs(REPLY_GRACE_SECS));
+    let _ = stream.shutdown(Shutdown::Both);
+    let _ = reader.join();
+    Ok(())
+}
+
+fn dump(path: &str) -> Result<()> {
+    for record in read_records(path)? {
+        record.print();
+    }
+    Ok(())
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    let res = match args.as_slice() {
+        &["record", listen_addr, broker_addr, prefix] => record(listen_addr, broker_addr, prefix),
+        &["replay", broker_addr, path] => replay(broker_addr, path, 1.0),
+        &["replay", broker_addr, path, speed] => match speed.parse() {
+            Ok(speed) => replay(broker_addr, path, speed),
+            Err(_) => usage()
+        },
+        &["dump", path] => dump(path),
+        _ => usage()
+    };
+    if let Err(e) = res {
+        eprintln!("mqtt-capture: {:?}", e);
+        process::exit(1);
+    }
+}
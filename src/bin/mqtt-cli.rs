@@ -0,0 +1,225 @@
+extern crate libmqtt;
+
+use libmqtt::{client::{self, recv, send}, connopts::*, ctrlpkt::*, ctrlpkt::CtrlPkt::*, error::*,
+    pktstream::*};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{env, process, thread};
+
+const USAGE: &str = "\
+Usage: mqtt-cli pub [options] -t <topic> -m <message>
+       mqtt-cli sub [options] -t <topic> [-t <topic>...]
+
+Options:
+  -h <host>             broker host (default 127.0.0.1)
+  -p <port>             broker port (default 1883)
+  -i <client id>        client id (default mqtt-cli-<pid>)
+  -k <secs>             keep-alive (default 60)
+  -q <qos>              QoS of the publish or subscriptions (default 0)
+  -r                    pub: set the retain flag
+  -c                    sub: don't clean the session on connect
+  -v                    sub: print the topic alongside each message
+  -u <username>
+  -P <password>
+  --will-topic <topic>
+  --will-payload <message>
+  --will-qos <qos>
+  --will-retain";
+
+struct Opts {
+    host: String,
+    port: u16,
+    client_id: String,
+    keep_alive: u16,
+    clean_session: bool,
+    username: Option<String>,
+    password: Option<String>,
+    will: Option<Will>,
+    topics: Vec<String>,
+    qos_lv: QosLv,
+    retain: bool,
+    message: Option<String>,
+    verbose: bool
+}
+
+impl Opts {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> std::result::Result<Opts, String> {
+        let mut opts = Opts {
+            host: "127.0.0.1".to_string(),
+            port: 1883,
+            client_id: format!("mqtt-cli-{}", process::id()),
+            keep_alive: 60,
+            clean_session: true,
+            username: None,
+            password: None,
+            will: None,
+            topics: vec![],
+            qos_lv: QosLv::AtMostOnce,
+            retain: false,
+            message: None,
+            verbose: false
+        };
+        let (mut will_topic, mut will_payload, mut will_qos_lv, mut will_retain) =
+            (None, None, QosLv::AtMostOnce, false);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} requires a value", arg));
+            match arg.as_str() {
+                "-h" => opts.host = value()?,
+                "-p" => opts.port = value()?.parse().map_err(|_| "invalid port".to_string())?,
+                "-i" => opts.client_id = value()?,
+                "-k" => opts.keep_alive = value()?.parse()
+                    .map_err(|_| "invalid keep-alive".to_string())?,
+                "-q" => opts.qos_lv = parse_qos(&value()?)?,
+                "-r" => opts.retain = true,
+                "-c" => opts.clean_session = false,
+                "-v" => opts.verbose = true,
+                "-t" => opts.topics.push(value()?),
+                "-m" => opts.message = Some(value()?),
+                "-u" => opts.username = Some(value()?),
+                "-P" => opts.password = Some(value()?),
+                "--will-topic" => will_topic = Some(value()?),
+                "--will-payload" => will_payload = Some(value()?),
+                "--will-qos" => will_qos_lv = parse_qos(&value()?)?,
+                "--will-retain" => will_retain = true,
+                _ => return Err(format!("unknown option {}", arg))
+            }
+        }
+        if opts.topics.is_empty() {
+            return Err("at least one topic (-t) is required".to_string());
+        }
+        if let Some(topic) = will_topic {
+            let mut will = Will::new(topic, will_payload.unwrap_or_default().into_bytes());
+            will.set_qos_lv(will_qos_lv).set_retain(will_retain);
+            opts.will = Some(will);
+        }
+        Ok(opts)
+    }
+}
+
+fn parse_qos(s: &str) -> std::result::Result<QosLv, String> {
+    s.parse().ok().and_then(|i| QosLv::from_int(i).ok()).ok_or(format!("invalid QoS {}", s))
+}
+
+fn connect(opts: &Opts) -> Result<(TcpStream, PacketStream<TcpStream>)> {
+    let mut connect_opts = ConnectOptions::new(opts.client_id.clone());
+    connect_opts.set_keep_alive(opts.keep_alive).set_clean_session(opts.clean_session);
+    if let Some(ref will) = opts.will {
+        connect_opts.set_will(will.clone());
+    }
+    if let Some(ref username) = opts.username {
+        connect_opts.set_username(username.clone());
+    }
+    if let Some(ref password) = opts.password {
+        connect_opts.set_password(password.clone().into_bytes());
+    }
+    client::connect((opts.host.as_str(), opts.port), &connect_opts)
+}
+
+fn publish(opts: &Opts) -> Result<()> {
+    let (mut stream, mut pkts) = connect(opts)?;
+    let pkt_id = if opts.qos_lv == QosLv::AtMostOnce { None } else { Some(1) };
+    for topic_name in &opts.topics {
+        send(&mut stream, &Publish {
+            dup: false,
+            qos_lv: opts.qos_lv,
+            retain: opts.retain,
+            topic_name: topic_name.clone(),
+            pkt_id,
+            payload: opts.message.clone().unwrap_or_default().into_bytes()
+        })?;
+        match opts.qos_lv {
+            QosLv::AtMostOnce => (),
+            QosLv::AtLeastOnce => match recv(&mut pkts)? {
+                PubAck(1) => (),
+                pkt => return Err(Error::UnexpectedPkt(pkt))
+            },
+            QosLv::ExactlyOnce => {
+                match recv(&mut pkts)? {
+                    PubRec(1) => send(&mut stream, &PubRel(1))?,
+                    pkt => return Err(Error::UnexpectedPkt(pkt))
+                }
+                match recv(&mut pkts)? {
+                    PubComp(1) => (),
+                    pkt => return Err(Error::UnexpectedPkt(pkt))
+                }
+            }
+        }
+    }
+    send(&mut stream, &Disconnect)
+}
+
+fn subscribe(opts: &Opts) -> Result<()> {
+    let (stream, mut pkts) = connect(opts)?;
+    let writer = Arc::new(Mutex::new(stream));
+    let subs = opts.topics.iter().map(|topic| (topic.clone(), opts.qos_lv)).collect();
+    send(&mut *writer.lock().unwrap(), &Subscribe { pkt_id: 1, subs })?;
+    match recv(&mut pkts)? {
+        SubAck { pkt_id: 1, sub_ack_ret_codes } => {
+            for (topic, ret_code) in opts.topics.iter().zip(sub_ack_ret_codes) {
+                if let SubAckRetCode::Failure = ret_code {
+                    eprintln!("Subscription to {} was rejected", topic);
+                }
+            }
+        }
+        pkt => return Err(Error::UnexpectedPkt(pkt))
+    }
+    if opts.keep_alive > 0 {
+        let writer = Arc::clone(&writer);
+        let interval = Duration::from_secs(((opts.keep_alive / 2) as u64).max(1));
+        thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+                if send(&mut *writer.lock().unwrap(), &PingReq).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+    loop {
+        match recv(&mut pkts)? {
+            Publish { qos_lv, topic_name, pkt_id, payload, .. } => {
+                let payload = String::from_utf8_lossy(&payload);
+                if opts.verbose {
+                    println!("{} {}", topic_name, payload);
+                } else {
+                    println!("{}", payload);
+                }
+                match (qos_lv, pkt_id) {
+                    (QosLv::AtLeastOnce, Some(pkt_id)) =>
+                        send(&mut *writer.lock().unwrap(), &PubAck(pkt_id))?,
+                    (QosLv::ExactlyOnce, Some(pkt_id)) =>
+                        send(&mut *writer.lock().unwrap(), &PubRec(pkt_id))?,
+                    _ => ()
+                }
+            }
+            PubRel(pkt_id) => send(&mut *writer.lock().unwrap(), &PubComp(pkt_id))?,
+            PingResp => (),
+            pkt => return Err(Error::UnexpectedPkt(pkt))
+        }
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let command = args.next().unwrap_or_default();
+    let opts = match Opts::parse(args) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let res = match command.as_str() {
+        "pub" => publish(&opts),
+        "sub" => subscribe(&opts),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = res {
+        eprintln!("mqtt-cli: {:?}", e);
+        process::exit(1);
+    }
+}
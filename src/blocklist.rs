@@ -0,0 +1,91 @@
+use std::sync::RwLock;
+
+// Client ids and usernames whose CONNECTs are refused, e.g. to quarantine a misbehaving firmware
+// version by its client id prefix. Client ids are matched against globs: `*` matches any run of
+// characters and `?` any one character. Changes apply to later CONNECTs; clients that are already
+// connected stay connected.
+pub struct Blocklist {
+    client_ids: RwLock<Vec<String>>,
+    usernames: RwLock<Vec<String>>
+}
+
+impl Blocklist {
+    pub fn new() -> Blocklist {
+        Blocklist { client_ids: RwLock::new(vec![]), usernames: RwLock::new(vec![]) }
+    }
+
+    // Returns false if the pattern was already blocked
+    pub fn block_client_id(&self, pattern: &str) -> bool {
+        add(&self.client_ids, pattern)
+    }
+
+    // Returns false if the pattern wasn't blocked
+    pub fn unblock_client_id(&self, pattern: &str) -> bool {
+        remove(&self.client_ids, pattern)
+    }
+
+    pub fn block_username(&self, username: &str) -> bool {
+        add(&self.usernames, username)
+    }
+
+    pub fn unblock_username(&self, username: &str) -> bool {
+        remove(&self.usernames, username)
+    }
+
+    pub fn client_ids(&self) -> Vec<String> {
+        self.client_ids.read().unwrap().clone()
+    }
+
+    pub fn usernames(&self) -> Vec<String> {
+        self.usernames.read().unwrap().clone()
+    }
+
+    pub fn blocks_client_id(&self, client_id: &str) -> bool {
+        self.client_ids.read().unwrap().iter().any(|pattern| glob_matches(pattern, client_id))
+    }
+
+    pub fn blocks_username(&self, username: &str) -> bool {
+        self.usernames.read().unwrap().iter().any(|blocked| blocked == username)
+    }
+}
+
+fn add(list: &RwLock<Vec<String>>, entry: &str) -> bool {
+    let mut list = list.write().unwrap();
+    if list.iter().any(|e| e == entry) {
+        return false;
+    }
+    list.push(entry.to_string());
+    true
+}
+
+fn remove(list: &RwLock<Vec<String>>, entry: &str) -> bool {
+    let mut list = list.write().unwrap();
+    let len = list.len();
+    list.retain(|e| e != entry);
+    list.len() != len
+}
+
+pub fn glob_matches(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut pi, mut si) = (0, 0);
+    // Where the last `*` was and where in `s` its match currently ends, to backtrack to
+    let mut star: Option<(usize, usize)> = None;
+    while si < s.len() {
+        if pi < pattern.len() && (pattern[pi] == '?' || pattern[pi] == s[si]) {
+            pi += 1;
+            si += 1;
+        } else if pi < pattern.len() && pattern[pi] == '*' {
+            star = Some((pi, si));
+            pi += 1;
+        } else if let Some((star_pi, star_si)) = star {
+            // Let the `*` match one more character
+            star = Some((star_pi, star_si + 1));
+            pi = star_pi + 1;
+            si = star_si + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[pi..].iter().all(|&c| c == '*')
+}
@@ -0,0 +1,3052 @@
+use libmqtt::{bufpool::*, connopts::Will, ctrlpkt::*, ctrlpkt::CtrlPkt::*, error::*, pktid::*,
+    pktstream::*};
+use std::cmp;
+use std::fmt;
+use std::mem;
+use std::collections::{btree_map::BTreeMap, hash_map::{DefaultHasher, HashMap},
+    hash_set::HashSet, vec_deque::VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{mpsc, RwLock, Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::io::{self, BufWriter, Cursor, Read, Write};
+use std::net::TcpListener;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use audit::{AuditSink, Trace};
+use blocklist::{self, Blocklist};
+use clientid::ClientIdGenerator;
+use clock::{Clock, SystemClock};
+use config::ListenerConfig;
+use connection::{ConnectionManager, Registration, WriteBatching};
+use deadletter::{self, DeadLetters};
+use dispatch::{self, Dispatcher};
+use fault::Faults;
+use inflight::Inflight;
+use keepalive::{self, KeepAliveHistogram, KeepAliveStats, GAP_BOUNDS, KEEP_ALIVE_BOUNDS};
+use observer::{self, DropReason, Event, Observed, ObservedMessage, Observer};
+use process;
+use rand;
+use ratelimit::RateLimiter;
+use info;
+use subscriptions::{FilterStats, SubscriptionTable, TableStats};
+use topic::{self, TopicTree};
+use transport::{RetryWrites, Transport};
+use validation::{PayloadValidator, ValidationAction, Validators};
+
+#[derive(Debug, Clone)]
+struct Session {
+    pub client_id: String,
+    pub subscriptions: HashMap<String, QosLv>,
+    pub waiting_for_ack: Inflight<InflightMessage>,
+    // Messages that arrived while the client was offline, oldest first
+    pub pending_tx: VecDeque<QueuedMessage>,
+    // Packet ids of QoS 2 messages from the client that were delivered but not yet released, with
+    // when they arrived, oldest first
+    pub awaiting_rel: VecDeque<(u16, Instant)>,
+    pub clean_session: bool,
+    // Prefix of every topic the client sees: the mount point of the listener it last connected
+    // on, followed by its tenant on listeners with tenant isolation
+    pub namespace: Option<String>,
+    // What the client's last connection agreed to
+    pub params: Option<ConnectionParams>,
+    pub stats: SessionStats
+}
+
+#[derive(Debug, Clone)]
+struct SessionStats {
+    // Messages sent to the client
+    delivered: u64,
+    // Messages for the client that were discarded because it wasn't connected
+    dropped: u64,
+    // QoS 2 messages the client sent again before releasing them, which weren't delivered again
+    qos2_duplicates: u64,
+    // QoS 2 messages the client never released, whose packet ids were forgotten
+    qos2_abandoned: u64,
+    // Packets of a type the broker can't decode
+    unknown_packets: u64,
+    // Acks for deliveries from an earlier epoch, or for none at all, which were ignored
+    stale_acks: u64,
+    // Shared with the thread serving the client's current connection
+    activity: Arc<Mutex<Activity>>
+}
+
+// What a connection updates on every packet from the client. The thread serving it only takes
+// this lock for that, never the sessions lock, so connections don't contend over their
+// statistics; they are read from here when asked for.
+#[derive(Debug)]
+struct Activity {
+    // What the client's current or last connection sent
+    decode: DecodeStats,
+    // When the client last sent a packet
+    last_activity: Instant
+}
+
+impl Activity {
+    fn new(now: Instant) -> Arc<Mutex<Activity>> {
+        Arc::new(Mutex::new(Activity { decode: DecodeStats::new(), last_activity: now }))
+    }
+}
+
+impl Session {
+    fn new(client_id: String, clean_session: bool, now: Instant) -> Session {
+        Session {
+            client_id,
+            subscriptions: HashMap::new(),
+            waiting_for_ack: Inflight::new(),
+            pending_tx: VecDeque::new(),
+            awaiting_rel: VecDeque::new(),
+            clean_session,
+            namespace: None,
+            params: None,
+            stats: SessionStats {
+                delivered: 0,
+                dropped: 0,
+                qos2_duplicates: 0,
+                qos2_abandoned: 0,
+                unknown_packets: 0,
+                stale_acks: 0,
+                activity: Activity::new(now)
+            }
+        }
+    }
+
+    fn namespace(&self) -> Option<&str> {
+        self.namespace.as_ref().map(|namespace| namespace.as_str())
+    }
+
+    // Whether a message on `topic_name` may be delivered to the client. A client with a namespace
+    // only ever gets messages from inside it, however its subscriptions came about.
+    fn can_see(&self, topic_name: &str) -> bool {
+        self.namespace().map_or(true, |namespace| topic::is_mounted(namespace, topic_name))
+    }
+
+    // Keeps a QoS 1 or 2 delivery on `topic_name` until the client acknowledges it
+    fn await_ack(&mut self, pkt_id: u16, topic_name: &str, msg: Message) {
+        self.waiting_for_ack.insert(pkt_id, InflightMessage {
+            topic_name: topic_name.to_string(),
+            msg,
+            released: false
+        });
+    }
+
+    // Takes back a delivery whose write failed. A QoS 1 or 2 delivery to a persistent session
+    // stays in flight, to be resent when the client resumes the session; anything else is counted
+    // as dropped, and its packet id freed. Returns whether it was dropped.
+    fn write_failed(&mut self, pkt_id: Option<u16>, pkt_id_gen: &Mutex<PktIdGen>) -> bool {
+        if let Some(pkt_id) = pkt_id {
+            if !self.clean_session && self.waiting_for_ack.contains(pkt_id) {
+                return false;
+            }
+            if self.waiting_for_ack.remove(pkt_id).is_some() {
+                pkt_id_gen.lock().unwrap().rm(pkt_id);
+            }
+        }
+        self.stats.delivered = self.stats.delivered.saturating_sub(1);
+        self.stats.dropped += 1;
+        true
+    }
+
+    // The delivery an ack from the client is for. Packet ids are shared by every client, so one
+    // that isn't waiting for this client's ack in this epoch may have been given to another
+    // delivery since; such an ack is counted as stale and ignored.
+    fn acked(&mut self, pkt_id: u16) -> Option<&mut InflightMessage> {
+        let epoch = self.waiting_for_ack.epoch();
+        if self.waiting_for_ack.epoch_of(pkt_id) != Some(epoch) {
+            log!(Info, "Ignoring stale ack of {} from {}", pkt_id, self.client_id);
+            self.stats.stale_acks += 1;
+            return None;
+        }
+        self.waiting_for_ack.get_mut(pkt_id)
+    }
+
+    // Queues a message for when the client reconnects. Returns false if the queue is full.
+    // Messages dropped by queue policy go to `dead_letters`, if any.
+    fn enqueue(&mut self, topic_name: &str, msg: Message, now: Instant, limits: &QueueLimits,
+               dead_letters: Option<&DeadLetters>) -> bool {
+        self.expire_queued(now, limits.max_age, dead_letters);
+        if self.pending_tx.len() >= limits.max_messages {
+            msg.audit(format_args!("dropped for {}: queue full", self.client_id));
+            msg.observe(Event::Dropped(Some(&self.client_id), DropReason::QueueFull));
+            deadletter::send(dead_letters, &self.client_id, topic_name, msg.qos_lv, &msg.payload,
+                DropReason::QueueFull);
+            return false;
+        }
+        msg.audit(format_args!("queued for {}", self.client_id));
+        msg.observe(Event::Enqueued(&self.client_id));
+        self.pending_tx.push_back(QueuedMessage {
+            topic_name: topic_name.to_string(),
+            msg,
+            queued_at: now
+        });
+        true
+    }
+
+    // Records a QoS 2 message from the client until it is released. Returns false if one with the
+    // same packet id is still waiting for its PUBREL. Exchanges the client abandoned, ones older
+    // than the release timeout or the oldest beyond the cap, are forgotten first.
+    fn await_rel(&mut self, pkt_id: u16, now: Instant, limits: &Qos2Limits) -> bool {
+        if let Some(timeout) = limits.release_timeout {
+            while self.awaiting_rel.front().map_or(false, |&(_, at)| now - at > timeout) {
+                self.awaiting_rel.pop_front();
+                self.stats.qos2_abandoned += 1;
+            }
+        }
+        if self.awaiting_rel.iter().any(|&(id, _)| id == pkt_id) {
+            return false;
+        }
+        if let Some(max) = limits.max_awaiting_rel {
+            while self.awaiting_rel.len() >= max {
+                self.awaiting_rel.pop_front();
+                self.stats.qos2_abandoned += 1;
+            }
+        }
+        self.awaiting_rel.push_back((pkt_id, now));
+        true
+    }
+
+    // Approximate bytes held for the client: its subscriptions, in-flight deliveries, queued
+    // messages and QoS 2 packet ids awaiting release. A payload shared with other sessions counts
+    // in full for each of them, since each keeps it alive.
+    fn memory_bytes(&self) -> usize {
+        let subscriptions: usize = self.subscriptions.keys()
+            .map(|filter| filter.len() + mem::size_of::<(String, QosLv)>())
+            .sum();
+        let inflight: usize = self.waiting_for_ack.iter()
+            .map(|(_, inflight)| {
+                inflight.topic_name.len() + inflight.msg.payload.len() +
+                    mem::size_of::<(u16, InflightMessage)>()
+            })
+            .sum();
+        let queued: usize = self.pending_tx.iter()
+            .map(|queued| {
+                queued.topic_name.len() + queued.msg.payload.len() +
+                    mem::size_of::<QueuedMessage>()
+            })
+            .sum();
+        let awaiting_rel = self.awaiting_rel.len() * mem::size_of::<(u16, Instant)>();
+        subscriptions + inflight + queued + awaiting_rel
+    }
+
+    // Only worked out when there is a ceiling, since it walks the session's queues
+    fn over_memory_limit(&self, limits: &MemoryLimits) -> bool {
+        limits.max_bytes.map_or(false, |max_bytes| self.memory_bytes() > max_bytes)
+    }
+
+    // Drops queued messages that have waited longer than `max_age` and counts them as dropped
+    fn expire_queued(&mut self, now: Instant, max_age: Option<Duration>,
+                     dead_letters: Option<&DeadLetters>) {
+        if let Some(max_age) = max_age {
+            while self.pending_tx.front().map_or(false, |queued| now - queued.queued_at > max_age) {
+                let queued = self.pending_tx.pop_front().unwrap();
+                queued.msg.audit(format_args!("dropped for {}: queued for too long",
+                    self.client_id));
+                queued.msg.observe(Event::Dropped(Some(&self.client_id), DropReason::Expired));
+                deadletter::send(dead_letters, &self.client_id, &queued.topic_name,
+                    queued.msg.qos_lv, &queued.msg.payload, DropReason::Expired);
+                self.stats.dropped += 1;
+            }
+        }
+    }
+}
+
+// A QoS 1 or 2 delivery waiting for the client's acknowledgement: a PUBACK, or for QoS 2 a PUBREC
+// and then a PUBCOMP
+#[derive(Debug, Clone)]
+struct InflightMessage {
+    // With the namespace applied, as queued messages have it, since the client may resume the
+    // session on another listener
+    topic_name: String,
+    msg: Message,
+    // Whether the client has sent PUBREC for it, so that it only waits for PUBCOMP
+    released: bool
+}
+
+#[derive(Debug, Clone)]
+struct Message {
+    qos_lv: QosLv,
+    // Shared by every copy of the message, so a large payload is held once however many
+    // subscribers, queues and in-flight deliveries it is in
+    payload: Arc<Vec<u8>>,
+    // Set in audit mode
+    trace: Option<Trace>,
+    // Set while a dispatch observer is
+    observed: Option<Observed>
+}
+
+impl Message {
+    fn new(qos_lv: QosLv, payload: Arc<Vec<u8>>) -> Message {
+        Message { qos_lv, payload, trace: None, observed: None }
+    }
+
+    fn audit(&self, event: fmt::Arguments) {
+        audit(self.trace.as_ref(), event);
+    }
+
+    fn observe(&self, event: Event) {
+        observer::observe(self.observed.as_ref(), event);
+    }
+}
+
+fn audit(trace: Option<&Trace>, event: fmt::Arguments) {
+    if let Some(trace) = trace {
+        trace.record(event);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RetainedMessage {
+    msg: Message,
+    retained_at: SystemTime
+}
+
+impl RetainedMessage {
+    fn new(qos_lv: QosLv, payload: Arc<Vec<u8>>) -> RetainedMessage {
+        RetainedMessage { msg: Message::new(qos_lv, payload), retained_at: SystemTime::now() }
+    }
+}
+
+// The retained messages, by topic. Fleets often retain the same payload, such as a config blob, on
+// a topic per device, so identical payloads are stored once and shared. They are found through an
+// index by hash that holds them weakly: a payload is freed as soon as no topic or delivery holds
+// it, and compact() later sweeps its entry out of the index.
+//
+// A SUBSCRIBE looks up the messages retained on topics matching each of its filters. Stores can
+// hold a message for every device in a fleet, so the topics are also indexed by level, and a
+// filter is matched by walking its levels rather than against every topic.
+struct RetainedStore {
+    msgs: HashMap<String, RetainedMessage>,
+    topics: TopicTree,
+    payloads: HashMap<u64, Vec<Weak<Vec<u8>>>>,
+    // Payloads retained that were already stored for another topic
+    deduplicated: u64,
+    // The last `history_len` changes to each topic, oldest first, if history is kept. A deleted
+    // message is recorded as None.
+    history: HashMap<String, VecDeque<(SystemTime, Option<Message>)>>,
+    history_len: usize
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RetainedStats {
+    pub messages: usize,
+    // Distinct payloads stored, and their total size
+    pub payloads: usize,
+    pub payload_bytes: usize,
+    pub deduplicated: u64
+}
+
+impl RetainedStore {
+    fn new() -> RetainedStore {
+        RetainedStore {
+            msgs: HashMap::new(),
+            topics: TopicTree::new(),
+            payloads: HashMap::new(),
+            deduplicated: 0,
+            history: HashMap::new(),
+            history_len: 0
+        }
+    }
+
+    // Retains a message on `topic`, replacing the one retained there. Returns the stored payload,
+    // which is `payload` unless an identical one was already stored, so that deliveries of the
+    // message can share it too.
+    fn insert(&mut self, topic: &str, qos_lv: QosLv, payload: Arc<Vec<u8>>) -> Arc<Vec<u8>> {
+        let mut hasher = DefaultHasher::new();
+        payload.hash(&mut hasher);
+        let same_hash = self.payloads.entry(hasher.finish()).or_insert_with(Vec::new);
+        let stored = same_hash.iter().filter_map(|weak| weak.upgrade())
+            .find(|stored| **stored == *payload);
+        let payload = match stored {
+            Some(stored) => {
+                self.deduplicated += 1;
+                stored
+            }
+            None => {
+                same_hash.push(Arc::downgrade(&payload));
+                payload
+            }
+        };
+        let retained = RetainedMessage::new(qos_lv, Arc::clone(&payload));
+        self.record(topic, retained.retained_at, Some(retained.msg.clone()));
+        if self.msgs.insert(topic.to_string(), retained).is_none() {
+            self.topics.insert(topic);
+        }
+        payload
+    }
+
+    // Handles a PUBLISH with the retain flag set. One with an empty payload deletes the message
+    // retained on `topic` rather than being retained itself, as the spec requires. Returns the
+    // payload to deliver, as insert does.
+    fn publish(&mut self, topic: &str, qos_lv: QosLv, payload: Arc<Vec<u8>>) -> Arc<Vec<u8>> {
+        if payload.is_empty() {
+            self.remove(topic);
+            payload
+        } else {
+            self.insert(topic, qos_lv, payload)
+        }
+    }
+
+    fn remove(&mut self, topic: &str) -> bool {
+        let removed = self.msgs.remove(topic).is_some();
+        if removed {
+            self.topics.remove(topic);
+            self.record(topic, SystemTime::now(), None);
+        }
+        removed
+    }
+
+    // $SYS topics change every few seconds and are only ever read for their latest value, so
+    // their history isn't kept
+    fn record(&mut self, topic: &str, at: SystemTime, msg: Option<Message>) {
+        if self.history_len == 0 || topic.starts_with('$') {
+            return;
+        }
+        let history = self.history.entry(topic.to_string()).or_insert_with(VecDeque::new);
+        if history.len() == self.history_len {
+            history.pop_front();
+        }
+        history.push_back((at, msg));
+    }
+
+    fn set_history_len(&mut self, history_len: usize) {
+        self.history_len = history_len;
+        for history in self.history.values_mut() {
+            while history.len() > history_len {
+                history.pop_front();
+            }
+        }
+        self.history.retain(|_, history| !history.is_empty());
+    }
+
+    // The message retained on `topic` at `at`, according to its history. None if nothing was
+    // retained then, or the history doesn't go back that far.
+    fn retained_at(&self, topic: &str, at: SystemTime) -> Option<(SystemTime, &Message)> {
+        self.history.get(topic)?.iter().rev().find(|&&(changed_at, _)| changed_at <= at)
+            .and_then(|&(changed_at, ref msg)| msg.as_ref().map(|msg| (changed_at, msg)))
+    }
+
+    // Drops the index entries of payloads that have been freed and gives back the space the maps
+    // no longer need. Returns the number of entries dropped.
+    fn compact(&mut self) -> usize {
+        let mut dropped = 0;
+        for same_hash in self.payloads.values_mut() {
+            let before = same_hash.len();
+            same_hash.retain(|weak| weak.upgrade().is_some());
+            dropped += before - same_hash.len();
+        }
+        self.payloads.retain(|_, same_hash| !same_hash.is_empty());
+        self.payloads.shrink_to_fit();
+        self.msgs.shrink_to_fit();
+        self.history.shrink_to_fit();
+        dropped
+    }
+
+    fn stats(&self) -> RetainedStats {
+        let payloads: Vec<Arc<Vec<u8>>> = self.payloads.values()
+            .flat_map(|same_hash| same_hash.iter().filter_map(|weak| weak.upgrade()))
+            .collect();
+        RetainedStats {
+            messages: self.msgs.len(),
+            payloads: payloads.len(),
+            payload_bytes: payloads.iter().map(|payload| payload.len()).sum(),
+            deduplicated: self.deduplicated
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct QueuedMessage {
+    topic_name: String,
+    msg: Message,
+    queued_at: Instant
+}
+
+pub const DEFAULT_MAX_QUEUED_MESSAGES: usize = 1000;
+// Subscribers a message is sent to before its dispatch worker gives other topics a turn
+pub const DEFAULT_FANOUT_CHUNK_SIZE: usize = 1000;
+
+pub const DEFAULT_RETAINED_BATCH_SIZE: usize = 100;
+
+// Well-behaved clients ping once per keep-alive interval, so this is only reached by floods
+pub const DEFAULT_MAX_PING_RATE: u32 = 10;
+
+// The keep-alive statistics change slowly, so their suggestions are only repeated this often
+pub const KEEP_ALIVE_SUGGESTION_SECS: u64 = 60 * 60;
+
+// How long a drain waits for the drained connections to be torn down and their messages delivered
+pub const DRAIN_TIMEOUT_SECS: u64 = 10;
+
+// Clients listed on $SYS/broker/memory/top
+pub const MEMORY_TOP_CLIENTS: usize = 10;
+
+// Generated client ids tried before a CONNECT with an empty one is refused, if they all belong to
+// existing sessions
+pub const CLIENT_ID_ATTEMPTS: usize = 10;
+
+pub const DEFAULT_TAKEOVER_GRACE_MILLIS: u64 = 1000;
+
+// How often a connection waiting to take over a client id checks whether the old one is alive
+const TAKEOVER_POLL_MILLIS: u64 = 20;
+
+// The node id of a broker that wasn't given one: the host name, or `localhost` if it can't be read
+pub fn default_node_id() -> String {
+    process::hostname().unwrap_or_else(|| "localhost".to_string())
+}
+
+// Node ids become a topic level, so they can't be empty or contain separators or wildcards
+pub fn is_valid_node_id(node_id: &str) -> bool {
+    is_valid_tenant(node_id)
+}
+
+// How many QoS 1 and 2 messages are kept for an offline persistent session, and for how long.
+// Messages that don't fit are dropped, and so are messages older than `max_age` when the client
+// reconnects, so that a device that was away for a long time isn't flooded with stale commands.
+#[derive(Debug, Copy, Clone)]
+pub struct QueueLimits {
+    pub max_messages: usize,
+    pub max_age: Option<Duration>
+}
+
+impl Default for QueueLimits {
+    fn default() -> QueueLimits {
+        QueueLimits { max_messages: DEFAULT_MAX_QUEUED_MESSAGES, max_age: None }
+    }
+}
+
+// The QoS retained messages are delivered at when a client subscribes
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RetainedQos {
+    // The QoS the message was published with
+    Original,
+    // The QoS granted to the subscription
+    Granted,
+    // The lower of the two, as the spec requires
+    Minimum
+}
+
+impl RetainedQos {
+    pub fn from_str(s: &str) -> Option<RetainedQos> {
+        match s {
+            "original" => Some(RetainedQos::Original),
+            "granted" => Some(RetainedQos::Granted),
+            "minimum" => Some(RetainedQos::Minimum),
+            _ => None
+        }
+    }
+
+    fn qos_lv(self, msg_qos_lv: QosLv, granted_qos_lv: QosLv) -> QosLv {
+        match self {
+            RetainedQos::Original => msg_qos_lv,
+            RetainedQos::Granted => granted_qos_lv,
+            RetainedQos::Minimum => cmp::min(msg_qos_lv, granted_qos_lv)
+        }
+    }
+}
+
+// What happens to a retained publish, or will, on a topic the client may publish to but not
+// retain on
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RetainViolation {
+    // Deliver it without retaining it
+    Clear,
+    // Drop the publish, or refuse the connection for a will
+    Drop
+}
+
+impl RetainViolation {
+    pub fn from_str(s: &str) -> Option<RetainViolation> {
+        match s {
+            "clear" => Some(RetainViolation::Clear),
+            "drop" => Some(RetainViolation::Drop),
+            _ => None
+        }
+    }
+}
+
+// What happens when a connected client sends a packet the broker doesn't support: one of a type
+// it can't decode, or one it doesn't take from clients, such as an UNSUBACK. Packets before
+// CONNECT always close the connection.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum UnknownPacketPolicy {
+    // Close the connection, as the spec requires for a protocol violation
+    Close,
+    // Skip the packet and keep reading, for devices that can't be fixed
+    Ignore
+}
+
+impl UnknownPacketPolicy {
+    pub fn from_str(s: &str) -> Option<UnknownPacketPolicy> {
+        match s {
+            "close" => Some(UnknownPacketPolicy::Close),
+            "ignore" => Some(UnknownPacketPolicy::Ignore),
+            _ => None
+        }
+    }
+}
+
+// What happens to a message published below the minimum QoS of its topic
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MinQosAction {
+    // Drop it. MQTT 3.1.1 has no way to tell the publisher, so a QoS 1 or 2 publish is still
+    // acknowledged.
+    Reject,
+    // Deliver it as if it had been published at the minimum QoS, which is what it is retained at.
+    // Subscribers get it at the QoS of their subscriptions either way.
+    Upgrade
+}
+
+impl MinQosAction {
+    pub fn from_str(s: &str) -> Option<MinQosAction> {
+        match s {
+            "reject" => Some(MinQosAction::Reject),
+            "upgrade" => Some(MinQosAction::Upgrade),
+            _ => None
+        }
+    }
+}
+
+// Messages published on topics matching `filter` must be at least `qos_lv`, e.g. on command topics
+// where silent loss is unacceptable
+#[derive(Debug, Clone, PartialEq)]
+pub struct MinQosRule {
+    pub filter: String,
+    pub qos_lv: QosLv,
+    pub action: MinQosAction
+}
+
+// What happens when a client connects with the client id of a connection that is still open
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TakeoverPolicy {
+    // The new connection replaces the old one, as the spec requires
+    Immediate,
+    // The new connection is refused with "identifier rejected"
+    Reject,
+    // The new connection waits up to this long for a packet on the old one. If one arrives, the
+    // old connection is alive and the new one is refused; otherwise the new one takes over. Behind
+    // NATs that drop connections silently, a reconnect can look like a duplicate client. The
+    // broker can't ping a client, so this relies on the old connection's own traffic, such as its
+    // next PINGREQ.
+    Grace(Duration)
+}
+
+impl TakeoverPolicy {
+    // `immediate`, `reject`, or `grace` followed by an optional wait in milliseconds
+    pub fn from_str(s: &str) -> Option<TakeoverPolicy> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words.as_slice() {
+            &["immediate"] => Some(TakeoverPolicy::Immediate),
+            &["reject"] => Some(TakeoverPolicy::Reject),
+            &["grace"] => Some(TakeoverPolicy::Grace(
+                Duration::from_millis(DEFAULT_TAKEOVER_GRACE_MILLIS))),
+            &["grace", millis] => millis.parse().ok()
+                .map(|millis| TakeoverPolicy::Grace(Duration::from_millis(millis))),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            &TakeoverPolicy::Immediate => "immediate".to_string(),
+            &TakeoverPolicy::Reject => "reject".to_string(),
+            &TakeoverPolicy::Grace(grace) => {
+                format!("grace {}", grace.as_secs() * 1000 + grace.subsec_millis() as u64)
+            }
+        }
+    }
+}
+
+// Largest will, in bytes, that a client may leave. Wills are kept for the whole connection.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct WillLimits {
+    pub max_topic_len: Option<usize>,
+    pub max_payload_len: Option<usize>
+}
+
+impl WillLimits {
+    fn allows(&self, topic_name: &str, payload: &[u8]) -> bool {
+        self.max_topic_len.map_or(true, |max| topic_name.len() <= max) &&
+            self.max_payload_len.map_or(true, |max| payload.len() <= max)
+    }
+}
+
+// Clients allowed to keep a session after disconnecting, by client id glob (see blocklist.rs) or
+// username. Other clients get a clean session even if they ask for a persistent one, so they can't
+// fill the broker with offline queues.
+#[derive(Debug, Clone, Default)]
+pub struct PersistentSessions {
+    pub client_ids: Vec<String>,
+    pub usernames: Vec<String>
+}
+
+impl PersistentSessions {
+    fn allows(&self, client_id: &str, username: Option<&str>) -> bool {
+        self.client_ids.iter().any(|pattern| blocklist::glob_matches(pattern, client_id)) ||
+            username.map_or(false, |username| self.usernames.iter().any(|u| u == username))
+    }
+}
+
+// How long, and for how many packet ids, QoS 2 messages from a client wait for their PUBREL.
+// Past these the broker forgets them, so a resent copy would be delivered again.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Qos2Limits {
+    pub release_timeout: Option<Duration>,
+    pub max_awaiting_rel: Option<usize>
+}
+
+// What is done about a connected client whose session holds more than the memory ceiling
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MemoryAction {
+    // QoS 0 messages for the client are dropped until it is back under the ceiling
+    DropQos0,
+    Disconnect
+}
+
+impl MemoryAction {
+    pub fn from_str(s: &str) -> Option<MemoryAction> {
+        match s {
+            "drop_qos0" => Some(MemoryAction::DropQos0),
+            "disconnect" => Some(MemoryAction::Disconnect),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            &MemoryAction::DropQos0 => "drop_qos0",
+            &MemoryAction::Disconnect => "disconnect"
+        }
+    }
+}
+
+// A ceiling on the approximate bytes each session holds (see Session::memory_bytes). Offline
+// sessions over it stop queueing messages, whatever the action.
+#[derive(Debug, Copy, Clone)]
+pub struct MemoryLimits {
+    pub max_bytes: Option<usize>,
+    pub action: MemoryAction
+}
+
+impl Default for MemoryLimits {
+    fn default() -> MemoryLimits {
+        MemoryLimits { max_bytes: None, action: MemoryAction::DropQos0 }
+    }
+}
+
+// Broker-wide event counters, shared by all connections and dispatch workers
+struct Counters {
+    // Most subscribers matching a single message
+    max_fanout: AtomicUsize,
+    // Messages whose fan-out was split into chunks
+    chunked_fanouts: AtomicUsize,
+    // Clients disconnected for sending PINGREQs too fast
+    ping_floods: AtomicUsize,
+    // Messages that failed payload validation, by the action taken
+    invalid_rejected: AtomicUsize,
+    invalid_flagged: AtomicUsize,
+    // Messages published below their topic's minimum QoS, by the action taken
+    min_qos_rejected: AtomicUsize,
+    min_qos_upgraded: AtomicUsize,
+    keep_alive: KeepAliveStats
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ValidationStats {
+    pub rejected: usize,
+    pub flagged: usize
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MinQosStats {
+    pub rejected: usize,
+    pub upgraded: usize
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FanoutStats {
+    // Most subscribers matching a single message
+    pub max: usize,
+    // Messages whose fan-out was split into chunks
+    pub chunked: usize
+}
+
+impl Counters {
+    fn record_fanout(&self, subscribers: usize, chunk_size: usize) {
+        self.max_fanout.fetch_max(subscribers, Ordering::Relaxed);
+        if chunk_size > 0 && subscribers > chunk_size {
+            self.chunked_fanouts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Broker-wide options that connections read
+#[derive(Debug, Clone)]
+struct Settings {
+    queue_limits: QueueLimits,
+    memory_limits: MemoryLimits,
+    // Topic filter clients without a username are confined to
+    anonymous_topics: Option<String>,
+    // Topic filter clients without a username may retain messages on. None allows it wherever
+    // they may publish.
+    anonymous_retain_topics: Option<String>,
+    retain_violation: RetainViolation,
+    unknown_packets: UnknownPacketPolicy,
+    takeover: TakeoverPolicy,
+    retained_qos: RetainedQos,
+    // Subscribers per dispatch step; 0 sends every message to all its subscribers in one step
+    fanout_chunk_size: usize,
+    // Highest QoS granted to subscriptions
+    max_qos: QosLv,
+    will_limits: WillLimits,
+    // PINGREQs per second a connection may send, in bursts of as many; None doesn't limit them
+    max_ping_rate: Option<u32>,
+    // None lets every client have a persistent session
+    persistent_sessions: Option<PersistentSessions>,
+    qos2_limits: Qos2Limits,
+    // Set in audit mode
+    audit: Option<AuditSink>,
+    observer: Option<Observer>,
+    validators: Validators,
+    // The first rule matching a message's topic applies
+    min_qos: Vec<MinQosRule>,
+    // Where QoS 1 and 2 messages dropped by queue policy are republished
+    dead_letters: Option<Arc<DeadLetters>>,
+    // Retained messages written on subscribe before the connection is flushed
+    retained_batch_size: usize,
+    // Whether tuning suggestions from the keep-alive statistics are logged
+    keep_alive_suggestions: bool,
+    // Assigns client ids to clients that connect with an empty one
+    client_ids: ClientIdGenerator,
+    // Names this broker in $SYS/broker/nodes/<id>/...
+    node_id: String
+}
+
+fn send<W: Write>(writer: &mut W, pkt: &CtrlPkt) -> Result<()> {
+    pkt.write_to(writer)?;
+    Ok(writer.flush()?)
+}
+
+fn send_publish<W: Write>(writer: &mut W, pkt: PublishRef) -> Result<()> {
+    pkt.write_to(writer)?;
+    Ok(writer.flush()?)
+}
+
+// Writes a delivery without flushing it, followed by a DUP copy if fault injection asks for one
+fn write_delivery<W: Write>(writer: &mut W, pkt: PublishRef, duplicate: bool) -> Result<()> {
+    pkt.write_to(writer)?;
+    if duplicate {
+        PublishRef { dup: true, ..pkt }.write_to(writer)?;
+    }
+    Ok(())
+}
+
+fn deliver<W: Write>(writer: &mut W, pkt: PublishRef, duplicate: bool) -> Result<()> {
+    write_delivery(writer, pkt, duplicate)?;
+    Ok(writer.flush()?)
+}
+
+// A delivery to a connected subscriber, decided on under the sessions lock and written once the
+// lock is released
+struct Delivery {
+    client_id: String,
+    topic_name: String,
+    qos_lv: QosLv,
+    pkt_id: Option<u16>,
+    duplicate: bool
+}
+
+// Delivers a publish to its subscribers. Which subscribers get it, their packet ids and what is
+// queued for offline ones are settled under the sessions lock, but the writes happen after it is
+// released so a slow subscriber doesn't hold up everyone else's sessions.
+fn publish_msg<'a, I>(broker: &Broker,
+                      sender_id: &str,
+                      topic_name: &str,
+                      msg: &Message,
+                      subscribers: I,
+                      now: Instant) -> Result<()>
+    where I: Iterator<Item = (&'a String, &'a QosLv)> {
+    let Broker { ref connections, ref sessions, ref pkt_id_gen, ref faults, ref settings, .. } =
+        *broker;
+    let (payload, trace, observed) = (&msg.payload, msg.trace.as_ref(), msg.observed.as_ref());
+    let (queue_limits, memory_limits) = (&settings.queue_limits, &settings.memory_limits);
+    let dead_letters = settings.dead_letters.as_ref().map(|dead_letters| &**dead_letters);
+    let deliveries = {
+        let mut sessions = sessions.write().unwrap();
+        let mut pkt_id_gen = pkt_id_gen.lock().unwrap();
+        let mut deliveries = vec![];
+        for (client_id, qos_lv) in subscribers {
+            if client_id == sender_id {
+                continue;
+            }
+            let client_topic_name = match sessions.get(client_id) {
+                Some(session) if !session.can_see(topic_name) => continue,
+                Some(session) => topic::unmount(session.namespace(), topic_name).to_string(),
+                None => topic_name.to_string()
+            };
+            let over_memory_limit = sessions.get(client_id)
+                .map_or(false, |session| session.over_memory_limit(memory_limits));
+            if over_memory_limit && memory_limits.action == MemoryAction::Disconnect {
+                if let Ok(true) = connections.close(client_id) {
+                    log!(Info, "Client {} is over its memory ceiling; disconnecting", client_id);
+                }
+            }
+            if !connections.is_connected(client_id) {
+                if let Some(session) = sessions.get_mut(client_id) {
+                    let msg = Message {
+                        qos_lv: *qos_lv,
+                        payload: Arc::clone(payload),
+                        trace: trace.cloned(),
+                        observed: observed.cloned()
+                    };
+                    if session.clean_session || *qos_lv == QosLv::AtMostOnce {
+                        msg.audit(format_args!("dropped for {}: not connected", client_id));
+                        msg.observe(Event::Dropped(Some(client_id), DropReason::NotConnected));
+                        session.stats.dropped += 1;
+                    } else if over_memory_limit {
+                        msg.audit(format_args!("dropped for {}: over its memory ceiling",
+                            client_id));
+                        msg.observe(Event::Dropped(Some(client_id), DropReason::MemoryLimit));
+                        deadletter::send(dead_letters, client_id, topic_name, msg.qos_lv, payload,
+                            DropReason::MemoryLimit);
+                        session.stats.dropped += 1;
+                    } else if !session.enqueue(topic_name, msg, now, queue_limits, dead_letters) {
+                        session.stats.dropped += 1;
+                    }
+                }
+                continue;
+            }
+            // QoS 1 and 2 messages are still delivered, since dropping them would break their
+            // guarantees; they are what the client's acknowledgements bring back down
+            if over_memory_limit && *qos_lv == QosLv::AtMostOnce {
+                audit(trace, format_args!("dropped for {}: over its memory ceiling", client_id));
+                observer::observe(observed,
+                    Event::Dropped(Some(client_id), DropReason::MemoryLimit));
+                if let Some(session) = sessions.get_mut(client_id) {
+                    session.stats.dropped += 1;
+                }
+                continue;
+            }
+            let pkt_id = if *qos_lv == QosLv::AtMostOnce {
+                None
+            } else {
+                match pkt_id_gen.gen() {
+                    None => return Err(Error::PublishOutOfPktIds),
+                    pkt_id => pkt_id
+                }
+            };
+            // Counted and in flight before it is written, so neither the stats nor the pending
+            // acknowledgements lag behind what the client sees. A failed write undoes both.
+            if let Some(session) = sessions.get_mut(client_id) {
+                session.stats.delivered += 1;
+                if let Some(pkt_id) = pkt_id {
+                    session.await_ack(pkt_id, topic_name, Message {
+                        qos_lv: *qos_lv,
+                        payload: Arc::clone(payload),
+                        trace: trace.cloned(),
+                        observed: observed.cloned()
+                    });
+                }
+            }
+            deliveries.push(Delivery {
+                client_id: client_id.clone(),
+                topic_name: client_topic_name,
+                qos_lv: *qos_lv,
+                pkt_id,
+                duplicate: *qos_lv == QosLv::AtLeastOnce &&
+                    faults.duplicate_qos1(client_id, topic_name)
+            });
+        }
+        deliveries
+    };
+
+    let mut failed = vec![];
+    for delivery in deliveries {
+        let Delivery { client_id, topic_name: client_topic_name, qos_lv, pkt_id, duplicate } =
+            delivery;
+        // A failed write is the subscriber's problem, not the publisher's
+        let delivered = match faults.delivery_delay(&client_id, topic_name) {
+            Some(delay) => {
+                let (connections, client_id) = (connections.clone(), client_id.clone());
+                let payload = Arc::clone(payload);
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    let pkt = PublishRef {
+                        dup: false,
+                        qos_lv,
+                        retain: false,
+                        topic_name: &client_topic_name,
+                        pkt_id,
+                        payload: &payload
+                    };
+                    connections.write(&client_id, |writer| deliver(writer, pkt, duplicate));
+                });
+                true
+            }
+            None => {
+                let pkt = PublishRef {
+                    dup: false,
+                    qos_lv,
+                    retain: false,
+                    topic_name: &client_topic_name,
+                    pkt_id,
+                    payload
+                };
+                connections.write_batched(&client_id,
+                    |writer| write_delivery(writer, pkt, duplicate))
+            }
+        };
+        if delivered {
+            audit(trace, format_args!("delivered to {} qos={} pkt_id={:?}", client_id,
+                qos_lv as u8, pkt_id));
+            observer::observe(observed, Event::Delivered(&client_id, qos_lv));
+        } else {
+            audit(trace, format_args!("dropped for {}: write failed", client_id));
+            observer::observe(observed, Event::Dropped(Some(&client_id), DropReason::WriteFailed));
+        }
+        if !delivered {
+            failed.push((client_id, pkt_id));
+        }
+    }
+
+    if failed.is_empty() {
+        return Ok(());
+    }
+    let mut sessions = sessions.write().unwrap();
+    for (client_id, pkt_id) in failed {
+        let session = match sessions.get_mut(&client_id) {
+            Some(session) => session,
+            None => continue
+        };
+        session.write_failed(pkt_id, pkt_id_gen);
+    }
+    Ok(())
+}
+
+// Retained messages on topics matching `filter`, ordered by topic
+fn retained_matching(retained_msgs: &RetainedStore, filter: &str) -> Vec<(String, Message)> {
+    let mut msgs: Vec<(String, Message)> = retained_msgs.topics.matching(filter).into_iter()
+        .filter_map(|topic_name| {
+            let msg = retained_msgs.msgs.get(&topic_name)?.msg.clone();
+            Some((topic_name, msg))
+        })
+        .collect();
+    msgs.sort_by(|a, b| a.0.cmp(&b.0));
+    msgs
+}
+
+// Hands a message to the dispatcher, which delivers it to subscribers from a worker thread. A
+// message with more than `fanout_chunk_size` subscribers is sent to them in chunks, so that one
+// message to a crowded topic doesn't hold up the other topics on its worker.
+fn dispatch_msg(broker: &Broker, sender_id: &str, topic_name: &str, msg: Message) {
+    let (sender_id, topic) = (sender_id.to_string(), topic_name.to_string());
+    let broker = broker.clone();
+    let chunk_size = broker.settings.fanout_chunk_size;
+    // A queued message's age counts from when it was published, not from when it was dispatched
+    let now = broker.clock.now();
+    // The subscribers when the fan-out started, and how many of them have been sent to
+    let mut subscribers: Option<HashMap<String, QosLv>> = None;
+    let mut sent = 0;
+    let dispatcher = Arc::clone(&broker.dispatcher);
+    dispatcher.dispatch_steps(topic_name, move || {
+        let subscribers = subscribers.get_or_insert_with(|| {
+            let mut subscribers = broker.subscriptions.subscribers(&topic);
+            // Delivered at the lower of the message's QoS and the subscription's
+            for sub_qos_lv in subscribers.values_mut() {
+                *sub_qos_lv = cmp::min(*sub_qos_lv, msg.qos_lv);
+            }
+            broker.counters.record_fanout(subscribers.len(), chunk_size);
+            msg.audit(format_args!("dispatched to {} subscribers", subscribers.len()));
+            msg.observe(Event::Matched(subscribers.len()));
+            subscribers
+        });
+        let chunk = if chunk_size == 0 { subscribers.len() } else { chunk_size };
+        if let Err(e) = publish_msg(&broker, &sender_id, &topic, &msg,
+            subscribers.iter().skip(sent).take(chunk), now) {
+            log!(Warn, "Failed to deliver message on {}: {:?}", topic, e);
+            msg.audit(format_args!("dropped for the remaining subscribers: {:?}", e));
+            msg.observe(Event::Dropped(None, DropReason::OutOfPktIds));
+            return true;
+        }
+        sent += chunk;
+        sent >= subscribers.len()
+    });
+}
+
+// Sends the deliveries the client hadn't acknowledged when its last connection ended again, in
+// the order they were first sent, as the spec requires of a resumed session: a PUBLISH with DUP
+// set, or a PUBREL for QoS 2 deliveries the client already sent PUBREC for. They keep their
+// packet ids, so a QoS 2 one the client already has isn't delivered to it twice, and move to the
+// session's current epoch so that acks on this connection complete them.
+fn resend_inflight<W: Write>(writer: &mut W, session: &mut Session) -> Result<()> {
+    let pkt_ids: Vec<u16> = session.waiting_for_ack.iter().map(|(pkt_id, _)| pkt_id).collect();
+    for pkt_id in pkt_ids {
+        let inflight = session.waiting_for_ack.remove(pkt_id).unwrap();
+        if inflight.released {
+            send(writer, &PubRel(pkt_id))?;
+        } else {
+            send_publish(writer, PublishRef {
+                dup: true,
+                qos_lv: inflight.msg.qos_lv,
+                retain: false,
+                topic_name: topic::unmount(session.namespace(), &inflight.topic_name),
+                pkt_id: Some(pkt_id),
+                payload: &inflight.msg.payload
+            })?;
+        }
+        inflight.msg.audit(format_args!("resent to {} pkt_id={}", session.client_id, pkt_id));
+        session.waiting_for_ack.insert(pkt_id, inflight);
+    }
+    Ok(())
+}
+
+// Sends the messages queued while the client was offline, minus those that have expired
+fn deliver_queued<W: Write>(writer: &mut W,
+                            session: &mut Session,
+                            pkt_id_gen: &Arc<Mutex<PktIdGen>>,
+                            now: Instant,
+                            max_age: Option<Duration>,
+                            dead_letters: Option<&DeadLetters>) -> Result<()> {
+    session.expire_queued(now, max_age, dead_letters);
+    let mut pkt_id_gen = pkt_id_gen.lock().unwrap();
+    while let Some(QueuedMessage { topic_name, msg, .. }) = session.pending_tx.pop_front() {
+        let pkt_id = pkt_id_gen.gen().ok_or(Error::PublishOutOfPktIds)?;
+        send_publish(writer, PublishRef {
+            dup: false,
+            qos_lv: msg.qos_lv,
+            retain: false,
+            topic_name: topic::unmount(session.namespace(), &topic_name),
+            pkt_id: Some(pkt_id),
+            payload: &msg.payload
+        })?;
+        msg.audit(format_args!("delivered to {} from the queue qos={} pkt_id={}",
+            session.client_id, msg.qos_lv as u8, pkt_id));
+        msg.observe(Event::Delivered(&session.client_id, msg.qos_lv));
+        session.stats.delivered += 1;
+        session.await_ack(pkt_id, &topic_name, msg);
+    }
+    Ok(())
+}
+
+// Request methods that an HTTP client's first bytes start with, followed by a space. None of them
+// is the start of a valid MQTT packet.
+const HTTP_METHODS: &[&str] =
+    &["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH", "PRI"];
+// Enough for the longest method and its space
+const HTTP_SNIFF_LEN: usize = 8;
+
+fn http_method(head: &[u8]) -> Option<&'static str> {
+    HTTP_METHODS.iter().cloned()
+        .find(|method| head.starts_with(method.as_bytes()) && head.get(method.len()) == Some(&b' '))
+}
+
+// Answers CONNECT with a refusing CONNACK, after which the connection is closed
+fn refuse<W: Write>(writer: &mut W, return_code: ConnAckRetCode) -> Result<()> {
+    send(writer, &ConnAck { session_present: false, return_code })?;
+    Err(Error::ConnectionRefused(return_code))
+}
+
+// Whether the connection open for `client_id` sends a packet within `grace`. False as soon as it
+// closes.
+fn sends_within(connections: &ConnectionManager, sessions: &RwLock<HashMap<String, Session>>,
+                client_id: &str, grace: Duration) -> bool {
+    let last_activity = || {
+        sessions.read().unwrap().get(client_id)
+            .map(|session| session.stats.activity.lock().unwrap().last_activity)
+    };
+    let (before, started) = (last_activity(), Instant::now());
+    while started.elapsed() < grace {
+        thread::sleep(cmp::min(Duration::from_millis(TAKEOVER_POLL_MILLIS), grace));
+        if !connections.is_connected(client_id) {
+            return false;
+        }
+        if last_activity() != before {
+            return true;
+        }
+    }
+    false
+}
+
+// The CONNACK return code for a CONNECT that failed to decode, for the errors where the spec
+// asks for one. Other errors close the connection without a reply: MQTT 3.1.1 has no packet
+// carrying an error after the CONNACK, which MQTT 5 would send a DISCONNECT reason code for.
+fn connack_for(e: &Error) -> Option<ConnAckRetCode> {
+    match *e {
+        Error::InvalidProtocol | Error::UnacceptableProtocolLv =>
+            Some(ConnAckRetCode::UnacceptableProtocolVer),
+        Error::IdRejected => Some(ConnAckRetCode::IdRejected),
+        _ => None
+    }
+}
+
+// Tenant names become a topic level, so they can't contain separators or wildcards
+fn is_valid_tenant(tenant: &str) -> bool {
+    !tenant.is_empty() && !tenant.contains(|c| c == '/' || c == '+' || c == '#')
+}
+
+fn check_for_session(client_id: &Option<String>,
+                     sessions: &Arc<RwLock<HashMap<String, Session>>>) -> Result<()> {
+    match client_id {
+        &Some(ref client_id) =>
+            if sessions.read().unwrap().contains_key(client_id) {
+                Ok(())
+            } else {
+                Err(Error::NoSession)
+            }
+        &None => Err(Error::NoSession)
+    }
+}
+
+// What is known about a connection when it ends, for Broker::teardown
+struct ConnState {
+    client_id: Option<String>,
+    // Set from CONNECT on
+    registration: Option<Registration>,
+    // With the client's namespace applied to the topic
+    will: Option<Will>,
+    // The client sent DISCONNECT, so its will is discarded
+    disconnected: bool,
+    // The session's activity, which this connection updates, from CONNECT on
+    activity: Option<Arc<Mutex<Activity>>>
+}
+
+impl ConnState {
+    fn new() -> ConnState {
+        ConnState {
+            client_id: None,
+            registration: None,
+            will: None,
+            disconnected: false,
+            activity: None
+        }
+    }
+}
+
+fn handle_client(mut stream: Box<dyn Transport>,
+                 broker: &Broker,
+                 listener_config: Arc<ListenerConfig>,
+                 conn: &mut ConnState) -> Result<()> {
+    let Broker { ref connections, ref sessions, ref retained_msgs, ref subscriptions,
+        ref pkt_id_gen, ref faults, ref clock, ref blocklist, ref draining, ref counters,
+        ref buf_pool, ref settings, .. } = *broker;
+    log!(Info, "Accepted connection from {}", stream.peer_addr());
+    let mut namespace = listener_config.mount_point.clone();
+    // Topic filter that the client's publishes and subscriptions must fall within
+    let mut allowed_topics: Option<String> = None;
+    // Topic filter that the client's retained publishes must fall within
+    let mut retain_topics: Option<String> = None;
+    let mut ping_limiter = settings.max_ping_rate
+        .map(|rate| RateLimiter::new(rate, rate, clock.now()));
+    // The client's keep-alive in seconds, and when its last packet arrived
+    let (mut keep_alive_secs, mut last_packet) = (0, clock.now());
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    // Browsers and HTTP health checks pointed at the MQTT port would otherwise show up as decode
+    // errors. A single read returns what has arrived without waiting for more, so MQTT clients
+    // aren't held up, and the bytes are then decoded as usual.
+    let mut head = vec![0; HTTP_SNIFF_LEN];
+    let len = stream.read(&mut head)?;
+    head.truncate(len);
+    if let Some(method) = http_method(&head) {
+        log!(Info, "Closing connection from {}: it sent an HTTP {} request, not an MQTT CONNECT",
+            stream.peer_addr(), method);
+        return Ok(());
+    }
+    let pkts = PacketStream::with_pool(Cursor::new(head).chain(stream.try_clone()?),
+        Arc::clone(buf_pool));
+    for pkt in pkts {
+        if let Some(ref activity) = conn.activity {
+            let now = clock.now();
+            {
+                let mut activity = activity.lock().unwrap();
+                activity.last_activity = now;
+                activity.decode.record(&pkt);
+            }
+            if pkt.is_ok() {
+                counters.keep_alive.record_gap(now - last_packet, keep_alive_secs);
+                last_packet = now;
+            }
+        }
+        // CONNECT may only be sent once on a connection, and a second one is a protocol violation
+        if let (&Some(ref cid), &Ok(Connect { .. })) = (&conn.client_id, &pkt) {
+            log!(Info, "Disconnecting {}: sent a second CONNECT", cid);
+            return Err(Error::UnexpectedPkt(pkt.unwrap()));
+        }
+        if let (&Some(ref cid), &Ok(ref pkt)) = (&conn.client_id, &pkt) {
+            let topic = match pkt {
+                &Publish { ref topic_name, .. } => Some(topic_name.as_str()),
+                _ => None
+            };
+            if faults.close_connection(cid, topic) {
+                log!(Info, "Fault injection: closing connection of {}", cid);
+                return Ok(());
+            }
+        }
+        match match pkt {
+            Ok(Connect {
+                protocol_lv,
+                connect_flags,
+                keep_alive,
+                client_id: cid,
+                will_topic,
+                will_message,
+                username,
+                password
+            }) => {
+                log!(Debug, "Received {:?}", Connect {
+                    protocol_lv,
+                    connect_flags,
+                    keep_alive,
+                    client_id: cid.clone(),
+                    will_topic: will_topic.clone(),
+                    will_message: will_message.clone(),
+                    username: username.clone(),
+                    password: password.clone()
+                });
+                if draining.load(Ordering::SeqCst) {
+                    log!(Info, "Refusing {}: the broker is draining for a handoff",
+                        stream.peer_addr());
+                    return refuse(&mut writer, ConnAckRetCode::ServerUnavailable);
+                }
+                let cid = if cid.is_empty() {
+                    let generated = {
+                        let sessions = sessions.read().unwrap();
+                        (0..CLIENT_ID_ATTEMPTS).map(|_| settings.client_ids.generate())
+                            .find(|cid| !cid.is_empty() && !sessions.contains_key(cid))
+                    };
+                    match generated {
+                        Some(cid) => {
+                            log!(Info, "Assigned client id {} to {}", cid, stream.peer_addr());
+                            cid
+                        }
+                        None => {
+                            log!(Warn, "Refusing {}: no unused client id to assign after {} \
+                                tries", stream.peer_addr(), CLIENT_ID_ATTEMPTS);
+                            return refuse(&mut writer, ConnAckRetCode::IdRejected);
+                        }
+                    }
+                } else {
+                    cid
+                };
+                // Clients on a pre-authenticated listener are whoever the listener says they are
+                let username = match listener_config.principal {
+                    Some(ref principal) => Some(principal.clone()),
+                    None => username
+                };
+                if blocklist.blocks_client_id(&cid) {
+                    log!(Info, "Refusing {}: client id is blocked", cid);
+                    return refuse(&mut writer, ConnAckRetCode::IdRejected);
+                }
+                if let Some(ref username) = username {
+                    if blocklist.blocks_username(username) {
+                        log!(Info, "Refusing {}: user {} is blocked", cid, username);
+                        return refuse(&mut writer, ConnAckRetCode::NotAuthorized);
+                    }
+                }
+                let mut clean_session = connect_flags.contains(ConnectFlags::CLEAN_SESSION);
+                let may_persist = settings.persistent_sessions.as_ref().map_or(true, |allowed| {
+                    allowed.allows(&cid, username.as_ref().map(|u| u.as_str()))
+                });
+                if !clean_session && !may_persist {
+                    log!(Info, "Giving {} a clean session: it may not keep a persistent one", cid);
+                    clean_session = true;
+                }
+                // Tenants get their own topic namespace, and their own client ids so that they
+                // can't take over or resume each other's sessions
+                let cid = if listener_config.tenant_isolation {
+                    match username {
+                        Some(ref tenant) if is_valid_tenant(tenant) => {
+                            namespace = Some(topic::mount(listener_config.mount_point.as_ref()
+                                .map(|m| m.as_str()), &format!("{}/", tenant)));
+                            format!("{}/{}", tenant, cid)
+                        }
+                        _ => return refuse(&mut writer, ConnAckRetCode::NotAuthorized)
+                    }
+                } else {
+                    cid
+                };
+                if username.is_none() {
+                    allowed_topics = settings.anonymous_topics.clone();
+                    retain_topics = settings.anonymous_retain_topics.clone();
+                }
+                conn.will = match (will_topic, will_message) {
+                    (Some(topic), Some(message))
+                        if connect_flags.contains(ConnectFlags::WILL_FLAG) => Some(Will {
+                        topic: topic::mount(namespace.as_ref().map(|n| n.as_str()), &topic),
+                        message,
+                        qos_lv: connect_flags.will_qos_lv()?,
+                        retain: connect_flags.contains(ConnectFlags::WILL_RETAIN)
+                    }),
+                    _ => None
+                };
+                if let Some(ref will) = conn.will {
+                    // The limits are on what the client sent, not on the mounted topic
+                    let ns = namespace.as_ref().map(|n| n.as_str());
+                    let topic_name = topic::unmount(ns, &will.topic);
+                    if topic::has_wildcards(topic_name) {
+                        log!(Info, "Disconnecting {}: will on {}, which has wildcards", cid,
+                            will.topic);
+                        return Err(Error::PublishTopicHasWildcards);
+                    }
+                    if !settings.will_limits.allows(topic_name, &will.message) {
+                        log!(Info, "Refusing {}: will on {} with {} bytes is over the limits", cid,
+                            will.topic, will.message.len());
+                        return refuse(&mut writer, ConnAckRetCode::NotAuthorized);
+                    }
+                }
+                // Otherwise a will could publish where the client itself isn't allowed to
+                if let (Some(allowed), Some(topic)) = (allowed_topics.as_ref(),
+                    conn.will.as_ref().map(|will| will.topic.as_str())) {
+                    let ns = namespace.as_ref().map(|n| n.as_str());
+                    if !topic::matches(allowed, topic::unmount(ns, topic)) {
+                        log!(Info, "Refusing {}: will topic {} is outside {}", cid, topic, allowed);
+                        return refuse(&mut writer, ConnAckRetCode::NotAuthorized);
+                    }
+                }
+                if let (Some(allowed), Some(will)) = (retain_topics.as_ref(), conn.will.as_mut()) {
+                    let ns = namespace.as_ref().map(|n| n.as_str());
+                    if will.retain && !topic::matches(allowed, topic::unmount(ns, &will.topic)) {
+                        if settings.retain_violation == RetainViolation::Drop {
+                            log!(Info, "Refusing {}: retained will on {} is outside {}", cid,
+                                will.topic, allowed);
+                            return refuse(&mut writer, ConnAckRetCode::NotAuthorized);
+                        }
+                        log!(Info, "Not retaining the will of {} on {}: outside {}", cid,
+                            will.topic, allowed);
+                        will.retain = false;
+                    }
+                }
+                if settings.takeover != TakeoverPolicy::Immediate && connections.is_connected(&cid) {
+                    let alive = match settings.takeover {
+                        TakeoverPolicy::Grace(grace) =>
+                            sends_within(&connections, &sessions, &cid, grace),
+                        _ => true
+                    };
+                    if alive {
+                        log!(Info, "Refusing {}: the client id is already connected", cid);
+                        return refuse(&mut writer, ConnAckRetCode::IdRejected);
+                    }
+                    log!(Info, "Client {} takes over its previous connection, which was silent",
+                        cid);
+                }
+                conn.client_id = Some(cid.clone());
+                // The pre-CONNECT timeout no longer applies. The spec allows one and a half
+                // keep-alive periods between packets before the client is considered gone.
+                stream.set_read_timeout(if keep_alive == 0 {
+                    None
+                } else {
+                    Some(Duration::from_millis(keep_alive as u64 * 1500))
+                })?;
+                counters.keep_alive.record_keep_alive(keep_alive);
+                keep_alive_secs = keep_alive;
+                last_packet = clock.now();
+                let mut sessions = sessions.write().unwrap();
+                // A drain closes the connections registered before it takes this lock, so one
+                // that started since the check above refuses the client here
+                if draining.load(Ordering::SeqCst) {
+                    drop(sessions);
+                    log!(Info, "Refusing {}: the broker is draining for a handoff", cid);
+                    return refuse(&mut writer, ConnAckRetCode::ServerUnavailable);
+                }
+                // Register while holding the sessions lock so that deliveries to this client id
+                // see the connection and its session together
+                conn.registration = Some(connections.register(&cid, &*stream)?);
+                let (session_present, return_code) =
+                    if clean_session || !sessions.contains_key(&cid) {
+                        (false, ConnAckRetCode::Accepted)
+                    } else {
+                        (true, ConnAckRetCode::Accepted)
+                    };
+                if clean_session {
+                    // Clear old session and create new one
+                    sessions.remove(&cid);
+                    sessions.insert(cid.clone(), Session::new(cid, clean_session, clock.now()));
+                } else {
+                    // Get old session or create a new one. A resumed session starts a new epoch, so
+                    // acks for what was sent before can't complete deliveries sent since.
+                    match sessions.get_mut(&cid) {
+                        Some(session) => {
+                            session.waiting_for_ack.next_epoch();
+                        }
+                        None => {
+                            sessions.insert(cid.clone(), Session::new(cid, clean_session,
+                                clock.now()));
+                        }
+                    }
+                }
+                let session_present = session_present && protocol_lv.has_session_present();
+                // The CONNACK and queued messages are written once the sessions lock is released
+                let mut out = vec![];
+                send(&mut out, &CtrlPkt::ConnAck { session_present, return_code })?;
+                let params = ConnectionParams {
+                    protocol_lv,
+                    keep_alive,
+                    clean_session,
+                    session_present,
+                    username: username.clone(),
+                    max_qos: settings.max_qos
+                };
+                if let Some(ref observer) = settings.observer {
+                    observer.connected(conn.client_id.as_ref().unwrap(), &params);
+                }
+                {
+                    let session = sessions.get_mut(conn.client_id.as_ref().unwrap()).unwrap();
+                    session.namespace = namespace.clone();
+                    session.params = Some(params);
+                    // Decode statistics are per connection, starting with its CONNECT
+                    let activity = Activity::new(clock.now());
+                    activity.lock().unwrap().decode.count(CtrlPktType::Connect);
+                    session.stats.activity = Arc::clone(&activity);
+                    conn.activity = Some(activity);
+                    resend_inflight(&mut out, session)?;
+                    deliver_queued(&mut out, session, &pkt_id_gen, clock.now(),
+                        settings.queue_limits.max_age,
+                        settings.dead_letters.as_ref().map(|dead_letters| &**dead_letters))?;
+                }
+                conn.registration.as_ref().unwrap()
+                    .write_releasing(sessions, |writer| Ok(writer.write_all(&out)?))
+            }
+            Ok(Publish { dup, qos_lv, retain, topic_name, pkt_id, payload }) => {
+                log!(Debug, "Received {:?}", Publish {
+                    dup,
+                    qos_lv,
+                    retain,
+                    topic_name: topic_name.clone(),
+                    pkt_id: pkt_id.clone(),
+                    payload: payload.clone()
+                });
+                check_for_session(&conn.client_id, &sessions)?;
+                // Wildcards belong in filters; the spec has a PUBLISH to a topic name with one in
+                // it treated as a protocol violation
+                if topic::has_wildcards(&topic_name) {
+                    log!(Info, "Disconnecting {}: published to {}, which has wildcards",
+                        conn.client_id.as_ref().unwrap(), topic_name);
+                    return Err(Error::PublishTopicHasWildcards);
+                }
+                let payload = Arc::new(payload);
+                let trace = settings.audit.as_ref().map(Trace::new);
+                audit(trace.as_ref(), format_args!("received from {} on {} qos={} pkt_id={:?} \
+                    retain={} bytes={}", conn.client_id.as_ref().unwrap(), topic_name,
+                    qos_lv as u8, pkt_id, retain, payload.len()));
+                // A QoS 2 message is delivered when it first arrives. Until the client releases
+                // it, sending it again only gets it acknowledged again.
+                let duplicate = qos_lv == QosLv::ExactlyOnce && {
+                    let mut sessions = sessions.write().unwrap();
+                    let session = sessions.get_mut(conn.client_id.as_ref().unwrap()).unwrap();
+                    let duplicate =
+                        !session.await_rel(pkt_id.unwrap(), clock.now(), &settings.qos2_limits);
+                    if duplicate {
+                        session.stats.qos2_duplicates += 1;
+                    }
+                    duplicate
+                };
+                // MQTT 3.1.1 has no way to refuse a PUBLISH, so a forbidden one is acknowledged
+                // and dropped
+                let mut allowed = allowed_topics.as_ref()
+                    .map_or(true, |allowed| topic::matches(allowed, &topic_name));
+                let mut retain = retain;
+                let may_retain = retain_topics.as_ref()
+                    .map_or(true, |allowed| topic::matches(allowed, &topic_name));
+                let topic_name = topic::mount(namespace.as_ref().map(|n| n.as_str()), &topic_name);
+                let observed = settings.observer.as_ref().map(|observer| {
+                    observer.observe(ObservedMessage {
+                        id: 0,
+                        sender_id: conn.client_id.clone().unwrap(),
+                        topic_name: topic_name.clone(),
+                        qos_lv,
+                        retain,
+                        payload: payload.to_vec()
+                    })
+                });
+                if duplicate {
+                    log!(Debug, "Not delivering duplicate {:?} from {} on {}", pkt_id,
+                        conn.client_id.as_ref().unwrap(), topic_name);
+                    audit(trace.as_ref(), format_args!("dropped: duplicate of an unreleased QoS 2 \
+                        message"));
+                    observer::observe(observed.as_ref(),
+                        Event::Dropped(None, DropReason::Duplicate));
+                } else if !allowed {
+                    log!(Info, "Dropping message from {} on {}: outside {}",
+                        conn.client_id.as_ref().unwrap(), topic_name,
+                        allowed_topics.as_ref().unwrap());
+                    audit(trace.as_ref(), format_args!("dropped: outside {}",
+                        allowed_topics.as_ref().unwrap()));
+                    observer::observe(observed.as_ref(),
+                        Event::Dropped(None, DropReason::NotAllowed));
+                } else if retain && !may_retain {
+                    let cid = conn.client_id.as_ref().unwrap();
+                    let filter = retain_topics.as_ref().unwrap();
+                    match settings.retain_violation {
+                        RetainViolation::Clear => {
+                            log!(Info, "Not retaining message from {} on {}: outside {}", cid,
+                                topic_name, filter);
+                            audit(trace.as_ref(), format_args!("not retained: outside {}", filter));
+                            retain = false;
+                        }
+                        RetainViolation::Drop => {
+                            log!(Info, "Dropping retained message from {} on {}: outside {}", cid,
+                                topic_name, filter);
+                            audit(trace.as_ref(), format_args!("dropped: retained outside {}",
+                                filter));
+                            observer::observe(observed.as_ref(),
+                                Event::Dropped(None, DropReason::RetainNotAllowed));
+                            allowed = false;
+                        }
+                    }
+                }
+                // The QoS the message is retained at, which a minimum QoS rule may raise. It is
+                // still acknowledged at the QoS it was sent with.
+                let mut msg_qos_lv = qos_lv;
+                let min_qos = settings.min_qos.iter()
+                    .find(|rule| topic::matches(&rule.filter, &topic_name));
+                match min_qos {
+                    Some(rule) if allowed && !duplicate && qos_lv < rule.qos_lv => {
+                        let cid = conn.client_id.as_ref().unwrap();
+                        match rule.action {
+                            MinQosAction::Reject => {
+                                log!(Info, "Dropping message from {} on {}: below QoS {} of {}",
+                                    cid, topic_name, rule.qos_lv as u8, rule.filter);
+                                audit(trace.as_ref(), format_args!("dropped: below QoS {} of {}",
+                                    rule.qos_lv as u8, rule.filter));
+                                observer::observe(observed.as_ref(),
+                                    Event::Dropped(None, DropReason::QosTooLow));
+                                counters.min_qos_rejected.fetch_add(1, Ordering::Relaxed);
+                                allowed = false;
+                            }
+                            MinQosAction::Upgrade => {
+                                audit(trace.as_ref(), format_args!("upgraded to QoS {} of {}",
+                                    rule.qos_lv as u8, rule.filter));
+                                counters.min_qos_upgraded.fetch_add(1, Ordering::Relaxed);
+                                msg_qos_lv = rule.qos_lv;
+                            }
+                        }
+                    }
+                    _ => ()
+                }
+                // Last, so that only payloads that would otherwise be delivered are parsed
+                if allowed && !duplicate {
+                    let cid = conn.client_id.as_ref().unwrap();
+                    match settings.validators.check(&topic_name, &payload) {
+                        Some((ValidationAction::Reject, reason)) => {
+                            log!(Info, "Dropping message from {} on {}: {}", cid, topic_name,
+                                reason);
+                            audit(trace.as_ref(), format_args!("dropped: invalid payload: {}",
+                                reason));
+                            observer::observe(observed.as_ref(),
+                                Event::Dropped(None, DropReason::Invalid));
+                            counters.invalid_rejected.fetch_add(1, Ordering::Relaxed);
+                            allowed = false;
+                        }
+                        Some((ValidationAction::Flag, reason)) => {
+                            log!(Info, "Delivering message from {} on {} with an invalid payload: \
+                                {}", cid, topic_name, reason);
+                            audit(trace.as_ref(), format_args!("flagged: invalid payload: {}",
+                                reason));
+                            counters.invalid_flagged.fetch_add(1, Ordering::Relaxed);
+                        }
+                        None => ()
+                    }
+                }
+                if allowed && !duplicate {
+                    let payload = if retain {
+                        retained_msgs.write().unwrap().publish(&topic_name, msg_qos_lv, payload)
+                    } else {
+                        payload
+                    };
+                    dispatch_msg(broker, conn.client_id.as_ref().unwrap(), &topic_name,
+                        Message { qos_lv: msg_qos_lv, payload, trace, observed });
+                }
+
+                match qos_lv {
+                    QosLv::AtMostOnce => Ok(()),
+                    _ if faults.drop_ack(conn.client_id.as_ref().unwrap(), Some(&topic_name)) => {
+                        log!(Info, "Fault injection: dropping ack of {:?}", pkt_id);
+                        Ok(())
+                    }
+                    QosLv::AtLeastOnce => send(&mut writer, &PubAck(pkt_id.unwrap())),
+                    QosLv::ExactlyOnce => send(&mut writer, &PubRec(pkt_id.unwrap()))
+                }
+            }
+            Ok(PubAck(pkt_id)) => {
+                log!(Debug, "Received {:?}", PubAck(pkt_id));
+                check_for_session(&conn.client_id, &sessions)?;
+                let mut sessions = sessions.write().unwrap();
+                let session = sessions.get_mut(conn.client_id.as_ref().unwrap()).unwrap();
+                if session.acked(pkt_id).is_some() {
+                    let inflight = session.waiting_for_ack.remove(pkt_id).unwrap();
+                    inflight.msg.audit(format_args!("acknowledged by {}", session.client_id));
+                    pkt_id_gen.lock().unwrap().rm(pkt_id);
+                }
+                Ok(())
+            }
+            Ok(PubRec(pkt_id)) => {
+                log!(Debug, "Received {:?}", PubRec(pkt_id));
+                check_for_session(&conn.client_id, &sessions)?;
+                // The client has the QoS 2 message. It keeps the packet id until PUBCOMP, so the
+                // delivery stays in flight, now only waiting for its release to be acknowledged.
+                let received = {
+                    let mut sessions = sessions.write().unwrap();
+                    let session = sessions.get_mut(conn.client_id.as_ref().unwrap()).unwrap();
+                    match session.acked(pkt_id) {
+                        Some(inflight) => {
+                            if !inflight.released {
+                                inflight.msg.audit(format_args!("received by {}",
+                                    conn.client_id.as_ref().unwrap()));
+                            }
+                            inflight.released = true;
+                            true
+                        }
+                        None => false
+                    }
+                };
+                if received {
+                    send(&mut writer, &PubRel(pkt_id))
+                } else {
+                    Ok(())
+                }
+            }
+            Ok(PubComp(pkt_id)) => {
+                log!(Debug, "Received {:?}", PubComp(pkt_id));
+                check_for_session(&conn.client_id, &sessions)?;
+                let mut sessions = sessions.write().unwrap();
+                let session = sessions.get_mut(conn.client_id.as_ref().unwrap()).unwrap();
+                if session.acked(pkt_id).is_some() {
+                    let inflight = session.waiting_for_ack.remove(pkt_id).unwrap();
+                    inflight.msg.audit(format_args!("completed by {}", session.client_id));
+                    pkt_id_gen.lock().unwrap().rm(pkt_id);
+                }
+                Ok(())
+            }
+            Ok(PubRel(pkt_id)) => {
+                log!(Debug, "Received {:?}", PubRel(pkt_id));
+                check_for_session(&conn.client_id, &sessions)?;
+                sessions.write().unwrap().get_mut(conn.client_id.as_ref().unwrap()).unwrap()
+                    .awaiting_rel.retain(|&(id, _)| id != pkt_id);
+                if faults.drop_ack(conn.client_id.as_ref().unwrap(), None) {
+                    log!(Info, "Fault injection: dropping PUBCOMP {}", pkt_id);
+                    Ok(())
+                } else {
+                    send(&mut writer, &PubComp(pkt_id))
+                }
+            }
+            Ok(Subscribe { pkt_id, subs }) => {
+                log!(Debug, "Received {:?}", Subscribe {
+                    pkt_id,
+                    subs: subs.clone()
+                });
+                check_for_session(&conn.client_id, &sessions)?;
+                // Deliveries take this lock too, and it's held until the client's connection is
+                // locked for the SUBACK, so a message on a filter granted here can't reach the
+                // client ahead of its SUBACK
+                let mut sessions = sessions.write().unwrap();
+                let session = sessions.get_mut(conn.client_id.as_ref().unwrap()).unwrap();
+                // One return code per filter, in request order, whether or not it was granted:
+                // clients match them up by index
+                let mut sub_ack_ret_codes: Vec<SubAckRetCode> = vec![];
+                let mut granted = vec![];
+                for (topic_name, requested_qos_lv) in subs {
+                    let allowed = allowed_topics.as_ref()
+                        .map_or(true, |allowed| topic::within(&topic_name, allowed));
+                    // MQTT 3.1.1 has no return code saying why, so a wildcard refused by the
+                    // listener is a plain failure
+                    let wildcard_refused = !listener_config.wildcard_subscriptions &&
+                        topic::has_wildcards(&topic_name);
+                    if wildcard_refused {
+                        log!(Debug, "Refusing wildcard subscription to {} from {}", topic_name,
+                            session.client_id);
+                    }
+                    sub_ack_ret_codes.push(if !topic::is_valid_filter(&topic_name) || !allowed ||
+                        wildcard_refused {
+                        SubAckRetCode::Failure
+                    } else {
+                        let topic_name =
+                            topic::mount(namespace.as_ref().map(|n| n.as_str()), &topic_name);
+                        let granted_qos_lv = cmp::min(requested_qos_lv, settings.max_qos);
+                        session.subscriptions.insert(topic_name.clone(), granted_qos_lv);
+                        granted.push((topic_name.clone(), granted_qos_lv));
+                        SubAckRetCode::from(granted_qos_lv)
+                    });
+                }
+                let client_subs: Vec<(String, String, QosLv)> = granted.iter()
+                    .map(|&(ref filter, qos_lv)| {
+                        (filter.clone(), session.client_id.clone(), qos_lv)
+                    })
+                    .collect();
+                subscriptions.subscribe(&client_subs);
+                let pkt = SubAck { pkt_id, sub_ack_ret_codes };
+                log!(Trace, "Response: {:?}", pkt);
+                log!(Trace, "{:?}", session);
+                log!(Trace, "{:?}", subscriptions.snapshot());
+                log!(Trace, "{:?}", pkt.serialize()?);
+                // Retained messages follow the SUBACK. They are flushed in batches rather than one
+                // by one, so that clients resubscribing to many retained topics at once, e.g.
+                // after a restart, don't each cost a write per message.
+                let mut batches = vec![pkt.serialize()?];
+                let retained_msgs = retained_msgs.read().unwrap();
+                let mut pkt_id_gen = pkt_id_gen.lock().unwrap();
+                let mut unflushed = 0;
+                for (filter, granted_qos_lv) in granted {
+                    for (topic_name, msg) in retained_matching(&retained_msgs, &filter) {
+                        let qos_lv = settings.retained_qos.qos_lv(msg.qos_lv, granted_qos_lv);
+                        let pkt_id = if qos_lv == QosLv::AtMostOnce {
+                            None
+                        } else {
+                            Some(pkt_id_gen.gen().ok_or(Error::PublishOutOfPktIds)?)
+                        };
+                        PublishRef {
+                            dup: false,
+                            qos_lv,
+                            retain: true,
+                            topic_name: topic::unmount(session.namespace(), &topic_name),
+                            pkt_id,
+                            payload: &msg.payload
+                        }.write_to(batches.last_mut().unwrap())?;
+                        unflushed += 1;
+                        if unflushed >= settings.retained_batch_size {
+                            batches.push(vec![]);
+                            unflushed = 0;
+                        }
+                        session.stats.delivered += 1;
+                        if let Some(pkt_id) = pkt_id {
+                            session.await_ack(pkt_id, &topic_name,
+                                Message::new(qos_lv, msg.payload));
+                        }
+                    }
+                }
+                drop((retained_msgs, pkt_id_gen));
+                conn.registration.as_ref().unwrap().write_releasing(sessions, |writer| {
+                    for batch in batches.iter().filter(|batch| !batch.is_empty()) {
+                        writer.write_all(batch)?;
+                        writer.flush()?;
+                    }
+                    Ok(())
+                })
+            }
+            Ok(pkt@PingReq) => {
+                log!(Debug, "Received {:?}", pkt);
+                check_for_session(&conn.client_id, &sessions)?;
+                // A PINGREQ costs the client nothing to send, so a flood of them would keep this
+                // thread decoding and answering them
+                if let Some(ref mut limiter) = ping_limiter {
+                    if !limiter.try_acquire(clock.now()) {
+                        log!(Info, "Client {} is sending PINGREQs too fast; disconnecting",
+                            conn.client_id.as_ref().unwrap());
+                        counters.ping_floods.fetch_add(1, Ordering::Relaxed);
+                        return Ok(());
+                    }
+                }
+                send(&mut writer, &PingResp)
+            }
+            Ok(pkt@Disconnect) => {
+                log!(Debug, "Received {:?}", pkt);
+                check_for_session(&conn.client_id, &sessions)?;
+                conn.disconnected = true;
+                return Ok(());
+            }
+            Ok(Unsubscribe { pkt_id, topic_filters }) => {
+                log!(Debug, "Received {:?}", Unsubscribe {
+                    pkt_id,
+                    topic_filters: topic_filters.clone()
+                });
+                check_for_session(&conn.client_id, &sessions)?;
+                let cid = conn.client_id.as_ref().unwrap();
+                let mut sessions = sessions.write().unwrap();
+                let session = sessions.get_mut(cid).unwrap();
+                // Subscriptions are keyed by the filter itself, so only the exact filter goes:
+                // unsubscribing from a/# leaves a subscription to a/b alone, and the other way
+                // around. Filters the client isn't subscribed to are acknowledged all the same.
+                let client_subs: Vec<(String, String)> = topic_filters.iter()
+                    .map(|filter| topic::mount(namespace.as_ref().map(|n| n.as_str()), filter))
+                    .filter(|filter| session.subscriptions.remove(filter).is_some())
+                    .map(|filter| (filter, cid.clone()))
+                    .collect();
+                subscriptions.unsubscribe(&client_subs);
+                drop(sessions);
+                send(&mut writer, &UnsubAck(pkt_id))
+            }
+            Ok(pkt@_) => {
+                log!(Debug, "Received {:?}", pkt);
+                check_for_session(&conn.client_id, &sessions)?;
+                let cid = conn.client_id.as_ref().unwrap();
+                log!(Info, "Client {} sent a {:?}, which clients aren't expected to send", cid,
+                    pkt);
+                count_unknown_packet(&sessions, cid);
+                if settings.unknown_packets == UnknownPacketPolicy::Ignore {
+                    Ok(())
+                } else {
+                    return Err(Error::UnimplementedPkt(pkt));
+                }
+            }
+            Err(Error::UnimplementedPktType(ty)) => {
+                let header = (ty as u8) << 4 | ty.reserved_flags().unwrap_or(0);
+                match conn.client_id {
+                    Some(ref cid) => {
+                        log!(Info, "Client {} sent a {:?} packet (fixed header {:#04x}), which \
+                            isn't supported", cid, ty, header);
+                        count_unknown_packet(&sessions, cid);
+                    }
+                    None => log!(Info, "Connection from {} sent a {:?} packet (fixed header \
+                        {:#04x}) instead of CONNECT", stream.peer_addr(), ty, header)
+                }
+                if conn.client_id.is_some() &&
+                    settings.unknown_packets == UnknownPacketPolicy::Ignore {
+                    Ok(())
+                } else {
+                    return Err(Error::UnimplementedPktType(ty));
+                }
+            }
+            Err(e) => {
+                log!(Debug, "{:?}", e);
+                if let (true, &Error::Io(ref e)) = (conn.client_id.is_some(), &e) {
+                    let kind = e.kind();
+                    if kind == io::ErrorKind::WouldBlock || kind == io::ErrorKind::TimedOut {
+                        counters.keep_alive.record_timeout();
+                    }
+                }
+                // Once the client is connected, the connection can only be closed
+                if let (None, Some(return_code)) = (conn.client_id.as_ref(), connack_for(&e)) {
+                    send(&mut writer, &CtrlPkt::ConnAck { session_present: false, return_code })?;
+                }
+                return Err(e);
+            }
+        } {
+            Err(e) => return Err(Error::from(e)),
+            _ => ()
+        }
+    }
+    Ok(())
+}
+
+fn count_unknown_packet(sessions: &RwLock<HashMap<String, Session>>, client_id: &str) {
+    if let Some(session) = sessions.write().unwrap().get_mut(client_id) {
+        session.stats.unknown_packets += 1;
+    }
+}
+
+// Snapshot of a client session, for operators and embedders
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub connected: bool,
+    pub subscriptions: usize,
+    // QoS 1 and 2 deliveries waiting for an acknowledgement
+    pub inflight: usize,
+    // Messages waiting for the client to connect
+    pub queued: usize,
+    pub delivered: u64,
+    pub dropped: u64,
+    // QoS 2 messages the client resent before releasing them
+    pub qos2_duplicates: u64,
+    // QoS 2 messages from the client waiting for a PUBREL
+    pub awaiting_rel: usize,
+    // QoS 2 messages from the client that were forgotten without a PUBREL
+    pub qos2_abandoned: u64,
+    // Packets from the client of a type the broker can't decode
+    pub unknown_packets: u64,
+    // When the client last sent a packet, on the broker's clock
+    pub last_activity: Instant,
+    // Acks from the client that were ignored as stale
+    pub stale_acks: u64,
+    // Goes up every time the session is resumed or imported
+    pub epoch: u32,
+    // What the client's current or last connection sent
+    pub decode: DecodeStats,
+    // Approximate bytes held for the session
+    pub memory_bytes: usize,
+    // What the client's last connection agreed to; None for sessions that were imported and
+    // haven't connected since
+    pub params: Option<ConnectionParams>
+}
+
+// What a client's connection sent, for tracking down a device that sends broken packets. A decode
+// error closes the connection, so a persistent session keeps the statistics until the client
+// connects again, with the packet that closed it counted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeStats {
+    // Packets decoded, by type (e.g. PUBLISH), including those of types the broker doesn't handle
+    pub packets: BTreeMap<&'static str, u64>,
+    // Packets that failed to decode, other than for the reasons below
+    pub malformed: u64,
+    // Packets with a remaining length over the 4 bytes the spec allows
+    pub oversized: u64,
+    // Packets with reserved flags set, in the fixed header or a CONNECT's flags
+    pub unknown_flags: u64
+}
+
+impl DecodeStats {
+    pub fn new() -> DecodeStats {
+        DecodeStats { packets: BTreeMap::new(), malformed: 0, oversized: 0, unknown_flags: 0 }
+    }
+
+    fn count(&mut self, ty: CtrlPktType) {
+        *self.packets.entry(ty.name()).or_insert(0) += 1;
+    }
+
+    // I/O errors, such as the connection closing, aren't the packet's fault and aren't counted
+    fn record(&mut self, pkt: &Result<CtrlPkt>) {
+        match *pkt {
+            Ok(ref pkt) => self.count(pkt.pkt_type()),
+            Err(Error::UnimplementedPktType(ty)) => self.count(ty),
+            Err(Error::Io(_)) => (),
+            Err(Error::MalformedRemainingLen) => self.oversized += 1,
+            Err(Error::InvalidFixedHeaderFlags) | Err(Error::InvalidConnectReservedFlag) =>
+                self.unknown_flags += 1,
+            Err(_) => self.malformed += 1
+        }
+    }
+}
+
+// The parameters a connection ended up with, after the broker's policies were applied to what the
+// CONNECT asked for. MQTT 3.1.1 doesn't negotiate a maximum packet size or receive maximum; those
+// will join them with MQTT 5.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionParams {
+    pub protocol_lv: ProtocolLv,
+    // In seconds, as the client asked: MQTT 3.1.1 has no way for the broker to change it. 0 means
+    // no keep-alive.
+    pub keep_alive: u16,
+    // False unless the client asked for a persistent session and may keep one
+    pub clean_session: bool,
+    // The CONNACK's session present flag
+    pub session_present: bool,
+    // After the listener's principal, if any, is applied
+    pub username: Option<String>,
+    // Highest QoS the client's subscriptions are granted
+    pub max_qos: QosLv
+}
+
+// A retained message, for operators and embedders
+#[derive(Debug, Clone)]
+pub struct RetainedInfo {
+    pub qos_lv: QosLv,
+    pub payload: Vec<u8>,
+    // When the message was retained, on the system clock
+    pub retained_at: SystemTime
+}
+
+// What the broker keeps for one client's session, for moving it to another broker, e.g. to debug a
+// misbehaving device on a staging broker. Filters and topics have the namespace applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionExport {
+    pub client_id: String,
+    pub clean_session: bool,
+    pub namespace: Option<String>,
+    // The session's epoch when it was exported. The importing broker starts the next one.
+    pub epoch: u32,
+    pub subscriptions: Vec<(String, QosLv)>,
+    // Messages waiting for the client to connect, oldest first
+    pub queued: Vec<QueuedExport>,
+    // (packet id, QoS, payload) of deliveries waiting for an acknowledgement, oldest first
+    pub inflight: Vec<(u16, QosLv, Vec<u8>)>,
+    // Packet ids of QoS 2 messages from the client waiting for a PUBREL, oldest first
+    pub awaiting_rel: Vec<u16>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedExport {
+    pub topic_name: String,
+    pub qos_lv: QosLv,
+    pub payload: Vec<u8>,
+    // How long the message had been queued for
+    pub age: Duration
+}
+
+// Handle to the shared broker state. Clones refer to the same broker.
+#[derive(Clone)]
+pub struct Broker {
+    connections: ConnectionManager,
+    sessions: Arc<RwLock<HashMap<String, Session>>>,
+    retained_msgs: Arc<RwLock<RetainedStore>>,
+    subscriptions: Arc<SubscriptionTable>,
+    pkt_id_gen: Arc<Mutex<PktIdGen>>,
+    clock: Arc<dyn Clock>,
+    faults: Arc<Faults>,
+    dispatcher: Arc<Dispatcher>,
+    // Shared by all clones, so that it can be changed while the broker runs
+    blocklist: Arc<Blocklist>,
+    // Shared by all clones. Once set by drain, every CONNECT is refused.
+    draining: Arc<AtomicBool>,
+    counters: Arc<Counters>,
+    // Decode buffers shared by all connections
+    buf_pool: Arc<BufPool>,
+    settings: Arc<Settings>
+}
+
+pub const BUF_POOL_SIZE: usize = 1024;
+// Packets larger than this are decoded into a buffer that is freed afterwards
+pub const BUF_POOL_MAX_BUF_LEN: usize = 64 * 1024;
+
+impl Broker {
+    pub fn new() -> Broker {
+        Broker::with_clock(Arc::new(SystemClock))
+    }
+
+    // Runs the broker's timers on `clock`, e.g. a VirtualClock in tests
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Broker {
+        Broker {
+            connections: ConnectionManager::new(),
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            retained_msgs: Arc::new(RwLock::new(RetainedStore::new())),
+            subscriptions: Arc::new(SubscriptionTable::new()),
+            pkt_id_gen: Arc::new(Mutex::new(PktIdGen::new())),
+            clock,
+            faults: Arc::new(Faults::new(vec![])),
+            dispatcher: Arc::new(Dispatcher::new(dispatch::DEFAULT_WORKERS)),
+            blocklist: Arc::new(Blocklist::new()),
+            draining: Arc::new(AtomicBool::new(false)),
+            counters: Arc::new(Counters {
+                max_fanout: AtomicUsize::new(0),
+                chunked_fanouts: AtomicUsize::new(0),
+                ping_floods: AtomicUsize::new(0),
+                invalid_rejected: AtomicUsize::new(0),
+                invalid_flagged: AtomicUsize::new(0),
+                min_qos_rejected: AtomicUsize::new(0),
+                min_qos_upgraded: AtomicUsize::new(0),
+                keep_alive: KeepAliveStats::new()
+            }),
+            buf_pool: Arc::new(BufPool::new(BUF_POOL_SIZE, BUF_POOL_MAX_BUF_LEN)),
+            settings: Arc::new(Settings {
+                queue_limits: QueueLimits::default(),
+                memory_limits: MemoryLimits::default(),
+                anonymous_topics: None,
+                anonymous_retain_topics: None,
+                retain_violation: RetainViolation::Clear,
+                unknown_packets: UnknownPacketPolicy::Close,
+                takeover: TakeoverPolicy::Immediate,
+                retained_qos: RetainedQos::Minimum,
+                fanout_chunk_size: DEFAULT_FANOUT_CHUNK_SIZE,
+                max_qos: QosLv::ExactlyOnce,
+                will_limits: WillLimits::default(),
+                max_ping_rate: Some(DEFAULT_MAX_PING_RATE),
+                persistent_sessions: None,
+                qos2_limits: Qos2Limits::default(),
+                audit: None,
+                observer: None,
+                validators: Validators::new(),
+                min_qos: vec![],
+                dead_letters: None,
+                retained_batch_size: DEFAULT_RETAINED_BATCH_SIZE,
+                keep_alive_suggestions: false,
+                client_ids: ClientIdGenerator::uuid(),
+                node_id: default_node_id()
+            })
+        }
+    }
+
+    // Enables fault injection. Clones made before this call keep the old rules.
+    pub fn set_faults(&mut self, faults: Faults) -> &mut Broker {
+        self.faults = Arc::new(faults);
+        self
+    }
+
+    // Replaces the dispatcher with one running `workers` fan-out threads
+    pub fn set_dispatch_workers(&mut self, workers: usize) -> &mut Broker {
+        self.dispatcher = Arc::new(Dispatcher::new(workers));
+        self
+    }
+
+    // Limits the queues of offline sessions. Clones made before this call keep the old limits.
+    pub fn set_queue_limits(&mut self, queue_limits: QueueLimits) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).queue_limits = queue_limits;
+        self
+    }
+
+    // Limits the memory each session may hold. Clones made before this call keep the old limits.
+    pub fn set_memory_limits(&mut self, memory_limits: MemoryLimits) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).memory_limits = memory_limits;
+        self
+    }
+
+    // Confines clients that connect without a username to the topics matching `filter`, e.g.
+    // public/#. Clones made before this call keep the old setting.
+    pub fn set_anonymous_topics(&mut self, filter: Option<String>) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).anonymous_topics = filter;
+        self
+    }
+
+    // Narrows where clients without a username may publish retained messages, since retained
+    // messages outlive the publisher. Clones made before this call keep the old setting.
+    pub fn set_anonymous_retain_topics(&mut self, filter: Option<String>) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).anonymous_retain_topics = filter;
+        self
+    }
+
+    // Sets what happens to packets of a type the broker can't decode. Clones made before this call
+    // keep the old setting.
+    pub fn set_unknown_packets(&mut self, policy: UnknownPacketPolicy) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).unknown_packets = policy;
+        self
+    }
+
+    // Sets the minimum QoS of messages published on matching topics. Clones made before this call
+    // keep the old rules.
+    pub fn set_min_qos(&mut self, rules: Vec<MinQosRule>) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).min_qos = rules;
+        self
+    }
+
+    // Sets what happens when a client id that is connected connects again. Clones made before this
+    // call keep the old setting.
+    pub fn set_takeover(&mut self, policy: TakeoverPolicy) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).takeover = policy;
+        self
+    }
+
+    // Sets what happens to retained publishes outside the retain filter. Clones made before this
+    // call keep the old setting.
+    pub fn set_retain_violation(&mut self, retain_violation: RetainViolation) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).retain_violation = retain_violation;
+        self
+    }
+
+    // Sets how many subscribers a dispatch worker sends a message to before giving other topics a
+    // turn; 0 turns chunking off. Clones made before this call keep the old setting.
+    pub fn set_fanout_chunk_size(&mut self, chunk_size: usize) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).fanout_chunk_size = chunk_size;
+        self
+    }
+
+    // Lets deliveries to a client wait to be written together with later ones (see WriteBatching);
+    // None, the default, writes each one right away. Clones made before this call keep the old
+    // setting.
+    pub fn set_write_batching(&mut self, batching: Option<WriteBatching>) -> &mut Broker {
+        self.connections.set_batching(batching);
+        self
+    }
+
+    // Caps the QoS granted to subscriptions, e.g. at QoS 1 to avoid keeping QoS 2 state. Clones
+    // made before this call keep the old setting.
+    pub fn set_max_qos(&mut self, max_qos: QosLv) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).max_qos = max_qos;
+        self
+    }
+
+    // Limits the wills clients may leave; CONNECTs with larger ones are refused. Clones made
+    // before this call keep the old limits.
+    pub fn set_will_limits(&mut self, will_limits: WillLimits) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).will_limits = will_limits;
+        self
+    }
+
+    // Disconnects clients sending more than `max_ping_rate` PINGREQs per second; None turns the
+    // limit off. Clones made before this call keep the old limit.
+    pub fn set_max_ping_rate(&mut self, max_ping_rate: Option<u32>) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).max_ping_rate = max_ping_rate;
+        self
+    }
+
+    // Limits the QoS 2 messages from each client that wait for a PUBREL. Clones made before this
+    // call keep the old limits.
+    pub fn set_qos2_limits(&mut self, qos2_limits: Qos2Limits) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).qos2_limits = qos2_limits;
+        self
+    }
+
+    // Republishes QoS 1 and 2 messages dropped by queue policy (a full queue, one queued for too
+    // long, or a session over its memory ceiling) on `topic`, as JSON saying whose they were and
+    // why, so that operators can inspect and replay them (see deadletter.rs). None stops. Clones
+    // made before this call keep the old setting.
+    pub fn set_dead_letter_topic(&mut self, topic: Option<String>) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).dead_letters = topic.map(|topic| {
+            let (tx, rx) = mpsc::channel::<deadletter::DeadLetter>();
+            // Without dead letters of its own, so that those dropped on their way to the
+            // dead-letter topic's subscribers don't come back around. The thread ends once every
+            // handle that can send it dead letters is gone.
+            let mut broker = self.clone();
+            Arc::make_mut(&mut broker.settings).dead_letters = None;
+            let dead_letter_topic = topic.clone();
+            thread::spawn(move || {
+                for letter in rx {
+                    let payload = Arc::new(letter.to_json().to_string().into_bytes());
+                    dispatch_msg(&broker, "", &dead_letter_topic,
+                        Message::new(letter.qos_lv, payload));
+                }
+            });
+            Arc::new(DeadLetters::new(topic, tx))
+        });
+        self
+    }
+
+    // Messages handed over to be republished on the dead-letter topic so far
+    pub fn dead_letters(&self) -> usize {
+        self.settings.dead_letters.as_ref().map_or(0, |dead_letters| dead_letters.sent())
+    }
+
+    // Sets an observer that is told what happens to every message from a client (see
+    // observer.rs). Clones made before this call keep the old setting.
+    pub fn set_dispatch_observer(&mut self, observer: Option<Observer>) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).observer = observer;
+        self
+    }
+
+    // Checks the payloads of messages published on topics matching `filter` with `validator`
+    // before they are delivered or retained (see validation.rs). Clones made before this call
+    // keep the old validators.
+    pub fn add_validator<V: PayloadValidator + 'static>(&mut self, filter: &str, validator: V,
+                                                         action: ValidationAction)
+        -> &mut Broker {
+        Arc::make_mut(&mut self.settings).validators.add(filter, validator, action);
+        self
+    }
+
+    // Sets how client ids are made up for clients that connect with an empty one (see
+    // clientid.rs). Clones made before this call keep the old setting.
+    pub fn set_client_id_generator(&mut self, generator: ClientIdGenerator) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).client_ids = generator;
+        self
+    }
+
+    // Turns on audit mode (see audit.rs), which records what happens to every message from a
+    // client in `sink`. Clones made before this call keep the old setting.
+    pub fn set_audit(&mut self, sink: Option<AuditSink>) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).audit = sink;
+        self
+    }
+
+    // Restricts persistent sessions to some clients; None allows them for everyone. Clones made
+    // before this call keep the old setting.
+    pub fn set_persistent_sessions(&mut self, persistent_sessions: Option<PersistentSessions>)
+        -> &mut Broker {
+        Arc::make_mut(&mut self.settings).persistent_sessions = persistent_sessions;
+        self
+    }
+
+    // Sets whether suggestions for tuning client keep-alives are logged, at most once every
+    // KEEP_ALIVE_SUGGESTION_SECS. Clones made before this call keep the old setting.
+    pub fn set_keep_alive_suggestions(&mut self, enabled: bool) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).keep_alive_suggestions = enabled;
+        self
+    }
+
+    // Sets the id the broker publishes its node statistics under. Clones made before this call keep
+    // the old setting.
+    pub fn set_node_id(&mut self, node_id: &str) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).node_id = node_id.to_string();
+        self
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.settings.node_id
+    }
+
+    // Sets how many retained messages are sent on subscribe before the connection is flushed (at
+    // least one). Clones made before this call keep the old setting.
+    pub fn set_retained_batch_size(&mut self, batch_size: usize) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).retained_batch_size = cmp::max(batch_size, 1);
+        self
+    }
+
+    // Sets the QoS of retained messages delivered on subscribe. Clones made before this call keep
+    // the old setting.
+    pub fn set_retained_qos(&mut self, retained_qos: RetainedQos) -> &mut Broker {
+        Arc::make_mut(&mut self.settings).retained_qos = retained_qos;
+        self
+    }
+
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(&self.clock)
+    }
+
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        let mut clients: Vec<ClientInfo> = self.sessions.read().unwrap().values()
+            .map(|session| {
+                let activity = session.stats.activity.lock().unwrap();
+                ClientInfo {
+                    client_id: session.client_id.clone(),
+                    connected: self.connections.is_connected(&session.client_id),
+                    subscriptions: session.subscriptions.len(),
+                    inflight: session.waiting_for_ack.len(),
+                    queued: session.pending_tx.len(),
+                    delivered: session.stats.delivered,
+                    dropped: session.stats.dropped,
+                    qos2_duplicates: session.stats.qos2_duplicates,
+                    awaiting_rel: session.awaiting_rel.len(),
+                    qos2_abandoned: session.stats.qos2_abandoned,
+                    unknown_packets: session.stats.unknown_packets,
+                    last_activity: activity.last_activity,
+                    stale_acks: session.stats.stale_acks,
+                    epoch: session.waiting_for_ack.epoch(),
+                    decode: activity.decode.clone(),
+                    memory_bytes: session.memory_bytes(),
+                    params: session.params.clone()
+                }
+            })
+            .collect();
+        clients.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        clients
+    }
+
+    // (topic filter, client id, QoS) of every subscription, or only those to `topic_filter`
+    pub fn subscriptions(&self, topic_filter: Option<&str>) -> Vec<(String, String, QosLv)> {
+        let mut subs = vec![];
+        for (filter, client_id_to_qos) in self.subscriptions.snapshot().iter() {
+            if topic_filter.map_or(true, |topic_filter| topic_filter == filter) {
+                for (client_id, qos_lv) in client_id_to_qos.iter() {
+                    subs.push((filter.clone(), client_id.clone(), *qos_lv));
+                }
+            }
+        }
+        subs.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        subs
+    }
+
+    // What the client's current or last connection sent, or None if it has no session
+    pub fn decode_stats(&self, client_id: &str) -> Option<DecodeStats> {
+        self.sessions.read().unwrap().get(client_id)
+            .map(|session| session.stats.activity.lock().unwrap().decode.clone())
+    }
+
+    // Topic filters the client is subscribed to, with their QoS, or None if it has no session
+    pub fn client_subscriptions(&self, client_id: &str) -> Option<Vec<(String, QosLv)>> {
+        self.sessions.read().unwrap().get(client_id).map(|session| {
+            let mut subs: Vec<(String, QosLv)> = session.subscriptions.iter()
+                .map(|(filter, qos_lv)| (filter.clone(), *qos_lv))
+                .collect();
+            subs.sort_by(|a, b| a.0.cmp(&b.0));
+            subs
+        })
+    }
+
+    // Subscribes the client's session to a topic filter on its behalf, as if it had sent a
+    // SUBSCRIBE. The filter is in the client's namespace, as in a SUBSCRIBE.
+    pub fn subscribe(&self, client_id: &str, filter: &str, qos_lv: QosLv) -> Result<()> {
+        if !topic::is_valid_filter(filter) {
+            return Err(Error::InvalidTopicFilter);
+        }
+        let qos_lv = cmp::min(qos_lv, self.settings.max_qos);
+        let mut sessions = self.sessions.write().unwrap();
+        let session = sessions.get_mut(client_id).ok_or(Error::NoSession)?;
+        let filter = topic::mount(session.namespace(), filter);
+        session.subscriptions.insert(filter.clone(), qos_lv);
+        self.subscriptions.subscribe(&[(filter, client_id.to_string(), qos_lv)]);
+        Ok(())
+    }
+
+    // Removes one of the client's subscriptions, named as in `subscribe`. Returns false if it
+    // wasn't subscribed.
+    pub fn unsubscribe(&self, client_id: &str, filter: &str) -> Result<bool> {
+        let mut sessions = self.sessions.write().unwrap();
+        let session = sessions.get_mut(client_id).ok_or(Error::NoSession)?;
+        let filter = topic::mount(session.namespace(), filter);
+        if session.subscriptions.remove(&filter).is_none() {
+            return Ok(false);
+        }
+        self.subscriptions.unsubscribe(&[(filter, client_id.to_string())]);
+        Ok(true)
+    }
+
+    // Sends a message straight to one client, bypassing subscriptions. The topic is in the
+    // client's namespace. If the client is offline, QoS 1 and 2 messages are queued for it within
+    // the queue limits. Returns false if the message was dropped.
+    pub fn push_message(&self, client_id: &str, topic_name: &str, qos_lv: QosLv, payload: Vec<u8>)
+        -> Result<bool> {
+        if topic::has_wildcards(topic_name) {
+            return Err(Error::PublishTopicHasWildcards);
+        }
+        let payload = Arc::new(payload);
+        let (client_topic_name, pkt_id) = {
+            let mut sessions = self.sessions.write().unwrap();
+            let session = sessions.get_mut(client_id).ok_or(Error::NoSession)?;
+            let topic_name = &topic::mount(session.namespace(), topic_name);
+            let msg = Message::new(qos_lv, Arc::clone(&payload));
+            if !self.connections.is_connected(client_id) {
+                let queued = qos_lv != QosLv::AtMostOnce &&
+                    session.enqueue(topic_name, msg, self.clock.now(),
+                        &self.settings.queue_limits,
+                        self.settings.dead_letters.as_ref().map(|dead_letters| &**dead_letters));
+                if !queued {
+                    session.stats.dropped += 1;
+                }
+                return Ok(queued);
+            }
+            let pkt_id = if qos_lv == QosLv::AtMostOnce {
+                None
+            } else {
+                let pkt_id = self.pkt_id_gen.lock().unwrap().gen()
+                    .ok_or(Error::PublishOutOfPktIds)?;
+                session.await_ack(pkt_id, topic_name, msg);
+                Some(pkt_id)
+            };
+            session.stats.delivered += 1;
+            (topic::unmount(session.namespace(), topic_name).to_string(), pkt_id)
+        };
+        let pkt = PublishRef {
+            dup: false,
+            qos_lv,
+            retain: false,
+            topic_name: &client_topic_name,
+            pkt_id,
+            payload: &payload
+        };
+        if self.connections.write(client_id, |writer| send_publish(writer, pkt)) {
+            return Ok(true);
+        }
+        let mut sessions = self.sessions.write().unwrap();
+        Ok(sessions.get_mut(client_id)
+            .map_or(false, |session| !session.write_failed(pkt_id, &self.pkt_id_gen)))
+    }
+
+    // Everything in the client's session, or None if it has none
+    pub fn export_session(&self, client_id: &str) -> Option<SessionExport> {
+        let sessions = self.sessions.read().unwrap();
+        let session = sessions.get(client_id)?;
+        let now = self.clock.now();
+        let mut subscriptions: Vec<(String, QosLv)> = session.subscriptions.iter()
+            .map(|(filter, qos_lv)| (filter.clone(), *qos_lv))
+            .collect();
+        subscriptions.sort_by(|a, b| a.0.cmp(&b.0));
+        Some(SessionExport {
+            client_id: client_id.to_string(),
+            clean_session: session.clean_session,
+            namespace: session.namespace.clone(),
+            epoch: session.waiting_for_ack.epoch(),
+            subscriptions,
+            queued: session.pending_tx.iter().map(|queued| QueuedExport {
+                topic_name: queued.topic_name.clone(),
+                qos_lv: queued.msg.qos_lv,
+                payload: queued.msg.payload.to_vec(),
+                age: now - queued.queued_at
+            }).collect(),
+            inflight: session.waiting_for_ack.iter()
+                .map(|(pkt_id, inflight)| {
+                    (pkt_id, inflight.msg.qos_lv, inflight.msg.payload.to_vec())
+                })
+                .collect(),
+            awaiting_rel: session.awaiting_rel.iter().map(|&(pkt_id, _)| pkt_id).collect()
+        })
+    }
+
+    // Recreates an exported session, as if the client had just disconnected, with its
+    // subscriptions and queued messages. The in-flight deliveries and QoS 2 packet ids aren't
+    // imported, as their packet ids may already be in use here. Returns false if the client
+    // already has a session.
+    pub fn import_session(&self, export: &SessionExport) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        if sessions.contains_key(&export.client_id) {
+            return false;
+        }
+        let now = self.clock.now();
+        let mut session = Session::new(export.client_id.clone(), export.clean_session, now);
+        session.namespace = export.namespace.clone();
+        session.waiting_for_ack.set_epoch(export.epoch.saturating_add(1));
+        let subs: Vec<(String, String, QosLv)> = export.subscriptions.iter()
+            .map(|&(ref filter, qos_lv)| {
+                (filter.clone(), export.client_id.clone(), cmp::min(qos_lv, self.settings.max_qos))
+            })
+            .collect();
+        for &(ref filter, _, qos_lv) in &subs {
+            session.subscriptions.insert(filter.clone(), qos_lv);
+        }
+        for queued in &export.queued {
+            session.pending_tx.push_back(QueuedMessage {
+                topic_name: queued.topic_name.clone(),
+                msg: Message::new(queued.qos_lv, Arc::new(queued.payload.clone())),
+                queued_at: now.checked_sub(queued.age).unwrap_or(now)
+            });
+        }
+        self.subscriptions.subscribe(&subs);
+        sessions.insert(export.client_id.clone(), session);
+        true
+    }
+
+    // Client ids and usernames refused at CONNECT
+    pub fn blocklist(&self) -> &Blocklist {
+        &self.blocklist
+    }
+
+    // Closes the client's connection. Returns false if it isn't connected.
+    pub fn kick(&self, client_id: &str) -> Result<bool> {
+        self.connections.close(client_id)
+    }
+
+    // Every persistent session, by client id, for handing them over to another broker
+    pub fn export_persistent_sessions(&self) -> Vec<SessionExport> {
+        let mut client_ids: Vec<String> = self.sessions.read().unwrap().values()
+            .filter(|session| !session.clean_session)
+            .map(|session| session.client_id.clone())
+            .collect();
+        client_ids.sort();
+        client_ids.iter().filter_map(|client_id| self.export_session(client_id)).collect()
+    }
+
+    // Starts handing the clients over to another broker: from now on every CONNECT is refused
+    // with "server unavailable", and the connected clients are closed without publishing their
+    // wills, so that they reconnect elsewhere and their sessions stop changing. Returns the number
+    // of connections closed, once their threads have torn them down and the messages already
+    // accepted have been delivered or queued, or after DRAIN_TIMEOUT_SECS.
+    pub fn drain(&self) -> usize {
+        self.draining.store(true, Ordering::SeqCst);
+        let closed = {
+            let _sessions = self.sessions.read().unwrap();
+            self.connections.close_all()
+        };
+        let deadline = Instant::now() + Duration::from_secs(DRAIN_TIMEOUT_SECS);
+        let released = closed.iter().filter(|conn| conn.wait_until(deadline)).count();
+        if released < closed.len() {
+            log!(Warn, "{} of {} drained connections were not torn down within {}s",
+                closed.len() - released, closed.len(), DRAIN_TIMEOUT_SECS);
+        }
+        if !self.dispatcher.wait_idle(deadline) {
+            log!(Warn, "Messages were still being delivered {}s into the drain",
+                DRAIN_TIMEOUT_SECS);
+        }
+        closed.len()
+    }
+
+    // Accepts CONNECTs again, e.g. after a handoff was called off
+    pub fn stop_draining(&self) {
+        self.draining.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn retained_topics(&self) -> Vec<String> {
+        let mut topics: Vec<String> =
+            self.retained_msgs.read().unwrap().msgs.keys().cloned().collect();
+        topics.sort();
+        topics
+    }
+
+    // QoS and payload of the message retained on `topic`
+    pub fn retained(&self, topic: &str) -> Option<(QosLv, Vec<u8>)> {
+        self.retained_msgs.read().unwrap().msgs.get(topic)
+            .map(|retained| (retained.msg.qos_lv, retained.msg.payload.to_vec()))
+    }
+
+    pub fn retained_info(&self, topic: &str) -> Option<RetainedInfo> {
+        self.retained_msgs.read().unwrap().msgs.get(topic).map(|retained| RetainedInfo {
+            qos_lv: retained.msg.qos_lv,
+            payload: retained.msg.payload.to_vec(),
+            retained_at: retained.retained_at
+        })
+    }
+
+    // Keeps the last `entries` changes to each retained topic outside $SYS (0, the default, keeps
+    // none), so that past values can be looked up with retained_at. Shared with every clone, like
+    // the retained messages themselves.
+    pub fn set_retained_history(&mut self, entries: usize) -> &mut Broker {
+        self.retained_msgs.write().unwrap().set_history_len(entries);
+        self
+    }
+
+    // The message that was retained on `topic` at `at`, e.g. a device's reported state at the
+    // time of an incident. None if nothing was retained then or the history doesn't reach back
+    // that far.
+    pub fn retained_at(&self, topic: &str, at: SystemTime) -> Option<RetainedInfo> {
+        self.retained_msgs.read().unwrap().retained_at(topic, at)
+            .map(|(retained_at, msg)| RetainedInfo {
+                qos_lv: msg.qos_lv,
+                payload: msg.payload.to_vec(),
+                retained_at
+            })
+    }
+
+    // The recorded changes to `topic`, oldest first: when each one happened, and the message
+    // retained then, or None where it was deleted
+    pub fn retained_history(&self, topic: &str) -> Vec<(SystemTime, Option<(QosLv, Vec<u8>)>)> {
+        match self.retained_msgs.read().unwrap().history.get(topic) {
+            Some(history) => history.iter().map(|&(changed_at, ref msg)| {
+                (changed_at, msg.as_ref().map(|msg| (msg.qos_lv, msg.payload.to_vec())))
+            }).collect(),
+            None => vec![]
+        }
+    }
+
+    // Retains a message on `topic`, replacing the one retained there, and delivers it to the
+    // topic's subscribers as if a client had published it. An empty payload deletes the retained
+    // message.
+    pub fn set_retained(&self, topic: &str, qos_lv: QosLv, payload: Vec<u8>) {
+        let payload = self.retained_msgs.write().unwrap().publish(topic, qos_lv, Arc::new(payload));
+        dispatch_msg(self, "", topic, Message::new(qos_lv, payload));
+    }
+
+    // Retains a message on `topic` without delivering it, e.g. one handed over from another broker
+    // whose subscribers already got it there
+    pub fn import_retained(&self, topic: &str, qos_lv: QosLv, payload: Vec<u8>) {
+        self.retained_msgs.write().unwrap().insert(topic, qos_lv, Arc::new(payload));
+    }
+
+    // Forgets the message retained on `topic` without telling subscribers. Returns false if
+    // nothing was retained there.
+    pub fn delete_retained(&self, topic: &str) -> bool {
+        self.retained_msgs.write().unwrap().remove(topic)
+    }
+
+    // Retained messages on every topic matching a topic filter, e.g. devices/+/status
+    pub fn retained_matching(&self, filter: &str) -> Vec<(String, QosLv, Vec<u8>)> {
+        retained_matching(&self.retained_msgs.read().unwrap(), filter).into_iter()
+            .map(|(topic_name, msg)| (topic_name, msg.qos_lv, msg.payload.to_vec()))
+            .collect()
+    }
+
+    // Publishes a broker status message. It is retained so that later subscribers see the latest
+    // value.
+    fn publish_sys(&self, topic_name: &str, payload: Vec<u8>) {
+        let payload = self.retained_msgs.write().unwrap()
+            .insert(topic_name, QosLv::AtMostOnce, Arc::new(payload));
+        // Client ids are never empty, so no subscriber is skipped as the sender
+        dispatch_msg(self, "", topic_name, Message::new(QosLv::AtMostOnce, payload));
+    }
+
+    // Publishes the broker version, and the lines describing the build and config from
+    // info::describe, as retained $SYS messages
+    pub fn publish_info(&self, info: &[String]) {
+        self.publish_sys("$SYS/broker/version", info::VERSION.as_bytes().to_vec());
+        self.publish_sys("$SYS/broker/info", info.join("\n").into_bytes());
+    }
+
+    pub fn buf_pool_stats(&self) -> PoolStats {
+        self.buf_pool.stats()
+    }
+
+    pub fn fanout_stats(&self) -> FanoutStats {
+        FanoutStats {
+            max: self.counters.max_fanout.load(Ordering::Relaxed),
+            chunked: self.counters.chunked_fanouts.load(Ordering::Relaxed)
+        }
+    }
+
+    pub fn subscription_stats(&self) -> TableStats {
+        self.subscriptions.stats()
+    }
+
+    // Subscriber counts and matches of every topic filter, or only `topic_filter`, with those
+    // accounting for the most deliveries first
+    pub fn filter_stats(&self, topic_filter: Option<&str>) -> Vec<FilterStats> {
+        self.subscriptions.filter_stats(topic_filter)
+    }
+
+    // Reclaims the space unsubscribes left in the subscription table. Returns the number of maps
+    // shrunk.
+    pub fn compact_subscriptions(&self) -> usize {
+        self.subscriptions.compact()
+    }
+
+    pub fn retained_stats(&self) -> RetainedStats {
+        self.retained_msgs.read().unwrap().stats()
+    }
+
+    // Forgets the payloads no retained message or delivery holds any more and reclaims the space
+    // replaced and deleted retained messages left. Returns the number of payloads forgotten.
+    pub fn compact_retained(&self) -> usize {
+        self.retained_msgs.write().unwrap().compact()
+    }
+
+    // Messages that failed payload validation so far, by the action taken
+    pub fn validation_stats(&self) -> ValidationStats {
+        ValidationStats {
+            rejected: self.counters.invalid_rejected.load(Ordering::Relaxed),
+            flagged: self.counters.invalid_flagged.load(Ordering::Relaxed)
+        }
+    }
+
+    // Messages published below their topic's minimum QoS so far, by the action taken
+    pub fn min_qos_stats(&self) -> MinQosStats {
+        MinQosStats {
+            rejected: self.counters.min_qos_rejected.load(Ordering::Relaxed),
+            upgraded: self.counters.min_qos_upgraded.load(Ordering::Relaxed)
+        }
+    }
+
+    // Clients disconnected so far for sending PINGREQs faster than `max_ping_rate`
+    pub fn ping_floods(&self) -> usize {
+        self.counters.ping_floods.load(Ordering::Relaxed)
+    }
+
+    // Keep-alive periods clients connected with and the time between their packets, across all
+    // clients since the broker started (see keepalive.rs)
+    pub fn keep_alive_stats(&self) -> KeepAliveHistogram {
+        self.counters.keep_alive.histogram()
+    }
+
+    // Publishes the statistics of every session under $SYS/broker/clients/<client id>/, with the
+    // client id escaped into one topic level, and the broker's own under $SYS/broker/.
+    // last_activity is a Unix timestamp. The topics of sessions that have ended are deleted.
+    pub fn publish_sys_stats(&self) {
+        let pool_stats = self.buf_pool_stats();
+        self.publish_sys("$SYS/broker/bufpool/hits", pool_stats.hits.to_string().into_bytes());
+        self.publish_sys("$SYS/broker/bufpool/misses", pool_stats.misses.to_string().into_bytes());
+        let fanout_stats = self.fanout_stats();
+        self.publish_sys("$SYS/broker/fanout/max", fanout_stats.max.to_string().into_bytes());
+        self.publish_sys("$SYS/broker/fanout/chunked",
+            fanout_stats.chunked.to_string().into_bytes());
+        self.publish_sys("$SYS/broker/ping_floods", self.ping_floods().to_string().into_bytes());
+        let validation_stats = self.validation_stats();
+        self.publish_sys("$SYS/broker/validation/rejected",
+            validation_stats.rejected.to_string().into_bytes());
+        self.publish_sys("$SYS/broker/validation/flagged",
+            validation_stats.flagged.to_string().into_bytes());
+        let min_qos_stats = self.min_qos_stats();
+        self.publish_sys("$SYS/broker/min_qos/rejected",
+            min_qos_stats.rejected.to_string().into_bytes());
+        self.publish_sys("$SYS/broker/min_qos/upgraded",
+            min_qos_stats.upgraded.to_string().into_bytes());
+        self.publish_sys("$SYS/broker/dead_letters", self.dead_letters().to_string().into_bytes());
+        let process_stats = process::stats();
+        let stats = [
+            ("rss_bytes", process_stats.rss_bytes),
+            ("open_fds", process_stats.open_fds.map(|fds| fds as u64)),
+            ("threads", process_stats.threads.map(|threads| threads as u64))
+        ];
+        for &(name, value) in stats.iter() {
+            if let Some(value) = value {
+                self.publish_sys(&format!("$SYS/broker/process/{}", name),
+                    value.to_string().into_bytes());
+            }
+        }
+        let keep_alive_stats = self.keep_alive_stats();
+        let buckets = [
+            ("clients", keepalive::bucket_names(KEEP_ALIVE_BOUNDS), &keep_alive_stats.keep_alives),
+            ("gaps", keepalive::bucket_names(GAP_BOUNDS), &keep_alive_stats.gaps)
+        ];
+        for &(name, ref bucket_names, counts) in buckets.iter() {
+            for (bucket, count) in bucket_names.iter().zip(counts) {
+                self.publish_sys(&format!("$SYS/broker/keep_alive/{}/{}", name, bucket),
+                    count.to_string().into_bytes());
+            }
+        }
+        self.publish_sys("$SYS/broker/keep_alive/timeouts",
+            keep_alive_stats.timeouts.to_string().into_bytes());
+        let retained_stats = self.retained_stats();
+        let stats = [
+            ("messages", retained_stats.messages as u64),
+            ("payloads", retained_stats.payloads as u64),
+            ("payload_bytes", retained_stats.payload_bytes as u64),
+            ("deduplicated", retained_stats.deduplicated)
+        ];
+        for &(name, value) in stats.iter() {
+            self.publish_sys(&format!("$SYS/broker/retained/{}", name),
+                value.to_string().into_bytes());
+        }
+        let table_stats = self.subscription_stats();
+        let stats = [
+            ("filters", table_stats.filters),
+            ("count", table_stats.subscriptions),
+            ("max_depth", table_stats.max_depth),
+            ("estimated_bytes", table_stats.estimated_bytes),
+            ("updates", table_stats.updates),
+            ("slow_updates", table_stats.slow_updates),
+            ("max_update_micros", table_stats.max_update_micros)
+        ];
+        for &(name, value) in stats.iter() {
+            self.publish_sys(&format!("$SYS/broker/subscriptions/{}", name),
+                value.to_string().into_bytes());
+        }
+        // Per node, so that the topics of several brokers can be collected side by side. A single
+        // broker has no peers, and routes every topic filter in its own table.
+        let clients = self.clients().iter().filter(|client| client.connected).count();
+        let stats = [("clients", clients), ("routes", table_stats.filters), ("peers", 0)];
+        for &(name, value) in stats.iter() {
+            self.publish_sys(&format!("$SYS/broker/nodes/{}/{}", self.settings.node_id, name),
+                value.to_string().into_bytes());
+        }
+        // The clients holding the most memory, one `<client id> <bytes>` line each, largest first
+        let mut by_memory = self.clients();
+        by_memory.sort_by(|a, b| b.memory_bytes.cmp(&a.memory_bytes));
+        let top: Vec<String> = by_memory.iter().take(MEMORY_TOP_CLIENTS)
+            .map(|client| format!("{} {}", client.client_id, client.memory_bytes))
+            .collect();
+        self.publish_sys("$SYS/broker/memory/top", top.join("\n").into_bytes());
+        let (now, wall_now) = (self.clock.now(), SystemTime::now());
+        let client_levels: HashSet<String> = by_memory.iter()
+            .map(|client| topic::escape_level(&client.client_id))
+            .collect();
+        for client in by_memory {
+            let last_activity = wall_now.checked_sub(now - client.last_activity).unwrap_or(wall_now)
+                .duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let stats = [
+                ("queued", client.queued as u64),
+                ("dropped", client.dropped),
+                ("delivered", client.delivered),
+                ("qos2_duplicates", client.qos2_duplicates),
+                ("awaiting_rel", client.awaiting_rel as u64),
+                ("qos2_abandoned", client.qos2_abandoned),
+                ("unknown_packets", client.unknown_packets),
+                ("stale_acks", client.stale_acks),
+                ("inflight", client.inflight as u64),
+                ("memory_bytes", client.memory_bytes as u64),
+                ("last_activity", last_activity)
+            ];
+            let client_level = topic::escape_level(&client.client_id);
+            for &(name, value) in stats.iter() {
+                let topic_name = format!("$SYS/broker/clients/{}/{}", client_level, name);
+                self.publish_sys(&topic_name, value.to_string().into_bytes());
+            }
+        }
+        // Otherwise the retained store would keep the statistics of every client id ever seen
+        let mut retained_msgs = self.retained_msgs.write().unwrap();
+        let ended: Vec<String> = retained_msgs.topics.matching("$SYS/broker/clients/+/+")
+            .into_iter()
+            .filter(|topic_name| topic_name.split('/').nth(3)
+                .map_or(false, |level| !client_levels.contains(level)))
+            .collect();
+        for topic_name in ended {
+            retained_msgs.remove(&topic_name);
+        }
+    }
+
+    // Publishes $SYS statistics every `interval` on the broker's clock, compacting the
+    // subscription table and retained messages first, and logs keep-alive suggestions if they're
+    // turned on
+    pub fn start_sys(&self, interval: Duration) -> JoinHandle<()> {
+        let broker = self.clone();
+        thread::spawn(move || {
+            let mut last_suggestions: Option<Instant> = None;
+            loop {
+                broker.clock.sleep(interval);
+                let shrunk = broker.compact_subscriptions();
+                if shrunk > 0 {
+                    log!(Debug, "Shrank {} maps in the subscription table", shrunk);
+                }
+                let dropped = broker.compact_retained();
+                if dropped > 0 {
+                    log!(Debug, "Forgot {} freed retained payloads", dropped);
+                }
+                broker.publish_sys_stats();
+                let now = broker.clock.now();
+                let suggestion_interval = Duration::from_secs(KEEP_ALIVE_SUGGESTION_SECS);
+                if broker.settings.keep_alive_suggestions &&
+                    last_suggestions.map_or(true, |last| now - last >= suggestion_interval) {
+                    for suggestion in broker.keep_alive_stats().suggestions() {
+                        log!(Info, "Keep-alive: {}", suggestion);
+                    }
+                    last_suggestions = Some(now);
+                }
+            }
+        })
+    }
+
+    // Serves a single connection accepted on a listener configured by `listener_config` on the
+    // current thread until it is closed
+    pub fn handle_client(&self, stream: Box<dyn Transport>, listener_config: Arc<ListenerConfig>)
+        -> Result<()> {
+        // Every writer to the connection, including other clients' deliveries, is a clone of this
+        let stream: Box<dyn Transport> = Box::new(RetryWrites::new(stream));
+        let transport = stream.try_clone()?;
+        let mut conn = ConnState::new();
+        let res = handle_client(stream, self, listener_config, &mut conn);
+        self.teardown(&*transport, conn, &res);
+        res
+    }
+
+    // Every connection ends here, however it ended: DISCONNECT, a protocol or I/O error, a
+    // keep-alive timeout, being taken over, or being kicked. Closes the connection, discards a
+    // clean session and releases its packet ids, and publishes the will unless the client sent
+    // DISCONNECT.
+    fn teardown(&self, transport: &dyn Transport, mut conn: ConnState, res: &Result<()>) {
+        let client_id = match conn.client_id.take() {
+            Some(client_id) => client_id,
+            None => {
+                if let &Err(ref e) = res {
+                    log!(Info, "Connection from {} closed before CONNECT completed: {:?}",
+                        transport.peer_addr(), e);
+                }
+                let _ = transport.shutdown();
+                return;
+            }
+        };
+        {
+            let mut sessions = self.sessions.write().unwrap();
+            // A connection that was taken over leaves the session to the new one
+            let current = conn.registration.as_ref().map_or(false, |reg| !reg.taken_over());
+            conn.registration = None;
+            if current && sessions.get(&client_id).map_or(false, |session| session.clean_session) {
+                let session = sessions.remove(&client_id).unwrap();
+                let mut pkt_id_gen = self.pkt_id_gen.lock().unwrap();
+                for (pkt_id, inflight) in session.waiting_for_ack.iter() {
+                    inflight.msg.audit(format_args!("unacknowledged when the session of {} ended",
+                        client_id));
+                    pkt_id_gen.rm(pkt_id);
+                }
+                let filters: Vec<(String, String)> = session.subscriptions.keys()
+                    .map(|filter| (filter.clone(), client_id.clone()))
+                    .collect();
+                self.subscriptions.unsubscribe(&filters);
+            }
+        }
+        // Other threads may still hold a handle to this connection, so close it explicitly
+        // rather than relying on drop. The client sees it closed only once its session is gone.
+        let _ = transport.shutdown();
+        // A client drained for a handoff is expected to reconnect to the new broker, so it isn't
+        // announced as gone
+        if self.is_draining() {
+            conn.will = None;
+        }
+        if let (false, Some(will)) = (conn.disconnected, conn.will.take()) {
+            log!(Debug, "Publishing the will of {} on {}", client_id, will.topic);
+            let mut message = Arc::new(will.message);
+            if will.retain {
+                message = self.retained_msgs.write().unwrap()
+                    .publish(&will.topic, will.qos_lv, message);
+            }
+            // Not skipping anyone: a client that took over the connection gets the will too
+            dispatch_msg(self, "", &will.topic, Message::new(will.qos_lv, message));
+        }
+        match (conn.disconnected, res) {
+            (true, _) => log!(Info, "Client {} disconnected", client_id),
+            (false, &Ok(())) => log!(Info, "Connection of {} closed", client_id),
+            (false, &Err(ref e)) => log!(Info, "Connection of {} closed: {:?}", client_id, e)
+        }
+    }
+
+    pub fn spawn_client(&self, stream: Box<dyn Transport>, listener_config: Arc<ListenerConfig>)
+        -> JoinHandle<()> {
+        self.spawn_client_after(stream, listener_config, None)
+    }
+
+    fn spawn_client_after(&self,
+                          stream: Box<dyn Transport>,
+                          listener_config: Arc<ListenerConfig>,
+                          delay: Option<Duration>) -> JoinHandle<()> {
+        let broker = self.clone();
+        thread::spawn(move || {
+            if let Some(delay) = delay {
+                thread::sleep(delay);
+            }
+            match broker.handle_client(stream, listener_config) {
+                Ok(_) => log!(Debug, "handle_client exited with Ok"),
+                Err(e) => log!(Warn, "handle_client exited with error: {:?}", e)
+            }
+        })
+    }
+
+    // Binds the listener and accepts connections on it from a new thread
+    pub fn listen(&self, listener_config: ListenerConfig) -> Result<JoinHandle<()>> {
+        let listener = listener_config.bind()?;
+        Ok(self.accept(listener, listener_config))
+    }
+
+    // Accepts connections on an already bound listener from a new thread
+    pub fn accept(&self, listener: TcpListener, listener_config: ListenerConfig) -> JoinHandle<()> {
+        let (broker, listener_config) = (self.clone(), Arc::new(listener_config));
+        let mut limiter = listener_config.connection_rate.map(|rate| {
+            RateLimiter::new(rate, listener_config.connection_burst.unwrap_or(rate),
+                self.clock.now())
+        });
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let mut delay = None;
+                        if let Some(ref mut limiter) = limiter {
+                            if !limiter.try_acquire(broker.clock.now()) {
+                                log!(Debug, "Closing a new connection to {}: over its \
+                                    connection_rate", listener_config.addr);
+                                continue;
+                            }
+                            if limiter.is_overloaded() {
+                                delay = listener_config.overload_connack_delay
+                                    .map(|max| max.mul_f64(rand::random::<f64>()));
+                            }
+                        }
+                        if let Err(e) = listener_config.configure(&stream) {
+                            log!(Warn, "Failed to set socket options: {:?}", e);
+                            continue;
+                        }
+                        broker.spawn_client_after(Box::new(stream), Arc::clone(&listener_config),
+                            delay);
+                    }
+                    Err(e) => log!(Warn, "{}", e)
+                }
+            }
+        })
+    }
+}
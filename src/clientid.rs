@@ -0,0 +1,39 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use uuid;
+
+// Makes up client ids for clients that connect with an empty one, which MQTT 3.1.1 allows for
+// clean sessions. The broker skips ids that already have a session, so a generator doesn't have
+// to avoid the ids clients pick for themselves.
+#[derive(Clone)]
+pub struct ClientIdGenerator(Arc<dyn Fn() -> String + Send + Sync>);
+
+impl ClientIdGenerator {
+    pub fn new<F: Fn() -> String + Send + Sync + 'static>(generate: F) -> ClientIdGenerator {
+        ClientIdGenerator(Arc::new(generate))
+    }
+
+    // Random UUIDs, the default
+    pub fn uuid() -> ClientIdGenerator {
+        ClientIdGenerator::new(uuid::new_v4)
+    }
+
+    // `prefix` followed by a number counting up from 1, e.g. auto-1, auto-2
+    pub fn counter(prefix: &str) -> ClientIdGenerator {
+        let (prefix, next) = (prefix.to_string(), AtomicUsize::new(1));
+        ClientIdGenerator::new(move || {
+            format!("{}{}", prefix, next.fetch_add(1, Ordering::Relaxed))
+        })
+    }
+
+    pub fn generate(&self) -> String {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for ClientIdGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ClientIdGenerator")
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Source of time for the broker's timers, so tests can substitute a VirtualClock and fast-forward
+// instead of sleeping
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    // Blocks the calling thread until `dur` has passed on this clock
+    fn sleep(&self, dur: Duration);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, dur: Duration) {
+        thread::sleep(dur)
+    }
+}
+
+// A clock that only moves when advance() is called. Threads sleeping on it wake once the clock
+// has been advanced past their deadline.
+pub struct VirtualClock {
+    start: Instant,
+    state: Mutex<VirtualState>,
+    changed: Condvar
+}
+
+struct VirtualState {
+    elapsed: Duration,
+    sleepers: usize
+}
+
+impl VirtualClock {
+    pub fn new() -> VirtualClock {
+        VirtualClock {
+            start: Instant::now(),
+            state: Mutex::new(VirtualState { elapsed: Duration::from_secs(0), sleepers: 0 }),
+            changed: Condvar::new()
+        }
+    }
+
+    pub fn advance(&self, dur: Duration) {
+        self.state.lock().unwrap().elapsed += dur;
+        self.changed.notify_all();
+    }
+
+    // Time advanced so far
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().unwrap().elapsed
+    }
+
+    // Blocks until at least `n` threads are sleeping on the clock, so a test knows the timers it
+    // is about to fire have been armed
+    pub fn wait_for_sleepers(&self, n: usize) {
+        let mut state = self.state.lock().unwrap();
+        while state.sleepers < n {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn sleep(&self, dur: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let deadline = state.elapsed + dur;
+        state.sleepers += 1;
+        self.changed.notify_all();
+        while state.elapsed < deadline {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.sleepers -= 1;
+    }
+}
@@ -0,0 +1,946 @@
+use broker::{self, MemoryAction, MinQosAction, MinQosRule, RetainViolation, RetainedQos,
+             TakeoverPolicy, UnknownPacketPolicy, DEFAULT_FANOUT_CHUNK_SIZE, DEFAULT_MAX_PING_RATE, DEFAULT_MAX_QUEUED_MESSAGES,
+             DEFAULT_RETAINED_BATCH_SIZE};
+use connection::{WriteBatching, DEFAULT_BATCH_BYTES};
+use dispatch;
+use error::{Error, Result};
+use fault::FaultRule;
+use libmqtt::ctrlpkt::QosLv;
+use log::Level;
+use net2::{TcpBuilder, TcpStreamExt};
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::iter;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::time::Duration;
+use topic;
+use validation::ValidationAction;
+
+// The config file is a list of `key value` lines. Some options apply to the whole broker and can
+// appear anywhere:
+//
+//     node_id eu-west-1a
+//     log_level debug
+//     audit true
+//     control_socket /run/mqtt-broker.sock
+//     sys_interval 10
+//     keep_alive_suggestions true
+//     dispatch_workers 4
+//     fanout_chunk_size 1000
+//     write_batch_delay 5
+//     write_batch_bytes 4096
+//     max_qos 2
+//     max_will_topic_length 256
+//     max_will_payload_size 4096
+//     max_ping_rate 10
+//     max_queued_messages 1000
+//     max_queued_age 60
+//     dead_letter_topic dead-letters
+//     qos2_release_timeout 300
+//     max_awaiting_rel 100
+//     max_connection_memory 1048576
+//     connection_memory_action disconnect
+//     anonymous_topics public/#
+//     anonymous_retain_topics public/status/#
+//     retain_violation clear
+//     retained_qos minimum
+//     retained_batch_size 100
+//     retained_history 100
+//     unknown_packets close
+//     session_takeover grace 1000
+//     block_client_id sensor-fw-1.2-*
+//     block_username legacy
+//     persistent_client_id gateway-*
+//     persistent_username fleet
+//     client_id_prefix auto-
+//     validate_json telemetry/# /etc/mqtt-broker/telemetry.json reject
+//     min_qos commands/# 1 reject
+//
+// `node_id` names the broker in its log lines and in $SYS/broker/nodes/<id>/... topics (default:
+// the host name). `audit` (default false) turns on audit mode, which logs what happens to every
+// message from a client (see audit.rs). `control_socket` opens the operator console (see
+// control.rs) on a Unix socket. `sys_interval` is how often, in seconds, statistics are published
+// on $SYS topics (default 10, 0 disables). `keep_alive_suggestions` (default false) also logs, at most hourly,
+// suggestions for tuning client keep-alives drawn from those statistics (see keepalive.rs).
+// `dispatch_workers` is the number of threads delivering messages to subscribers (default 4).
+// A message with more than `fanout_chunk_size` subscribers (default 1000, 0 for no limit) is sent
+// to them in chunks, with other topics getting a turn on the worker in between.
+// `write_batch_delay`, in milliseconds (default 0, off), lets a delivery wait that long in its
+// subscriber's buffer, so that it is written together with later ones, unless
+// `write_batch_bytes` (default 4096) are waiting already. `max_qos` (0, 1 or 2, default 2) is the
+// highest QoS granted; subscriptions asking for more get `max_qos`. `max_will_topic_length` and
+// `max_will_payload_size`, in bytes (default 0, no limit), refuse CONNECTs with larger wills. A
+// client sending more than `max_ping_rate` PINGREQs per second (default 10, 0 for no limit) is
+// disconnected.
+// `max_queued_messages` (default 1000) and `max_queued_age`, in minutes (default 0, no limit),
+// bound the QoS 1 and 2 messages kept for offline persistent sessions. `dead_letter_topic`
+// republishes those these limits or `max_connection_memory` drop on that topic, as JSON saying
+// whose they were and why (see deadletter.rs). A QoS 2 message from a client is deduplicated
+// until the client releases it; `qos2_release_timeout`, in seconds, and `max_awaiting_rel`, per
+// session (both default 0, no limit), bound how long and for how many packet ids the broker waits
+// for the PUBREL. `max_connection_memory` caps the approximate bytes each session holds in queued
+// and in-flight messages, subscriptions and QoS 2 packet ids (default 0, no limit). A connected
+// client over it has QoS 0 messages dropped (`connection_memory_action drop_qos0`, default) or is
+// disconnected (`disconnect`); an offline one stops having messages queued. `anonymous_topics`
+// confines clients without a username to a topic filter: they can only publish and subscribe
+// inside it.
+// `anonymous_retain_topics` further limits where they may publish retained messages (default:
+// wherever they may publish), and `retain_violation` is what happens to a retained message outside
+// it: `clear` (default) delivers it without retaining it, `drop` drops it. Wills are treated
+// the same way, except that a will that would be dropped refuses the connection.
+// `retained_qos` is the QoS retained messages are sent at on subscribe: `original`, `granted`
+// (the subscription's), or `minimum` of the two (default). They are written in batches of
+// `retained_batch_size` (default 100) between flushes of the connection. `retained_history` keeps
+// that many past values of each retained topic (default 0, none), which the console can look up
+// by time. A packet of a type the broker doesn't support is logged and counted; `unknown_packets`
+// is whether the connection is then closed (`close`, default) or the packet skipped (`ignore`).
+// `session_takeover` is what happens when a connected client id connects again: the new
+// connection replaces the old one (`immediate`, default) or is refused (`reject`), or with
+// `grace [milliseconds]` (default 1000) it waits that long and is refused only if the old
+// connection sends a packet meanwhile.
+// `block_client_id` (a glob, see blocklist.rs) and `block_username` can be repeated and refuse
+// matching CONNECTs; the console can change the blocklist at runtime. Once
+// `persistent_client_id` (a glob) or `persistent_username` is given, both repeatable, only
+// matching clients may keep a persistent session; the others get a clean session whatever they
+// ask for. Clients connecting with an empty client id are assigned a random UUID, or with
+// `client_id_prefix`, the prefix followed by a counter (auto-1, auto-2, ...).
+// `validate_json <topic filter> <schema file> [reject|flag]`, repeatable and only in builds with
+// the `json-schema` feature, checks the payloads published on matching topics against a JSON
+// Schema (see validation.rs); those that fail are dropped (`reject`, default) or delivered and
+// counted (`flag`). `min_qos <topic filter> <1|2> [reject|upgrade]`, repeatable, sets the lowest
+// QoS messages on matching topics may be published at, for command topics where silent loss is
+// unacceptable. Messages below it are dropped (`reject`, default) or handled as if published at it
+// (`upgrade`); the first matching line applies.
+//
+// A `listener <addr>` line starts a new listener
+// and the socket options that follow it apply to that listener only:
+//
+//     listener 0.0.0.0:1883
+//     tcp_nodelay true
+//     tcp_keepalive 60
+//     send_buffer_size 65536
+//     recv_buffer_size 65536
+//     backlog 1024
+//     connect_timeout 10
+//     write_timeout 30
+//     mount_point tenant-a/
+//     tenant_isolation true
+//     principal operator
+//     wildcard_subscriptions false
+//     connection_rate 100
+//     connection_burst 500
+//     overload_connack_delay 2000
+//
+// `connect_timeout` closes connections that don't send CONNECT within that many seconds and
+// `write_timeout` bounds every socket write. A write that times out is retried a few times (see
+// transport.rs) before the connection is closed. Both are independent of the MQTT keep-alive.
+// `mount_point` is prepended to the topics clients on the listener publish and subscribe to, and
+// stripped from the messages they receive, so clients on different listeners can't see each
+// other's topics. `tenant_isolation` does the same per CONNECT username: each user's topics, and
+// client ids, are prefixed with `<username>/`, and clients without a username are refused.
+// `principal` pre-authenticates the listener: its clients are treated as that user whatever
+// username they send, so on-host tooling can connect to a listener on 127.0.0.1 without
+// credentials. `wildcard_subscriptions false` refuses subscriptions to filters with `+` or `#`,
+// for public-facing listeners where a `#` subscription would expose and load too much.
+// `connection_rate` limits new connections per second (default 0, no limit), in
+// bursts of up to `connection_burst` (default: the rate); connections over the limit are closed
+// right away. While more than half of the burst is used up, new connections are also held for a
+// random time of up to `overload_connack_delay` milliseconds before they are served, which spreads
+// out the CONNACKs of a reconnect storm.
+//
+// A `fault <client id> <topic>` line starts a fault injection rule (see fault.rs); `*` matches
+// any client or topic:
+//
+//     fault sensor-1 *
+//     drop_ack 0.2
+//     delay_delivery 500
+//     duplicate_qos1 0.1
+//     close_connection 0.01
+//
+// `delay_delivery` is in milliseconds and the others are probabilities.
+//
+// Environment variables override the config file, which overrides the defaults. `MQTT_BROKER__`
+// followed by a key in upper case sets it, replacing every line with that key, e.g.
+// `MQTT_BROKER__MAX_QOS=1`. `MQTT_BROKER__LISTENERS__<n>__<option>` sets an option of the nth
+// listener (from 0) the same way, and its `ADDR` or just its `PORT`; setting the `ADDR` of the
+// listener after the last one adds a listener. Fault rules can't be overridden.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    pub faults: Vec<FaultRule>,
+    pub node_id: Option<String>,
+    pub log_level: Option<Level>,
+    pub audit: bool,
+    pub control_socket: Option<PathBuf>,
+    pub sys_interval: Option<Duration>,
+    pub keep_alive_suggestions: bool,
+    pub dispatch_workers: usize,
+    pub fanout_chunk_size: usize,
+    pub write_batching: Option<WriteBatching>,
+    pub max_qos: QosLv,
+    pub max_will_topic_length: Option<usize>,
+    pub max_will_payload_size: Option<usize>,
+    pub max_ping_rate: Option<u32>,
+    pub max_queued_messages: usize,
+    pub max_queued_age: Option<Duration>,
+    pub qos2_release_timeout: Option<Duration>,
+    pub max_awaiting_rel: Option<usize>,
+    pub max_connection_memory: Option<usize>,
+    pub connection_memory_action: MemoryAction,
+    pub anonymous_topics: Option<String>,
+    pub anonymous_retain_topics: Option<String>,
+    pub retain_violation: RetainViolation,
+    pub retained_qos: RetainedQos,
+    pub retained_batch_size: usize,
+    pub retained_history: usize,
+    pub unknown_packets: UnknownPacketPolicy,
+    pub session_takeover: TakeoverPolicy,
+    pub blocked_client_ids: Vec<String>,
+    pub blocked_usernames: Vec<String>,
+    pub persistent_client_ids: Vec<String>,
+    pub persistent_usernames: Vec<String>,
+    pub client_id_prefix: Option<String>,
+    pub dead_letter_topic: Option<String>,
+    pub json_schemas: Vec<SchemaRule>,
+    pub min_qos: Vec<MinQosRule>
+}
+
+// A `validate_json` line
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaRule {
+    pub filter: String,
+    pub path: PathBuf,
+    pub action: ValidationAction
+}
+
+const DEFAULT_SYS_INTERVAL_SECS: u64 = 10;
+
+pub const ENV_PREFIX: &str = "MQTT_BROKER__";
+
+// The block the options being parsed belong to
+enum Section {
+    None,
+    Listener,
+    Fault
+}
+
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    pub addr: SocketAddr,
+    pub tcp_nodelay: bool,
+    pub tcp_keepalive: Option<Duration>,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    pub backlog: i32,
+    pub connect_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub mount_point: Option<String>,
+    pub tenant_isolation: bool,
+    // False refuses filters with wildcards
+    pub wildcard_subscriptions: bool,
+    pub principal: Option<String>,
+    // New connections accepted per second, on average
+    pub connection_rate: Option<u32>,
+    pub connection_burst: Option<u32>,
+    pub overload_connack_delay: Option<Duration>
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listeners: vec![ListenerConfig::new("127.0.0.1:1883".parse().unwrap())],
+            faults: vec![],
+            node_id: None,
+            log_level: None,
+            audit: false,
+            control_socket: None,
+            sys_interval: Some(Duration::from_secs(DEFAULT_SYS_INTERVAL_SECS)),
+            keep_alive_suggestions: false,
+            dispatch_workers: dispatch::DEFAULT_WORKERS,
+            fanout_chunk_size: DEFAULT_FANOUT_CHUNK_SIZE,
+            write_batching: None,
+            max_qos: QosLv::ExactlyOnce,
+            max_will_topic_length: None,
+            max_will_payload_size: None,
+            max_ping_rate: Some(DEFAULT_MAX_PING_RATE),
+            max_queued_messages: DEFAULT_MAX_QUEUED_MESSAGES,
+            max_queued_age: None,
+            qos2_release_timeout: None,
+            max_awaiting_rel: None,
+            max_connection_memory: None,
+            connection_memory_action: MemoryAction::DropQos0,
+            anonymous_topics: None,
+            anonymous_retain_topics: None,
+            retain_violation: RetainViolation::Clear,
+            retained_qos: RetainedQos::Minimum,
+            retained_batch_size: DEFAULT_RETAINED_BATCH_SIZE,
+            retained_history: 0,
+            unknown_packets: UnknownPacketPolicy::Close,
+            session_takeover: TakeoverPolicy::Immediate,
+            blocked_client_ids: vec![],
+            blocked_usernames: vec![],
+            persistent_client_ids: vec![],
+            persistent_usernames: vec![],
+            client_id_prefix: None,
+            dead_letter_topic: None,
+            json_schemas: vec![],
+            min_qos: vec![]
+        }
+    }
+}
+
+impl Config {
+    // Reads the config file with the environment's overrides applied
+    pub fn from_file(path: &str) -> Result<Config> {
+        let mut s = String::new();
+        File::open(path)?.read_to_string(&mut s)?;
+        Config::parse_with_env(&s, env::vars())
+    }
+
+    // The defaults with the environment's overrides applied, for running without a config file
+    pub fn from_env() -> Result<Config> {
+        if env::vars().any(|(name, _)| name.starts_with(ENV_PREFIX)) {
+            let default_listener = Config::default().listeners[0].addr;
+            Config::parse_with_env(&format!("listener {}", default_listener), env::vars())
+        } else {
+            Ok(Config::default())
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Config> {
+        Config::parse_with_env(s, vec![])
+    }
+
+    // Parses `s` with the `MQTT_BROKER__` variables among `vars` applied on top. Other variables
+    // are ignored. Problems with an override are reported against the variable's name.
+    pub fn parse_with_env<I: IntoIterator<Item = (String, String)>>(s: &str, vars: I)
+        -> Result<Config> {
+        let mut lines: Vec<(String, String)> = s.lines().enumerate()
+            .map(|(i, line)| (format!("line {}", i + 1), line.to_string()))
+            .collect();
+        let mut overrides: Vec<(String, String)> = vars.into_iter()
+            .filter(|&(ref name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        // Listeners are added before their other options are set
+        overrides.sort_by_key(|&(ref name, _)| (!name.ends_with("__ADDR"), name.clone()));
+        for (name, value) in overrides {
+            apply_override(&mut lines, &name, &value)
+                .map_err(|msg| Error::Config(format!("{}: {}", name, msg)))?;
+        }
+        let mut listeners: Vec<ListenerConfig> = vec![];
+        let mut faults: Vec<FaultRule> = vec![];
+        let mut section = Section::None;
+        let (mut node_id, mut log_level, mut control_socket) = (None, None, None);
+        let (mut audit, mut keep_alive_suggestions) = (false, false);
+        let mut sys_interval = Some(Duration::from_secs(DEFAULT_SYS_INTERVAL_SECS));
+        let (mut dispatch_workers, mut fanout_chunk_size) =
+            (dispatch::DEFAULT_WORKERS, DEFAULT_FANOUT_CHUNK_SIZE);
+        let (mut write_batch_delay, mut write_batch_bytes) = (None, DEFAULT_BATCH_BYTES);
+        let mut max_qos = QosLv::ExactlyOnce;
+        let (mut max_will_topic_length, mut max_will_payload_size) = (None, None);
+        let mut max_ping_rate = Some(DEFAULT_MAX_PING_RATE);
+        let (mut max_queued_messages, mut max_queued_age) = (DEFAULT_MAX_QUEUED_MESSAGES, None);
+        let (mut qos2_release_timeout, mut max_awaiting_rel) = (None, None);
+        let (mut max_connection_memory, mut connection_memory_action) =
+            (None, MemoryAction::DropQos0);
+        let (mut anonymous_topics, mut anonymous_retain_topics) = (None, None);
+        let (mut retain_violation, mut retained_qos) =
+            (RetainViolation::Clear, RetainedQos::Minimum);
+        let (mut retained_batch_size, mut retained_history) = (DEFAULT_RETAINED_BATCH_SIZE, 0);
+        let mut unknown_packets = UnknownPacketPolicy::Close;
+        let mut session_takeover = TakeoverPolicy::Immediate;
+        let (mut blocked_client_ids, mut blocked_usernames) = (vec![], vec![]);
+        let (mut persistent_client_ids, mut persistent_usernames) = (vec![], vec![]);
+        let (mut client_id_prefix, mut dead_letter_topic) = (None, None);
+        let (mut json_schemas, mut min_qos) = (vec![], vec![]);
+        for &(ref source, ref line) in &lines {
+            let line = line.trim();
+            if line.len() == 0 || line.starts_with("#") {
+                continue;
+            }
+            let (key, value) = split_line(line);
+            let err = |msg: &str| Error::Config(format!("{}: {}", source, msg));
+            if key == "node_id" {
+                if !broker::is_valid_node_id(value) {
+                    return Err(err("expected a node id without /, + or #"));
+                }
+                node_id = Some(value.to_string());
+                continue;
+            }
+            if key == "log_level" {
+                log_level = Some(Level::from_str(value)
+                    .ok_or_else(|| err("expected error, warn, info, debug or trace"))?);
+                continue;
+            }
+            if key == "audit" {
+                audit = parse_bool(value).ok_or_else(|| err("expected true or false"))?;
+                continue;
+            }
+            if key == "control_socket" {
+                if value.is_empty() {
+                    return Err(err("expected a socket path"));
+                }
+                control_socket = Some(PathBuf::from(value));
+                continue;
+            }
+            if key == "sys_interval" {
+                sys_interval = parse_secs(value).ok_or_else(|| err("expected seconds"))?;
+                continue;
+            }
+            if key == "keep_alive_suggestions" {
+                keep_alive_suggestions = parse_bool(value)
+                    .ok_or_else(|| err("expected true or false"))?;
+                continue;
+            }
+            if key == "dispatch_workers" {
+                dispatch_workers = value.parse().map_err(|_| err("expected a number"))?;
+                continue;
+            }
+            if key == "fanout_chunk_size" {
+                fanout_chunk_size = value.parse().map_err(|_| err("expected a number"))?;
+                continue;
+            }
+            if key == "write_batch_delay" {
+                write_batch_delay = match value.parse() {
+                    Ok(0) => None,
+                    Ok(millis) => Some(Duration::from_millis(millis)),
+                    Err(_) => return Err(err("expected milliseconds"))
+                };
+                continue;
+            }
+            if key == "write_batch_bytes" {
+                write_batch_bytes = match value.parse() {
+                    Ok(0) | Err(_) => return Err(err("expected a positive number")),
+                    Ok(max_bytes) => max_bytes
+                };
+                continue;
+            }
+            if key == "max_qos" {
+                max_qos = value.parse().ok().and_then(|qos| QosLv::from_int(qos).ok())
+                    .ok_or_else(|| err("expected 0, 1 or 2"))?;
+                continue;
+            }
+            if key == "max_will_topic_length" {
+                max_will_topic_length = parse_limit(value).ok_or_else(|| err("expected bytes"))?;
+                continue;
+            }
+            if key == "max_will_payload_size" {
+                max_will_payload_size = parse_limit(value).ok_or_else(|| err("expected bytes"))?;
+                continue;
+            }
+            if key == "max_ping_rate" {
+                max_ping_rate = match value.parse() {
+                    Ok(0) => None,
+                    Ok(rate) => Some(rate),
+                    Err(_) => return Err(err("expected PINGREQs per second"))
+                };
+                continue;
+            }
+            if key == "max_queued_messages" {
+                max_queued_messages = value.parse().map_err(|_| err("expected a number"))?;
+                continue;
+            }
+            if key == "dead_letter_topic" {
+                if value.is_empty() || topic::has_wildcards(value) {
+                    return Err(err("expected a topic without wildcards"));
+                }
+                dead_letter_topic = Some(value.to_string());
+                continue;
+            }
+            if key == "max_queued_age" {
+                max_queued_age = parse_secs(value).ok_or_else(|| err("expected minutes"))?
+                    .map(|minutes| minutes * 60);
+                continue;
+            }
+            if key == "qos2_release_timeout" {
+                qos2_release_timeout = parse_secs(value).ok_or_else(|| err("expected seconds"))?;
+                continue;
+            }
+            if key == "max_awaiting_rel" {
+                max_awaiting_rel = parse_limit(value).ok_or_else(|| err("expected a number"))?;
+                continue;
+            }
+            if key == "max_connection_memory" {
+                max_connection_memory = parse_limit(value).ok_or_else(|| err("expected bytes"))?;
+                continue;
+            }
+            if key == "connection_memory_action" {
+                connection_memory_action = MemoryAction::from_str(value)
+                    .ok_or_else(|| err("expected drop_qos0 or disconnect"))?;
+                continue;
+            }
+            if key == "anonymous_topics" {
+                if value.is_empty() {
+                    return Err(err("expected a topic filter"));
+                }
+                anonymous_topics = Some(value.to_string());
+                continue;
+            }
+            if key == "anonymous_retain_topics" {
+                if value.is_empty() {
+                    return Err(err("expected a topic filter"));
+                }
+                anonymous_retain_topics = Some(value.to_string());
+                continue;
+            }
+            if key == "retain_violation" {
+                retain_violation = RetainViolation::from_str(value)
+                    .ok_or_else(|| err("expected clear or drop"))?;
+                continue;
+            }
+            if key == "retained_qos" {
+                retained_qos = RetainedQos::from_str(value)
+                    .ok_or_else(|| err("expected original, granted or minimum"))?;
+                continue;
+            }
+            if key == "unknown_packets" {
+                unknown_packets = UnknownPacketPolicy::from_str(value)
+                    .ok_or_else(|| err("expected close or ignore"))?;
+                continue;
+            }
+            if key == "session_takeover" {
+                session_takeover = TakeoverPolicy::from_str(value)
+                    .ok_or_else(|| err("expected immediate, reject or grace [milliseconds]"))?;
+                continue;
+            }
+            if key == "retained_batch_size" {
+                retained_batch_size = match value.parse() {
+                    Ok(0) | Err(_) => return Err(err("expected a positive number")),
+                    Ok(batch_size) => batch_size
+                };
+                continue;
+            }
+            if key == "retained_history" {
+                retained_history = value.parse().map_err(|_| err("expected a number"))?;
+                continue;
+            }
+            if key == "block_client_id" {
+                if value.is_empty() {
+                    return Err(err("expected a client id glob"));
+                }
+                blocked_client_ids.push(value.to_string());
+                continue;
+            }
+            if key == "block_username" {
+                if value.is_empty() {
+                    return Err(err("expected a username"));
+                }
+                blocked_usernames.push(value.to_string());
+                continue;
+            }
+            if key == "persistent_client_id" {
+                if value.is_empty() {
+                    return Err(err("expected a client id glob"));
+                }
+                persistent_client_ids.push(value.to_string());
+                continue;
+            }
+            if key == "persistent_username" {
+                if value.is_empty() {
+                    return Err(err("expected a username"));
+                }
+                persistent_usernames.push(value.to_string());
+                continue;
+            }
+            if key == "client_id_prefix" {
+                if value.is_empty() {
+                    return Err(err("expected a prefix"));
+                }
+                client_id_prefix = Some(value.to_string());
+                continue;
+            }
+            if key == "validate_json" {
+                if !cfg!(feature = "json-schema") {
+                    return Err(err("validate_json needs a build with the json-schema feature"));
+                }
+                let words: Vec<&str> = value.split_whitespace().collect();
+                if words.len() < 2 || words.len() > 3 {
+                    return Err(err("expected `validate_json <topic filter> <schema file> \
+                        [reject|flag]`"));
+                }
+                let action = match words.get(2) {
+                    Some(action) => ValidationAction::from_str(action)
+                        .ok_or_else(|| err("expected reject or flag"))?,
+                    None => ValidationAction::Reject
+                };
+                json_schemas.push(SchemaRule {
+                    filter: words[0].to_string(),
+                    path: PathBuf::from(words[1]),
+                    action
+                });
+                continue;
+            }
+            if key == "min_qos" {
+                let words: Vec<&str> = value.split_whitespace().collect();
+                if words.len() < 2 || words.len() > 3 {
+                    return Err(err("expected `min_qos <topic filter> <qos> [reject|upgrade]`"));
+                }
+                let qos_lv = match words[1] {
+                    "1" => QosLv::AtLeastOnce,
+                    "2" => QosLv::ExactlyOnce,
+                    _ => return Err(err("expected a QoS of 1 or 2"))
+                };
+                let action = match words.get(2) {
+                    Some(action) => MinQosAction::from_str(action)
+                        .ok_or_else(|| err("expected reject or upgrade"))?,
+                    None => MinQosAction::Reject
+                };
+                min_qos.push(MinQosRule { filter: words[0].to_string(), qos_lv, action });
+                continue;
+            }
+            if key == "listener" {
+                let addr = value.parse().map_err(|_| err("invalid listener address"))?;
+                listeners.push(ListenerConfig::new(addr));
+                section = Section::Listener;
+                continue;
+            }
+            if key == "fault" {
+                let mut words = value.split_whitespace();
+                let (client_id, topic) = match (words.next(), words.next(), words.next()) {
+                    (Some(client_id), Some(topic), None) => (client_id, topic),
+                    _ => return Err(err("expected `fault <client id> <topic>`"))
+                };
+                let pattern = |s: &str| if s == "*" { None } else { Some(s.to_string()) };
+                faults.push(FaultRule::new(pattern(client_id), pattern(topic)));
+                section = Section::Fault;
+                continue;
+            }
+            let res = match section {
+                Section::None => Err(format!("`{}` must follow a `listener` or `fault` line", key)),
+                Section::Listener =>
+                    parse_listener_option(listeners.last_mut().unwrap(), key, value),
+                Section::Fault =>
+                    parse_fault_option(faults.last_mut().unwrap(), key, value)
+            };
+            res.map_err(|msg| err(&msg))?;
+        }
+        let config = Config {
+            listeners,
+            faults,
+            node_id,
+            log_level,
+            audit,
+            control_socket,
+            sys_interval,
+            keep_alive_suggestions,
+            dispatch_workers,
+            fanout_chunk_size,
+            write_batching: write_batch_delay.map(|max_delay| WriteBatching {
+                max_delay,
+                max_bytes: write_batch_bytes
+            }),
+            max_qos,
+            max_will_topic_length,
+            max_will_payload_size,
+            max_ping_rate,
+            max_queued_messages,
+            max_queued_age,
+            qos2_release_timeout,
+            max_awaiting_rel,
+            max_connection_memory,
+            connection_memory_action,
+            anonymous_topics,
+            anonymous_retain_topics,
+            retain_violation,
+            retained_qos,
+            retained_batch_size,
+            retained_history,
+            unknown_packets,
+            session_takeover,
+            blocked_client_ids,
+            blocked_usernames,
+            persistent_client_ids,
+            persistent_usernames,
+            client_id_prefix,
+            dead_letter_topic,
+            json_schemas,
+            min_qos
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Checks the config as a whole and reports every problem found, one per line
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = vec![];
+        if self.listeners.is_empty() {
+            problems.push("no listeners configured".to_string());
+        }
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if self.listeners[..i].iter().any(|other| other.addr == listener.addr) {
+                problems.push(format!("listener {}: address is used by more than one listener",
+                    listener.addr));
+            }
+            if listener.backlog < 1 {
+                problems.push(format!("listener {}: backlog must be at least 1", listener.addr));
+            }
+            if listener.send_buffer_size == Some(0) || listener.recv_buffer_size == Some(0) {
+                problems.push(format!("listener {}: buffer sizes must be greater than 0",
+                    listener.addr));
+            }
+            let wildcard = |c| c == '+' || c == '#';
+            if listener.mount_point.as_ref().map_or(false, |m| m.contains(wildcard)) {
+                problems.push(format!("listener {}: mount_point must not contain wildcards",
+                    listener.addr));
+            }
+            if listener.connection_rate.is_none() &&
+                (listener.connection_burst.is_some() || listener.overload_connack_delay.is_some()) {
+                problems.push(format!("listener {}: connection_burst and overload_connack_delay \
+                    need a connection_rate", listener.addr));
+            }
+            if listener.connection_burst == Some(0) {
+                problems.push(format!("listener {}: connection_burst must be at least 1",
+                    listener.addr));
+            }
+        }
+        if self.dispatch_workers < 1 {
+            problems.push("dispatch_workers must be at least 1".to_string());
+        }
+        for rule in &self.faults {
+            if rule.drop_ack == 0.0 && rule.delay_delivery.is_none() &&
+                rule.duplicate_qos1 == 0.0 && rule.close_connection == 0.0 {
+                problems.push(format!("fault {} {}: rule has no effect",
+                    rule.client_id.as_ref().map_or("*", |id| id.as_str()),
+                    rule.topic.as_ref().map_or("*", |topic| topic.as_str())));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Config(problems.join("\n")))
+        }
+    }
+}
+
+fn split_line(line: &str) -> (&str, &str) {
+    let mut words = line.trim().splitn(2, char::is_whitespace);
+    let key = words.next().unwrap();
+    (key, words.next().map(|v| v.trim()).unwrap_or(""))
+}
+
+// Applies one `MQTT_BROKER__` variable to the config's (source, line) pairs. The line it adds is
+// put where it takes effect: a broker-wide key first, a listener option right after its listener.
+fn apply_override(lines: &mut Vec<(String, String)>, name: &str, value: &str)
+    -> ::std::result::Result<(), String> {
+    let path: Vec<String> = name[ENV_PREFIX.len()..].split("__")
+        .map(|part| part.to_lowercase())
+        .collect();
+    let key_of = |line: &str| split_line(line).0.to_string();
+    match path.as_slice() {
+        &[ref key] if key == "listener" || key == "fault" || key.is_empty() =>
+            Err("expected a config key, or LISTENERS__<n>__<option>".to_string()),
+        &[ref key] => {
+            lines.retain(|&(_, ref line)| key_of(line) != *key);
+            lines.insert(0, (name.to_string(), format!("{} {}", key, value)));
+            Ok(())
+        }
+        &[ref listeners, ref n, ref option] if listeners == "listeners" => {
+            let n: usize = n.parse().map_err(|_| "expected a listener number".to_string())?;
+            let starts: Vec<usize> = (0..lines.len())
+                .filter(|&i| key_of(&lines[i].1) == "listener")
+                .collect();
+            let start = match starts.get(n) {
+                Some(&start) => start,
+                None if n == starts.len() && option == "addr" => {
+                    lines.push((name.to_string(), format!("listener {}", value)));
+                    return Ok(());
+                }
+                None => return Err(format!("there is no listener {}", n))
+            };
+            match option.as_str() {
+                "addr" => lines[start] = (name.to_string(), format!("listener {}", value)),
+                "port" => {
+                    let mut addr: SocketAddr = split_line(&lines[start].1).1.parse()
+                        .map_err(|_| "the listener's address is invalid".to_string())?;
+                    addr.set_port(value.parse().map_err(|_| "expected a port".to_string())?);
+                    lines[start] = (name.to_string(), format!("listener {}", addr));
+                }
+                option => {
+                    // The listener's options run until the next listener or fault rule
+                    let end = (start + 1..lines.len())
+                        .find(|&i| ["listener", "fault"].contains(&key_of(&lines[i].1).as_str()))
+                        .unwrap_or(lines.len());
+                    let others: Vec<(String, String)> = lines.drain(start + 1..end)
+                        .filter(|&(_, ref line)| key_of(line) != option)
+                        .collect();
+                    let set = (name.to_string(), format!("{} {}", option, value));
+                    lines.splice(start + 1..start + 1, iter::once(set).chain(others));
+                }
+            }
+            Ok(())
+        }
+        _ => Err("expected a config key, or LISTENERS__<n>__<option>".to_string())
+    }
+}
+
+fn parse_listener_option(listener: &mut ListenerConfig, key: &str, value: &str)
+    -> ::std::result::Result<(), String> {
+    let err = |msg: &str| msg.to_string();
+    match key {
+        "tcp_nodelay" => {
+            listener.tcp_nodelay = parse_bool(value).ok_or_else(|| err("expected true or false"))?;
+        }
+        "tcp_keepalive" => {
+            listener.tcp_keepalive = parse_secs(value).ok_or_else(|| err("expected seconds"))?;
+        }
+        "send_buffer_size" => {
+            listener.send_buffer_size = Some(value.parse()
+                .map_err(|_| err("expected a size in bytes"))?);
+        }
+        "recv_buffer_size" => {
+            listener.recv_buffer_size = Some(value.parse()
+                .map_err(|_| err("expected a size in bytes"))?);
+        }
+        "backlog" => {
+            listener.backlog = value.parse().map_err(|_| err("expected a number"))?;
+        }
+        "connect_timeout" => {
+            listener.connect_timeout = parse_secs(value).ok_or_else(|| err("expected seconds"))?;
+        }
+        "write_timeout" => {
+            listener.write_timeout = parse_secs(value).ok_or_else(|| err("expected seconds"))?;
+        }
+        "mount_point" => {
+            if value.is_empty() {
+                return Err(err("expected a topic prefix"));
+            }
+            // Mount points are whole levels, so `tenant-a` can't reach into `tenant-ab/`
+            let separator = if value.ends_with('/') { "" } else { "/" };
+            listener.mount_point = Some(format!("{}{}", value, separator));
+        }
+        "principal" => {
+            if value.is_empty() {
+                return Err(err("expected a username"));
+            }
+            listener.principal = Some(value.to_string());
+        }
+        "tenant_isolation" => {
+            listener.tenant_isolation =
+                parse_bool(value).ok_or_else(|| err("expected true or false"))?;
+        }
+        "wildcard_subscriptions" => {
+            listener.wildcard_subscriptions =
+                parse_bool(value).ok_or_else(|| err("expected true or false"))?;
+        }
+        "connection_rate" => {
+            listener.connection_rate = match value.parse() {
+                Ok(0) => None,
+                Ok(rate) => Some(rate),
+                Err(_) => return Err(err("expected connections per second"))
+            };
+        }
+        "connection_burst" => {
+            listener.connection_burst = Some(value.parse().map_err(|_| err("expected a number"))?);
+        }
+        "overload_connack_delay" => {
+            listener.overload_connack_delay = match value.parse() {
+                Ok(0) => None,
+                Ok(millis) => Some(Duration::from_millis(millis)),
+                Err(_) => return Err(err("expected milliseconds"))
+            };
+        }
+        _ => return Err(format!("unknown listener option `{}`", key))
+    }
+    Ok(())
+}
+
+fn parse_fault_option(rule: &mut FaultRule, key: &str, value: &str)
+    -> ::std::result::Result<(), String> {
+    match key {
+        "drop_ack" => rule.drop_ack = parse_probability(value)?,
+        "delay_delivery" => {
+            rule.delay_delivery = match value.parse() {
+                Ok(0) => None,
+                Ok(millis) => Some(Duration::from_millis(millis)),
+                Err(_) => return Err("expected milliseconds".to_string())
+            };
+        }
+        "duplicate_qos1" => rule.duplicate_qos1 = parse_probability(value)?,
+        "close_connection" => rule.close_connection = parse_probability(value)?,
+        _ => return Err(format!("unknown fault option `{}`", key))
+    }
+    Ok(())
+}
+
+impl ListenerConfig {
+    pub fn new(addr: SocketAddr) -> ListenerConfig {
+        ListenerConfig {
+            addr,
+            tcp_nodelay: false,
+            tcp_keepalive: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            backlog: 128,
+            connect_timeout: None,
+            write_timeout: None,
+            mount_point: None,
+            tenant_isolation: false,
+            wildcard_subscriptions: true,
+            principal: None,
+            connection_rate: None,
+            connection_burst: None,
+            overload_connack_delay: None
+        }
+    }
+
+    pub fn bind(&self) -> io::Result<TcpListener> {
+        let builder = match self.addr {
+            SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+            SocketAddr::V6(_) => TcpBuilder::new_v6()?
+        };
+        builder.reuse_address(true)?;
+        builder.bind(self.addr)?;
+        Ok(builder.listen(self.backlog)?)
+    }
+
+    // Applies the socket options to a newly accepted connection
+    pub fn configure(&self, stream: &TcpStream) -> io::Result<()> {
+        // handle_client clears the read timeout once CONNECT arrives
+        stream.set_read_timeout(self.connect_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        stream.set_nodelay(self.tcp_nodelay)?;
+        TcpStreamExt::set_keepalive(stream, self.tcp_keepalive)?;
+        if let Some(size) = self.send_buffer_size {
+            stream.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            stream.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+// 0 disables the option
+fn parse_secs(s: &str) -> Option<Option<Duration>> {
+    match s.parse::<u64>() {
+        Ok(0) => Some(None),
+        Ok(secs) => Some(Some(Duration::from_secs(secs))),
+        Err(_) => None
+    }
+}
+
+// A size where 0 means no limit
+fn parse_limit(s: &str) -> Option<Option<usize>> {
+    match s.parse::<usize>() {
+        Ok(0) => Some(None),
+        Ok(limit) => Some(Some(limit)),
+        Err(_) => None
+    }
+}
+
+fn parse_probability(s: &str) -> ::std::result::Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if p >= 0.0 && p <= 1.0 => Ok(p),
+        _ => Err("expected a probability between 0 and 1".to_string())
+    }
+}
+
+fn parse_bool(s: &str) -> Option<bool> {
+    match s {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None
+    }
+}
@@ -0,0 +1,303 @@
+use libmqtt::error::{Error, Result};
+use std::collections::hash_map::HashMap;
+use std::cmp;
+use std::io::{self, BufWriter, Write};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use transport::Transport;
+
+struct Connection {
+    writer: BufWriter<Box<dyn Transport>>,
+    // When the oldest batched delivery still in `writer` was written, if there is one
+    pending_since: Option<Instant>
+}
+
+// A connection's entry in the manager. Each connection has its own lock, so a client whose socket
+// stalls only holds up writes to itself. The transport is a second handle to the socket for
+// shutting it down without waiting for that lock, which also unblocks a write stuck on it.
+#[derive(Clone)]
+struct Handle {
+    // Tells a connection apart from a later one with the same client id
+    id: usize,
+    conn: Arc<Mutex<Connection>>,
+    transport: Arc<Mutex<Box<dyn Transport>>>,
+    released: Arc<Released>
+}
+
+impl Handle {
+    fn shutdown(&self) {
+        let _ = self.transport.lock().unwrap().shutdown();
+    }
+}
+
+// Set once the connection's registration is dropped, which the thread serving it does in its
+// teardown, after it has stopped handling packets and settled the session
+struct Released {
+    done: Mutex<bool>,
+    changed: Condvar
+}
+
+// A connection closed by close_all, for waiting until the thread serving it is done with it
+pub struct Closed {
+    released: Arc<Released>
+}
+
+impl Closed {
+    // Returns false if the connection is still registered at `deadline`
+    pub fn wait_until(&self, deadline: Instant) -> bool {
+        let mut done = self.released.done.lock().unwrap();
+        while !*done {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            done = self.released.changed.wait_timeout(done, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+// Lets deliveries to a client wait in its connection's buffer, so that several small messages go
+// out in one socket write, trading latency for throughput on fan-in workloads. The buffer is
+// flushed once `max_bytes` are waiting or the oldest delivery in it has waited `max_delay`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct WriteBatching {
+    pub max_delay: Duration,
+    pub max_bytes: usize
+}
+
+pub const DEFAULT_BATCH_BYTES: usize = 4096;
+
+// The connected clients, by client id, and the handles other threads use to send to them. An
+// entry is removed as soon as its connection is closed or a write to it fails, so a dead
+// connection is never written to. The map's lock is only held to look up, add or remove entries,
+// never while writing. Clones refer to the same connections.
+#[derive(Clone)]
+pub struct ConnectionManager {
+    conns: Arc<Mutex<HashMap<String, Handle>>>,
+    next_id: Arc<AtomicUsize>,
+    // None flushes every delivery right away
+    batching: Option<WriteBatching>
+}
+
+// Keeps a client registered until it is dropped. If the client connected again in the meantime,
+// dropping the old registration leaves the new connection alone.
+pub struct Registration {
+    connections: ConnectionManager,
+    client_id: String,
+    id: usize,
+    released: Arc<Released>
+}
+
+impl Registration {
+    // Whether the client has connected again, replacing this connection
+    pub fn taken_over(&self) -> bool {
+        self.connections.conns.lock().unwrap().get(&self.client_id)
+            .map_or(false, |conn| conn.id != self.id)
+    }
+
+    // Writes `write` to this connection and flushes it. `held` is released only once the
+    // connection is locked, so a caller holding a lock that deliveries also take can keep its
+    // write ahead of theirs without holding that lock through the write, which may wait out
+    // retries on a slow socket. Fails if the connection was closed or taken over.
+    pub fn write_releasing<G, F>(&self, held: G, write: F) -> Result<()>
+        where F: FnOnce(&mut BufWriter<Box<dyn Transport>>) -> Result<()> {
+        let handle = match self.connections.conns.lock().unwrap().get(&self.client_id) {
+            Some(handle) if handle.id == self.id => handle.clone(),
+            _ => return Err(Error::Io(io::Error::new(io::ErrorKind::NotConnected,
+                "connection closed")))
+        };
+        self.connections.write_handle(&self.client_id, handle, held, |conn| {
+            write(&mut conn.writer)?;
+            conn.writer.flush()?;
+            conn.pending_since = None;
+            Ok(())
+        })
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut conns = self.connections.conns.lock().unwrap();
+        if conns.get(&self.client_id).map_or(false, |conn| conn.id == self.id) {
+            conns.remove(&self.client_id);
+        }
+        drop(conns);
+        *self.released.done.lock().unwrap() = true;
+        self.released.changed.notify_all();
+    }
+}
+
+impl ConnectionManager {
+    pub fn new() -> ConnectionManager {
+        ConnectionManager {
+            conns: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicUsize::new(0)),
+            batching: None
+        }
+    }
+
+    // Turns write batching on or off for write_batched. While it is on, a thread flushes the
+    // deliveries that have waited long enough; it exits once every clone is dropped. Clones made
+    // before this call keep the old setting.
+    pub fn set_batching(&mut self, batching: Option<WriteBatching>) {
+        self.batching = batching;
+        if let Some(batching) = batching {
+            let conns = Arc::downgrade(&self.conns);
+            thread::spawn(move || flush_batches(conns, batching.max_delay));
+        }
+    }
+
+    // Makes `stream` the connection for `client_id`. An existing connection with the same client
+    // id is closed, as the spec requires.
+    pub fn register(&self, client_id: &str, stream: &dyn Transport) -> Result<Registration> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let writer = BufWriter::new(stream.try_clone()?);
+        let handle = Handle {
+            id,
+            conn: Arc::new(Mutex::new(Connection { writer, pending_since: None })),
+            transport: Arc::new(Mutex::new(stream.try_clone()?)),
+            released: Arc::new(Released { done: Mutex::new(false), changed: Condvar::new() })
+        };
+        let released = Arc::clone(&handle.released);
+        let old = self.conns.lock().unwrap().insert(client_id.to_string(), handle);
+        if let Some(old) = old {
+            log!(Info, "Client {} connected again; closing its previous connection", client_id);
+            old.shutdown();
+        }
+        Ok(Registration {
+            connections: self.clone(),
+            client_id: client_id.to_string(),
+            id,
+            released
+        })
+    }
+
+    pub fn is_connected(&self, client_id: &str) -> bool {
+        self.conns.lock().unwrap().contains_key(client_id)
+    }
+
+    // Runs `write` on the client's connection. Returns false if the client isn't connected or the
+    // write failed, in which case the connection is closed and forgotten.
+    pub fn write<F>(&self, client_id: &str, write: F) -> bool
+        where F: FnOnce(&mut BufWriter<Box<dyn Transport>>) -> Result<()> {
+        self.write_conn(client_id, |conn| {
+            write(&mut conn.writer)?;
+            if conn.writer.buffer().is_empty() {
+                conn.pending_since = None;
+            }
+            Ok(())
+        })
+    }
+
+    // Like write, but `write` doesn't flush: with batching on, what it writes waits in the buffer
+    // until the batch is full or old enough, and otherwise it is flushed right away
+    pub fn write_batched<F>(&self, client_id: &str, write: F) -> bool
+        where F: FnOnce(&mut BufWriter<Box<dyn Transport>>) -> Result<()> {
+        let batching = self.batching;
+        self.write_conn(client_id, |conn| {
+            write(&mut conn.writer)?;
+            match batching {
+                Some(batching) if conn.writer.buffer().len() < batching.max_bytes => {
+                    if conn.writer.buffer().is_empty() {
+                        conn.pending_since = None;
+                    } else if conn.pending_since.is_none() {
+                        conn.pending_since = Some(Instant::now());
+                    }
+                }
+                _ => {
+                    conn.writer.flush()?;
+                    conn.pending_since = None;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn write_conn<F>(&self, client_id: &str, write: F) -> bool
+        where F: FnOnce(&mut Connection) -> Result<()> {
+        let handle = match self.conns.lock().unwrap().get(client_id) {
+            Some(handle) => handle.clone(),
+            None => return false
+        };
+        self.write_handle(client_id, handle, (), write).is_ok()
+    }
+
+    fn write_handle<G, F>(&self, client_id: &str, handle: Handle, held: G, write: F) -> Result<()>
+        where F: FnOnce(&mut Connection) -> Result<()> {
+        let res = {
+            let mut conn = handle.conn.lock().unwrap();
+            drop(held);
+            write(&mut conn)
+        };
+        if let Err(ref e) = res {
+            log!(Debug, "Write to {} failed: {:?}", client_id, e);
+            remove(&self.conns, client_id, &handle);
+        }
+        res
+    }
+
+    // Closes the client's connection. Returns false if it isn't connected.
+    pub fn close(&self, client_id: &str) -> Result<bool> {
+        match self.conns.lock().unwrap().remove(client_id) {
+            Some(handle) => {
+                handle.transport.lock().unwrap().shutdown()?;
+                Ok(true)
+            }
+            None => Ok(false)
+        }
+    }
+
+    // Closes every connection, e.g. to hand the clients over to another broker
+    pub fn close_all(&self) -> Vec<Closed> {
+        let handles: Vec<Handle> = self.conns.lock().unwrap().drain()
+            .map(|(_, handle)| handle)
+            .collect();
+        handles.iter().map(|handle| {
+            handle.shutdown();
+            Closed { released: Arc::clone(&handle.released) }
+        }).collect()
+    }
+}
+
+// Forgets a connection whose write failed and closes it, unless the client has connected again
+// in the meantime
+fn remove(conns: &Mutex<HashMap<String, Handle>>, client_id: &str, handle: &Handle) {
+    let mut conns = conns.lock().unwrap();
+    if conns.get(client_id).map_or(false, |current| current.id == handle.id) {
+        conns.remove(client_id);
+    }
+    handle.shutdown();
+}
+
+// Flushes the batched deliveries that have waited `max_delay`, until the connections are dropped
+fn flush_batches(conns: Weak<Mutex<HashMap<String, Handle>>>, max_delay: Duration) {
+    let interval = cmp::max(max_delay / 2, Duration::from_millis(1));
+    loop {
+        thread::sleep(interval);
+        let conns = match conns.upgrade() {
+            Some(conns) => conns,
+            None => return
+        };
+        let handles: Vec<(String, Handle)> = conns.lock().unwrap().iter()
+            .map(|(client_id, handle)| (client_id.clone(), handle.clone()))
+            .collect();
+        let now = Instant::now();
+        for (client_id, handle) in handles {
+            let res = {
+                let mut conn = handle.conn.lock().unwrap();
+                if conn.pending_since.map_or(true, |since| now - since < max_delay) {
+                    continue;
+                }
+                conn.pending_since = None;
+                conn.writer.flush()
+            };
+            if let Err(e) = res {
+                log!(Debug, "Write to {} failed: {:?}", client_id, e);
+                remove(&conns, &client_id, &handle);
+            }
+        }
+    }
+}
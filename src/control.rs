@@ -0,0 +1,472 @@
+use base64;
+use broker::{Broker, QueuedExport, SessionExport};
+use json::{self, Json};
+use keepalive::{self, GAP_BOUNDS, KEEP_ALIVE_BOUNDS};
+use libmqtt::ctrlpkt::QosLv;
+use libmqtt::error::Result;
+use log::{self, Level};
+use process;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::thread::{self, JoinHandle};
+
+// Line-based operator console on a Unix socket, e.g. `socat - UNIX-CONNECT:<path>`. Each command
+// is answered with one or more lines.
+const HELP: &str = "\
+clients                 list sessions with their connection state and statistics
+subs [topic filter]     list subscriptions, optionally only those to one filter
+subtable [compact]      show the size of the subscription table, or reclaim unused space in it
+subtable filters [filter]
+                        show each filter's subscribers and matches, busiest first
+decode <client id>      count the packets a client's connection sent, by type, and those that
+                        failed to decode
+kick <client id>        close a client's connection
+session export <client id>
+                        show a client's whole session as JSON, on one line
+session import <json>   recreate an exported session, with its subscriptions and queued messages
+handoff export <path>   refuse new connections, close the connected clients, and write every
+                        persistent session and retained message to a file for another broker
+handoff import <path>   load the sessions and retained messages from a handoff file
+handoff cancel          accept connections again after handoff export
+block client <glob>     refuse CONNECTs from client ids matching a glob (`*`, `?`)
+block user <username>   refuse CONNECTs with a username
+unblock client|user <x> lift a block
+blocked                 list blocked client ids and usernames
+retained [topic filter] list retained topics, or show the messages retained under a filter
+retained get <topic>    show the message retained on a topic, with its payload in base64
+retained set <topic> <qos> <base64 payload>
+                        retain a message on a topic and deliver it to its subscribers
+retained delete <topic> forget the message retained on a topic
+retained history <topic>
+                        list the recorded changes to a retained topic, oldest first
+retained at <topic> <unix time>
+                        show the message that was retained on a topic at a time
+loglevel [level]        show or set the log level (error, warn, info, debug, trace)
+bufpool                 show how often packet buffers are reused
+process                 show the broker's memory, open file descriptors, and threads
+keepalive               show the keep-alives clients use and how far apart their packets arrive
+info                    show the broker version, features, listeners, and limits
+help                    show this message
+";
+
+pub fn serve(broker: Broker, path: &Path) -> Result<JoinHandle<()>> {
+    // A socket file left behind by a previous run would make bind fail
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    Ok(thread::spawn(move || {
+        for conn in listener.incoming() {
+            match conn {
+                Ok(conn) => {
+                    let broker = broker.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve_conn(&broker, conn) {
+                            log!(Debug, "Control connection closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => log!(Warn, "Failed to accept control connection: {}", e)
+            }
+        }
+    }))
+}
+
+fn serve_conn(broker: &Broker, conn: UnixStream) -> io::Result<()> {
+    let mut writer = conn.try_clone()?;
+    for line in BufReader::new(conn).lines() {
+        writer.write_all(execute(broker, &line?).as_bytes())?;
+    }
+    Ok(())
+}
+
+fn lines<I: Iterator<Item = String>>(lines: I) -> String {
+    let reply: String = lines.map(|line| line + "\n").collect();
+    if reply.is_empty() {
+        "(none)\n".to_string()
+    } else {
+        reply
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn qos_from_json(json: &Json) -> Option<QosLv> {
+    json.as_u64().and_then(|qos| QosLv::from_int(qos as u8).ok())
+}
+
+fn session_to_json(export: &SessionExport) -> Json {
+    let string = |s: &str| Json::String(s.to_string());
+    let qos = |qos_lv: QosLv| Json::Number(qos_lv as u64);
+    Json::Object(vec![
+        ("client_id".to_string(), string(&export.client_id)),
+        ("clean_session".to_string(), Json::Bool(export.clean_session)),
+        ("namespace".to_string(), export.namespace.as_ref().map_or(Json::Null, |ns| string(ns))),
+        ("epoch".to_string(), Json::Number(export.epoch as u64)),
+        ("subscriptions".to_string(), Json::Array(export.subscriptions.iter()
+            .map(|&(ref filter, qos_lv)| Json::Object(vec![
+                ("filter".to_string(), string(filter)),
+                ("qos".to_string(), qos(qos_lv))
+            ]))
+            .collect())),
+        ("queued".to_string(), Json::Array(export.queued.iter()
+            .map(|queued| Json::Object(vec![
+                ("topic".to_string(), string(&queued.topic_name)),
+                ("qos".to_string(), qos(queued.qos_lv)),
+                ("payload".to_string(), Json::String(base64::encode(&queued.payload))),
+                ("age_secs".to_string(), Json::Number(queued.age.as_secs()))
+            ]))
+            .collect())),
+        ("inflight".to_string(), Json::Array(export.inflight.iter()
+            .map(|&(pkt_id, qos_lv, ref payload)| Json::Object(vec![
+                ("pkt_id".to_string(), Json::Number(pkt_id as u64)),
+                ("qos".to_string(), qos(qos_lv)),
+                ("payload".to_string(), Json::String(base64::encode(payload)))
+            ]))
+            .collect())),
+        ("awaiting_rel".to_string(), Json::Array(export.awaiting_rel.iter()
+            .map(|&pkt_id| Json::Number(pkt_id as u64))
+            .collect()))
+    ])
+}
+
+// Returns None if a field is missing or has the wrong type. The in-flight state is ignored, as
+// import_session doesn't use it. Exports from before sessions had epochs are in the first one.
+fn session_from_json(json: &Json) -> Option<SessionExport> {
+    let namespace = match json.get("namespace")? {
+        &Json::Null => None,
+        namespace => Some(namespace.as_str()?.to_string())
+    };
+    let mut subscriptions = vec![];
+    for sub in json.get("subscriptions")?.as_array()? {
+        let filter = sub.get("filter")?.as_str()?.to_string();
+        subscriptions.push((filter, qos_from_json(sub.get("qos")?)?));
+    }
+    let mut queued = vec![];
+    for msg in json.get("queued")?.as_array()? {
+        queued.push(QueuedExport {
+            topic_name: msg.get("topic")?.as_str()?.to_string(),
+            qos_lv: qos_from_json(msg.get("qos")?)?,
+            payload: base64::decode(msg.get("payload")?.as_str()?)?,
+            age: Duration::from_secs(msg.get("age_secs")?.as_u64()?)
+        });
+    }
+    Some(SessionExport {
+        client_id: json.get("client_id")?.as_str()?.to_string(),
+        clean_session: json.get("clean_session")?.as_bool()?,
+        namespace,
+        epoch: match json.get("epoch") {
+            Some(epoch) => epoch.as_u64()? as u32,
+            None => 1
+        },
+        subscriptions,
+        queued,
+        inflight: vec![],
+        awaiting_rel: vec![]
+    })
+}
+
+fn retained_to_json(topic_name: &str, qos_lv: QosLv, payload: &[u8]) -> Json {
+    Json::Object(vec![
+        ("topic".to_string(), Json::String(topic_name.to_string())),
+        ("qos".to_string(), Json::Number(qos_lv as u64)),
+        ("payload".to_string(), Json::String(base64::encode(payload)))
+    ])
+}
+
+fn retained_from_json(json: &Json) -> Option<(String, QosLv, Vec<u8>)> {
+    Some((json.get("topic")?.as_str()?.to_string(), qos_from_json(json.get("qos")?)?,
+        base64::decode(json.get("payload")?.as_str()?)?))
+}
+
+// A handoff file has one JSON object per line: `{"session": <session export>}` for each persistent
+// session and `{"retained": {"topic", "qos", "payload"}}` for each retained message outside $SYS,
+// which the broker publishes itself. Returns the number of each written.
+fn write_handoff(broker: &Broker, path: &str) -> io::Result<(usize, usize)> {
+    let mut file = BufWriter::new(fs::File::create(path)?);
+    let sessions = broker.export_persistent_sessions();
+    for export in &sessions {
+        writeln!(file, "{}", Json::Object(vec![("session".to_string(), session_to_json(export))]))?;
+    }
+    let mut retained = 0;
+    for topic_name in broker.retained_topics() {
+        if topic_name.starts_with("$SYS/") {
+            continue;
+        }
+        if let Some((qos_lv, payload)) = broker.retained(&topic_name) {
+            writeln!(file, "{}", Json::Object(vec![
+                ("retained".to_string(), retained_to_json(&topic_name, qos_lv, &payload))
+            ]))?;
+            retained += 1;
+        }
+    }
+    file.flush()?;
+    Ok((sessions.len(), retained))
+}
+
+// Loads a handoff file, or nothing if any line of it is invalid. Sessions that already exist here
+// are left alone, and retained messages are stored without being delivered. Returns the number of
+// sessions imported, sessions skipped, and retained messages.
+fn read_handoff(broker: &Broker, path: &str) -> io::Result<(usize, usize, usize)> {
+    let mut sessions = vec![];
+    let mut retained = vec![];
+    for (i, line) in BufReader::new(fs::File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let json = json::parse(&line);
+        let session = json.as_ref().and_then(|json| json.get("session"));
+        let msg = json.as_ref().and_then(|json| json.get("retained"));
+        match (session.and_then(session_from_json), msg.and_then(retained_from_json)) {
+            (Some(export), _) => sessions.push(export),
+            (None, Some(msg)) => retained.push(msg),
+            (None, None) => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("line {}: expected a session or retained message", i + 1)))
+        }
+    }
+    let imported = sessions.iter().filter(|export| broker.import_session(export)).count();
+    for &(ref topic_name, qos_lv, ref payload) in &retained {
+        broker.import_retained(topic_name, qos_lv, payload.clone());
+    }
+    Ok((imported, sessions.len() - imported, retained.len()))
+}
+
+// Runs one console command and returns its reply
+pub fn execute(broker: &Broker, line: &str) -> String {
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        Some(command) => command,
+        None => return String::new()
+    };
+    let args: Vec<&str> = words.collect();
+    match (command, args.as_slice()) {
+        ("clients", &[]) => {
+            let now = broker.clock().now();
+            lines(broker.clients().into_iter().map(|client| {
+                let mut line = format!("{} {} subscriptions={} inflight={} queued={} \
+                    delivered={} dropped={} qos2_duplicates={} awaiting_rel={} qos2_abandoned={} \
+                    unknown_packets={} stale_acks={} memory={}B idle={}s",
+                    client.client_id, if client.connected { "connected" } else { "disconnected" },
+                    client.subscriptions, client.inflight, client.queued, client.delivered,
+                    client.dropped, client.qos2_duplicates, client.awaiting_rel,
+                    client.qos2_abandoned, client.unknown_packets, client.stale_acks,
+                    client.memory_bytes,
+                    (now - client.last_activity).as_secs());
+                if let Some(params) = client.params {
+                    line.push_str(&format!(" protocol={} keep_alive={}s max_qos={}",
+                        params.protocol_lv.version(), params.keep_alive, params.max_qos as u8));
+                }
+                line
+            }))
+        }
+        ("subs", &[]) | ("subs", &[_]) => {
+            lines(broker.subscriptions(args.first().cloned()).into_iter()
+                .map(|(filter, client_id, qos_lv)| {
+                    format!("{} {} qos={}", filter, client_id, qos_lv as u8)
+                }))
+        }
+        ("subtable", &[]) => {
+            let stats = broker.subscription_stats();
+            format!("filters={} subscriptions={} max_depth={} estimated_bytes={} updates={} \
+                slow_updates={} max_update_micros={}\n",
+                stats.filters, stats.subscriptions, stats.max_depth, stats.estimated_bytes,
+                stats.updates, stats.slow_updates, stats.max_update_micros)
+        }
+        ("subtable", &["filters"]) | ("subtable", &["filters", _]) => {
+            lines(broker.filter_stats(args.get(1).cloned()).into_iter().map(|stats| {
+                format!("{} subscribers={} matched={} deliveries={} rate={:.2}/s", stats.filter,
+                    stats.subscribers, stats.matched, stats.deliveries, stats.match_rate)
+            }))
+        }
+        ("subtable", &["compact"]) => {
+            let before = broker.subscription_stats().estimated_bytes;
+            let shrunk = broker.compact_subscriptions();
+            format!("shrank {} maps; estimated_bytes={} (was {})\n", shrunk,
+                broker.subscription_stats().estimated_bytes, before)
+        }
+        ("session", &["export", client_id]) => match broker.export_session(client_id) {
+            Some(export) => format!("{}\n", session_to_json(&export)),
+            None => format!("no session for {}\n", client_id)
+        },
+        // The JSON is the rest of the line, spaces and all
+        ("session", _) if args.first() == Some(&"import") => {
+            let json = line[line.find("import").unwrap() + "import".len()..].trim();
+            match json::parse(json).as_ref().and_then(session_from_json) {
+                Some(export) => if broker.import_session(&export) {
+                    format!("imported the session of {}\n", export.client_id)
+                } else {
+                    format!("{} already has a session\n", export.client_id)
+                },
+                None => "invalid session; expected the JSON from session export\n".to_string()
+            }
+        }
+        ("decode", &[client_id]) => match broker.decode_stats(client_id) {
+            Some(stats) => {
+                let packets: Vec<String> = stats.packets.iter()
+                    .map(|(name, count)| format!("{}={}", name, count))
+                    .collect();
+                format!("packets {}\nmalformed={} oversized={} unknown_flags={}\n",
+                    packets.join(" "), stats.malformed, stats.oversized, stats.unknown_flags)
+            }
+            None => format!("no session for {}\n", client_id)
+        },
+        ("handoff", &["export", path]) => {
+            let drained = broker.drain();
+            match write_handoff(broker, path) {
+                Ok((sessions, retained)) => format!("closed {} connections; wrote {} sessions and \
+                    {} retained messages to {}\n", drained, sessions, retained, path),
+                Err(e) => format!("closed {} connections; failed to write {}: {}\n", drained,
+                    path, e)
+            }
+        }
+        ("handoff", &["import", path]) => match read_handoff(broker, path) {
+            Ok((imported, skipped, retained)) => format!("imported {} sessions ({} already here) \
+                and {} retained messages\n", imported, skipped, retained),
+            Err(e) => format!("failed to import {}: {}\n", path, e)
+        },
+        ("handoff", &["cancel"]) => {
+            broker.stop_draining();
+            "accepting connections again\n".to_string()
+        }
+        ("kick", &[client_id]) => match broker.kick(client_id) {
+            Ok(true) => format!("kicked {}\n", client_id),
+            Ok(false) => format!("{} is not connected\n", client_id),
+            Err(e) => format!("failed to kick {}: {:?}\n", client_id, e)
+        },
+        ("block", &["client", pattern]) => if broker.blocklist().block_client_id(pattern) {
+            format!("blocked client {}\n", pattern)
+        } else {
+            format!("client {} is already blocked\n", pattern)
+        },
+        ("block", &["user", username]) => if broker.blocklist().block_username(username) {
+            format!("blocked user {}\n", username)
+        } else {
+            format!("user {} is already blocked\n", username)
+        },
+        ("unblock", &["client", pattern]) => if broker.blocklist().unblock_client_id(pattern) {
+            format!("unblocked client {}\n", pattern)
+        } else {
+            format!("client {} is not blocked\n", pattern)
+        },
+        ("unblock", &["user", username]) => if broker.blocklist().unblock_username(username) {
+            format!("unblocked user {}\n", username)
+        } else {
+            format!("user {} is not blocked\n", username)
+        },
+        ("blocked", &[]) => {
+            let blocklist = broker.blocklist();
+            let client_ids = blocklist.client_ids().into_iter()
+                .map(|pattern| format!("client {}", pattern));
+            let usernames = blocklist.usernames().into_iter()
+                .map(|username| format!("user {}", username));
+            lines(client_ids.chain(usernames))
+        }
+        ("retained", &[]) => lines(broker.retained_topics().into_iter()),
+        ("retained", &["get", topic_name]) => match broker.retained_info(topic_name) {
+            Some(retained) => format!("{} qos={} retained_at={} payload={}\n", topic_name,
+                retained.qos_lv as u8, unix_secs(retained.retained_at),
+                base64::encode(&retained.payload)),
+            None => format!("nothing retained on {}\n", topic_name)
+        },
+        ("retained", &["set", topic_name, qos_lv, payload]) => {
+            let qos_lv = match qos_lv.parse().ok().and_then(|qos| QosLv::from_int(qos).ok()) {
+                Some(qos_lv) => qos_lv,
+                None => return format!("invalid QoS `{}`; expected 0, 1 or 2\n", qos_lv)
+            };
+            let payload = match base64::decode(payload) {
+                Some(payload) => payload,
+                None => return "invalid payload; expected base64\n".to_string()
+            };
+            if topic_name.contains(|c| c == '+' || c == '#') {
+                return format!("{} is not a topic name\n", topic_name);
+            }
+            broker.set_retained(topic_name, qos_lv, payload);
+            format!("retained a message on {}\n", topic_name)
+        }
+        ("retained", &["delete", topic_name]) => if broker.delete_retained(topic_name) {
+            format!("deleted the message retained on {}\n", topic_name)
+        } else {
+            format!("nothing retained on {}\n", topic_name)
+        },
+        ("retained", &["history", topic_name]) => {
+            lines(broker.retained_history(topic_name).into_iter().map(|(changed_at, msg)| {
+                let changed_at = unix_secs(changed_at);
+                match msg {
+                    Some((qos_lv, payload)) => format!("{} qos={} payload={}", changed_at,
+                        qos_lv as u8, base64::encode(&payload)),
+                    None => format!("{} deleted", changed_at)
+                }
+            }))
+        }
+        ("retained", &["at", topic_name, at]) => {
+            let at = match at.parse() {
+                Ok(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+                Err(_) => return format!("invalid time `{}`; expected a Unix timestamp\n", at)
+            };
+            match broker.retained_at(topic_name, at) {
+                Some(retained) => format!("{} qos={} retained_at={} payload={}\n", topic_name,
+                    retained.qos_lv as u8, unix_secs(retained.retained_at),
+                    base64::encode(&retained.payload)),
+                None => format!("no recorded message on {} at that time\n", topic_name)
+            }
+        }
+        ("retained", &[filter]) => {
+            let msgs = broker.retained_matching(filter);
+            if msgs.is_empty() {
+                format!("nothing retained on {}\n", filter)
+            } else {
+                lines(msgs.into_iter().map(|(topic_name, qos_lv, payload)| {
+                    format!("{} qos={} bytes={} {}", topic_name, qos_lv as u8, payload.len(),
+                        String::from_utf8_lossy(&payload))
+                }))
+            }
+        }
+        ("loglevel", &[]) => format!("{}\n", log::level().name()),
+        ("loglevel", &[level]) => match Level::from_str(level) {
+            Some(level) => {
+                log::set_level(level);
+                format!("log level set to {}\n", level.name())
+            }
+            None => format!("unknown log level `{}`\n", level)
+        },
+        ("bufpool", &[]) => {
+            let stats = broker.buf_pool_stats();
+            format!("hits={} misses={} idle={}\n", stats.hits, stats.misses, stats.idle)
+        }
+        ("process", &[]) => {
+            let stats = process::stats();
+            let show = |value: Option<u64>| value.map_or("unknown".to_string(), |v| v.to_string());
+            format!("rss_bytes={} open_fds={} threads={}\n", show(stats.rss_bytes),
+                show(stats.open_fds.map(|fds| fds as u64)),
+                show(stats.threads.map(|threads| threads as u64)))
+        }
+        ("keepalive", &[]) => {
+            let stats = broker.keep_alive_stats();
+            let show = |bounds: Vec<String>, counts: &[usize]| {
+                bounds.iter().zip(counts).map(|(bound, count)| format!("{}={}", bound, count))
+                    .collect::<Vec<_>>().join(" ")
+            };
+            let mut reply = format!("clients {}
+gaps {}
+timeouts={}
+",
+                show(keepalive::bucket_names(KEEP_ALIVE_BOUNDS), &stats.keep_alives),
+                show(keepalive::bucket_names(GAP_BOUNDS), &stats.gaps), stats.timeouts);
+            for suggestion in stats.suggestions() {
+                reply += &format!("suggestion: {}
+", suggestion);
+            }
+            reply
+        }
+        ("info", &[]) => match broker.retained("$SYS/broker/info") {
+            Some((_, info)) => format!("{}\n", String::from_utf8_lossy(&info)),
+            None => "no broker info published\n".to_string()
+        },
+        ("help", _) => HELP.to_string(),
+        _ => format!("invalid command `{}`; try help\n", line.trim())
+    }
+}
@@ -0,0 +1,84 @@
+use base64;
+use json::Json;
+use libmqtt::ctrlpkt::QosLv;
+use observer::DropReason;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A QoS 1 or 2 message the broker gave up on for one subscriber
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadLetter {
+    pub client_id: String,
+    pub topic_name: String,
+    // The QoS it would have been delivered at
+    pub qos_lv: QosLv,
+    pub reason: DropReason,
+    pub dropped_at: SystemTime,
+    pub payload: Vec<u8>
+}
+
+impl DeadLetter {
+    // What is published on the dead-letter topic: the message and why it was dropped, with the
+    // payload in base64 so that it can be replayed as it was
+    pub fn to_json(&self) -> Json {
+        let dropped_at = self.dropped_at.duration_since(UNIX_EPOCH).map(|d| d.as_secs())
+            .unwrap_or(0);
+        Json::Object(vec![
+            ("client_id".to_string(), Json::String(self.client_id.clone())),
+            ("topic".to_string(), Json::String(self.topic_name.clone())),
+            ("qos".to_string(), Json::Number(self.qos_lv as u64)),
+            ("reason".to_string(), Json::String(self.reason.name().to_string())),
+            ("dropped_at".to_string(), Json::Number(dropped_at)),
+            ("payload".to_string(), Json::String(base64::encode(&self.payload)))
+        ])
+    }
+}
+
+// Hands messages dropped by queue policy to the thread that republishes them on `topic`. Sessions
+// call it while they are locked, so it only copies the message and sends it on.
+#[derive(Debug)]
+pub struct DeadLetters {
+    pub topic: String,
+    tx: Mutex<Sender<DeadLetter>>,
+    sent: AtomicUsize
+}
+
+impl DeadLetters {
+    pub fn new(topic: String, tx: Sender<DeadLetter>) -> DeadLetters {
+        DeadLetters { topic, tx: Mutex::new(tx), sent: AtomicUsize::new(0) }
+    }
+
+    // QoS 0 messages may be lost anyway, so they aren't kept
+    pub fn send(&self, client_id: &str, topic_name: &str, qos_lv: QosLv, payload: &[u8],
+                reason: DropReason) {
+        if qos_lv == QosLv::AtMostOnce {
+            return;
+        }
+        let letter = DeadLetter {
+            client_id: client_id.to_string(),
+            topic_name: topic_name.to_string(),
+            qos_lv,
+            reason,
+            dropped_at: SystemTime::now(),
+            payload: payload.to_vec()
+        };
+        if self.tx.lock().unwrap().send(letter).is_ok() {
+            self.sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Messages handed over so far
+    pub fn sent(&self) -> usize {
+        self.sent.load(Ordering::Relaxed)
+    }
+}
+
+// Sends a dead letter if there is somewhere to send it
+pub fn send(dead_letters: Option<&DeadLetters>, client_id: &str, topic_name: &str, qos_lv: QosLv,
+            payload: &[u8], reason: DropReason) {
+    if let Some(dead_letters) = dead_letters {
+        dead_letters.send(client_id, topic_name, qos_lv, payload, reason);
+    }
+}
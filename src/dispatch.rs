@@ -0,0 +1,124 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::collections::hash_map::{DefaultHasher, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Instant;
+
+// Runs a step of a job and returns true once the job is done
+type Job = Box<dyn FnMut() -> bool + Send>;
+
+pub const DEFAULT_WORKERS: usize = 4;
+
+// Runs message fan-out on a pool of worker threads so that a publisher's connection thread doesn't
+// wait for every subscriber to be written to. Jobs are sharded across the workers by topic, which
+// keeps messages on one topic in the order they were published.
+pub struct Dispatcher {
+    workers: Vec<Mutex<Sender<(String, Job)>>>,
+    pending: Arc<Pending>
+}
+
+// The jobs dispatched that haven't finished yet
+struct Pending {
+    jobs: Mutex<usize>,
+    changed: Condvar
+}
+
+impl Pending {
+    fn finish(&self) {
+        *self.jobs.lock().unwrap() -= 1;
+        self.changed.notify_all();
+    }
+}
+
+impl Dispatcher {
+    // Starts `workers` threads (at least one). They exit once the dispatcher is dropped.
+    pub fn new(workers: usize) -> Dispatcher {
+        let pending = Arc::new(Pending { jobs: Mutex::new(0), changed: Condvar::new() });
+        let workers = (0..cmp::max(workers, 1)).map(|_| {
+            let (tx, rx) = mpsc::channel();
+            let pending = Arc::clone(&pending);
+            thread::spawn(move || run_worker(rx, &pending));
+            Mutex::new(tx)
+        });
+        Dispatcher { workers: workers.collect(), pending }
+    }
+
+    pub fn dispatch<F: FnOnce() + Send + 'static>(&self, topic_name: &str, job: F) {
+        let mut job = Some(job);
+        self.dispatch_steps(topic_name, move || {
+            if let Some(job) = job.take() {
+                job();
+            }
+            true
+        });
+    }
+
+    // Dispatches a job that runs in steps, e.g. a fan-out to a large number of subscribers.
+    // `step` is called until it returns true. Between steps the worker runs jobs on other topics,
+    // while later jobs on the same topic wait for this one to finish.
+    pub fn dispatch_steps<F: FnMut() -> bool + Send + 'static>(&self, topic_name: &str, step: F) {
+        let mut hasher = DefaultHasher::new();
+        topic_name.hash(&mut hasher);
+        let worker = &self.workers[(hasher.finish() % self.workers.len() as u64) as usize];
+        *self.pending.jobs.lock().unwrap() += 1;
+        // Sending only fails if the worker panicked
+        if worker.lock().unwrap().send((topic_name.to_string(), Box::new(step))).is_err() {
+            log!(Error, "Dispatch worker for {} has stopped; message dropped", topic_name);
+            self.pending.finish();
+        }
+    }
+
+    // Waits until no job is left to run, including any dispatched while waiting. Returns false if
+    // there still is one at `deadline`.
+    pub fn wait_idle(&self, deadline: Instant) -> bool {
+        let mut jobs = self.pending.jobs.lock().unwrap();
+        while *jobs > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            jobs = self.pending.changed.wait_timeout(jobs, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+fn run_worker(rx: Receiver<(String, Job)>, pending: &Pending) {
+    // The first unfinished job of every topic, taking turns
+    let mut running: VecDeque<(String, Job)> = VecDeque::new();
+    // Jobs waiting for an earlier job on the same topic
+    let mut waiting: HashMap<String, VecDeque<Job>> = HashMap::new();
+    loop {
+        let received = if running.is_empty() {
+            match rx.recv() {
+                Ok(job) => Some(job),
+                Err(_) => return
+            }
+        } else {
+            rx.try_recv().ok()
+        };
+        if let Some((topic_name, job)) = received {
+            match waiting.get_mut(&topic_name) {
+                Some(jobs) => jobs.push_back(job),
+                None => {
+                    waiting.insert(topic_name.clone(), VecDeque::new());
+                    running.push_back((topic_name, job));
+                }
+            }
+        }
+        let (topic_name, mut job) = running.pop_front().unwrap();
+        if !job() {
+            running.push_back((topic_name, job));
+            continue;
+        }
+        pending.finish();
+        let next = waiting.get_mut(&topic_name).unwrap().pop_front();
+        match next {
+            Some(next) => running.push_back((topic_name, next)),
+            None => { waiting.remove(&topic_name); }
+        }
+    }
+}
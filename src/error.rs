@@ -0,0 +1,19 @@
+use std::io;
+use std::result;
+
+pub type Result<T> = result::Result<T, Error>;
+
+// Errors from setting up the broker, as opposed to the protocol errors of a connection
+#[derive(Debug)]
+pub enum Error {
+    Config(String),
+    Passwd(String),
+
+    Io(io::Error)
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
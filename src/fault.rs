@@ -0,0 +1,83 @@
+use rand;
+use std::time::Duration;
+
+// Deliberate misbehaviour for testing how clients cope with a flaky broker. Rules come from
+// `fault` blocks in the config file; none are active by default.
+//
+// A rule applies to a client id and topic, either of which can be `*`. For acknowledgements the
+// client is the publisher; for deliveries it is the subscriber. Probabilities are in [0, 1].
+#[derive(Debug, Clone)]
+pub struct FaultRule {
+    pub client_id: Option<String>,
+    pub topic: Option<String>,
+    // Don't send PUBACK, PUBREC or PUBCOMP
+    pub drop_ack: f64,
+    // Hold back deliveries to subscribers
+    pub delay_delivery: Option<Duration>,
+    // Send QoS 1 deliveries a second time with DUP set
+    pub duplicate_qos1: f64,
+    // Close the connection instead of handling a packet
+    pub close_connection: f64
+}
+
+impl FaultRule {
+    pub fn new(client_id: Option<String>, topic: Option<String>) -> FaultRule {
+        FaultRule {
+            client_id,
+            topic,
+            drop_ack: 0.0,
+            delay_delivery: None,
+            duplicate_qos1: 0.0,
+            close_connection: 0.0
+        }
+    }
+
+    // A rule for a specific topic doesn't match packets without one, e.g. PUBCOMP
+    fn matches(&self, client_id: &str, topic: Option<&str>) -> bool {
+        let client_matches = self.client_id.as_ref().map_or(true, |id| id == client_id);
+        let topic_matches = match (&self.topic, topic) {
+            (&None, _) => true,
+            (&Some(ref rule_topic), Some(topic)) => rule_topic == topic,
+            (&Some(_), None) => false
+        };
+        client_matches && topic_matches
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Faults {
+    rules: Vec<FaultRule>
+}
+
+fn roll(probability: f64) -> bool {
+    probability > 0.0 && rand::random::<f64>() < probability
+}
+
+impl Faults {
+    pub fn new(rules: Vec<FaultRule>) -> Faults {
+        Faults { rules }
+    }
+
+    fn matching<'a>(&'a self, client_id: &'a str, topic: Option<&'a str>)
+        -> impl Iterator<Item = &'a FaultRule> + 'a {
+        self.rules.iter().filter(move |rule| rule.matches(client_id, topic))
+    }
+
+    pub fn drop_ack(&self, client_id: &str, topic: Option<&str>) -> bool {
+        self.matching(client_id, topic).any(|rule| roll(rule.drop_ack))
+    }
+
+    // The longest delay of the matching rules
+    pub fn delivery_delay(&self, client_id: &str, topic: &str) -> Option<Duration> {
+        self.matching(client_id, Some(topic)).filter_map(|rule| rule.delay_delivery).max()
+    }
+
+    pub fn duplicate_qos1(&self, client_id: &str, topic: &str) -> bool {
+        self.matching(client_id, Some(topic)).any(|rule| roll(rule.duplicate_qos1))
+    }
+
+    // `topic` is the topic of the packet about to be handled, if it has one
+    pub fn close_connection(&self, client_id: &str, topic: Option<&str>) -> bool {
+        self.matching(client_id, topic).any(|rule| roll(rule.close_connection))
+    }
+}
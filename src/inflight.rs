@@ -0,0 +1,79 @@
+use std::collections::{btree_map::BTreeMap, hash_map::HashMap};
+
+// QoS 1 and 2 deliveries waiting for an acknowledgement. Acks are looked up by packet id, and
+// the deliveries are also kept in the order they were sent, which is the order the spec requires
+// them to be resent in. Acking is O(log n) rather than a scan of the whole window.
+//
+// Each delivery is also stamped with the epoch it was sent in. The epoch goes up every time the
+// session is resumed, so an ack can be told apart from one for a delivery the client got before it
+// reconnected.
+#[derive(Debug, Clone)]
+pub struct Inflight<T> {
+    // Packet id -> (send sequence number, epoch, delivery)
+    by_pkt_id: HashMap<u16, (u64, u32, T)>,
+    // Send sequence number -> packet id
+    order: BTreeMap<u64, u16>,
+    next_seq: u64,
+    epoch: u32
+}
+
+impl<T> Inflight<T> {
+    pub fn new() -> Inflight<T> {
+        Inflight { by_pkt_id: HashMap::new(), order: BTreeMap::new(), next_seq: 0, epoch: 1 }
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    // Starts a new epoch, e.g. when the session is resumed. Returns it.
+    pub fn next_epoch(&mut self) -> u32 {
+        self.epoch += 1;
+        self.epoch
+    }
+
+    pub fn set_epoch(&mut self, epoch: u32) {
+        self.epoch = epoch;
+    }
+
+    // The epoch the delivery with this packet id was sent in, if it is waiting
+    pub fn epoch_of(&self, pkt_id: u16) -> Option<u32> {
+        self.by_pkt_id.get(&pkt_id).map(|&(_, epoch, _)| epoch)
+    }
+
+    // Adds a delivery after all the others, in the current epoch. A delivery already waiting with
+    // the same packet id is replaced.
+    pub fn insert(&mut self, pkt_id: u16, msg: T) {
+        self.remove(pkt_id);
+        self.by_pkt_id.insert(pkt_id, (self.next_seq, self.epoch, msg));
+        self.order.insert(self.next_seq, pkt_id);
+        self.next_seq += 1;
+    }
+
+    pub fn remove(&mut self, pkt_id: u16) -> Option<T> {
+        let (seq, _, msg) = self.by_pkt_id.remove(&pkt_id)?;
+        self.order.remove(&seq);
+        Some(msg)
+    }
+
+    pub fn get_mut(&mut self, pkt_id: u16) -> Option<&mut T> {
+        self.by_pkt_id.get_mut(&pkt_id).map(|&mut (_, _, ref mut msg)| msg)
+    }
+
+    pub fn contains(&self, pkt_id: u16) -> bool {
+        self.by_pkt_id.contains_key(&pkt_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_pkt_id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_pkt_id.is_empty()
+    }
+
+    // The waiting deliveries, oldest first
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (u16, &'a T)> + 'a {
+        self.order.values().map(move |pkt_id| (*pkt_id, &self.by_pkt_id[pkt_id].2))
+    }
+}
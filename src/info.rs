@@ -0,0 +1,98 @@
+use broker::{self, TakeoverPolicy, BUF_POOL_MAX_BUF_LEN, BUF_POOL_SIZE};
+use config::Config;
+use libmqtt::ctrlpkt::ProtocolLv;
+use std::time::Duration;
+
+pub const VERSION: &str = concat!("mqtt-broker ", env!("CARGO_PKG_VERSION"));
+
+fn secs(duration: Option<Duration>) -> String {
+    duration.map_or("off".to_string(), |duration| format!("{}s", duration.as_secs()))
+}
+
+// What build is running with which config, as `key: value` lines. Logged at startup and published
+// on $SYS/broker/info.
+pub fn describe(config: &Config) -> Vec<String> {
+    let mut features: Vec<String> = ProtocolLv::supported().iter()
+        .map(|protocol_lv| format!("mqtt-{}", protocol_lv.version()))
+        .collect();
+    if cfg!(unix) {
+        features.push("control-socket".to_string());
+    }
+    if cfg!(feature = "json-schema") {
+        features.push("json-schema".to_string());
+    }
+    let mut info = vec![
+        format!("version: {}", VERSION),
+        format!("features: {}", features.join(" ")),
+        format!("node: {}", config.node_id.clone().unwrap_or_else(broker::default_node_id))
+    ];
+    for listener in &config.listeners {
+        let mut line = format!("listener: {} backlog={} tcp_nodelay={} tcp_keepalive={} \
+            connect_timeout={} write_timeout={}", listener.addr, listener.backlog,
+            listener.tcp_nodelay, secs(listener.tcp_keepalive), secs(listener.connect_timeout),
+            secs(listener.write_timeout));
+        if let Some(ref mount_point) = listener.mount_point {
+            line.push_str(&format!(" mount_point={}", mount_point));
+        }
+        if listener.tenant_isolation {
+            line.push_str(" tenant_isolation=true");
+        }
+        if let Some(ref principal) = listener.principal {
+            line.push_str(&format!(" principal={}", principal));
+        }
+        if !listener.wildcard_subscriptions {
+            line.push_str(" wildcard_subscriptions=false");
+        }
+        if let Some(rate) = listener.connection_rate {
+            line.push_str(&format!(" connection_rate={} connection_burst={}", rate,
+                listener.connection_burst.unwrap_or(rate)));
+        }
+        info.push(line);
+    }
+    info.push(format!("limits: dispatch_workers={} fanout_chunk_size={} sys_interval={} \
+        buffer_pool={}x{}B max_queued_messages={} max_queued_age={} max_qos={} \
+        max_ping_rate={} retained_batch_size={}",
+        config.dispatch_workers, config.fanout_chunk_size, secs(config.sys_interval),
+        BUF_POOL_SIZE, BUF_POOL_MAX_BUF_LEN, config.max_queued_messages,
+        secs(config.max_queued_age), config.max_qos as u8,
+        config.max_ping_rate.map_or("off".to_string(), |rate| rate.to_string()),
+        config.retained_batch_size));
+    if let Some(batching) = config.write_batching {
+        info.push(format!("write_batching: max_delay={}ms max_bytes={}",
+            batching.max_delay.as_secs() * 1000 + batching.max_delay.subsec_millis() as u64,
+            batching.max_bytes));
+    }
+    if let Some(max_bytes) = config.max_connection_memory {
+        info.push(format!("connection_memory: max={}B action={}", max_bytes,
+            config.connection_memory_action.name()));
+    }
+    if config.session_takeover != TakeoverPolicy::Immediate {
+        info.push(format!("session_takeover: {}", config.session_takeover.name()));
+    }
+    if let Some(ref topic) = config.dead_letter_topic {
+        info.push(format!("dead_letter_topic: {}", topic));
+    }
+    if config.retained_history > 0 {
+        info.push(format!("retained_history: {} per topic", config.retained_history));
+    }
+    if let Some(ref filter) = config.anonymous_topics {
+        info.push(format!("anonymous_topics: {}", filter));
+    }
+    if let Some(ref filter) = config.anonymous_retain_topics {
+        info.push(format!("anonymous_retain_topics: {} retain_violation={}", filter,
+            format!("{:?}", config.retain_violation).to_lowercase()));
+    }
+    for rule in &config.json_schemas {
+        info.push(format!("validate_json: {} schema={} action={}", rule.filter,
+            rule.path.display(), rule.action.name()));
+    }
+    for rule in &config.min_qos {
+        info.push(format!("min_qos: {} qos={} action={}", rule.filter, rule.qos_lv as u8,
+            format!("{:?}", rule.action).to_lowercase()));
+    }
+    if !config.persistent_client_ids.is_empty() || !config.persistent_usernames.is_empty() {
+        info.push(format!("persistent_sessions: client_ids={} usernames={}",
+            config.persistent_client_ids.join(","), config.persistent_usernames.join(",")));
+    }
+    info
+}
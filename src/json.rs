@@ -0,0 +1,300 @@
+use std::char;
+use std::fmt::{self, Write};
+use std::iter::Peekable;
+use std::str::Chars;
+
+// Just enough JSON for exporting and importing sessions on the console, and for validating
+// payloads against schemas. The broker only writes non-negative integers, which are Numbers; other
+// numbers are only read from payloads and schemas, as Reals.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    Real(f64),
+    String(String),
+    Array(Vec<Json>),
+    // Keys in the order they were written
+    Object(Vec<(String, Json)>)
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            &Json::Object(ref fields) => fields.iter().find(|&&(ref k, _)| k == key).map(|f| &f.1),
+            _ => None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            &Json::Bool(b) => Some(b),
+            _ => None
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            &Json::Number(n) => Some(n),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            &Json::String(ref s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            &Json::Array(ref items) => Some(items),
+            _ => None
+        }
+    }
+
+    fn write_to(&self, s: &mut String) {
+        match self {
+            &Json::Null => s.push_str("null"),
+            &Json::Bool(b) => s.push_str(if b { "true" } else { "false" }),
+            &Json::Number(n) => s.push_str(&n.to_string()),
+            &Json::Real(x) => s.push_str(&x.to_string()),
+            &Json::String(ref string) => write_str(string, s),
+            &Json::Array(ref items) => {
+                s.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        s.push(',');
+                    }
+                    item.write_to(s);
+                }
+                s.push(']');
+            }
+            &Json::Object(ref fields) => {
+                s.push('{');
+                for (i, &(ref key, ref value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        s.push(',');
+                    }
+                    write_str(key, s);
+                    s.push(':');
+                    value.write_to(s);
+                }
+                s.push('}');
+            }
+        }
+    }
+}
+
+// Compact, on one line
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = String::new();
+        self.write_to(&mut s);
+        f.write_str(&s)
+    }
+}
+
+fn write_str(string: &str, s: &mut String) {
+    s.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\r' => s.push_str("\\r"),
+            '\t' => s.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(s, "\\u{:04x}", c as u32); }
+            c => s.push(c)
+        }
+    }
+    s.push('"');
+}
+
+// Returns None unless `s` is a single JSON value that fits the subset above, without Reals
+pub fn parse(s: &str) -> Option<Json> {
+    parse_with(s, false)
+}
+
+// Like parse, but also accepts negative, fractional and exponent numbers, as Reals
+pub fn parse_payload(s: &str) -> Option<Json> {
+    parse_with(s, true)
+}
+
+fn parse_with(s: &str, reals: bool) -> Option<Json> {
+    let mut chars = s.chars().peekable();
+    let value = parse_value(&mut chars, reals)?;
+    skip_whitespace(&mut chars);
+    match chars.next() {
+        None => Some(value),
+        Some(_) => None
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().map_or(false, |c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+fn expect_word(chars: &mut Peekable<Chars>, word: &str) -> Option<()> {
+    for expected in word.chars() {
+        if chars.next()? != expected {
+            return None;
+        }
+    }
+    Some(())
+}
+
+fn parse_value(chars: &mut Peekable<Chars>, reals: bool) -> Option<Json> {
+    skip_whitespace(chars);
+    match *chars.peek()? {
+        'n' => expect_word(chars, "null").map(|_| Json::Null),
+        't' => expect_word(chars, "true").map(|_| Json::Bool(true)),
+        'f' => expect_word(chars, "false").map(|_| Json::Bool(false)),
+        '"' => parse_str(chars).map(Json::String),
+        '[' => {
+            chars.next();
+            let mut items = vec![];
+            skip_whitespace(chars);
+            if chars.peek() == Some(&']') {
+                chars.next();
+                return Some(Json::Array(items));
+            }
+            loop {
+                items.push(parse_value(chars, reals)?);
+                skip_whitespace(chars);
+                match chars.next()? {
+                    ',' => (),
+                    ']' => return Some(Json::Array(items)),
+                    _ => return None
+                }
+            }
+        }
+        '{' => {
+            chars.next();
+            let mut fields = vec![];
+            skip_whitespace(chars);
+            if chars.peek() == Some(&'}') {
+                chars.next();
+                return Some(Json::Object(fields));
+            }
+            loop {
+                skip_whitespace(chars);
+                let key = parse_str(chars)?;
+                skip_whitespace(chars);
+                if chars.next()? != ':' {
+                    return None;
+                }
+                fields.push((key, parse_value(chars, reals)?));
+                skip_whitespace(chars);
+                match chars.next()? {
+                    ',' => (),
+                    '}' => return Some(Json::Object(fields)),
+                    _ => return None
+                }
+            }
+        }
+        c if reals && (c == '-' || c.is_digit(10)) => parse_number(chars),
+        c if c.is_digit(10) => {
+            let mut n: u64 = 0;
+            while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+                n = n.checked_mul(10)?.checked_add(digit as u64)?;
+                chars.next();
+            }
+            Some(Json::Number(n))
+        }
+        _ => None
+    }
+}
+
+// Any JSON number. Non-negative integers that fit are still Numbers.
+fn parse_number(chars: &mut Peekable<Chars>) -> Option<Json> {
+    let mut s = String::new();
+    if chars.peek() == Some(&'-') {
+        s.push(chars.next()?);
+    }
+    let int_start = s.len();
+    // No leading zeros
+    let digits = take_digits(chars, &mut s);
+    if digits == 0 || (digits > 1 && s[int_start..].starts_with('0')) {
+        return None;
+    }
+    if chars.peek() == Some(&'.') {
+        s.push(chars.next()?);
+        if take_digits(chars, &mut s) == 0 {
+            return None;
+        }
+    }
+    if chars.peek() == Some(&'e') || chars.peek() == Some(&'E') {
+        s.push(chars.next()?);
+        if chars.peek() == Some(&'+') || chars.peek() == Some(&'-') {
+            s.push(chars.next()?);
+        }
+        if take_digits(chars, &mut s) == 0 {
+            return None;
+        }
+    }
+    match s.parse::<u64>() {
+        Ok(n) => Some(Json::Number(n)),
+        Err(_) => s.parse().ok().map(Json::Real)
+    }
+}
+
+// Moves digits from `chars` to `s` and returns how many there were
+fn take_digits(chars: &mut Peekable<Chars>, s: &mut String) -> usize {
+    let mut taken = 0;
+    while chars.peek().map_or(false, |c| c.is_digit(10)) {
+        s.push(chars.next().unwrap());
+        taken += 1;
+    }
+    taken
+}
+
+fn parse_str(chars: &mut Peekable<Chars>) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(s),
+            '\\' => s.push(match chars.next()? {
+                '"' => '"',
+                '\\' => '\\',
+                '/' => '/',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'u' => {
+                    let unit = parse_hex4(chars)?;
+                    // A character outside the BMP is written as a surrogate pair
+                    if unit >= 0xd800 && unit < 0xdc00 {
+                        expect_word(chars, "\\u")?;
+                        let low = parse_hex4(chars)?;
+                        if low < 0xdc00 || low >= 0xe000 {
+                            return None;
+                        }
+                        char::from_u32(0x10000 + ((unit - 0xd800) << 10) + (low - 0xdc00))?
+                    } else {
+                        char::from_u32(unit)?
+                    }
+                }
+                _ => return None
+            }),
+            c if (c as u32) < 0x20 => return None,
+            c => s.push(c)
+        }
+    }
+}
+
+fn parse_hex4(chars: &mut Peekable<Chars>) -> Option<u32> {
+    let mut n = 0;
+    for _ in 0..4 {
+        n = n << 4 | chars.next()?.to_digit(16)?;
+    }
+    Some(n)
+}
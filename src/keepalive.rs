@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+// Fleet-wide distribution of the keep-alive periods clients connect with and of the time between
+// their packets, to help operators tune device firmware. Each bucket counts the values up to its
+// bound and above the previous one; a last bucket counts everything above the last bound.
+
+// In seconds. A keep-alive of 0 (disabled) gets a bucket of its own.
+pub const KEEP_ALIVE_BOUNDS: &[u16] = &[0, 10, 30, 60, 120, 300, 600, 1800];
+// Time between two packets from a client, in percent of its keep-alive period. Clients without a
+// keep-alive aren't counted. The broker closes the connection after 150%.
+pub const GAP_BOUNDS: &[u64] = &[25, 50, 75, 100, 125, 150];
+
+// Suggestions need at least this many samples to go on
+const MIN_SAMPLES: usize = 100;
+
+pub struct KeepAliveStats {
+    keep_alives: Vec<AtomicUsize>,
+    gaps: Vec<AtomicUsize>,
+    timeouts: AtomicUsize
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeepAliveHistogram {
+    // One count per bound in KEEP_ALIVE_BOUNDS, then one for longer periods
+    pub keep_alives: Vec<usize>,
+    // One count per bound in GAP_BOUNDS, then one for longer gaps
+    pub gaps: Vec<usize>,
+    // Connections closed because nothing arrived for one and a half keep-alive periods
+    pub timeouts: usize
+}
+
+fn buckets(n: usize) -> Vec<AtomicUsize> {
+    (0..n + 1).map(|_| AtomicUsize::new(0)).collect()
+}
+
+fn bucket<T: PartialOrd>(bounds: &[T], value: T) -> usize {
+    bounds.iter().position(|bound| value <= *bound).unwrap_or(bounds.len())
+}
+
+impl KeepAliveStats {
+    pub fn new() -> KeepAliveStats {
+        KeepAliveStats {
+            keep_alives: buckets(KEEP_ALIVE_BOUNDS.len()),
+            gaps: buckets(GAP_BOUNDS.len()),
+            timeouts: AtomicUsize::new(0)
+        }
+    }
+
+    // A client connected with a keep-alive of `secs`
+    pub fn record_keep_alive(&self, secs: u16) {
+        self.keep_alives[bucket(KEEP_ALIVE_BOUNDS, secs)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // A packet arrived `gap` after the previous one from a client with a keep-alive of `secs`
+    pub fn record_gap(&self, gap: Duration, secs: u16) {
+        if secs == 0 {
+            return;
+        }
+        let millis = gap.as_secs() * 1000 + (gap.subsec_nanos() / 1_000_000) as u64;
+        let percent = millis * 100 / (secs as u64 * 1000);
+        self.gaps[bucket(GAP_BOUNDS, percent)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn histogram(&self) -> KeepAliveHistogram {
+        let load = |counts: &[AtomicUsize]| {
+            counts.iter().map(|count| count.load(Ordering::Relaxed)).collect()
+        };
+        KeepAliveHistogram {
+            keep_alives: load(&self.keep_alives),
+            gaps: load(&self.gaps),
+            timeouts: self.timeouts.load(Ordering::Relaxed)
+        }
+    }
+}
+
+impl Default for KeepAliveStats {
+    fn default() -> KeepAliveStats {
+        KeepAliveStats::new()
+    }
+}
+
+// The name of each bucket: its bound, then `more` for the last one
+pub fn bucket_names<T: ToString>(bounds: &[T]) -> Vec<String> {
+    bounds.iter().map(|bound| bound.to_string()).chain(Some("more".to_string())).collect()
+}
+
+impl KeepAliveHistogram {
+    // Hints for tuning client firmware, if the distribution suggests any
+    pub fn suggestions(&self) -> Vec<String> {
+        let mut suggestions = vec![];
+        let gaps: usize = self.gaps.iter().sum();
+        // Packets arriving later than the keep-alive period are close to getting the client
+        // disconnected, typically because the firmware pings at its keep-alive period exactly
+        // and network delays push it over
+        let on_time = bucket(GAP_BOUNDS, 100) + 1;
+        let late: usize = self.gaps[on_time..].iter().sum();
+        if gaps >= MIN_SAMPLES && late * 10 >= gaps {
+            suggestions.push(format!("{}% of packets arrived more than a keep-alive period after \
+                the previous one from the same client, and {} connections timed out: clients \
+                should ping well within their keep-alive, or connect with a longer one",
+                late * 100 / gaps, self.timeouts));
+        }
+        let clients: usize = self.keep_alives.iter().sum();
+        let short = self.keep_alives[bucket(KEEP_ALIVE_BOUNDS, 10)];
+        if clients >= MIN_SAMPLES && short * 2 >= clients {
+            suggestions.push(format!("{}% of clients connect with a keep-alive of 10 seconds or \
+                less: a longer one would cut down on PINGREQ traffic", short * 100 / clients));
+        }
+        suggestions
+    }
+}
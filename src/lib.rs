@@ -0,0 +1,37 @@
+#![feature(use_nested_groups)]
+extern crate bcrypt;
+extern crate libc;
+extern crate libmqtt;
+extern crate net2;
+extern crate rand;
+
+#[macro_use]
+pub mod log;
+pub mod audit;
+pub mod base64;
+pub mod blocklist;
+pub mod broker;
+pub mod clientid;
+pub mod clock;
+pub mod config;
+pub mod connection;
+#[cfg(unix)]
+pub mod control;
+pub mod deadletter;
+pub mod dispatch;
+pub mod error;
+pub mod fault;
+pub mod inflight;
+pub mod info;
+pub mod json;
+pub mod keepalive;
+pub mod observer;
+#[cfg(unix)]
+pub mod passwd;
+pub mod process;
+pub mod ratelimit;
+pub mod subscriptions;
+pub mod topic;
+pub mod transport;
+pub mod uuid;
+pub mod validation;
@@ -0,0 +1,86 @@
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+// Process-wide log level. Messages go to stdout like the rest of the broker's output.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4
+}
+
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+impl Level {
+    pub fn from_str(s: &str) -> Option<Level> {
+        match s {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Level::Error => "error",
+            &Level::Warn => "warn",
+            &Level::Info => "info",
+            &Level::Debug => "debug",
+            &Level::Trace => "trace"
+        }
+    }
+}
+
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => Level::Error,
+        1 => Level::Warn,
+        2 => Level::Info,
+        3 => Level::Debug,
+        _ => Level::Trace
+    }
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= self::level()
+}
+
+static NODE_ID: AtomicPtr<String> = AtomicPtr::new(ptr::null_mut());
+
+// Prefixes later log lines with `[<node id>]`, so that the logs of several brokers can be told
+// apart once collected. Meant to be called once at startup: the id is never freed, as another
+// thread may be printing it.
+pub fn set_node_id(node_id: &str) {
+    NODE_ID.store(Box::into_raw(Box::new(node_id.to_string())), Ordering::SeqCst);
+}
+
+pub fn node_id() -> Option<&'static str> {
+    let node_id = NODE_ID.load(Ordering::SeqCst);
+    if node_id.is_null() {
+        None
+    } else {
+        Some(unsafe { &*node_id })
+    }
+}
+
+// log!(Debug, "Received {:?}", pkt)
+#[macro_export]
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::$level) {
+            match $crate::log::node_id() {
+                Some(node_id) => println!("[{}] {}", node_id, format_args!($($arg)*)),
+                None => println!($($arg)*)
+            }
+        }
+    }
+}
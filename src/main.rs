@@ -1,330 +1,85 @@
 #![feature(use_nested_groups)]
-extern crate libmqtt;
+extern crate mqtt_broker;
+#[cfg(feature = "demo-clients")]
 extern crate mqttc;
+#[cfg(feature = "demo-clients")]
 extern crate netopt;
+#[cfg(feature = "demo-clients")]
 extern crate mqtt3;
 
+#[cfg(feature = "demo-clients")]
 use netopt::{NetworkOptions};
+#[cfg(feature = "demo-clients")]
 use mqttc::{ClientOptions, PubSub, PubOpt};
-use libmqtt::{ctrlpkt::*, ctrlpkt::CtrlPkt::*, error::*, pktid::*};
-use std::collections::{hash_map::HashMap, vec_deque::VecDeque};
-use std::sync::{RwLock, Arc, Mutex};
-use std::io::Write;
-use std::net::{TcpStream, TcpListener};
+use mqtt_broker::{audit::AuditSink,
+    broker::{self, Broker, MemoryLimits, PersistentSessions, Qos2Limits, QueueLimits,
+             WillLimits},
+    clientid::ClientIdGenerator, config::Config, error::Error, fault::Faults, info, log};
+#[cfg(unix)]
+use mqtt_broker::{control, passwd};
+#[cfg(feature = "json-schema")]
+use mqtt_broker::validation::JsonSchema;
+#[cfg(unix)]
+use std::path::Path;
+use std::{env, process};
+#[cfg(feature = "demo-clients")]
 use std::thread;
 
-#[derive(Debug, Clone)]
-struct Session {
-    pub client_id: String,
-    pub subscriptions: HashMap<String, QosLv>,
-    pub waiting_for_ack: VecDeque<(u16, Message)>,
-    pub pending_tx: VecDeque<(u16, Message)>,
-    pub clean_session: bool
-}
-
-impl Session {
-    fn new(client_id: String, clean_session: bool) -> Session {
-        Session {
-            client_id,
-            subscriptions: HashMap::new(),
-            waiting_for_ack: VecDeque::new(),
-            pending_tx: VecDeque::new(),
-            clean_session
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
-struct Message {
-    qos_lv: QosLv,
-    payload: Vec<u8>
-}
-
-fn publish_msg(sender_id: &str,
-               topic_name: &str,
-               payload: &Vec<u8>,
-               streams: &Arc<Mutex<HashMap<String, TcpStream>>>,
-               sessions: &Arc<RwLock<HashMap<String, Session>>>,
-               subscriptions: &Arc<RwLock<HashMap<String, HashMap<String, QosLv>>>>,
-               pkt_id_gen: &Arc<Mutex<PktIdGen>>) -> Result<()> {
-    let subscriptions = subscriptions.read().unwrap();
-    let mut sessions = sessions.write().unwrap();
-    let mut pkt_id_gen = pkt_id_gen.lock().unwrap();
-    match subscriptions.get(topic_name) {
-        Some(client_id_to_qos) => {
-            for (client_id, qos_lv) in client_id_to_qos.iter() {
-                if client_id == sender_id {
-                    continue;
-                }
-                let pkt_id = if *qos_lv == QosLv::AtMostOnce {
-                    None
-                } else {
-                    match pkt_id_gen.gen() {
-                        None => return Err(Error::PublishOutOfPktIds),
-                        pkt_id => pkt_id
-                    }
-                };
-                match streams.lock().unwrap().get(client_id) {
-                    Some(mut stream) => {
-                        stream.write_all(&(Publish {
-                            dup: false,
-                            qos_lv: *qos_lv,
-                            retain: false,
-                            topic_name: topic_name.to_string(),
-                            pkt_id,
-                            payload: payload.clone()
-                        }.serialize()?))?;
-                        match sessions.get_mut(client_id) {
-                            Some(session) => {
-                                if pkt_id.is_some() {
-                                    session.waiting_for_ack.push_back((pkt_id.unwrap(),
-                                        Message { qos_lv: *qos_lv, payload: payload.clone() }));
-                                }
-                            }
-                            None => ()
-                        }
-                    }
-                    None => ()
-                }
-            }
-            Ok(())
-        }
-        None => Ok(())
+#[cfg(feature = "demo-clients")]
+fn msg_get_payload(msg: &mqtt3::Message) -> String {
+    let mut v = vec![];
+    for c in msg.payload.iter() {
+        v.push(*c);
     }
+    String::from_utf8(v).unwrap()
 }
 
-fn check_for_session(client_id: &Option<String>,
-                     sessions: &Arc<RwLock<HashMap<String, Session>>>) -> Result<()> {
-    match client_id {
-        &Some(ref client_id) =>
-            if sessions.read().unwrap().contains_key(client_id) {
-                Ok(())
-            } else {
-                Err(Error::NoSession)
-            }
-        &None => Err(Error::NoSession)
+fn error_msg(e: Error) -> String {
+    match e {
+        Error::Config(msg) | Error::Passwd(msg) => msg,
+        e => format!("{:?}", e)
     }
 }
 
-// subscriptions: topic -> client id -> QoS
-fn handle_client(mut stream: TcpStream,
-                 streams: Arc<Mutex<HashMap<String, TcpStream>>>,
-                 sessions: Arc<RwLock<HashMap<String, Session>>>,
-                 retained_msgs: Arc<RwLock<HashMap<String, Message>>>,
-                 subscriptions: Arc<RwLock<HashMap<String, HashMap<String, QosLv>>>>,
-                 pkt_id_gen: Arc<Mutex<PktIdGen>>) -> Result<()> {
-    let mut client_id: Option<String> = None;
-    loop {
-        match match CtrlPkt::deserialize(&mut stream) {
-            Ok(Connect {
-                connect_flags,
-                keep_alive,
-                client_id: cid,
-                will_topic,
-                will_message,
-                username,
-                password
-            }) => {
-                println!("Received {:?}", Connect {
-                    connect_flags,
-                    keep_alive,
-                    client_id: cid.clone(),
-                    will_topic: will_topic.clone(),
-                    will_message: will_message.clone(),
-                    username: username.clone(),
-                    password: password.clone()
-                });
-                client_id = Some(cid.clone());
-                {
-                    // Add stream to streams so that other threads can send to this client id
-                    let mut streams = streams.lock().unwrap();
-                    streams.insert(cid.clone(), stream.try_clone().unwrap());
-                }
-                let mut sessions = sessions.write().unwrap();
-                let (session_present, return_code) =
-                    if connect_flags.contains(ConnectFlags::CLEAN_SESSION) ||
-                        !sessions.contains_key(&cid) {
-                            (false, ConnAckRetCode::Accepted)
-                        } else {
-                            (true, ConnAckRetCode::Accepted)
-                        };
-                if connect_flags.contains(ConnectFlags::CLEAN_SESSION) {
-                    // Clear old session and create new one
-                    sessions.remove(&cid);
-                    sessions.insert(cid.clone(), Session::new(cid,
-                        connect_flags.contains(ConnectFlags::CLEAN_SESSION)));
-                } else {
-                    // Get old session or create a new one
-                    let old_session_exists = sessions.get(&cid).is_some();
-                    if !old_session_exists {
-                        sessions.insert(cid.clone(), Session::new(cid,
-                            connect_flags.contains(ConnectFlags::CLEAN_SESSION)));
-                    }
-                }
-                let buf = CtrlPkt::ConnAck { session_present, return_code }.serialize()?;
-                stream.write_all(&buf)
-            }
-            Ok(Publish { dup, qos_lv, retain, topic_name, pkt_id, payload }) => {
-                println!("Received {:?}", Publish {
-                    dup,
-                    qos_lv,
-                    retain,
-                    topic_name: topic_name.clone(),
-                    pkt_id: pkt_id.clone(),
-                    payload: payload.clone()
-                });
-                check_for_session(&client_id, &sessions)?;
-                if retain {
-                    let mut retained_msgs = retained_msgs.write().unwrap();
-                    retained_msgs.insert(topic_name.clone(),
-                        Message { qos_lv, payload: payload.clone() });
-                }
-
-                publish_msg(client_id.as_ref().unwrap(), &topic_name, &payload, &streams, &sessions, &subscriptions, &pkt_id_gen)?;
-
-                match qos_lv {
-                    QosLv::AtMostOnce => Ok(()),
-                    QosLv::AtLeastOnce => stream.write_all(&(PubAck(pkt_id.unwrap())
-                        .serialize()?)),
-                    QosLv::ExactlyOnce => stream.write_all(&(PubRec(pkt_id.unwrap())
-                        .serialize()?))
-                }
-            }
-            Ok(PubAck(pkt_id)) => {
-                println!("Received {:?}", PubAck(pkt_id));
-                check_for_session(&client_id, &sessions)?;
-                let mut sessions = sessions.write().unwrap();
-                let mut session = sessions.get_mut(client_id.as_ref().unwrap()).unwrap();
-                let mut pkt_id_gen = pkt_id_gen.lock().unwrap();
-                pkt_id_gen.rm(pkt_id);
-                let mut idx: Option<usize> = None;
-                for (i, &(pi, _)) in session.waiting_for_ack.iter().enumerate() {
-                    if pkt_id == pi {
-                        idx = Some(i);
-                    }
-                }
-                match idx {
-                    Some(idx) => {
-                        session.waiting_for_ack.remove(idx);
-                    }
-                    None => ()
-                }
-                Ok(())
-            }
-            Ok(Subscribe { pkt_id, subs }) => {
-                println!("Received {:?}", Subscribe {
-                    pkt_id,
-                    subs: subs.clone()
-                });
-                check_for_session(&client_id, &sessions)?;
-                let mut sessions = sessions.write().unwrap();
-                let session = sessions.get_mut(client_id.as_ref().unwrap()).unwrap();
-                let mut subscriptions = subscriptions.write().unwrap();
-                let mut sub_ack_ret_codes: Vec<SubAckRetCode> = vec![];
-                for (topic_name, requested_qos_lv) in subs {
-                    sub_ack_ret_codes.push(if topic_name.contains("*") {
-                        SubAckRetCode::Failure
-                    } else {
-                        session.subscriptions.insert(topic_name.clone(), requested_qos_lv);
-                        match match subscriptions.get_mut(&topic_name) {
-                            Some(client_to_qos) => {
-                                client_to_qos.insert(session.client_id.clone(), requested_qos_lv);
-                                None
-                            }
-                            None => {
-                                let mut hm = HashMap::new();
-                                hm.insert(session.client_id.clone(), requested_qos_lv);
-                                Some(hm)
-                            }
-                        } {
-                            Some(hm) => {
-                                subscriptions.insert(topic_name.clone(), hm);
-                            }
-                            None => ()
-                        }
-                        SubAckRetCode::from(requested_qos_lv)
-                    });
-                }
-                let pkt = SubAck { pkt_id, sub_ack_ret_codes };
-                println!("Response: {:?}", pkt);
-                println!("{:?}", session);
-                println!("{:?}", subscriptions.clone());
-                println!("{:?}", pkt.serialize()?);
-                stream.write_all(&(pkt.serialize()?))
-            }
-            Ok(pkt@PingReq) => {
-                println!("Received {:?}", pkt);
-                check_for_session(&client_id, &sessions)?;
-                stream.write_all(&(PingResp.serialize()?))
-            }
-            Ok(pkt@Disconnect) => {
-                println!("Received {:?}", pkt);
-                check_for_session(&client_id, &sessions)?;
-                return Ok(());
-            }
-            Ok(pkt@_) => {
-                println!("Received {:?}", pkt);
-                check_for_session(&client_id, &sessions)?;
-                return Err(Error::UnimplementedPkt(pkt))
-            }
-            Err(e@Error::InvalidProtocol) => {
-                stream.write_all(&(CtrlPkt::ConnAck {
-                    session_present: false,
-                    return_code: ConnAckRetCode::UnacceptableProtocolVer
-                }.serialize()?))?;
-                return Err(e);
-            }
-            Err(e) => {
-                println!("{:?}", e);
-                return Err(e);
+// `mqtt-broker check-config <file>`: validates the config without starting the broker
+fn check_config(path: &str) -> ! {
+    match Config::from_file(path) {
+        Ok(config) => {
+            println!("{}: OK ({} listeners, {} fault rules)", path, config.listeners.len(),
+                config.faults.len());
+            process::exit(0);
+        }
+        Err(e) => {
+            for problem in error_msg(e).lines() {
+                println!("{}: {}", path, problem);
             }
-        } {
-            Err(e) => return Err(Error::from(e)),
-            _ => ()
+            process::exit(1);
         }
     }
 }
 
-fn msg_get_payload(msg: &mqtt3::Message) -> String {
-    let mut v = vec![];
-    for c in msg.payload.iter() {
-        v.push(*c);
+// `mqtt-broker passwd <file> <user>`: adds a user to the password file or changes their password
+#[cfg(unix)]
+fn set_password(path: &str, username: &str) -> ! {
+    let res = passwd::read_new_password()
+        .and_then(|password| passwd::hash(&password))
+        .and_then(|hash| passwd::set(Path::new(path), username, &hash));
+    match res {
+        Ok(added) => {
+            println!("{}: {} {}", path, if added { "added" } else { "updated" }, username);
+            process::exit(0);
+        }
+        Err(e) => {
+            println!("{}: {}", path, error_msg(e));
+            process::exit(1);
+        }
     }
-    String::from_utf8(v).unwrap()
 }
 
-fn main() {
-    let listener = TcpListener::bind("127.0.0.1:1883").unwrap();
-    let sessions: Arc<RwLock<HashMap<String, Session>>> = Arc::new(RwLock::new(HashMap::new()));
-    let retained_msgs: Arc<RwLock<HashMap<String, Message>>> =
-        Arc::new(RwLock::new(HashMap::new()));
-    let pkt_id_gen: Arc<Mutex<PktIdGen>> = Arc::new(Mutex::new(PktIdGen::new()));
-    let subscriptions: Arc<RwLock<HashMap<String, HashMap<String, QosLv>>>> =
-        Arc::new(RwLock::new(HashMap::new()));
-    let streams: Arc<Mutex<HashMap<String, TcpStream>>> = Arc::new(Mutex::new(HashMap::new()));
-    let th = thread::spawn(move || {
-        for stream in listener.incoming() {
-            let sessions = Arc::clone(&sessions);
-            let retained_msgs = Arc::clone(&retained_msgs);
-            let pkt_id_gen = Arc::clone(&pkt_id_gen);
-            let subscriptions = Arc::clone(&subscriptions);
-            let streams = Arc::clone(&streams);
-            match stream {
-                Ok(stream) => {
-                    // Make read calls block
-                    let _ = stream.set_read_timeout(None).unwrap();
-                    thread::spawn(move || {
-                        match handle_client(stream, streams, sessions, retained_msgs, subscriptions,
-                            pkt_id_gen) {
-                            Ok(_) => println!("handle_client exited with Ok"),
-                            Err(e) => println!("handle_client exited with error: {:?}", e)
-                        }
-                    });
-                }
-                Err(e) => println!("{}", e)
-            }
-        }
-    });
+// Two mqttc clients that subscribe to test_topic and publish to it. They pull in OpenSSL, so a
+// minimal build leaves them out with --no-default-features.
+#[cfg(feature = "demo-clients")]
+fn start_demo_clients() -> Vec<thread::JoinHandle<()>> {
     let t1 = thread::spawn(move || {
         let netopt = NetworkOptions::new();
         let mut opts = ClientOptions::new();
@@ -373,8 +128,143 @@ fn main() {
             }
         }
     });
+    vec![t1, t2]
+}
 
-    let _ = th.join();
-    t1.join();
-    t2.join();
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let config = match args.get(1).map(|arg| arg.as_str()) {
+        Some("check-config") => match args.get(2) {
+            Some(path) => check_config(path),
+            None => {
+                println!("Usage: mqtt-broker check-config <file>");
+                process::exit(2);
+            }
+        },
+        #[cfg(unix)]
+        Some("passwd") => match (args.get(2), args.get(3)) {
+            (Some(path), Some(username)) => set_password(path, username),
+            _ => {
+                println!("Usage: mqtt-broker passwd <file> <user>");
+                process::exit(2);
+            }
+        },
+        Some(path) => match Config::from_file(path) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Failed to load config {}: {}", path, error_msg(e));
+                process::exit(1);
+            }
+        },
+        None => match Config::from_env() {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Failed to load config from the environment: {}", error_msg(e));
+                process::exit(1);
+            }
+        }
+    };
+    if let Some(level) = config.log_level {
+        log::set_level(level);
+    }
+    let node_id = config.node_id.clone().unwrap_or_else(broker::default_node_id);
+    log::set_node_id(&node_id);
+    let info = info::describe(&config);
+    for line in &info {
+        log!(Info, "{}", line);
+    }
+    let mut broker = Broker::new();
+    broker.publish_info(&info);
+    broker.set_node_id(&node_id).set_faults(Faults::new(config.faults))
+        .set_dispatch_workers(config.dispatch_workers)
+        .set_fanout_chunk_size(config.fanout_chunk_size)
+        .set_write_batching(config.write_batching)
+        .set_max_qos(config.max_qos)
+        .set_will_limits(WillLimits {
+            max_topic_len: config.max_will_topic_length,
+            max_payload_len: config.max_will_payload_size
+        })
+        .set_max_ping_rate(config.max_ping_rate)
+        .set_qos2_limits(Qos2Limits {
+            release_timeout: config.qos2_release_timeout,
+            max_awaiting_rel: config.max_awaiting_rel
+        })
+        .set_queue_limits(QueueLimits {
+            max_messages: config.max_queued_messages,
+            max_age: config.max_queued_age
+        })
+        .set_memory_limits(MemoryLimits {
+            max_bytes: config.max_connection_memory,
+            action: config.connection_memory_action
+        })
+        .set_anonymous_topics(config.anonymous_topics)
+        .set_anonymous_retain_topics(config.anonymous_retain_topics)
+        .set_retain_violation(config.retain_violation)
+        .set_unknown_packets(config.unknown_packets)
+        .set_takeover(config.session_takeover)
+        .set_retained_qos(config.retained_qos)
+        .set_retained_batch_size(config.retained_batch_size)
+        .set_retained_history(config.retained_history)
+        .set_min_qos(config.min_qos.clone())
+        .set_keep_alive_suggestions(config.keep_alive_suggestions);
+    if config.audit {
+        broker.set_audit(Some(AuditSink::stdout()));
+    }
+    if config.dead_letter_topic.is_some() {
+        broker.set_dead_letter_topic(config.dead_letter_topic.clone());
+    }
+    if let Some(ref prefix) = config.client_id_prefix {
+        broker.set_client_id_generator(ClientIdGenerator::counter(prefix));
+    }
+    if !config.persistent_client_ids.is_empty() || !config.persistent_usernames.is_empty() {
+        broker.set_persistent_sessions(Some(PersistentSessions {
+            client_ids: config.persistent_client_ids.clone(),
+            usernames: config.persistent_usernames.clone()
+        }));
+    }
+    #[cfg(feature = "json-schema")]
+    {
+        for rule in &config.json_schemas {
+            match JsonSchema::from_file(&rule.path) {
+                Ok(schema) => {
+                    broker.add_validator(&rule.filter, schema, rule.action);
+                }
+                Err(e) => {
+                    println!("Failed to load schema {}: {}", rule.path.display(), e);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+    for pattern in &config.blocked_client_ids {
+        broker.blocklist().block_client_id(pattern);
+    }
+    for username in &config.blocked_usernames {
+        broker.blocklist().block_username(username);
+    }
+    #[cfg(unix)]
+    {
+        if let Some(ref path) = config.control_socket {
+            if let Err(e) = control::serve(broker.clone(), path) {
+                println!("Failed to open control socket {}: {:?}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(interval) = config.sys_interval {
+        broker.start_sys(interval);
+    }
+    let mut listener_threads = vec![];
+    for listener_config in config.listeners {
+        listener_threads.push(broker.listen(listener_config).unwrap());
+    }
+    #[cfg(feature = "demo-clients")]
+    let demo_clients = start_demo_clients();
+    for th in listener_threads {
+        let _ = th.join();
+    }
+    #[cfg(feature = "demo-clients")]
+    for th in demo_clients {
+        let _ = th.join();
+    }
 }
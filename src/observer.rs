@@ -0,0 +1,159 @@
+use broker::ConnectionParams;
+use libmqtt::ctrlpkt::QosLv;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Hooks for following messages from clients through the broker, at the same steps audit mode logs
+// (see audit.rs), so embedders can build their own metrics or audit layers. Every method does
+// nothing by default. They are called on connection and dispatch threads, some while sessions are
+// locked, so they should return quickly and must not call back into the broker.
+pub trait DispatchObserver: Send + Sync {
+    // A client connected, with the parameters the broker accepted. Called once the CONNACK is sent.
+    fn connected(&self, _client_id: &str, _params: &ConnectionParams) {}
+
+    // A PUBLISH arrived from a client
+    fn received(&self, _msg: &ObservedMessage) {}
+
+    // The message matched the subscriptions of `subscribers` clients, the sender included
+    fn matched(&self, _msg: &ObservedMessage, _subscribers: usize) {}
+
+    // The message was queued for an offline persistent session
+    fn enqueued(&self, _msg: &ObservedMessage, _client_id: &str) {}
+
+    // The message was written to a subscriber's connection at `qos_lv`, either straight away or
+    // from its queue
+    fn delivered(&self, _msg: &ObservedMessage, _client_id: &str, _qos_lv: QosLv) {}
+
+    // The message was dropped for one subscriber, or for all of them if `client_id` is None
+    fn dropped(&self, _msg: &ObservedMessage, _client_id: Option<&str>, _reason: DropReason) {}
+}
+
+// What an observer is told about a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObservedMessage {
+    // Counts up from 1 for each message the observer sees, and is the same in every event about
+    // one message
+    pub id: u64,
+    pub sender_id: String,
+    // Including the sender's mount point, if any
+    pub topic_name: String,
+    // As published; subscribers may get it at a lower QoS
+    pub qos_lv: QosLv,
+    pub retain: bool,
+    pub payload: Vec<u8>
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DropReason {
+    // A QoS 2 message sent again before the sender released it
+    Duplicate,
+    // Published outside the topics the sender may publish on
+    NotAllowed,
+    // Retained outside the topics the sender may retain on, with `retain_violation drop`
+    RetainNotAllowed,
+    // The subscriber is offline and its session doesn't queue the message (a clean session, or
+    // QoS 0)
+    NotConnected,
+    QueueFull,
+    // Queued for longer than `max_queued_age`
+    Expired,
+    // The subscriber's session is over `max_connection_memory`
+    MemoryLimit,
+    // The payload failed validation with `reject` (see validation.rs)
+    Invalid,
+    // Published below the minimum QoS of its topic, with `min_qos ... reject`
+    QosTooLow,
+    WriteFailed,
+    // The broker ran out of packet ids for the remaining subscribers
+    OutOfPktIds
+}
+
+impl DropReason {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DropReason::Duplicate => "duplicate",
+            DropReason::NotAllowed => "not_allowed",
+            DropReason::RetainNotAllowed => "retain_not_allowed",
+            DropReason::NotConnected => "not_connected",
+            DropReason::QueueFull => "queue_full",
+            DropReason::Expired => "expired",
+            DropReason::MemoryLimit => "memory_limit",
+            DropReason::Invalid => "invalid",
+            DropReason::QosTooLow => "qos_too_low",
+            DropReason::WriteFailed => "write_failed",
+            DropReason::OutOfPktIds => "out_of_pkt_ids"
+        }
+    }
+}
+
+// The observer set on a broker
+#[derive(Clone)]
+pub struct Observer {
+    observer: Arc<dyn DispatchObserver>,
+    next_id: Arc<AtomicUsize>
+}
+
+impl Observer {
+    pub fn new<O: DispatchObserver + 'static>(observer: O) -> Observer {
+        Observer { observer: Arc::new(observer), next_id: Arc::new(AtomicUsize::new(1)) }
+    }
+
+    pub fn connected(&self, client_id: &str, params: &ConnectionParams) {
+        self.observer.connected(client_id, params);
+    }
+
+    // Starts following a message that just arrived. `msg.id` is filled in.
+    pub fn observe(&self, mut msg: ObservedMessage) -> Observed {
+        msg.id = self.next_id.fetch_add(1, Ordering::Relaxed) as u64;
+        let observed = Observed { observer: Arc::clone(&self.observer), msg: Arc::new(msg) };
+        observed.record(Event::Received);
+        observed
+    }
+}
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Observer")
+    }
+}
+
+pub enum Event<'a> {
+    Received,
+    Matched(usize),
+    Enqueued(&'a str),
+    Delivered(&'a str, QosLv),
+    Dropped(Option<&'a str>, DropReason)
+}
+
+// A message being followed by an observer, carried along with it
+#[derive(Clone)]
+pub struct Observed {
+    observer: Arc<dyn DispatchObserver>,
+    msg: Arc<ObservedMessage>
+}
+
+impl Observed {
+    pub fn record(&self, event: Event) {
+        let (observer, msg) = (&self.observer, &*self.msg);
+        match event {
+            Event::Received => observer.received(msg),
+            Event::Matched(subscribers) => observer.matched(msg, subscribers),
+            Event::Enqueued(client_id) => observer.enqueued(msg, client_id),
+            Event::Delivered(client_id, qos_lv) => observer.delivered(msg, client_id, qos_lv),
+            Event::Dropped(client_id, reason) => observer.dropped(msg, client_id, reason)
+        }
+    }
+}
+
+impl fmt::Debug for Observed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Observed({})", self.msg.id)
+    }
+}
+
+pub fn observe(observed: Option<&Observed>, event: Event) {
+    if let Some(observed) = observed {
+        observed.record(event);
+    }
+}
@@ -0,0 +1,124 @@
+use bcrypt;
+use error::{Error, Result};
+use libc;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+// The password file has one `<username>:<bcrypt hash>` line per user; blank lines and lines
+// starting with `#` are kept as they are. Edits take an exclusive lock on `<file>.lock`, so two
+// concurrent edits can't lose each other's changes, and replace the file with a rename, so readers
+// never see it half-written.
+
+pub fn hash(password: &str) -> Result<String> {
+    bcrypt::hash(password, bcrypt::DEFAULT_COST).map_err(|e| Error::Passwd(e.to_string()))
+}
+
+// Sets the password hash of `username`, adding the user if it isn't in the file yet, and creates
+// the file if needed. Returns true if the user was added.
+pub fn set(path: &Path, username: &str, hash: &str) -> Result<bool> {
+    if username.is_empty() || username.contains(':') || username.contains(char::is_whitespace) {
+        return Err(Error::Passwd(format!("invalid username {:?}", username)));
+    }
+    let _lock = lock(path)?;
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut file) => { file.read_to_string(&mut contents)?; }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into())
+    }
+    let entry = format!("{}:{}", username, hash);
+    let mut added = true;
+    let mut lines = vec![];
+    for line in contents.lines() {
+        if line.starts_with('#') || line.split(':').next() != Some(username) {
+            lines.push(line);
+        } else if added {
+            lines.push(&entry);
+            added = false;
+        }
+    }
+    if added {
+        lines.push(&entry);
+    }
+
+    let tmp_path = with_suffix(path, ".tmp");
+    {
+        let mut tmp = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600)
+            .open(&tmp_path)?;
+        for line in lines {
+            writeln!(tmp, "{}", line)?;
+        }
+        tmp.sync_all()?;
+    }
+    // Keep the permissions the operator gave the file, e.g. group-readable by the broker's user
+    if let Ok(metadata) = fs::metadata(path) {
+        fs::set_permissions(&tmp_path, metadata.permissions())?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(added)
+}
+
+// Reads a new password from the terminal, without echoing it and asking twice, or as one line
+// from stdin when it isn't a terminal so that scripts can pipe it in
+pub fn read_new_password() -> Result<String> {
+    let password = match read_hidden("Password: ")? {
+        (password, true) => {
+            if read_hidden("Confirm password: ")?.0 != password {
+                return Err(Error::Passwd("passwords don't match".to_string()));
+            }
+            password
+        }
+        (password, false) => password
+    };
+    if password.is_empty() {
+        return Err(Error::Passwd("empty password".to_string()));
+    }
+    Ok(password)
+}
+
+// Returns the line read and whether stdin is a terminal
+fn read_hidden(prompt: &str) -> Result<(String, bool)> {
+    let stdin = io::stdin();
+    let fd = stdin.as_raw_fd();
+    let mut termios: libc::termios = unsafe { mem::zeroed() };
+    let tty = unsafe { libc::tcgetattr(fd, &mut termios) } == 0;
+    if tty {
+        print!("{}", prompt);
+        io::stdout().flush()?;
+        let mut hidden = termios;
+        hidden.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &hidden) };
+    }
+    let mut line = String::new();
+    let res = stdin.read_line(&mut line);
+    if tty {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) };
+        println!();
+    }
+    res?;
+    while line.ends_with('\n') || line.ends_with('\r') {
+        line.pop();
+    }
+    Ok((line, tty))
+}
+
+// The lock is released when the returned file is closed
+fn lock(path: &Path) -> Result<File> {
+    let file = OpenOptions::new().write(true).create(true).truncate(false).mode(0o600)
+        .open(with_suffix(path, ".lock"))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(file)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    PathBuf::from(path)
+}
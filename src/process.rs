@@ -0,0 +1,37 @@
+use std::fs::{self, File};
+use std::io::Read;
+
+// Resource usage of the broker process, read from /proc. Each is None where /proc isn't
+// available (e.g. on macOS) or doesn't have it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessStats {
+    pub rss_bytes: Option<u64>,
+    pub open_fds: Option<usize>,
+    pub threads: Option<usize>
+}
+
+pub fn stats() -> ProcessStats {
+    let mut status = String::new();
+    let _ = File::open("/proc/self/status").and_then(|mut f| f.read_to_string(&mut status));
+    ProcessStats {
+        rss_bytes: status_field(&status, "VmRSS").map(|kb| kb * 1024),
+        open_fds: fs::read_dir("/proc/self/fd").ok().map(|fds| fds.count()),
+        threads: status_field(&status, "Threads").map(|threads| threads as usize)
+    }
+}
+
+// The host name, from /proc
+pub fn hostname() -> Option<String> {
+    let mut hostname = String::new();
+    File::open("/proc/sys/kernel/hostname").and_then(|mut f| f.read_to_string(&mut hostname)).ok()?;
+    let hostname = hostname.trim();
+    if hostname.is_empty() { None } else { Some(hostname.to_string()) }
+}
+
+// The number in a `Name:   123 kB` line
+fn status_field(status: &str, name: &str) -> Option<u64> {
+    status.lines()
+        .find(|line| line.starts_with(name) && line[name.len()..].starts_with(':'))
+        .and_then(|line| line[name.len() + 1..].split_whitespace().next())
+        .and_then(|value| value.parse().ok())
+}
@@ -0,0 +1,41 @@
+use std::time::Instant;
+
+// Token bucket allowing `rate` events per second on average, in bursts of up to `burst`. It starts
+// full.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32, now: Instant) -> RateLimiter {
+        RateLimiter {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill: now
+        }
+    }
+
+    // Takes a token. Returns false if there was none left.
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        if now > self.last_refill {
+            let elapsed = now - self.last_refill;
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+            self.last_refill = now;
+        }
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    // Whether more than half of the burst has been used up
+    pub fn is_overloaded(&self) -> bool {
+        self.tokens < self.burst / 2.0
+    }
+}
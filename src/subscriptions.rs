@@ -0,0 +1,402 @@
+use libmqtt::ctrlpkt::QosLv;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::hash_map::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// topic filter -> client id -> QoS
+pub type SubscriptionMap = HashMap<String, Arc<HashMap<String, QosLv>>>;
+
+// Copy-on-write subscription table for the read-mostly dispatch path. The filters are kept in a
+// trie by level, whose nodes are shared between versions of the table. A writer copies the nodes
+// on the paths of the filters it changes, and the client maps of those filters, changes the
+// copies, and swaps in the new root; everything else stays shared with the old version.
+//
+// Readers never wait for writers. A dispatch thread keeps the root it last read, with the
+// table's version at the time, and only takes the lock to fetch the new root when the version has
+// moved on, i.e. once per update rather than once per publish. How long updates take to apply,
+// i.e. how long a SUBSCRIBE waits for its subscriptions to take effect, is tracked in the stats.
+//
+// A publish walks the trie along its topic's levels, following the level itself, `+` and `#` at
+// each, so it only visits filters that can match.
+pub struct SubscriptionTable {
+    // Tells this table's cached roots apart from other tables' on the same thread
+    id: usize,
+    current: RwLock<Arc<Node>>,
+    // Bumped once a new root has been swapped in
+    version: AtomicUsize,
+    // Serializes writers so that concurrent updates don't lose each other's changes
+    update: Mutex<()>,
+    updates: AtomicUsize,
+    slow_updates: AtomicUsize,
+    max_update_micros: AtomicUsize
+}
+
+static NEXT_TABLE_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // The root of every table this thread has read, with the version it was read at. A thread
+    // keeps an old root alive until it reads that table again or exits.
+    static ROOTS: RefCell<HashMap<usize, (usize, Arc<Node>)>> = RefCell::new(HashMap::new());
+}
+
+// One level of the trie
+#[derive(Clone, Default)]
+struct Node {
+    children: HashMap<String, Arc<Node>>,
+    // The filter ending at this level, if it has subscribers
+    filter: Option<Filter>
+}
+
+#[derive(Clone)]
+struct Filter {
+    clients: Arc<HashMap<String, QosLv>>,
+    // Every publish counts its matches, so the counters are atomics shared by every version of
+    // the filter from its first subscriber to its last, rather than part of the copy
+    matches: Arc<FilterMatches>
+}
+
+impl Filter {
+    fn new() -> Filter {
+        Filter {
+            clients: Arc::new(HashMap::new()),
+            matches: Arc::new(FilterMatches {
+                matched: AtomicU64::new(0),
+                deliveries: AtomicU64::new(0),
+                since: Instant::now()
+            })
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FilterMatches {
+    matched: AtomicU64,
+    deliveries: AtomicU64,
+    // When the filter got its first subscriber
+    since: Instant
+}
+
+impl FilterMatches {
+    fn record(&self, subscribers: usize) {
+        self.matched.fetch_add(1, Ordering::Relaxed);
+        self.deliveries.fetch_add(subscribers as u64, Ordering::Relaxed);
+    }
+}
+
+// Updates taking longer than this to apply are logged and counted as slow
+pub const SLOW_UPDATE_MILLIS: u64 = 100;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TableStats {
+    pub filters: usize,
+    pub subscriptions: usize,
+    // Most levels in a filter
+    pub max_depth: usize,
+    // Rough heap usage of the table, in bytes
+    pub estimated_bytes: usize,
+    // Since the table was created
+    pub updates: usize,
+    pub slow_updates: usize,
+    pub max_update_micros: usize
+}
+
+// How much of the fan-out a topic filter is responsible for
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterStats {
+    pub filter: String,
+    pub subscribers: usize,
+    // Messages published to a topic the filter matches, since it got its first subscriber
+    pub matched: u64,
+    // Copies of those messages sent to the filter's subscribers
+    pub deliveries: u64,
+    // Matched messages per second
+    pub match_rate: f64
+}
+
+impl SubscriptionTable {
+    pub fn new() -> SubscriptionTable {
+        SubscriptionTable {
+            id: NEXT_TABLE_ID.fetch_add(1, Ordering::Relaxed),
+            current: RwLock::new(Arc::new(Node::default())),
+            version: AtomicUsize::new(0),
+            update: Mutex::new(()),
+            updates: AtomicUsize::new(0),
+            slow_updates: AtomicUsize::new(0),
+            max_update_micros: AtomicUsize::new(0)
+        }
+    }
+
+    fn root(&self) -> Arc<Node> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    // Runs `f` on the current root, as cached by this thread. `f` must not read the table again.
+    fn with_root<F: FnOnce(&Node) -> R, R>(&self, f: F) -> R {
+        let version = self.version.load(Ordering::Acquire);
+        ROOTS.with(|roots| {
+            let mut roots = roots.borrow_mut();
+            let fresh = roots.get(&self.id).map_or(false, |&(cached, _)| cached == version);
+            if !fresh {
+                roots.insert(self.id, (version, self.root()));
+            }
+            f(&roots[&self.id].1)
+        })
+    }
+
+    // Every filter with its subscribers, copied out of the trie
+    pub fn snapshot(&self) -> Arc<SubscriptionMap> {
+        let mut subscriptions = HashMap::new();
+        walk(&self.root(), &mut vec![], &mut |levels, filter| {
+            subscriptions.insert(levels.join("/"), Arc::clone(&filter.clients));
+        });
+        Arc::new(subscriptions)
+    }
+
+    // The clients subscribed to a filter matching `topic_name`, each with the highest QoS of its
+    // matching subscriptions, so that a client with overlapping subscriptions gets one copy.
+    // Counts the match for every filter that matched.
+    pub fn subscribers(&self, topic_name: &str) -> HashMap<String, QosLv> {
+        let levels: Vec<&str> = topic_name.split('/').collect();
+        let mut subscribers = HashMap::new();
+        self.with_root(|root| {
+            let mut filters = vec![];
+            collect(root, &levels, topic_name.starts_with('$'), &mut filters);
+            for filter in filters {
+                filter.matches.record(filter.clients.len());
+                for (client_id, &qos_lv) in filter.clients.iter() {
+                    let max_qos_lv = subscribers.entry(client_id.clone()).or_insert(qos_lv);
+                    *max_qos_lv = cmp::max(*max_qos_lv, qos_lv);
+                }
+            }
+        });
+        subscribers
+    }
+
+    // Applies `f` to a private copy of the root, then swaps the copy in
+    fn update<F: FnOnce(&mut Arc<Node>) -> R, R>(&self, f: F) -> R {
+        let started = Instant::now();
+        let _update = self.update.lock().unwrap();
+        let mut root = self.root();
+        let res = f(&mut root);
+        *self.current.write().unwrap() = root;
+        self.version.fetch_add(1, Ordering::Release);
+        self.record_update(started.elapsed());
+        res
+    }
+
+    // Counts an update that took `latency` from the call, including waiting for other writers,
+    // until readers could see it
+    fn record_update(&self, latency: Duration) {
+        let micros =
+            latency.as_secs() as usize * 1_000_000 + latency.subsec_nanos() as usize / 1000;
+        self.updates.fetch_add(1, Ordering::Relaxed);
+        self.max_update_micros.fetch_max(micros, Ordering::Relaxed);
+        if latency > Duration::from_millis(SLOW_UPDATE_MILLIS) {
+            self.slow_updates.fetch_add(1, Ordering::Relaxed);
+            log!(Warn, "Subscription table update took {} ms to apply", micros / 1000);
+        }
+    }
+
+    pub fn subscribe(&self, subs: &[(String, String, QosLv)]) {
+        self.update(|root| {
+            for &(ref filter, ref client_id, qos_lv) in subs {
+                change(root, &filter.split('/').collect::<Vec<_>>(), |entry| {
+                    let filter = entry.get_or_insert_with(Filter::new);
+                    Arc::make_mut(&mut filter.clients).insert(client_id.clone(), qos_lv);
+                });
+            }
+        })
+    }
+
+    // Every filter, or only `topic_filter`, with the most deliveries first
+    pub fn filter_stats(&self, topic_filter: Option<&str>) -> Vec<FilterStats> {
+        let mut stats = vec![];
+        walk(&self.root(), &mut vec![], &mut |levels, filter| {
+            let name = levels.join("/");
+            if topic_filter.map_or(false, |topic_filter| topic_filter != name) {
+                return;
+            }
+            let elapsed = filter.matches.since.elapsed();
+            let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            let matched = filter.matches.matched.load(Ordering::Relaxed);
+            stats.push(FilterStats {
+                filter: name,
+                subscribers: filter.clients.len(),
+                matched,
+                deliveries: filter.matches.deliveries.load(Ordering::Relaxed),
+                match_rate: if secs > 0.0 { matched as f64 / secs } else { 0.0 }
+            });
+        });
+        stats.sort_by(|a, b| {
+            b.deliveries.cmp(&a.deliveries).then_with(|| a.filter.cmp(&b.filter))
+        });
+        stats
+    }
+
+    pub fn stats(&self) -> TableStats {
+        let root = self.root();
+        let mut stats = TableStats {
+            filters: 0,
+            subscriptions: 0,
+            max_depth: 0,
+            estimated_bytes: node_bytes(&root),
+            updates: self.updates.load(Ordering::Relaxed),
+            slow_updates: self.slow_updates.load(Ordering::Relaxed),
+            max_update_micros: self.max_update_micros.load(Ordering::Relaxed)
+        };
+        walk(&root, &mut vec![], &mut |levels, filter| {
+            stats.filters += 1;
+            stats.subscriptions += filter.clients.len();
+            stats.max_depth = stats.max_depth.max(levels.len());
+        });
+        stats
+    }
+
+    // Gives back the space that unsubscribed clients and dropped filters leave in the maps of the
+    // trie. Returns the number of maps shrunk. The trie is only copied if there is something to
+    // reclaim.
+    pub fn compact(&self) -> usize {
+        if wasteful_maps(&self.root()) == 0 {
+            return 0;
+        }
+        self.update(shrink)
+    }
+
+    // Removes (topic filter, client id) subscriptions. Filters left without subscribers are
+    // dropped, along with the levels only they used.
+    pub fn unsubscribe(&self, subs: &[(String, String)]) {
+        self.update(|root| {
+            for &(ref filter, ref client_id) in subs {
+                change(root, &filter.split('/').collect::<Vec<_>>(), |entry| {
+                    let now_empty = match *entry {
+                        Some(ref mut filter) => {
+                            Arc::make_mut(&mut filter.clients).remove(client_id);
+                            filter.clients.is_empty()
+                        }
+                        None => false
+                    };
+                    if now_empty {
+                        *entry = None;
+                    }
+                });
+            }
+        })
+    }
+}
+
+// Applies `f` to the filter at `levels` below `node`, copying the nodes on the way that are shared
+// with other versions of the table. Levels left without filters are dropped. Returns whether
+// `node` itself is left empty.
+fn change<F: FnOnce(&mut Option<Filter>)>(node: &mut Arc<Node>, levels: &[&str], f: F) -> bool {
+    let node = Arc::make_mut(node);
+    match levels.split_first() {
+        None => f(&mut node.filter),
+        Some((level, rest)) => {
+            let empty = {
+                let child = node.children.entry(level.to_string())
+                    .or_insert_with(|| Arc::new(Node::default()));
+                change(child, rest, f)
+            };
+            if empty {
+                node.children.remove(*level);
+            }
+        }
+    }
+    node.filter.is_none() && node.children.is_empty()
+}
+
+// Adds the filters below `node` that match a topic with `levels` to `filters`. `dollar` is whether
+// the topic starts with `$`, which filters starting with a wildcard don't match.
+fn collect<'a>(node: &'a Node, levels: &[&str], dollar: bool, filters: &mut Vec<&'a Filter>) {
+    // `#` also matches the level above it, so `a/#` matches `a`
+    if let Some(child) = node.children.get("#") {
+        if !dollar {
+            filters.extend(child.filter.as_ref());
+        }
+    }
+    let (level, rest) = match levels.split_first() {
+        Some(split) => split,
+        None => {
+            filters.extend(node.filter.as_ref());
+            return;
+        }
+    };
+    if let Some(child) = node.children.get(*level) {
+        collect(child, rest, false, filters);
+    }
+    if !dollar {
+        if let Some(child) = node.children.get("+") {
+            collect(child, rest, false, filters);
+        }
+    }
+}
+
+// Calls `visit` with the levels and subscribers of every filter below `node`
+fn walk<'a, F: FnMut(&[&'a str], &'a Filter)>(node: &'a Node, levels: &mut Vec<&'a str>,
+                                               visit: &mut F) {
+    if let Some(ref filter) = node.filter {
+        visit(levels, filter);
+    }
+    for (level, child) in node.children.iter() {
+        levels.push(level);
+        walk(child, levels, visit);
+        levels.pop();
+    }
+}
+
+// Rough heap usage of the trie below `node`, in bytes
+fn node_bytes(node: &Node) -> usize {
+    let entry_size = mem::size_of::<(String, Arc<Node>)>();
+    let client_size = mem::size_of::<(String, QosLv)>();
+    let mut bytes = mem::size_of::<Node>() + node.children.capacity() * entry_size;
+    if let Some(ref filter) = node.filter {
+        bytes += filter.clients.capacity() * client_size +
+            filter.clients.keys().map(|client_id| client_id.len()).sum::<usize>();
+    }
+    for (level, child) in node.children.iter() {
+        bytes += level.len() + node_bytes(child);
+    }
+    bytes
+}
+
+fn wasteful_maps(node: &Node) -> usize {
+    let clients = node.filter.as_ref()
+        .map_or(false, |filter| is_wasteful(filter.clients.capacity(), filter.clients.len()));
+    let children = is_wasteful(node.children.capacity(), node.children.len());
+    clients as usize + children as usize +
+        node.children.values().map(|child| wasteful_maps(child)).sum::<usize>()
+}
+
+// Shrinks the wasteful maps below `node`, copying only the nodes on the way to them. Returns the
+// number shrunk.
+fn shrink(node: &mut Arc<Node>) -> usize {
+    if wasteful_maps(node) == 0 {
+        return 0;
+    }
+    let node = Arc::make_mut(node);
+    let mut shrunk = 0;
+    if let Some(ref mut filter) = node.filter {
+        if is_wasteful(filter.clients.capacity(), filter.clients.len()) {
+            Arc::make_mut(&mut filter.clients).shrink_to_fit();
+            shrunk += 1;
+        }
+    }
+    if is_wasteful(node.children.capacity(), node.children.len()) {
+        node.children.shrink_to_fit();
+        shrunk += 1;
+    }
+    for child in node.children.values_mut() {
+        shrunk += shrink(child);
+    }
+    shrunk
+}
+
+// Whether a map holds more than twice the space its entries need. Small maps are left alone so
+// that they don't shrink and grow again as clients come and go.
+fn is_wasteful(capacity: usize, len: usize) -> bool {
+    capacity / 2 > cmp::max(len, 8)
+}
@@ -0,0 +1,194 @@
+use std::collections::hash_map::HashMap;
+use std::mem;
+
+// Topic filter matching. `+` matches exactly one topic level and `#`, which must be the last
+// level, matches any number of levels including none. Filters starting with a wildcard don't
+// match topics starting with `$` such as $SYS/...
+pub fn matches(filter: &str, topic_name: &str) -> bool {
+    if topic_name.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic_name.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return filter_levels.next().is_none(),
+            (Some("+"), Some(_)) => (),
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => (),
+            (None, None) => return true,
+            _ => return false
+        }
+    }
+}
+
+// Whether a SUBSCRIBE may use `filter`: it isn't empty, `+` and `#` only appear as whole levels,
+// and `#` only as the last one. Other characters, including `*`, have no special meaning.
+pub fn is_valid_filter(filter: &str) -> bool {
+    if filter.is_empty() || filter.contains('\0') {
+        return false;
+    }
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        match level {
+            "#" => return levels.peek().is_none(),
+            "+" => (),
+            _ if has_wildcards(level) => return false,
+            _ => ()
+        }
+    }
+    true
+}
+
+pub fn has_wildcards(filter: &str) -> bool {
+    filter.contains('+') || filter.contains('#')
+}
+
+// Whether every topic matched by `filter` is also matched by `subtree`, e.g. public/+ is within
+// public/#
+pub fn within(filter: &str, subtree: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut subtree_levels = subtree.split('/');
+    loop {
+        match (filter_levels.next(), subtree_levels.next()) {
+            (_, Some("#")) => return true,
+            (Some("#"), _) => return false,
+            (Some(_), Some("+")) => (),
+            (Some(filter_level), Some(subtree_level)) if filter_level == subtree_level => (),
+            (None, None) => return true,
+            _ => return false
+        }
+    }
+}
+
+// `name`, e.g. a client id, as a single topic level: `%`, `/`, `+` and `#` are percent-encoded, so
+// that `alice/sensor` becomes `alice%2Fsensor`
+pub fn escape_level(name: &str) -> String {
+    let mut level = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '%' => level.push_str("%25"),
+            '/' => level.push_str("%2F"),
+            '+' => level.push_str("%2B"),
+            '#' => level.push_str("%23"),
+            c => level.push(c)
+        }
+    }
+    level
+}
+
+// The topic or filter a client on a listener with `mount_point` refers to. The mount point is
+// whole levels: a `/` is added after it unless it already ends with one.
+pub fn mount(mount_point: Option<&str>, topic: &str) -> String {
+    match mount_point {
+        Some(mount_point) if !mount_point.ends_with('/') => format!("{}/{}", mount_point, topic),
+        mount_point => format!("{}{}", mount_point.unwrap_or(""), topic)
+    }
+}
+
+// Whether `topic_name` is under `mount_point`, comparing whole levels, so that `tenant-ab/t` is
+// not under `tenant-a`
+pub fn is_mounted(mount_point: &str, topic_name: &str) -> bool {
+    topic_name.starts_with(mount_point) &&
+        (mount_point.ends_with('/') || topic_name[mount_point.len()..].starts_with('/'))
+}
+
+// The topic as seen by a client on a listener with `mount_point`
+pub fn unmount<'a>(mount_point: Option<&str>, topic_name: &'a str) -> &'a str {
+    match mount_point {
+        Some(mount_point) if is_mounted(mount_point, topic_name) => {
+            let unmounted = &topic_name[mount_point.len()..];
+            if mount_point.ends_with('/') { unmounted } else { &unmounted[1..] }
+        }
+        _ => topic_name
+    }
+}
+
+// Topic names indexed by level, so that the topics matching a filter are found by walking the
+// filter's levels rather than matching it against every topic. A filter only visits the levels it
+// can match, except under a `#`, which matches everything below it anyway.
+#[derive(Debug, Default)]
+pub struct TopicTree {
+    children: HashMap<String, TopicTree>,
+    // Whether a topic ends at this level
+    topic: bool
+}
+
+impl TopicTree {
+    pub fn new() -> TopicTree {
+        TopicTree::default()
+    }
+
+    pub fn insert(&mut self, topic_name: &str) {
+        let mut node = self;
+        for level in topic_name.split('/') {
+            node = node.children.entry(level.to_string()).or_insert_with(TopicTree::new);
+        }
+        node.topic = true;
+    }
+
+    // Returns false if the topic wasn't in the tree. Levels left without topics are dropped.
+    pub fn remove(&mut self, topic_name: &str) -> bool {
+        let levels: Vec<&str> = topic_name.split('/').collect();
+        self.remove_levels(&levels)
+    }
+
+    fn remove_levels(&mut self, levels: &[&str]) -> bool {
+        let (level, rest) = match levels.split_first() {
+            Some(split) => split,
+            None => return mem::replace(&mut self.topic, false)
+        };
+        let (removed, empty) = match self.children.get_mut(*level) {
+            Some(child) => {
+                let removed = child.remove_levels(rest);
+                (removed, !child.topic && child.children.is_empty())
+            }
+            None => return false
+        };
+        if empty {
+            self.children.remove(*level);
+        }
+        removed
+    }
+
+    // The topics that `filter` matches, in no particular order. Same rules as matches().
+    pub fn matching(&self, filter: &str) -> Vec<String> {
+        let levels: Vec<&str> = filter.split('/').collect();
+        let mut topics = vec![];
+        self.collect(&levels, &mut vec![], &mut topics);
+        topics
+    }
+
+    fn collect<'a>(&'a self, levels: &[&str], path: &mut Vec<&'a str>, topics: &mut Vec<String>) {
+        let (level, rest) = match levels.split_first() {
+            Some(split) => split,
+            None => {
+                if self.topic {
+                    topics.push(path.join("/"));
+                }
+                return;
+            }
+        };
+        match *level {
+            "#" | "+" => {
+                // The `$` rule only concerns the first level
+                let skip_dollar = path.is_empty();
+                if *level == "#" && self.topic && !path.is_empty() {
+                    topics.push(path.join("/"));
+                }
+                for (name, child) in self.children.iter() {
+                    if skip_dollar && name.starts_with('$') {
+                        continue;
+                    }
+                    path.push(name);
+                    child.collect(if *level == "#" { levels } else { rest }, path, topics);
+                    path.pop();
+                }
+            }
+            name => if let Some((name, child)) = self.children.get_key_value(name) {
+                path.push(name);
+                child.collect(rest, path, topics);
+                path.pop();
+            }
+        }
+    }
+}
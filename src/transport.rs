@@ -0,0 +1,265 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A client connection the broker core can read packets from and write packets to, regardless of
+// what carries the bytes
+pub trait Transport: Read + Write + Send {
+    // Returns a second handle to the same connection so reads and writes can happen on different
+    // threads
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+
+    // Human-readable description of the other end, used for logging
+    fn peer_addr(&self) -> String;
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()>;
+
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn peer_addr(&self) -> String {
+        match TcpStream::peer_addr(self) {
+            Ok(addr) => format!("tcp://{}", addr),
+            Err(_) => "tcp://<unknown>".to_string()
+        }
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, dur)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+// How many times a write failing with a transient error is retried, waiting twice as long each
+// time, starting at WRITE_RETRY_BACKOFF_MILLIS
+pub const WRITE_RETRIES: u32 = 3;
+pub const WRITE_RETRY_BACKOFF_MILLIS: u64 = 10;
+
+// Whether a write that failed with `e` may succeed if tried again: it was interrupted, or the
+// socket's send buffer stayed full for the whole write timeout. Anything else, such as a broken
+// pipe or a reset connection, means the client is gone.
+pub fn is_transient(e: &io::Error) -> bool {
+    match e.kind() {
+        ErrorKind::Interrupted | ErrorKind::WouldBlock => true,
+        _ => false
+    }
+}
+
+// Wraps a transport so that writes failing with a transient error are retried with backoff, and
+// only fatal errors, or running out of retries, reach the caller and close the connection.
+// The backoff sleeps on the writing thread, so the broker only writes while holding no lock but
+// the connection's own, and a client that is slow to take its bytes holds up nobody else.
+pub struct RetryWrites(Box<dyn Transport>);
+
+impl RetryWrites {
+    pub fn new(transport: Box<dyn Transport>) -> RetryWrites {
+        RetryWrites(transport)
+    }
+
+    fn retry<T, F: FnMut(&mut dyn Transport) -> io::Result<T>>(&mut self, mut op: F)
+        -> io::Result<T> {
+        let mut backoff = Duration::from_millis(WRITE_RETRY_BACKOFF_MILLIS);
+        let mut retries = 0;
+        loop {
+            match op(&mut *self.0) {
+                Err(ref e) if is_transient(e) && retries < WRITE_RETRIES => {
+                    log!(Debug, "Write to {} failed ({:?}); retrying in {:?}", self.0.peer_addr(),
+                        e.kind(), backoff);
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    retries += 1;
+                }
+                res => return res
+            }
+        }
+    }
+}
+
+impl Read for RetryWrites {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for RetryWrites {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.retry(|transport| transport.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.retry(|transport| transport.flush())
+    }
+}
+
+impl Transport for RetryWrites {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(RetryWrites(self.0.try_clone()?)))
+    }
+
+    fn peer_addr(&self) -> String {
+        self.0.peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.0.shutdown()
+    }
+}
+
+static NEXT_MEMORY_ID: AtomicUsize = AtomicUsize::new(0);
+
+struct Pipe {
+    buf: VecDeque<u8>,
+    closed: bool
+}
+
+struct Channel {
+    pipe: Mutex<Pipe>,
+    readable: Condvar
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            pipe: Mutex::new(Pipe { buf: VecDeque::new(), closed: false }),
+            readable: Condvar::new()
+        }
+    }
+
+    fn close(&self) {
+        self.pipe.lock().unwrap().closed = true;
+        self.readable.notify_all();
+    }
+}
+
+// One end of an in-process duplex connection, for driving the broker without real sockets. The
+// connection closes once every handle to either end has been dropped or either end is shut down.
+pub struct MemoryTransport {
+    id: usize,
+    incoming: Arc<Channel>,
+    outgoing: Arc<Channel>,
+    // Live handles to this end, shared between clones
+    handles: Arc<AtomicUsize>,
+    read_timeout: Arc<Mutex<Option<Duration>>>
+}
+
+impl MemoryTransport {
+    // Returns two connected ends: bytes written to one are read from the other
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        let id = NEXT_MEMORY_ID.fetch_add(1, Ordering::SeqCst);
+        let a_to_b = Arc::new(Channel::new());
+        let b_to_a = Arc::new(Channel::new());
+        (MemoryTransport::new(id, Arc::clone(&b_to_a), Arc::clone(&a_to_b)),
+         MemoryTransport::new(id, a_to_b, b_to_a))
+    }
+
+    fn new(id: usize, incoming: Arc<Channel>, outgoing: Arc<Channel>) -> MemoryTransport {
+        MemoryTransport {
+            id,
+            incoming,
+            outgoing,
+            handles: Arc::new(AtomicUsize::new(1)),
+            read_timeout: Arc::new(Mutex::new(None))
+        }
+    }
+
+    fn close(&self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = self.read_timeout.lock().unwrap().map(|timeout| Instant::now() + timeout);
+        let mut pipe = self.incoming.pipe.lock().unwrap();
+        while pipe.buf.is_empty() && !pipe.closed {
+            pipe = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(ErrorKind::WouldBlock, "read timed out"));
+                    }
+                    self.incoming.readable.wait_timeout(pipe, deadline - now).unwrap().0
+                }
+                None => self.incoming.readable.wait(pipe).unwrap()
+            };
+        }
+        // A closed, drained pipe reads as EOF
+        let len = cmp::min(buf.len(), pipe.buf.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buf.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.outgoing.pipe.lock().unwrap();
+        if pipe.closed {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "connection closed"));
+        }
+        pipe.buf.extend(buf);
+        self.outgoing.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        if self.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.close();
+        }
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        self.handles.fetch_add(1, Ordering::SeqCst);
+        Ok(Box::new(MemoryTransport {
+            id: self.id,
+            incoming: Arc::clone(&self.incoming),
+            outgoing: Arc::clone(&self.outgoing),
+            handles: Arc::clone(&self.handles),
+            read_timeout: Arc::clone(&self.read_timeout)
+        }))
+    }
+
+    fn peer_addr(&self) -> String {
+        format!("memory://{}", self.id)
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = dur;
+        Ok(())
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.close();
+        Ok(())
+    }
+}
@@ -0,0 +1,10 @@
+use rand;
+
+// A random (version 4) UUID, hyphenated
+pub fn new_v4() -> String {
+    let (hi, lo) = (rand::random::<u64>(), rand::random::<u64>());
+    let hi = hi & !0xf000 | 0x4000;
+    let lo = lo & !(0xc << 60) | 0x8 << 60;
+    format!("{:08x}-{:04x}-{:04x}-{:04x}-{:012x}", hi >> 32, hi >> 16 & 0xffff, hi & 0xffff,
+        lo >> 48, lo & 0xffff_ffff_ffff)
+}
@@ -0,0 +1,245 @@
+use std::fmt;
+use std::sync::Arc;
+use topic;
+#[cfg(feature = "json-schema")]
+use json::{self, Json};
+#[cfg(feature = "json-schema")]
+use std::fs::File;
+#[cfg(feature = "json-schema")]
+use std::io::Read;
+#[cfg(feature = "json-schema")]
+use std::path::Path;
+
+// Checks the payloads of messages published on some topics before they reach subscribers or are
+// retained, e.g. to hold devices to a telemetry contract. Called on connection threads for every
+// matching PUBLISH, so it should be quick.
+pub trait PayloadValidator: Send + Sync {
+    // Why `payload`, published on `topic_name`, doesn't conform, or Ok if it does
+    fn validate(&self, topic_name: &str, payload: &[u8]) -> Result<(), String>;
+}
+
+// What happens to a message that fails validation
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ValidationAction {
+    // Acknowledged to the publisher, since MQTT 3.1.1 has no way to refuse a PUBLISH, but neither
+    // delivered nor retained
+    Reject,
+    // Delivered anyway, and logged and counted
+    Flag
+}
+
+impl ValidationAction {
+    pub fn from_str(s: &str) -> Option<ValidationAction> {
+        match s {
+            "reject" => Some(ValidationAction::Reject),
+            "flag" => Some(ValidationAction::Flag),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            &ValidationAction::Reject => "reject",
+            &ValidationAction::Flag => "flag"
+        }
+    }
+}
+
+struct Rule {
+    filter: String,
+    validator: Arc<dyn PayloadValidator>,
+    action: ValidationAction
+}
+
+// Validators by topic filter. Filters are matched against topics with the publisher's mount point
+// applied, as subscribers see them across listeners. Clones share the validators.
+#[derive(Clone, Default)]
+pub struct Validators {
+    rules: Vec<Arc<Rule>>
+}
+
+impl Validators {
+    pub fn new() -> Validators {
+        Validators { rules: vec![] }
+    }
+
+    pub fn add<V: PayloadValidator + 'static>(&mut self, filter: &str, validator: V,
+                                               action: ValidationAction) -> &mut Validators {
+        self.rules.push(Arc::new(Rule {
+            filter: filter.to_string(),
+            validator: Arc::new(validator),
+            action
+        }));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    // Runs every validator whose filter matches `topic_name`. A failure with `reject` wins over
+    // one with `flag`, as the message can't be both delivered and dropped. Returns the action and
+    // the reason, or None if the payload conforms.
+    pub fn check(&self, topic_name: &str, payload: &[u8]) -> Option<(ValidationAction, String)> {
+        let mut flagged = None;
+        for rule in self.rules.iter().filter(|rule| topic::matches(&rule.filter, topic_name)) {
+            if let Err(reason) = rule.validator.validate(topic_name, payload) {
+                let reason = format!("{} (validated for {})", reason, rule.filter);
+                match rule.action {
+                    ValidationAction::Reject => return Some((ValidationAction::Reject, reason)),
+                    ValidationAction::Flag => flagged = flagged.or(Some(reason))
+                }
+            }
+        }
+        flagged.map(|reason| (ValidationAction::Flag, reason))
+    }
+}
+
+impl fmt::Debug for Validators {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let filters: Vec<&str> = self.rules.iter().map(|rule| rule.filter.as_str()).collect();
+        write!(f, "Validators({:?})", filters)
+    }
+}
+
+// Validates payloads as JSON against a schema. Only the keywords telemetry contracts tend to use
+// are supported: `type`, `enum`, `properties`, `required`, `additionalProperties` (true or false),
+// `items` (a single schema), `minimum`, `maximum`, `minLength`, `maxLength`, `minItems` and
+// `maxItems`. Other keywords are ignored, as the spec asks of unknown ones.
+#[cfg(feature = "json-schema")]
+#[derive(Debug, Clone)]
+pub struct JsonSchema {
+    schema: Json
+}
+
+#[cfg(feature = "json-schema")]
+impl JsonSchema {
+    pub fn parse(s: &str) -> Result<JsonSchema, String> {
+        match json::parse_payload(s) {
+            Some(schema@Json::Object(_)) => Ok(JsonSchema { schema }),
+            Some(_) => Err("a schema must be a JSON object".to_string()),
+            None => Err("a schema must be valid JSON".to_string())
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<JsonSchema, String> {
+        let mut s = String::new();
+        File::open(path).and_then(|mut file| file.read_to_string(&mut s))
+            .map_err(|e| e.to_string())?;
+        JsonSchema::parse(&s)
+    }
+}
+
+#[cfg(feature = "json-schema")]
+impl PayloadValidator for JsonSchema {
+    fn validate(&self, _topic_name: &str, payload: &[u8]) -> Result<(), String> {
+        let value = ::std::str::from_utf8(payload).ok().and_then(json::parse_payload)
+            .ok_or_else(|| "payload is not JSON".to_string())?;
+        check(&self.schema, &value, "")
+    }
+}
+
+#[cfg(feature = "json-schema")]
+fn type_name(value: &Json) -> &'static str {
+    match value {
+        &Json::Null => "null",
+        &Json::Bool(_) => "boolean",
+        &Json::Number(_) => "integer",
+        &Json::Real(x) if x.fract() == 0.0 => "integer",
+        &Json::Real(_) => "number",
+        &Json::String(_) => "string",
+        &Json::Array(_) => "array",
+        &Json::Object(_) => "object"
+    }
+}
+
+#[cfg(feature = "json-schema")]
+fn number(value: &Json) -> Option<f64> {
+    match value {
+        &Json::Number(n) => Some(n as f64),
+        &Json::Real(x) => Some(x),
+        _ => None
+    }
+}
+
+// Checks `value`, found at `path` (a JSON pointer) in the payload, against `schema`
+#[cfg(feature = "json-schema")]
+fn check(schema: &Json, value: &Json, path: &str) -> Result<(), String> {
+    let fail = |msg: String| Err(format!("{}: {}", if path.is_empty() { "/" } else { path }, msg));
+    let keyword = |name: &str| schema.get(name);
+    let actual = type_name(value);
+    let types: Vec<&str> = match keyword("type") {
+        Some(&Json::String(ref name)) => vec![name.as_str()],
+        Some(&Json::Array(ref names)) => names.iter().filter_map(|name| name.as_str()).collect(),
+        _ => vec![]
+    };
+    let type_ok = types.is_empty() || types.iter().any(|&name| {
+        name == actual || (name == "number" && actual == "integer")
+    });
+    if !type_ok {
+        return fail(format!("expected {}, got {}", types.join(" or "), actual));
+    }
+    if let Some(allowed) = keyword("enum").and_then(|allowed| allowed.as_array()) {
+        if !allowed.contains(value) {
+            return fail(format!("{} is not one of the allowed values", value));
+        }
+    }
+    if let Some(x) = number(value) {
+        if let Some(minimum) = keyword("minimum").and_then(number) {
+            if x < minimum {
+                return fail(format!("{} is less than {}", value, minimum));
+            }
+        }
+        if let Some(maximum) = keyword("maximum").and_then(number) {
+            if x > maximum {
+                return fail(format!("{} is more than {}", value, maximum));
+            }
+        }
+    }
+    let bounds = |min: &str, max: &str, len: usize, what: &str| {
+        if let Some(min) = keyword(min).and_then(|min| min.as_u64()) {
+            if (len as u64) < min {
+                return fail(format!("expected at least {} {}, got {}", min, what, len));
+            }
+        }
+        if let Some(max) = keyword(max).and_then(|max| max.as_u64()) {
+            if (len as u64) > max {
+                return fail(format!("expected at most {} {}, got {}", max, what, len));
+            }
+        }
+        Ok(())
+    };
+    match value {
+        &Json::String(ref s) => bounds("minLength", "maxLength", s.chars().count(), "characters"),
+        &Json::Array(ref items) => {
+            bounds("minItems", "maxItems", items.len(), "items")?;
+            if let Some(item_schema) = keyword("items") {
+                for (i, item) in items.iter().enumerate() {
+                    check(item_schema, item, &format!("{}/{}", path, i))?;
+                }
+            }
+            Ok(())
+        }
+        &Json::Object(ref fields) => {
+            if let Some(required) = keyword("required").and_then(|required| required.as_array()) {
+                for name in required.iter().filter_map(|name| name.as_str()) {
+                    if value.get(name).is_none() {
+                        return fail(format!("missing {}", name));
+                    }
+                }
+            }
+            let properties = keyword("properties");
+            for &(ref name, ref field) in fields {
+                match properties.and_then(|properties| properties.get(name)) {
+                    Some(field_schema) =>
+                        check(field_schema, field, &format!("{}/{}", path, name))?,
+                    None if keyword("additionalProperties") == Some(&Json::Bool(false)) =>
+                        return fail(format!("unexpected {}", name)),
+                    None => ()
+                }
+            }
+            Ok(())
+        }
+        _ => Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::{ConnectOptions, Will};
+use libmqtt::ctrlpkt::{ConnAckRetCode, CtrlPkt, CtrlPkt::*, QosLv, SubAckRetCode};
+use mqtt_broker::broker::{Broker, RetainViolation};
+use std::net::SocketAddr;
+
+fn publish(topic_name: &str) -> CtrlPkt {
+    Publish {
+        dup: false,
+        qos_lv: QosLv::AtLeastOnce,
+        retain: true,
+        topic_name: topic_name.to_string(),
+        pkt_id: Some(1),
+        payload: b"hello".to_vec()
+    }
+}
+
+fn start_confined() -> (Broker, SocketAddr) {
+    let mut broker = Broker::new();
+    broker.set_anonymous_topics(Some("public/#".to_string()));
+    let addr = start(&broker);
+    (broker, addr)
+}
+
+#[test]
+fn anonymous_subscriptions_are_confined() {
+    let (_broker, addr) = start_confined();
+    let mut client = Client::connect_id(addr, "anon-sub");
+    let ret_codes = client.subscribe(1, vec![
+        ("public/demo", QosLv::AtMostOnce),
+        ("public/+/status", QosLv::AtLeastOnce),
+        ("prod/status", QosLv::AtMostOnce),
+        ("#", QosLv::AtMostOnce)
+    ]);
+    assert_pkt!(ret_codes.as_slice(), &[
+        SubAckRetCode::MaxQos0,
+        SubAckRetCode::MaxQos1,
+        SubAckRetCode::Failure,
+        SubAckRetCode::Failure
+    ]);
+}
+
+#[test]
+fn anonymous_publishes_outside_are_dropped() {
+    let (broker, addr) = start_confined();
+    let mut client = Client::connect_id(addr, "anon-pub");
+    client.send(&publish("prod/status"));
+    assert_pkt!(client.recv(), PubAck(1));
+    client.send(&publish("public/status"));
+    assert_pkt!(client.recv(), PubAck(1));
+    assert_eq!(broker.retained_topics(), vec!["public/status"]);
+}
+
+#[test]
+fn clients_with_a_username_are_not_confined() {
+    let (broker, addr) = start_confined();
+    let mut opts = ConnectOptions::new("named".to_string());
+    opts.set_username("operator".to_string());
+    let (mut client, _) = Client::connect(addr, &opts);
+    let ret_codes = client.subscribe(1, vec![("prod/status", QosLv::AtMostOnce)]);
+    assert_pkt!(ret_codes.as_slice(), &[SubAckRetCode::MaxQos0]);
+    client.send(&publish("prod/status"));
+    assert_pkt!(client.recv(), PubAck(1));
+    assert_eq!(broker.retained_topics(), vec!["prod/status"]);
+}
+
+#[test]
+fn pre_authenticated_listeners_are_not_confined() {
+    let mut broker = Broker::new();
+    broker.set_anonymous_topics(Some("public/#".to_string()));
+    let mut listener_config = local_listener();
+    listener_config.principal = Some("operator".to_string());
+    let addr = start_listener(&broker, listener_config);
+    let mut client = Client::connect_id(addr, "on-host-tool");
+    let ret_codes = client.subscribe(1, vec![("prod/status", QosLv::AtMostOnce)]);
+    assert_pkt!(ret_codes.as_slice(), &[SubAckRetCode::MaxQos0]);
+}
+
+#[test]
+fn anonymous_wills_must_be_inside() {
+    let (_broker, addr) = start_confined();
+    let mut opts = ConnectOptions::new("anon-will".to_string());
+    opts.set_will(Will::new("prod/status".to_string(), b"offline".to_vec()));
+    let mut client = Client::open(addr);
+    client.send(&opts.build().unwrap());
+    assert_pkt!(client.recv(), ConnAck { return_code: ConnAckRetCode::NotAuthorized, .. });
+    client.expect_closed();
+    opts.set_will(Will::new("public/status".to_string(), b"offline".to_vec()));
+    Client::connect(addr, &opts);
+}
+
+fn start_retain_confined(retain_violation: RetainViolation) -> (Broker, SocketAddr) {
+    let mut broker = Broker::new();
+    broker.set_anonymous_topics(Some("public/#".to_string()))
+        .set_anonymous_retain_topics(Some("public/status/#".to_string()))
+        .set_retain_violation(retain_violation);
+    let addr = start(&broker);
+    (broker, addr)
+}
+
+#[test]
+fn anonymous_retained_publishes_outside_are_not_retained() {
+    let (broker, addr) = start_retain_confined(RetainViolation::Clear);
+    let mut sub = Client::connect_id(addr, "anon-retain-sub");
+    sub.subscribe(1, vec![("public/chat", QosLv::AtMostOnce)]);
+    let mut client = Client::connect_id(addr, "anon-retain-pub");
+    client.send(&publish("public/chat"));
+    assert_pkt!(client.recv(), PubAck(1));
+    match sub.recv() {
+        Publish { ref topic_name, .. } => assert_eq!(topic_name, "public/chat"),
+        pkt => panic!("expected a publish, got {:?}", pkt)
+    }
+    client.send(&publish("public/status/a"));
+    assert_pkt!(client.recv(), PubAck(1));
+    assert_eq!(broker.retained_topics(), vec!["public/status/a"]);
+}
+
+#[test]
+fn anonymous_retained_publishes_outside_can_be_dropped() {
+    let (broker, addr) = start_retain_confined(RetainViolation::Drop);
+    let mut client = Client::connect_id(addr, "anon-retain-drop");
+    client.send(&publish("public/chat"));
+    assert_pkt!(client.recv(), PubAck(1));
+    client.send(&publish("public/status/a"));
+    assert_pkt!(client.recv(), PubAck(1));
+    assert_eq!(broker.retained_topics(), vec!["public/status/a"]);
+
+    let mut opts = ConnectOptions::new("anon-retain-will".to_string());
+    let mut will = Will::new("public/chat".to_string(), b"offline".to_vec());
+    will.set_retain(true);
+    opts.set_will(will);
+    let mut client = Client::open(addr);
+    client.send(&opts.build().unwrap());
+    assert_pkt!(client.recv(), ConnAck { return_code: ConnAckRetCode::NotAuthorized, .. });
+    client.expect_closed();
+}
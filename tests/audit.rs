@@ -0,0 +1,58 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{CtrlPkt::*, QosLv};
+use mqtt_broker::audit::AuditSink;
+use mqtt_broker::broker::Broker;
+use std::sync::{Arc, Mutex};
+
+#[test]
+fn message_lifecycle_is_recorded() {
+    let lines = Arc::new(Mutex::new(vec![]));
+    let sink_lines = Arc::clone(&lines);
+    let mut broker = Broker::new();
+    broker.set_audit(Some(AuditSink::new(move |line| {
+        sink_lines.lock().unwrap().push(line.to_string());
+    })));
+    let addr = start(&broker);
+    let mut opts = ConnectOptions::new("audit-away".to_string());
+    opts.set_clean_session(false);
+    let (mut away, _) = Client::connect(addr, &opts);
+    away.subscribe(1, vec![("t", QosLv::AtLeastOnce)]);
+    away.send(&Disconnect);
+    away.expect_closed();
+    let mut sub = Client::connect_id(addr, "audit-sub");
+    sub.subscribe(1, vec![("t", QosLv::AtLeastOnce)]);
+    let mut publisher = Client::connect_id(addr, "audit-pub");
+    publisher.send(&Publish {
+        dup: false,
+        qos_lv: QosLv::AtLeastOnce,
+        retain: false,
+        topic_name: "t".to_string(),
+        pkt_id: Some(1),
+        payload: b"hello".to_vec()
+    });
+    assert_pkt!(publisher.recv(), PubAck(1));
+    match sub.recv() {
+        Publish { pkt_id: Some(pkt_id), .. } => sub.send(&PubAck(pkt_id)),
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+    wait_until("the acknowledgement to be recorded",
+        || lines.lock().unwrap().iter().any(|line| line.ends_with("acknowledged by audit-sub")));
+
+    let lines = lines.lock().unwrap();
+    let id = lines[0].split(' ').nth(1).unwrap().to_string();
+    assert_eq!(id.len(), 36);
+    let prefix = format!("audit {} ", id);
+    assert!(lines.iter().all(|line| line.starts_with(&prefix)));
+    let events: Vec<&str> = lines.iter().map(|line| &line[prefix.len()..]).collect();
+    assert_eq!(events[0], "received from audit-pub on t qos=1 pkt_id=Some(1) retain=false bytes=5");
+    assert_eq!(events[1], "dispatched to 2 subscribers");
+    assert!(events.contains(&"queued for audit-away"));
+    assert!(events.iter().any(|event| event.starts_with("delivered to audit-sub qos=1 pkt_id=")));
+}
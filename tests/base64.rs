@@ -0,0 +1,25 @@
+extern crate mqtt_broker;
+
+use mqtt_broker::base64::{decode, encode};
+
+#[test]
+fn round_trips() {
+    let cases: [(&[u8], &str); 5] = [
+        (b"", ""),
+        (b"f", "Zg=="),
+        (b"fo", "Zm8="),
+        (b"foo", "Zm9v"),
+        (&[0, 0xff, 0x10, 0x7f], "AP8Qfw==")
+    ];
+    for &(bytes, encoded) in cases.iter() {
+        assert_eq!(encode(bytes), encoded);
+        assert_eq!(decode(encoded).as_ref().map(|b| b.as_slice()), Some(bytes));
+    }
+}
+
+#[test]
+fn invalid_input_is_rejected() {
+    for s in ["Zg", "Zg=", "Z===", "Zg==Zg==", "Zm9*"].iter() {
+        assert_eq!(decode(s), None, "{}", s);
+    }
+}
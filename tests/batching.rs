@@ -0,0 +1,61 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::ctrlpkt::{CtrlPkt::*, QosLv};
+use mqtt_broker::broker::Broker;
+use mqtt_broker::connection::WriteBatching;
+use std::time::{Duration, Instant};
+
+fn publish(topic_name: &str, payload: &[u8]) -> libmqtt::ctrlpkt::CtrlPkt {
+    Publish {
+        dup: false,
+        qos_lv: QosLv::AtMostOnce,
+        retain: false,
+        topic_name: topic_name.to_string(),
+        pkt_id: None,
+        payload: payload.to_vec()
+    }
+}
+
+#[test]
+fn batched_deliveries_wait_for_the_delay() {
+    let mut broker = Broker::new();
+    broker.set_write_batching(Some(WriteBatching {
+        max_delay: Duration::from_millis(200),
+        max_bytes: 4096
+    }));
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "batch-sub");
+    sub.subscribe(1, vec![("telemetry", QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr, "batch-pub");
+    let started = Instant::now();
+    for i in 0..3 {
+        publisher.send(&publish("telemetry", &[i]));
+    }
+    for i in 0..3 {
+        match sub.recv() {
+            Publish { ref payload, .. } => assert_eq!(payload, &vec![i]),
+            pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+        }
+    }
+    assert!(started.elapsed() >= Duration::from_millis(200));
+}
+
+#[test]
+fn full_batches_are_written_right_away() {
+    let mut broker = Broker::new();
+    broker.set_write_batching(Some(WriteBatching {
+        max_delay: Duration::from_secs(60),
+        max_bytes: 16
+    }));
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "full-batch-sub");
+    sub.subscribe(1, vec![("telemetry", QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr, "full-batch-pub");
+    publisher.send(&publish("telemetry", &[0; 32]));
+    assert_pkt!(sub.recv(), Publish { .. });
+}
@@ -0,0 +1,67 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{ConnAckRetCode, CtrlPkt::*};
+use mqtt_broker::blocklist::glob_matches;
+use mqtt_broker::broker::Broker;
+use std::net::SocketAddr;
+
+fn expect_refused(addr: SocketAddr, opts: &ConnectOptions, code: ConnAckRetCode) {
+    let mut client = Client::open(addr);
+    client.send(&opts.build().unwrap());
+    match client.recv() {
+        ConnAck { return_code, .. } => assert_eq!(return_code, code),
+        pkt => panic!("expected a CONNACK, got {:?}", pkt)
+    }
+    client.expect_closed();
+}
+
+#[test]
+fn globs() {
+    for &(pattern, client_id, expected) in &[
+        ("sensor-*", "sensor-42", true),
+        ("sensor-*", "sensor-", true),
+        ("sensor-*", "actuator-1", false),
+        ("*-fw-1.2-*", "lamp-fw-1.2-0001", true),
+        ("*-fw-1.2-*", "lamp-fw-1.3-0001", false),
+        ("dev-??", "dev-07", true),
+        ("dev-??", "dev-7", false),
+        ("*a*b", "xaxxab", true),
+        ("*a*b", "xaxxa", false),
+        ("exact", "exact", true),
+        ("exact", "exactly", false)
+    ] {
+        assert_eq!(glob_matches(pattern, client_id), expected, "{} {}", pattern, client_id);
+    }
+}
+
+#[test]
+fn blocked_client_ids_are_rejected() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    assert!(broker.blocklist().block_client_id("lamp-fw-1.2-*"));
+    assert!(!broker.blocklist().block_client_id("lamp-fw-1.2-*"));
+    let opts = ConnectOptions::new("lamp-fw-1.2-0001".to_string());
+    expect_refused(addr, &opts, ConnAckRetCode::IdRejected);
+    Client::connect_id(addr, "lamp-fw-1.3-0001");
+
+    assert!(broker.blocklist().unblock_client_id("lamp-fw-1.2-*"));
+    Client::connect(addr, &opts);
+}
+
+#[test]
+fn blocked_usernames_are_not_authorized() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    broker.blocklist().block_username("legacy");
+    let mut opts = ConnectOptions::new("legacy-client".to_string());
+    opts.set_username("legacy".to_string());
+    expect_refused(addr, &opts, ConnAckRetCode::NotAuthorized);
+    opts.set_username("current".to_string());
+    Client::connect(addr, &opts);
+}
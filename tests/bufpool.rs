@@ -0,0 +1,75 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+mod common;
+
+use common::*;
+use libmqtt::bufpool::BufPool;
+use libmqtt::client::send_pooled;
+use libmqtt::ctrlpkt::{CtrlPkt, CtrlPkt::*, QosLv};
+use libmqtt::pktstream::PacketStream;
+use mqtt_broker::broker::Broker;
+use std::io::Cursor;
+use std::sync::Arc;
+
+fn publish(payload: &[u8]) -> CtrlPkt {
+    Publish {
+        dup: false,
+        qos_lv: QosLv::AtLeastOnce,
+        retain: false,
+        topic_name: "pool".to_string(),
+        pkt_id: Some(1),
+        payload: payload.to_vec()
+    }
+}
+
+#[test]
+fn buffers_are_reused() {
+    let pool = BufPool::new(2, 1024);
+    let mut buf = pool.take();
+    buf.extend_from_slice(b"hello");
+    pool.put(buf);
+    assert!(pool.take().is_empty());
+    let stats = pool.stats();
+    assert_eq!((stats.hits, stats.misses, stats.idle), (1, 1, 0));
+}
+
+#[test]
+fn oversized_buffers_are_not_kept() {
+    let pool = BufPool::new(2, 16);
+    pool.put(Vec::with_capacity(1024));
+    assert_eq!(pool.stats().idle, 0);
+    for _ in 0..3 {
+        pool.put(Vec::with_capacity(8));
+    }
+    assert_eq!(pool.stats().idle, 2);
+}
+
+#[test]
+fn pooled_packet_stream_decodes_every_packet() {
+    let pool = Arc::new(BufPool::new(1, 1024));
+    let mut bytes = vec![];
+    let pkts = vec![publish(b"one"), PingReq, publish(b"three")];
+    for pkt in &pkts {
+        send_pooled(&mut bytes, pkt, &pool).unwrap();
+    }
+    let decoded: Vec<CtrlPkt> = PacketStream::with_pool(Cursor::new(bytes), Arc::clone(&pool))
+        .map(|pkt| pkt.unwrap())
+        .collect();
+    assert_eq!(format!("{:?}", decoded), format!("{:?}", pkts));
+    // One allocation, reused for the other five encodes and decodes
+    let stats = pool.stats();
+    assert_eq!((stats.hits, stats.misses), (5, 1));
+}
+
+#[test]
+fn broker_decodes_into_pooled_buffers() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut client = Client::connect_id(addr, "pooled");
+    for _ in 0..10 {
+        client.send(&PingReq);
+        client.recv();
+    }
+    assert!(broker.buf_pool_stats().hits >= 10);
+}
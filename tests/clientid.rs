@@ -0,0 +1,57 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{ConnAckRetCode, CtrlPkt::*};
+use mqtt_broker::broker::Broker;
+use mqtt_broker::clientid::ClientIdGenerator;
+use std::net::SocketAddr;
+
+fn client_ids(broker: &Broker) -> Vec<String> {
+    let mut client_ids: Vec<String> =
+        broker.clients().into_iter().map(|client| client.client_id).collect();
+    client_ids.sort();
+    client_ids
+}
+
+fn connect_anonymous(addr: SocketAddr) -> Client {
+    Client::connect(addr, &ConnectOptions::new(String::new())).0
+}
+
+#[test]
+fn empty_client_ids_get_a_uuid() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let _client = connect_anonymous(addr);
+    let client_ids = client_ids(&broker);
+    assert_eq!(client_ids.len(), 1);
+    let groups: Vec<usize> = client_ids[0].split('-').map(|group| group.len()).collect();
+    assert_eq!(groups, vec![8, 4, 4, 4, 12]);
+}
+
+#[test]
+fn counter_skips_ids_with_a_session() {
+    let mut broker = Broker::new();
+    broker.set_client_id_generator(ClientIdGenerator::counter("auto-"));
+    let addr = start(&broker);
+    let _first = connect_anonymous(addr);
+    let _taken = Client::connect_id(addr, "auto-2");
+    let _second = connect_anonymous(addr);
+    assert_eq!(client_ids(&broker), vec!["auto-1", "auto-2", "auto-3"]);
+}
+
+#[test]
+fn connect_is_refused_without_an_unused_id() {
+    let mut broker = Broker::new();
+    broker.set_client_id_generator(ClientIdGenerator::new(|| "same".to_string()));
+    let addr = start(&broker);
+    let _first = connect_anonymous(addr);
+    let mut client = Client::open(addr);
+    client.send(&ConnectOptions::new(String::new()).build().unwrap());
+    assert_pkt!(client.recv(), ConnAck { return_code: ConnAckRetCode::IdRejected, .. });
+    client.expect_closed();
+}
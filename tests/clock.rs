@@ -0,0 +1,43 @@
+extern crate mqtt_broker;
+
+use mqtt_broker::broker::Broker;
+use mqtt_broker::clock::{Clock, VirtualClock};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn virtual_clock_only_moves_when_advanced() {
+    let clock = VirtualClock::new();
+    let start = clock.now();
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(clock.now(), start);
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(clock.now() - start, Duration::from_secs(60));
+}
+
+#[test]
+fn virtual_sleep_wakes_once_deadline_is_passed() {
+    let clock = Arc::new(VirtualClock::new());
+    let (tx, rx) = mpsc::channel();
+    let sleeper = Arc::clone(&clock);
+    thread::spawn(move || {
+        sleeper.sleep(Duration::from_secs(30));
+        tx.send(()).unwrap();
+    });
+    clock.wait_for_sleepers(1);
+    clock.advance(Duration::from_secs(29));
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    clock.advance(Duration::from_secs(1));
+    rx.recv_timeout(Duration::from_secs(2)).unwrap();
+}
+
+#[test]
+fn broker_uses_injected_clock() {
+    let clock = Arc::new(VirtualClock::new());
+    let broker = Broker::with_clock(clock.clone());
+    let start = broker.clock().now();
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(broker.clock().now() - start, Duration::from_secs(5));
+}
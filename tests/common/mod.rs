@@ -0,0 +1,150 @@
+// Helpers shared by the integration tests. Not every test file uses all of them.
+#![allow(dead_code, unused_macros)]
+
+use libmqtt::{connopts::*, ctrlpkt::*, error::*, pktstream::*};
+use mqtt_broker::{broker::Broker, config::ListenerConfig};
+use std::io::{ErrorKind, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long a test waits for the broker before giving up
+pub const TIMEOUT_SECS: u64 = 2;
+
+// Asserts that a packet matches a pattern, printing the packet otherwise
+macro_rules! assert_pkt {
+    ($pkt:expr, $pat:pat) => {
+        match $pkt {
+            $pat => (),
+            pkt => panic!("expected {}, got {:?}", stringify!($pat), pkt)
+        }
+    };
+}
+
+// Starts a broker listening on an ephemeral port and returns its address
+pub fn start_broker() -> SocketAddr {
+    start(&Broker::new())
+}
+
+pub fn start(broker: &Broker) -> SocketAddr {
+    start_listener(broker, local_listener())
+}
+
+// Config for a listener on an ephemeral port, for tests that need to set listener options
+pub fn local_listener() -> ListenerConfig {
+    ListenerConfig::new("127.0.0.1:0".parse().unwrap())
+}
+
+pub fn start_listener(broker: &Broker, listener_config: ListenerConfig) -> SocketAddr {
+    let listener = listener_config.bind().unwrap();
+    let addr = listener.local_addr().unwrap();
+    broker.accept(listener, listener_config);
+    addr
+}
+
+// Polls until `condition` holds, for broker state that is updated off the connection threads
+pub fn wait_until<F: Fn() -> bool>(what: &str, condition: F) {
+    let deadline = Instant::now() + Duration::from_secs(TIMEOUT_SECS);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// A test client that works at the packet level, so tests can send anything, including
+// malformed packets, and see exactly what the broker answers
+pub struct Client {
+    stream: TcpStream,
+    pkts: PacketStream<TcpStream>
+}
+
+impl Client {
+    pub fn open(addr: SocketAddr) -> Client {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS))).unwrap();
+        let pkts = PacketStream::new(stream.try_clone().unwrap());
+        Client { stream, pkts }
+    }
+
+    // Opens a connection and completes the CONNECT handshake. Returns the session present flag.
+    pub fn connect(addr: SocketAddr, opts: &ConnectOptions) -> (Client, bool) {
+        let mut client = Client::open(addr);
+        client.send(&opts.build().unwrap());
+        match client.recv() {
+            CtrlPkt::ConnAck { session_present, return_code: ConnAckRetCode::Accepted } =>
+                (client, session_present),
+            pkt => panic!("expected an accepting CONNACK, got {:?}", pkt)
+        }
+    }
+
+    pub fn connect_id(addr: SocketAddr, client_id: &str) -> Client {
+        Client::connect(addr, &ConnectOptions::new(client_id.to_string())).0
+    }
+
+    // Replaces the default read timeout of TIMEOUT_SECS
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.stream.set_read_timeout(Some(timeout)).unwrap();
+    }
+
+    pub fn send(&mut self, pkt: &CtrlPkt) {
+        self.send_raw(&pkt.serialize().unwrap());
+    }
+
+    pub fn send_raw(&mut self, bytes: &[u8]) {
+        self.stream.write_all(bytes).unwrap();
+    }
+
+    // Like send, but returns false instead of panicking if the broker has closed the connection
+    pub fn try_send(&mut self, pkt: &CtrlPkt) -> bool {
+        self.stream.write_all(&pkt.serialize().unwrap()).is_ok()
+    }
+
+    pub fn recv(&mut self) -> CtrlPkt {
+        match self.pkts.next() {
+            Some(Ok(pkt)) => pkt,
+            Some(Err(e)) => panic!("failed to read a packet: {:?}", e),
+            None => panic!("connection closed by broker")
+        }
+    }
+
+    // Like recv, but returns None instead of panicking once the broker closes the connection
+    pub fn try_recv(&mut self) -> Option<CtrlPkt> {
+        match self.pkts.next() {
+            Some(Ok(pkt)) => Some(pkt),
+            Some(Err(Error::Io(ref e)))
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+                panic!("no packet from the broker after {}s", TIMEOUT_SECS),
+            _ => None
+        }
+    }
+
+    pub fn subscribe(&mut self, pkt_id: u16, subs: Vec<(&str, QosLv)>) -> Vec<SubAckRetCode> {
+        let subs = subs.into_iter().map(|(topic, qos_lv)| (topic.to_string(), qos_lv)).collect();
+        self.send(&CtrlPkt::Subscribe { pkt_id, subs });
+        match self.recv() {
+            CtrlPkt::SubAck { pkt_id: id, sub_ack_ret_codes } if id == pkt_id => sub_ack_ret_codes,
+            pkt => panic!("expected SUBACK {}, got {:?}", pkt_id, pkt)
+        }
+    }
+
+    pub fn unsubscribe(&mut self, pkt_id: u16, topic_filters: Vec<&str>) {
+        let topic_filters = topic_filters.into_iter().map(|filter| filter.to_string()).collect();
+        self.send(&CtrlPkt::Unsubscribe { pkt_id, topic_filters });
+        match self.recv() {
+            CtrlPkt::UnsubAck(id) if id == pkt_id => (),
+            pkt => panic!("expected UNSUBACK {}, got {:?}", pkt_id, pkt)
+        }
+    }
+
+    // Asserts that the broker closes the connection without sending anything else
+    pub fn expect_closed(&mut self) {
+        match self.pkts.next() {
+            None => (),
+            Some(Err(Error::Io(ref e))) if e.kind() == ErrorKind::ConnectionReset => (),
+            Some(Err(Error::Io(ref e)))
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+                panic!("connection still open after {}s", TIMEOUT_SECS),
+            Some(res) => panic!("expected the connection to close, got {:?}", res)
+        }
+    }
+}
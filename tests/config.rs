@@ -0,0 +1,212 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+use libmqtt::ctrlpkt::QosLv;
+use mqtt_broker::broker::{MemoryAction, MinQosAction, MinQosRule, RetainViolation, RetainedQos,
+                          TakeoverPolicy, UnknownPacketPolicy};
+use mqtt_broker::config::{Config, SchemaRule};
+use mqtt_broker::connection::WriteBatching;
+use mqtt_broker::error::Error;
+use mqtt_broker::validation::ValidationAction;
+use std::path::PathBuf;
+use std::time::Duration;
+
+fn problems(s: &str) -> Vec<String> {
+    match Config::parse(s) {
+        Err(Error::Config(msg)) => msg.lines().map(|line| line.to_string()).collect(),
+        res => panic!("expected a config error, got {:?}", res)
+    }
+}
+
+#[test]
+fn valid_config_is_accepted() {
+    let config = Config::parse("\
+# Two listeners with socket options
+listener 127.0.0.1:1883
+tcp_nodelay true
+backlog 1024
+listener 0.0.0.0:8883
+connect_timeout 10
+mount_point tenant-a
+connection_rate 100
+connection_burst 500
+wildcard_subscriptions false
+sys_interval 0
+keep_alive_suggestions true
+audit true
+max_queued_age 60
+dead_letter_topic dead-letters
+qos2_release_timeout 300
+max_awaiting_rel 0
+max_connection_memory 1048576
+connection_memory_action disconnect
+retained_qos granted
+retained_batch_size 10
+retained_history 50
+retain_violation drop
+unknown_packets ignore
+session_takeover grace 500
+write_batch_delay 5
+max_qos 1
+max_will_payload_size 4096
+max_ping_rate 0
+block_client_id lamp-fw-1.2-*
+block_client_id test-*
+block_username legacy
+persistent_client_id gateway-*
+persistent_username fleet
+client_id_prefix auto-
+min_qos commands/# 1
+min_qos alarms/# 2 upgrade
+node_id eu-west-1a
+").unwrap();
+    assert_eq!(config.listeners.len(), 2);
+    assert!(config.listeners[0].tcp_nodelay);
+    assert_eq!(config.listeners[0].backlog, 1024);
+    assert_eq!(config.listeners[1].mount_point, Some("tenant-a/".to_string()));
+    assert_eq!(config.listeners[1].connection_rate, Some(100));
+    assert_eq!(config.listeners[1].connection_burst, Some(500));
+    assert!(config.listeners[0].wildcard_subscriptions);
+    assert!(!config.listeners[1].wildcard_subscriptions);
+    assert_eq!(config.sys_interval, None);
+    assert!(config.keep_alive_suggestions);
+    assert!(config.audit);
+    assert_eq!(config.max_queued_age, Some(Duration::from_secs(60 * 60)));
+    assert_eq!(config.dead_letter_topic, Some("dead-letters".to_string()));
+    assert_eq!(config.qos2_release_timeout, Some(Duration::from_secs(300)));
+    assert_eq!(config.max_awaiting_rel, None);
+    assert_eq!(config.max_connection_memory, Some(1048576));
+    assert_eq!(config.connection_memory_action, MemoryAction::Disconnect);
+    assert_eq!(config.retained_qos, RetainedQos::Granted);
+    assert_eq!(config.retained_batch_size, 10);
+    assert_eq!(config.retained_history, 50);
+    assert_eq!(config.retain_violation, RetainViolation::Drop);
+    assert_eq!(config.unknown_packets, UnknownPacketPolicy::Ignore);
+    assert_eq!(config.session_takeover, TakeoverPolicy::Grace(Duration::from_millis(500)));
+    assert_eq!(config.write_batching, Some(WriteBatching {
+        max_delay: Duration::from_millis(5),
+        max_bytes: 4096
+    }));
+    assert_eq!(config.max_qos, QosLv::AtLeastOnce);
+    assert_eq!((config.max_will_topic_length, config.max_will_payload_size), (None, Some(4096)));
+    assert_eq!(config.max_ping_rate, None);
+    assert_eq!(config.blocked_client_ids, vec!["lamp-fw-1.2-*", "test-*"]);
+    assert_eq!(config.blocked_usernames, vec!["legacy"]);
+    assert_eq!(config.persistent_client_ids, vec!["gateway-*"]);
+    assert_eq!(config.persistent_usernames, vec!["fleet"]);
+    assert_eq!(config.client_id_prefix, Some("auto-".to_string()));
+    assert_eq!(config.node_id, Some("eu-west-1a".to_string()));
+    assert_eq!(config.min_qos, vec![
+        MinQosRule {
+            filter: "commands/#".to_string(),
+            qos_lv: QosLv::AtLeastOnce,
+            action: MinQosAction::Reject
+        },
+        MinQosRule {
+            filter: "alarms/#".to_string(),
+            qos_lv: QosLv::ExactlyOnce,
+            action: MinQosAction::Upgrade
+        }
+    ]);
+}
+
+#[test]
+fn parse_errors_name_the_line() {
+    assert_eq!(problems("listener 127.0.0.1:1883\n\ntcp_nodelay maybe\n"),
+        vec!["line 3: expected true or false"]);
+    assert_eq!(problems("backlog 10\n"),
+        vec!["line 1: `backlog` must follow a `listener` or `fault` line"]);
+    assert_eq!(problems("node_id eu/west\n"), vec!["line 1: expected a node id without /, + or #"]);
+}
+
+#[test]
+fn validate_json_lines_need_the_json_schema_feature() {
+    let s = "listener 127.0.0.1:1883\nvalidate_json telemetry/# telemetry.json flag\n";
+    if cfg!(feature = "json-schema") {
+        assert_eq!(Config::parse(s).unwrap().json_schemas, vec![SchemaRule {
+            filter: "telemetry/#".to_string(),
+            path: PathBuf::from("telemetry.json"),
+            action: ValidationAction::Flag
+        }]);
+        assert_eq!(problems("validate_json telemetry/#\n"),
+            vec!["line 1: expected `validate_json <topic filter> <schema file> [reject|flag]`"]);
+    } else {
+        assert_eq!(problems(s), vec!["line 2: validate_json needs a build with the json-schema \
+            feature"]);
+    }
+}
+
+#[test]
+fn every_validation_problem_is_reported() {
+    assert_eq!(problems("\
+listener 127.0.0.1:1883
+backlog 0
+listener 127.0.0.1:1883
+send_buffer_size 0
+mount_point tenant/+/
+overload_connack_delay 500
+fault * *
+dispatch_workers 0
+"), vec![
+        "listener 127.0.0.1:1883: backlog must be at least 1",
+        "listener 127.0.0.1:1883: address is used by more than one listener",
+        "listener 127.0.0.1:1883: buffer sizes must be greater than 0",
+        "listener 127.0.0.1:1883: mount_point must not contain wildcards",
+        "listener 127.0.0.1:1883: connection_burst and overload_connack_delay need a \
+         connection_rate",
+        "dispatch_workers must be at least 1",
+        "fault * *: rule has no effect"
+    ]);
+    assert_eq!(problems("# nothing here\n"), vec!["no listeners configured"]);
+}
+
+fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+    vars.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect()
+}
+
+#[test]
+fn environment_overrides_the_file() {
+    let config = Config::parse_with_env("\
+max_qos 2
+listener 127.0.0.1:1883
+backlog 1024
+tcp_nodelay true
+listener 127.0.0.1:8883
+", vars(&[
+        ("MQTT_BROKER__MAX_QOS", "1"),
+        ("MQTT_BROKER__SYS_INTERVAL", "0"),
+        ("MQTT_BROKER__LISTENERS__0__PORT", "1884"),
+        ("MQTT_BROKER__LISTENERS__0__BACKLOG", "16"),
+        ("MQTT_BROKER__LISTENERS__1__MOUNT_POINT", "tenant-a/"),
+        ("MQTT_BROKER__LISTENERS__2__ADDR", "0.0.0.0:1885"),
+        ("MQTT_BROKER__LISTENERS__2__BACKLOG", "32"),
+        ("HOME", "/root")
+    ])).unwrap();
+    assert_eq!(config.max_qos, QosLv::AtLeastOnce);
+    assert_eq!(config.sys_interval, None);
+    let listeners: Vec<_> = config.listeners.iter()
+        .map(|l| (l.addr.to_string(), l.backlog, l.tcp_nodelay, l.mount_point.clone()))
+        .collect();
+    assert_eq!(listeners, vec![
+        ("127.0.0.1:1884".to_string(), 16, true, None),
+        ("127.0.0.1:8883".to_string(), 128, false, Some("tenant-a/".to_string())),
+        ("0.0.0.0:1885".to_string(), 32, false, None)
+    ]);
+}
+
+#[test]
+fn bad_overrides_name_the_variable() {
+    let parse = |name: &str, value: &str| {
+        match Config::parse_with_env("listener 127.0.0.1:1883", vars(&[(name, value)])) {
+            Err(Error::Config(msg)) => msg,
+            res => panic!("expected a config error, got {:?}", res)
+        }
+    };
+    assert_eq!(parse("MQTT_BROKER__MAX_QOS", "3"), "MQTT_BROKER__MAX_QOS: expected 0, 1 or 2");
+    assert_eq!(parse("MQTT_BROKER__LISTENERS__5__PORT", "1884"),
+        "MQTT_BROKER__LISTENERS__5__PORT: there is no listener 5");
+    assert_eq!(parse("MQTT_BROKER__LISTENERS__0__PORT", "x"),
+        "MQTT_BROKER__LISTENERS__0__PORT: expected a port");
+    assert_eq!(parse("MQTT_BROKER__FAULT", "* *"),
+        "MQTT_BROKER__FAULT: expected a config key, or LISTENERS__<n>__<option>");
+}
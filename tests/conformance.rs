@@ -0,0 +1,445 @@
+// Scripted packet exchanges against a live broker, checking the behaviour the MQTT 3.1.1 spec
+// requires. Tests for behaviour the broker doesn't implement yet are ignored with the reason.
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::{connopts::*, ctrlpkt::*, ctrlpkt::CtrlPkt::*};
+
+fn publish(topic_name: &str, qos_lv: QosLv, pkt_id: Option<u16>, payload: &[u8]) -> CtrlPkt {
+    Publish {
+        dup: false,
+        qos_lv,
+        retain: false,
+        topic_name: topic_name.to_string(),
+        pkt_id,
+        payload: payload.to_vec()
+    }
+}
+
+// CONNECT for "MQTT" at protocol level 5 with client id "c"
+const CONNECT_LV5: [u8; 15] = [0x10, 13, 0, 4, b'M', b'Q', b'T', b'T', 5, 0x02, 0, 60, 0, 1, b'c'];
+
+#[test]
+fn connect_is_accepted() {
+    let addr = start_broker();
+    let (_, session_present) = Client::connect(addr, &ConnectOptions::new("accept".to_string()));
+    assert!(!session_present);
+}
+
+#[test]
+fn connect_v31_is_accepted() {
+    let addr = start_broker();
+    let mut opts = ConnectOptions::new("accept-v31".to_string());
+    opts.set_protocol_lv(ProtocolLv::V31);
+    Client::connect(addr, &opts);
+}
+
+#[test]
+fn unacceptable_protocol_level_is_refused() {
+    let addr = start_broker();
+    let mut client = Client::open(addr);
+    client.send_raw(&CONNECT_LV5);
+    assert_pkt!(client.recv(), ConnAck {
+        session_present: false,
+        return_code: ConnAckRetCode::UnacceptableProtocolVer
+    });
+    client.expect_closed();
+}
+
+#[test]
+fn empty_client_id_without_clean_session_is_rejected() {
+    let addr = start_broker();
+    let mut opts = ConnectOptions::new("persistent".to_string());
+    opts.set_clean_session(false);
+    let mut pkt = opts.build().unwrap();
+    if let Connect { ref mut client_id, .. } = pkt {
+        client_id.clear();
+    }
+    let mut client = Client::open(addr);
+    client.send(&pkt);
+    assert_pkt!(client.recv(), ConnAck {
+        session_present: false,
+        return_code: ConnAckRetCode::IdRejected
+    });
+    client.expect_closed();
+}
+
+#[test]
+fn persistent_session_is_resumed() {
+    let addr = start_broker();
+    let mut opts = ConnectOptions::new("resume".to_string());
+    opts.set_clean_session(false);
+    let (mut client, session_present) = Client::connect(addr, &opts);
+    assert!(!session_present);
+    client.send(&Disconnect);
+    client.expect_closed();
+    let (_, session_present) = Client::connect(addr, &opts);
+    assert!(session_present);
+}
+
+#[test]
+fn clean_session_discards_stored_session() {
+    let addr = start_broker();
+    let mut opts = ConnectOptions::new("discard".to_string());
+    opts.set_clean_session(false);
+    let (mut client, _) = Client::connect(addr, &opts);
+    client.send(&Disconnect);
+    client.expect_closed();
+    opts.set_clean_session(true);
+    let (_, session_present) = Client::connect(addr, &opts);
+    assert!(!session_present);
+}
+
+#[test]
+fn v31_never_reports_session_present() {
+    let addr = start_broker();
+    let mut opts = ConnectOptions::new("resume-v31".to_string());
+    opts.set_protocol_lv(ProtocolLv::V31).set_clean_session(false);
+    let (mut client, _) = Client::connect(addr, &opts);
+    client.send(&Disconnect);
+    client.expect_closed();
+    let (_, session_present) = Client::connect(addr, &opts);
+    assert!(!session_present);
+}
+
+#[test]
+fn connecting_again_closes_previous_connection() {
+    let addr = start_broker();
+    let mut first = Client::connect_id(addr, "takeover");
+    let mut second = Client::connect_id(addr, "takeover");
+    first.expect_closed();
+    // The first connection going away must not unregister the second
+    second.subscribe(1, vec![("takeover", QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr, "takeover-pub");
+    publisher.send(&publish("takeover", QosLv::AtMostOnce, None, b"hi"));
+    assert_pkt!(second.recv(), Publish { .. });
+}
+
+#[test]
+fn first_packet_must_be_connect() {
+    let addr = start_broker();
+    let mut client = Client::open(addr);
+    client.send(&PingReq);
+    client.expect_closed();
+}
+
+#[test]
+fn second_connect_closes_the_connection() {
+    let addr = start_broker();
+    let mut client = Client::connect_id(addr, "twice");
+    client.send(&ConnectOptions::new("twice".to_string()).build().unwrap());
+    client.expect_closed();
+}
+
+#[test]
+fn ping_is_answered() {
+    let addr = start_broker();
+    let mut client = Client::connect_id(addr, "ping");
+    client.send(&PingReq);
+    assert_pkt!(client.recv(), PingResp);
+}
+
+#[test]
+fn suback_return_codes_follow_request_order() {
+    let addr = start_broker();
+    let mut client = Client::connect_id(addr, "suback");
+    let ret_codes = client.subscribe(7, vec![
+        ("a", QosLv::AtLeastOnce),
+        ("b", QosLv::AtMostOnce),
+        ("c", QosLv::ExactlyOnce)
+    ]);
+    assert_pkt!(ret_codes.as_slice(),
+        &[SubAckRetCode::MaxQos1, SubAckRetCode::MaxQos0, SubAckRetCode::MaxQos2]);
+}
+
+#[test]
+fn qos0_publish_is_delivered() {
+    let addr = start_broker();
+    let mut sub = Client::connect_id(addr, "qos0-sub");
+    sub.subscribe(1, vec![("qos0", QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr, "qos0-pub");
+    publisher.send(&publish("qos0", QosLv::AtMostOnce, None, b"hello"));
+    match sub.recv() {
+        Publish { qos_lv: QosLv::AtMostOnce, ref topic_name, pkt_id: None, ref payload, .. } => {
+            assert_eq!(topic_name, "qos0");
+            assert_eq!(payload, b"hello");
+        }
+        pkt => panic!("expected a QoS 0 PUBLISH, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn publishes_match_wildcard_filters() {
+    let addr = start_broker();
+    let mut sub = Client::connect_id(addr, "wildcard-sub");
+    sub.subscribe(1, vec![("sensors/+/temp", QosLv::AtMostOnce), ("alerts/#", QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr, "wildcard-pub");
+    for topic_name in ["sensors/1/humidity", "sensors/1/temp", "alerts", "alerts/fire/kitchen"]
+        .iter() {
+        publisher.send(&publish(topic_name, QosLv::AtMostOnce, None, b"x"));
+    }
+    // Messages on different topics may be delivered in any order
+    let mut received = (0..3).map(|_| match sub.recv() {
+        Publish { topic_name, .. } => topic_name,
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }).collect::<Vec<_>>();
+    received.sort();
+    assert_eq!(received, vec!["alerts", "alerts/fire/kitchen", "sensors/1/temp"]);
+}
+
+#[test]
+fn publish_to_a_topic_with_wildcards_closes_the_connection() {
+    let addr = start_broker();
+    let mut sub = Client::connect_id(addr, "wildcard-topic-sub");
+    sub.subscribe(1, vec![("a/+", QosLv::AtMostOnce)]);
+    for topic_name in ["a/+", "a/#"].iter() {
+        let mut publisher = Client::connect_id(addr, "wildcard-topic-pub");
+        publisher.send(&publish(topic_name, QosLv::AtMostOnce, None, b"x"));
+        publisher.expect_closed();
+    }
+    let mut opts = ConnectOptions::new("wildcard-topic-pub".to_string());
+    opts.set_will(Will::new("a/+".to_string(), b"gone".to_vec()));
+    let mut client = Client::open(addr);
+    client.send(&opts.build().unwrap());
+    client.expect_closed();
+    let mut publisher = Client::connect_id(addr, "wildcard-topic-pub");
+    publisher.send(&publish("a/b", QosLv::AtMostOnce, None, b"x"));
+    match sub.recv() {
+        Publish { ref topic_name, .. } => assert_eq!(topic_name, "a/b"),
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn overlapping_subscriptions_deliver_once() {
+    let addr = start_broker();
+    let mut sub = Client::connect_id(addr, "overlap-sub");
+    sub.subscribe(1, vec![("a/#", QosLv::AtMostOnce), ("a/+", QosLv::AtMostOnce),
+        ("a/b", QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr, "overlap-pub");
+    publisher.send(&publish("a/b", QosLv::AtMostOnce, None, b"x"));
+    assert_pkt!(sub.recv(), Publish { .. });
+    sub.send(&PingReq);
+    assert_pkt!(sub.recv(), PingResp);
+}
+
+#[test]
+fn qos1_flow() {
+    let addr = start_broker();
+    let mut sub = Client::connect_id(addr, "qos1-sub");
+    sub.subscribe(1, vec![("qos1", QosLv::AtLeastOnce)]);
+    let mut publisher = Client::connect_id(addr, "qos1-pub");
+    publisher.send(&publish("qos1", QosLv::AtLeastOnce, Some(10), b"hello"));
+    assert_pkt!(publisher.recv(), PubAck(10));
+    let pkt_id = match sub.recv() {
+        Publish { qos_lv: QosLv::AtLeastOnce, pkt_id: Some(pkt_id), ref payload, .. } => {
+            assert_eq!(payload, b"hello");
+            pkt_id
+        }
+        pkt => panic!("expected a QoS 1 PUBLISH, got {:?}", pkt)
+    };
+    sub.send(&PubAck(pkt_id));
+    // The connection stays usable after the acknowledgement
+    sub.send(&PingReq);
+    assert_pkt!(sub.recv(), PingResp);
+}
+
+#[test]
+fn qos2_inbound_flow() {
+    let addr = start_broker();
+    let mut publisher = Client::connect_id(addr, "qos2-pub");
+    publisher.send(&publish("qos2", QosLv::ExactlyOnce, Some(20), b"hello"));
+    assert_pkt!(publisher.recv(), PubRec(20));
+    publisher.send(&PubRel(20));
+    assert_pkt!(publisher.recv(), PubComp(20));
+}
+
+#[test]
+fn qos2_outbound_flow() {
+    let addr = start_broker();
+    let mut sub = Client::connect_id(addr, "qos2-sub");
+    sub.subscribe(1, vec![("qos2", QosLv::ExactlyOnce)]);
+    let mut publisher = Client::connect_id(addr, "qos2-pub");
+    publisher.send(&publish("qos2", QosLv::ExactlyOnce, Some(20), b"hello"));
+    let pkt_id = match sub.recv() {
+        Publish { qos_lv: QosLv::ExactlyOnce, pkt_id: Some(pkt_id), .. } => pkt_id,
+        pkt => panic!("expected a QoS 2 PUBLISH, got {:?}", pkt)
+    };
+    sub.send(&PubRec(pkt_id));
+    match sub.recv() {
+        PubRel(id) => assert_eq!(id, pkt_id),
+        pkt => panic!("expected PUBREL, got {:?}", pkt)
+    }
+    sub.send(&PubComp(pkt_id));
+}
+
+#[test]
+fn delivery_qos_is_capped_by_publish_qos() {
+    let addr = start_broker();
+    let mut sub = Client::connect_id(addr, "cap-sub");
+    sub.subscribe(1, vec![("cap", QosLv::ExactlyOnce)]);
+    let mut publisher = Client::connect_id(addr, "cap-pub");
+    publisher.send(&publish("cap", QosLv::AtMostOnce, None, b"hello"));
+    assert_pkt!(sub.recv(), Publish { qos_lv: QosLv::AtMostOnce, pkt_id: None, .. });
+}
+
+#[test]
+fn retained_message_is_delivered_on_subscribe() {
+    let addr = start_broker();
+    let mut publisher = Client::connect_id(addr, "retain-pub");
+    let mut pkt = publish("retained", QosLv::AtMostOnce, None, b"last");
+    if let Publish { ref mut retain, .. } = pkt {
+        *retain = true;
+    }
+    publisher.send(&pkt);
+    publisher.send(&PingReq);
+    assert_pkt!(publisher.recv(), PingResp);
+    let mut sub = Client::connect_id(addr, "retain-sub");
+    sub.subscribe(1, vec![("retained", QosLv::AtMostOnce)]);
+    match sub.recv() {
+        Publish { retain: true, ref payload, .. } => assert_eq!(payload, b"last"),
+        pkt => panic!("expected the retained PUBLISH, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn empty_retained_message_clears_the_retained_message() {
+    let addr = start_broker();
+    let mut publisher = Client::connect_id(addr, "clear-pub");
+    for payload in [&b"last"[..], b""].iter() {
+        let mut pkt = publish("cleared", QosLv::AtMostOnce, None, payload);
+        if let Publish { ref mut retain, .. } = pkt {
+            *retain = true;
+        }
+        publisher.send(&pkt);
+    }
+    publisher.send(&PingReq);
+    assert_pkt!(publisher.recv(), PingResp);
+    let mut sub = Client::connect_id(addr, "clear-sub");
+    sub.subscribe(1, vec![("cleared", QosLv::AtMostOnce)]);
+    sub.send(&PingReq);
+    assert_pkt!(sub.recv(), PingResp);
+}
+
+#[test]
+fn retained_messages_match_wildcard_filters_at_granted_qos() {
+    let addr = start_broker();
+    let mut publisher = Client::connect_id(addr, "retain-wild-pub");
+    for (i, topic_name) in ["devices/a/status", "devices/b/status", "devices/a/temp"].iter()
+        .enumerate() {
+        let mut pkt = publish(topic_name, QosLv::AtLeastOnce, Some(i as u16 + 1), b"on");
+        if let Publish { ref mut retain, .. } = pkt {
+            *retain = true;
+        }
+        publisher.send(&pkt);
+        assert_pkt!(publisher.recv(), PubAck(_));
+    }
+    let mut sub = Client::connect_id(addr, "retain-wild-sub");
+    sub.subscribe(1, vec![("devices/+/status", QosLv::AtMostOnce)]);
+    for expected in ["devices/a/status", "devices/b/status"].iter() {
+        match sub.recv() {
+            Publish { retain: true, qos_lv: QosLv::AtMostOnce, ref topic_name, .. } =>
+                assert_eq!(topic_name, expected),
+            pkt => panic!("expected a retained QoS 0 PUBLISH, got {:?}", pkt)
+        }
+    }
+    sub.send(&PingReq);
+    assert_pkt!(sub.recv(), PingResp);
+}
+
+#[test]
+fn will_is_published_on_unexpected_disconnect() {
+    let addr = start_broker();
+    let mut sub = Client::connect_id(addr, "will-sub");
+    sub.subscribe(1, vec![("will", QosLv::AtMostOnce)]);
+    let mut opts = ConnectOptions::new("will-owner".to_string());
+    opts.set_will(Will::new("will".to_string(), b"gone".to_vec()));
+    let (client, _) = Client::connect(addr, &opts);
+    drop(client);
+    match sub.recv() {
+        Publish { ref topic_name, ref payload, .. } => {
+            assert_eq!(topic_name, "will");
+            assert_eq!(payload, b"gone");
+        }
+        pkt => panic!("expected the will PUBLISH, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn will_is_discarded_on_disconnect() {
+    let addr = start_broker();
+    let mut sub = Client::connect_id(addr, "will-sub");
+    sub.subscribe(1, vec![("will", QosLv::AtMostOnce)]);
+    let mut opts = ConnectOptions::new("will-owner".to_string());
+    opts.set_will(Will::new("will".to_string(), b"gone".to_vec()));
+    let (mut client, _) = Client::connect(addr, &opts);
+    client.send(&Disconnect);
+    client.expect_closed();
+    sub.send(&PingReq);
+    assert_pkt!(sub.recv(), PingResp);
+}
+
+#[test]
+fn will_is_published_when_keep_alive_expires() {
+    let addr = start_broker();
+    let mut sub = Client::connect_id(addr, "will-sub");
+    sub.subscribe(1, vec![("will", QosLv::AtMostOnce)]);
+    let mut opts = ConnectOptions::new("silent".to_string());
+    opts.set_keep_alive(1).set_will(Will::new("will".to_string(), b"timed out".to_vec()));
+    let (mut client, _) = Client::connect(addr, &opts);
+    // Nothing is sent for one and a half keep-alive periods
+    match sub.recv() {
+        Publish { ref payload, .. } => assert_eq!(payload, b"timed out"),
+        pkt => panic!("expected the will PUBLISH, got {:?}", pkt)
+    }
+    client.expect_closed();
+}
+
+#[test]
+fn will_is_published_when_connection_is_taken_over() {
+    let addr = start_broker();
+    let mut sub = Client::connect_id(addr, "will-sub");
+    sub.subscribe(1, vec![("will", QosLv::AtMostOnce)]);
+    let mut opts = ConnectOptions::new("taken-over".to_string());
+    opts.set_will(Will::new("will".to_string(), b"replaced".to_vec()));
+    let (mut old, _) = Client::connect(addr, &opts);
+    let _new = Client::connect_id(addr, "taken-over");
+    old.expect_closed();
+    match sub.recv() {
+        Publish { ref payload, .. } => assert_eq!(payload, b"replaced"),
+        pkt => panic!("expected the will PUBLISH, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn clean_session_ends_with_the_connection() {
+    let addr = start_broker();
+    let (mut client, _) = Client::connect(addr, &ConnectOptions::new("clean".to_string()));
+    client.subscribe(1, vec![("t", QosLv::AtMostOnce)]);
+    client.send(&Disconnect);
+    client.expect_closed();
+    let mut opts = ConnectOptions::new("clean".to_string());
+    opts.set_clean_session(false);
+    let (_, session_present) = Client::connect(addr, &opts);
+    assert!(!session_present);
+}
+
+#[test]
+fn reserved_packet_type_closes_connection() {
+    let addr = start_broker();
+    let mut client = Client::open(addr);
+    client.send_raw(&[0x00, 0x00]);
+    client.expect_closed();
+}
+
+#[test]
+fn malformed_packet_after_connect_closes_connection() {
+    let addr = start_broker();
+    let mut client = Client::connect_id(addr, "malformed");
+    client.send_raw(&[0xf0, 0x00]);
+    client.expect_closed();
+}
@@ -0,0 +1,227 @@
+#![cfg(unix)]
+
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{CtrlPkt::*, QosLv};
+use mqtt_broker::{broker::Broker, config::Config, control, info};
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process;
+
+#[test]
+fn clients_and_subs() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut client = Client::connect_id(addr, "console-client");
+    client.subscribe(1, vec![("a/b", QosLv::AtLeastOnce), ("c", QosLv::AtMostOnce)]);
+    assert_eq!(control::execute(&broker, "clients"),
+        "console-client connected subscriptions=2 inflight=0 queued=0 delivered=0 dropped=0 \
+         qos2_duplicates=0 awaiting_rel=0 qos2_abandoned=0 unknown_packets=0 stale_acks=0 \
+         memory=68B idle=0s protocol=3.1.1 keep_alive=0s max_qos=2\n");
+    assert_eq!(control::execute(&broker, "subs"),
+        "a/b console-client qos=1\nc console-client qos=0\n");
+    assert_eq!(control::execute(&broker, "subs c"), "c console-client qos=0\n");
+    assert_eq!(control::execute(&broker, "subs d"), "(none)\n");
+    assert!(control::execute(&broker, "subtable")
+        .starts_with("filters=2 subscriptions=2 max_depth=2 estimated_bytes="));
+    let mut publisher = Client::connect_id(addr, "console-publisher");
+    publisher.send(&Publish {
+        dup: false,
+        qos_lv: QosLv::AtMostOnce,
+        retain: false,
+        topic_name: "c".to_string(),
+        pkt_id: None,
+        payload: b"x".to_vec()
+    });
+    client.recv();
+    let filters = control::execute(&broker, "subtable filters");
+    assert!(filters.starts_with("c subscribers=1 matched=1 deliveries=1 rate="), "{}", filters);
+    assert!(filters.contains("\na/b subscribers=1 matched=0 deliveries=0 rate=0.00/s\n"));
+    assert!(control::execute(&broker, "subtable filters a/b").starts_with("a/b subscribers=1"));
+}
+
+#[test]
+fn decode_stats_are_shown() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut client = Client::connect_id(addr, "decoded");
+    client.send(&PingReq);
+    assert_pkt!(client.recv(), PingResp);
+    assert_eq!(control::execute(&broker, "decode decoded"),
+        "packets CONNECT=1 PINGREQ=1\nmalformed=0 oversized=0 unknown_flags=0\n");
+    assert_eq!(control::execute(&broker, "decode other"), "no session for other\n");
+}
+
+#[test]
+fn kick_closes_connection() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut client = Client::connect_id(addr, "kicked");
+    assert_eq!(control::execute(&broker, "kick kicked"), "kicked kicked\n");
+    client.expect_closed();
+    assert_eq!(control::execute(&broker, "kick kicked"), "kicked is not connected\n");
+}
+
+#[test]
+fn retained_messages_are_shown() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut client = Client::connect_id(addr, "retainer");
+    client.send(&Publish {
+        dup: false,
+        qos_lv: QosLv::AtLeastOnce,
+        retain: true,
+        topic_name: "status".to_string(),
+        pkt_id: Some(1),
+        payload: b"online".to_vec()
+    });
+    assert_pkt!(client.recv(), PubAck(1));
+    assert_eq!(control::execute(&broker, "retained"), "status\n");
+    assert_eq!(control::execute(&broker, "retained status"), "status qos=1 bytes=6 online\n");
+    assert_eq!(control::execute(&broker, "retained other"), "nothing retained on other\n");
+    assert_eq!(control::execute(&broker, "retained +"), "status qos=1 bytes=6 online\n");
+    assert_eq!(control::execute(&broker, "retained status/#"), "status qos=1 bytes=6 online\n");
+}
+
+#[test]
+fn retained_messages_are_managed() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "shadow-sub");
+    sub.subscribe(1, vec![("shadow/lamp", QosLv::AtMostOnce)]);
+    assert_eq!(control::execute(&broker, "retained set shadow/lamp 1 eyJvbiI6dHJ1ZX0="),
+        "retained a message on shadow/lamp\n");
+    match sub.recv() {
+        Publish { ref topic_name, ref payload, .. }
+            if topic_name == "shadow/lamp" && payload == b"{\"on\":true}" => (),
+        pkt => panic!("expected the retained message, got {:?}", pkt)
+    }
+    let shown = control::execute(&broker, "retained get shadow/lamp");
+    assert!(shown.starts_with("shadow/lamp qos=1 retained_at="));
+    assert!(shown.ends_with(" payload=eyJvbiI6dHJ1ZX0=\n"));
+    assert_eq!(control::execute(&broker, "retained set shadow/+ 1 AA=="),
+        "shadow/+ is not a topic name\n");
+    assert_eq!(control::execute(&broker, "retained set shadow/lamp 3 AA=="),
+        "invalid QoS `3`; expected 0, 1 or 2\n");
+    assert_eq!(control::execute(&broker, "retained set shadow/lamp 0 AA="),
+        "invalid payload; expected base64\n");
+    assert_eq!(control::execute(&broker, "retained delete shadow/lamp"),
+        "deleted the message retained on shadow/lamp\n");
+    assert_eq!(control::execute(&broker, "retained get shadow/lamp"),
+        "nothing retained on shadow/lamp\n");
+    assert_eq!(control::execute(&broker, "retained delete shadow/lamp"),
+        "nothing retained on shadow/lamp\n");
+}
+
+#[test]
+fn retained_history_is_shown() {
+    let mut broker = Broker::new();
+    broker.set_retained_history(10);
+    assert_eq!(control::execute(&broker, "retained history shadow/lamp"), "(none)\n");
+    control::execute(&broker, "retained set shadow/lamp 1 eyJvbiI6dHJ1ZX0=");
+    control::execute(&broker, "retained delete shadow/lamp");
+    let history = control::execute(&broker, "retained history shadow/lamp");
+    let lines: Vec<&str> = history.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with(" qos=1 payload=eyJvbiI6dHJ1ZX0="));
+    assert!(lines[1].ends_with(" deleted"));
+    assert_eq!(control::execute(&broker, "retained at shadow/lamp 0"),
+        "no recorded message on shadow/lamp at that time\n");
+    assert_eq!(control::execute(&broker, "retained at shadow/lamp yesterday"),
+        "invalid time `yesterday`; expected a Unix timestamp\n");
+}
+
+#[test]
+fn sessions_are_exported_and_imported() {
+    let production = Broker::new();
+    let addr = start(&production);
+    let mut opts = ConnectOptions::new("lamp-7".to_string());
+    opts.set_clean_session(false);
+    let (mut lamp, _) = Client::connect(addr, &opts);
+    lamp.subscribe(1, vec![("lamps/7/cmd", QosLv::AtLeastOnce)]);
+    assert!(production.kick("lamp-7").unwrap());
+    lamp.expect_closed();
+    assert!(production.push_message("lamp-7", "lamps/7/cmd", QosLv::AtLeastOnce, b"on".to_vec())
+        .unwrap());
+    let export = control::execute(&production, "session export lamp-7");
+    assert_eq!(export, "{\"client_id\":\"lamp-7\",\"clean_session\":false,\"namespace\":null,\
+        \"epoch\":1,\"subscriptions\":[{\"filter\":\"lamps/7/cmd\",\"qos\":1}],\
+        \"queued\":[{\"topic\":\"lamps/7/cmd\",\"qos\":1,\"payload\":\"b24=\",\"age_secs\":0}],\
+        \"inflight\":[],\"awaiting_rel\":[]}\n");
+    assert_eq!(control::execute(&production, "session export lamp-8"), "no session for lamp-8\n");
+
+    let staging = Broker::new();
+    assert_eq!(control::execute(&staging, &format!("session import {}", export)),
+        "imported the session of lamp-7\n");
+    assert_eq!(control::execute(&staging, &format!("session import {}", export)),
+        "lamp-7 already has a session\n");
+    assert_eq!(control::execute(&staging, "session import {\"client_id\":\"lamp-8\"}"),
+        "invalid session; expected the JSON from session export\n");
+    assert_eq!(staging.client_subscriptions("lamp-7"),
+        Some(vec![("lamps/7/cmd".to_string(), QosLv::AtLeastOnce)]));
+    let (mut lamp, session_present) = Client::connect(start(&staging), &opts);
+    assert!(session_present);
+    match lamp.recv() {
+        Publish { ref topic_name, ref payload, .. }
+            if topic_name == "lamps/7/cmd" && payload == b"on" => (),
+        pkt => panic!("expected the queued message, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn broker_info_is_shown() {
+    let broker = Broker::new();
+    assert_eq!(control::execute(&broker, "info"), "no broker info published\n");
+    broker.publish_info(&info::describe(&Config::default()));
+    assert_eq!(broker.retained("$SYS/broker/version").unwrap().1, info::VERSION.as_bytes());
+    let shown = control::execute(&broker, "info");
+    assert!(shown.starts_with(&format!("version: {}\nfeatures: mqtt-", info::VERSION)));
+    assert!(shown.contains("\nlimits: dispatch_workers=4 fanout_chunk_size=1000 \
+        sys_interval=10s "));
+}
+
+#[test]
+fn invalid_commands_are_reported() {
+    let broker = Broker::new();
+    assert_eq!(control::execute(&broker, "kick"), "invalid command `kick`; try help\n");
+    assert_eq!(control::execute(&broker, "loglevel loud"), "unknown log level `loud`\n");
+    assert_eq!(control::execute(&broker, ""), "");
+}
+
+#[test]
+fn blocklist_commands() {
+    let broker = Broker::new();
+    assert_eq!(control::execute(&broker, "blocked"), "(none)\n");
+    assert_eq!(control::execute(&broker, "block client lamp-*"), "blocked client lamp-*\n");
+    assert_eq!(control::execute(&broker, "block client lamp-*"),
+        "client lamp-* is already blocked\n");
+    assert_eq!(control::execute(&broker, "block user legacy"), "blocked user legacy\n");
+    assert_eq!(control::execute(&broker, "blocked"), "client lamp-*\nuser legacy\n");
+    assert!(broker.blocklist().blocks_client_id("lamp-0001"));
+    assert_eq!(control::execute(&broker, "unblock user legacy"), "unblocked user legacy\n");
+    assert_eq!(control::execute(&broker, "unblock user legacy"), "user legacy is not blocked\n");
+    assert_eq!(control::execute(&broker, "blocked"), "client lamp-*\n");
+}
+
+#[test]
+fn commands_over_socket() {
+    let broker = Broker::new();
+    let path = env::temp_dir().join(format!("mqtt-broker-control-{}.sock", process::id()));
+    control::serve(broker, &path).unwrap();
+    let mut conn = UnixStream::connect(&path).unwrap();
+    let mut reader = BufReader::new(conn.try_clone().unwrap());
+    conn.write_all(b"clients\nretained\n").unwrap();
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "(none)\n");
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "(none)\n");
+}
@@ -0,0 +1,46 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{CtrlPkt::*, QosLv};
+use mqtt_broker::broker::{Broker, QueueLimits};
+use mqtt_broker::{base64, json};
+
+#[test]
+fn messages_dropped_from_a_full_queue_are_republished() {
+    let mut broker = Broker::new();
+    broker.set_queue_limits(QueueLimits { max_messages: 1, max_age: None })
+        .set_dead_letter_topic(Some("$dead".to_string()));
+    let addr = start(&broker);
+    let mut inspector = Client::connect_id(addr, "inspector");
+    inspector.subscribe(1, vec![("$dead", QosLv::AtLeastOnce)]);
+    let mut opts = ConnectOptions::new("valve-2".to_string());
+    opts.set_clean_session(false);
+    let (mut valve, _) = Client::connect(addr, &opts);
+    valve.subscribe(1, vec![("valves/2", QosLv::AtLeastOnce)]);
+    assert!(broker.kick("valve-2").unwrap());
+    valve.expect_closed();
+
+    assert!(broker.push_message("valve-2", "valves/2", QosLv::AtLeastOnce, b"open".to_vec())
+        .unwrap());
+    assert!(!broker.push_message("valve-2", "valves/2", QosLv::AtLeastOnce, b"close".to_vec())
+        .unwrap());
+    // QoS 0 messages may be lost anyway
+    assert!(!broker.push_message("valve-2", "valves/2", QosLv::AtMostOnce, b"ping".to_vec())
+        .unwrap());
+    let letter = match inspector.recv() {
+        Publish { ref topic_name, ref payload, .. } if topic_name == "$dead" =>
+            json::parse(&String::from_utf8(payload.clone()).unwrap()).unwrap(),
+        pkt => panic!("expected a dead letter, got {:?}", pkt)
+    };
+    assert_eq!(letter.get("client_id").and_then(|v| v.as_str()), Some("valve-2"));
+    assert_eq!(letter.get("topic").and_then(|v| v.as_str()), Some("valves/2"));
+    assert_eq!(letter.get("qos").and_then(|v| v.as_u64()), Some(1));
+    assert_eq!(letter.get("reason").and_then(|v| v.as_str()), Some("queue_full"));
+    let payload = letter.get("payload").and_then(|v| v.as_str()).and_then(base64::decode);
+    assert_eq!(payload, Some(b"close".to_vec()));
+    assert_eq!(broker.dead_letters(), 1);
+}
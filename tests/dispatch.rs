@@ -0,0 +1,185 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::ctrlpkt::{CtrlPkt::*, QosLv, SubAckRetCode};
+use mqtt_broker::broker::{Broker, FanoutStats};
+use mqtt_broker::dispatch::Dispatcher;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn jobs_on_one_topic_run_in_order() {
+    let dispatcher = Dispatcher::new(4);
+    let order = Arc::new(Mutex::new(vec![]));
+    let (tx, rx) = mpsc::channel();
+    for i in 0..1000 {
+        let (order, tx) = (Arc::clone(&order), tx.clone());
+        dispatcher.dispatch("ordered", move || {
+            order.lock().unwrap().push(i);
+            tx.send(()).unwrap();
+        });
+    }
+    for _ in 0..1000 {
+        rx.recv_timeout(Duration::from_secs(TIMEOUT_SECS)).unwrap();
+    }
+    assert_eq!(*order.lock().unwrap(), (0..1000).collect::<Vec<_>>());
+}
+
+#[test]
+fn dispatch_does_not_wait_for_the_job() {
+    let dispatcher = Dispatcher::new(1);
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    let (done_tx, done_rx) = mpsc::channel();
+    dispatcher.dispatch("slow", move || {
+        release_rx.recv().unwrap();
+        done_tx.send(()).unwrap();
+    });
+    // Still here while the job is blocked
+    release_tx.send(()).unwrap();
+    done_rx.recv_timeout(Duration::from_secs(TIMEOUT_SECS)).unwrap();
+}
+
+#[test]
+fn other_topics_run_between_steps() {
+    let dispatcher = Dispatcher::new(1);
+    let order = Arc::new(Mutex::new(vec![]));
+    let (tx, rx) = mpsc::channel();
+    let (steps_order, steps_tx) = (Arc::clone(&order), tx.clone());
+    let mut step = 0;
+    dispatcher.dispatch_steps("crowded", move || {
+        step += 1;
+        steps_order.lock().unwrap().push(format!("crowded {}", step));
+        if step == 1 {
+            // Give the other jobs time to be queued
+            thread::sleep(Duration::from_millis(100));
+        }
+        steps_tx.send(()).unwrap();
+        step == 3
+    });
+    for topic_name in &["quiet", "crowded"] {
+        let (order, tx) = (Arc::clone(&order), tx.clone());
+        dispatcher.dispatch(topic_name, move || {
+            order.lock().unwrap().push(topic_name.to_string());
+            tx.send(()).unwrap();
+        });
+    }
+    for _ in 0..5 {
+        rx.recv_timeout(Duration::from_secs(TIMEOUT_SECS)).unwrap();
+    }
+    // The quiet topic gets a turn before the stepped job is done, while the later job on the
+    // crowded topic waits for it
+    assert_eq!(*order.lock().unwrap(), vec!["crowded 1", "crowded 2", "quiet", "crowded 3",
+        "crowded"]);
+}
+
+#[test]
+fn messages_keep_their_order_through_the_broker() {
+    let addr = start_broker();
+    let mut sub = Client::connect_id(addr, "order-sub");
+    sub.subscribe(1, vec![("ordered", QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr, "order-pub");
+    for i in 0..100u8 {
+        publisher.send(&Publish {
+            dup: false,
+            qos_lv: QosLv::AtMostOnce,
+            retain: false,
+            topic_name: "ordered".to_string(),
+            pkt_id: None,
+            payload: vec![i]
+        });
+    }
+    for i in 0..100u8 {
+        match sub.recv() {
+            Publish { ref payload, .. } => assert_eq!(payload, &vec![i]),
+            pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+        }
+    }
+}
+
+#[test]
+fn large_fan_outs_are_chunked() {
+    let mut broker = Broker::new();
+    broker.set_fanout_chunk_size(2);
+    let addr = start(&broker);
+    let mut subs: Vec<Client> = (0..5).map(|i| {
+        let mut sub = Client::connect_id(addr, &format!("fanout-sub-{}", i));
+        sub.subscribe(1, vec![("dashboards", QosLv::AtMostOnce)]);
+        sub
+    }).collect();
+    let mut publisher = Client::connect_id(addr, "fanout-pub");
+    for i in 0..3u8 {
+        publisher.send(&Publish {
+            dup: false,
+            qos_lv: QosLv::AtMostOnce,
+            retain: false,
+            topic_name: "dashboards".to_string(),
+            pkt_id: None,
+            payload: vec![i]
+        });
+    }
+    for sub in subs.iter_mut() {
+        for i in 0..3u8 {
+            match sub.recv() {
+                Publish { ref payload, .. } => assert_eq!(payload, &vec![i]),
+                pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+            }
+        }
+    }
+    assert_eq!(broker.fanout_stats(), FanoutStats { max: 5, chunked: 3 });
+}
+
+#[test]
+fn a_subscriber_that_stops_reading_holds_up_nobody_else() {
+    let addr = start_broker();
+    let mut stalled = Client::connect_id(addr, "stalled-sub");
+    stalled.subscribe(1, vec![("bulk", QosLv::AtMostOnce)]);
+    // Enough to fill the socket buffers, so a write to the stalled subscriber blocks. Stops early
+    // rather than panicking if the broker closes the connection.
+    let bulk = thread::spawn(move || {
+        let mut publisher = Client::connect_id(addr, "bulk-pub");
+        for _ in 0..256 {
+            let sent = publisher.try_send(&Publish {
+                dup: false,
+                qos_lv: QosLv::AtMostOnce,
+                retain: false,
+                topic_name: "bulk".to_string(),
+                pkt_id: None,
+                payload: vec![0; 64 * 1024]
+            });
+            if !sent {
+                break;
+            }
+        }
+    });
+    thread::sleep(Duration::from_millis(500));
+    let mut other = Client::connect_id(addr, "other-sub");
+    other.set_read_timeout(Duration::from_secs(1));
+    let started = Instant::now();
+    let ret_codes = other.subscribe(1, vec![("news", QosLv::AtLeastOnce)]);
+    assert_pkt!(ret_codes.as_slice(), &[SubAckRetCode::MaxQos1]);
+    assert!(started.elapsed() < Duration::from_secs(1));
+    // `news` and `bulk` are dispatched by different workers, so only the stalled subscriber's
+    // worker is held up
+    let mut news_pub = Client::connect_id(addr, "news-pub");
+    news_pub.send(&Publish {
+        dup: false,
+        qos_lv: QosLv::AtMostOnce,
+        retain: false,
+        topic_name: "news".to_string(),
+        pkt_id: None,
+        payload: b"still here".to_vec()
+    });
+    match other.recv() {
+        Publish { ref topic_name, ref payload, .. } =>
+            assert_eq!((topic_name.as_str(), &payload[..]), ("news", &b"still here"[..])),
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+    drop(stalled);
+    bulk.join().unwrap();
+}
@@ -0,0 +1,117 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{CtrlPkt::*, QosLv};
+use libmqtt::error::Error;
+use mqtt_broker::broker::Broker;
+
+fn publish(client: &mut Client, topic_name: &str) {
+    client.send(&Publish {
+        dup: false,
+        qos_lv: QosLv::AtMostOnce,
+        retain: false,
+        topic_name: topic_name.to_string(),
+        pkt_id: None,
+        payload: b"hello".to_vec()
+    });
+}
+
+#[test]
+fn subscriptions_are_managed_for_clients() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "embed-sub");
+    broker.subscribe("embed-sub", "t", QosLv::AtMostOnce).unwrap();
+    assert_eq!(broker.client_subscriptions("embed-sub"),
+        Some(vec![("t".to_string(), QosLv::AtMostOnce)]));
+    let mut publisher = Client::connect_id(addr, "embed-pub");
+    publish(&mut publisher, "t");
+    assert_pkt!(sub.recv(), Publish { .. });
+    assert!(broker.unsubscribe("embed-sub", "t").unwrap());
+    assert!(!broker.unsubscribe("embed-sub", "t").unwrap());
+    assert!(broker.subscriptions(Some("t")).is_empty());
+    publish(&mut publisher, "t");
+    sub.send(&PingReq);
+    assert_pkt!(sub.recv(), PingResp);
+    assert_eq!(broker.client_subscriptions("embed-sub"), Some(vec![]));
+    assert_eq!(broker.client_subscriptions("nobody"), None);
+    assert_pkt!(broker.subscribe("nobody", "t", QosLv::AtMostOnce), Err(Error::NoSession));
+}
+
+#[test]
+fn messages_are_pushed_to_sessions() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut opts = ConnectOptions::new("embed-push".to_string());
+    opts.set_clean_session(false);
+    let (mut client, _) = Client::connect(addr, &opts);
+    assert!(broker.push_message("embed-push", "direct", QosLv::AtLeastOnce, b"now".to_vec())
+        .unwrap());
+    match client.recv() {
+        Publish { ref topic_name, ref payload, pkt_id: Some(pkt_id), .. } => {
+            assert_eq!((topic_name.as_str(), &payload[..]), ("direct", &b"now"[..]));
+            client.send(&PubAck(pkt_id));
+        }
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+    assert!(broker.kick("embed-push").unwrap());
+    client.expect_closed();
+    assert!(broker.push_message("embed-push", "direct", QosLv::AtLeastOnce, b"later".to_vec())
+        .unwrap());
+    assert!(!broker.push_message("embed-push", "direct", QosLv::AtMostOnce, b"lost".to_vec())
+        .unwrap());
+    let (mut client, _) = Client::connect(addr, &opts);
+    match client.recv() {
+        Publish { ref payload, .. } => assert_eq!(payload, b"later"),
+        pkt => panic!("expected the queued PUBLISH, got {:?}", pkt)
+    }
+    assert_pkt!(broker.push_message("nobody", "direct", QosLv::AtMostOnce, vec![]),
+        Err(Error::NoSession));
+}
+
+#[test]
+fn invalid_filters_and_topics_are_rejected() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let _client = Client::connect_id(addr, "embed-invalid");
+    assert_pkt!(broker.subscribe("embed-invalid", "a/#/b", QosLv::AtMostOnce),
+        Err(Error::InvalidTopicFilter));
+    assert_pkt!(broker.subscribe("embed-invalid", "a+", QosLv::AtMostOnce),
+        Err(Error::InvalidTopicFilter));
+    assert_pkt!(broker.push_message("embed-invalid", "a/+", QosLv::AtMostOnce, vec![]),
+        Err(Error::PublishTopicHasWildcards));
+    assert_pkt!(broker.push_message("embed-invalid", "a/#", QosLv::AtMostOnce, vec![]),
+        Err(Error::PublishTopicHasWildcards));
+    assert_eq!(broker.client_subscriptions("embed-invalid"), Some(vec![]));
+}
+
+#[test]
+fn filters_and_topics_are_in_the_clients_namespace() {
+    let broker = Broker::new();
+    let mut listener_config = local_listener();
+    listener_config.mount_point = Some("tenant/".to_string());
+    let mounted = start_listener(&broker, listener_config);
+    let mut sub = Client::connect_id(mounted, "embed-mounted");
+    broker.subscribe("embed-mounted", "t", QosLv::AtMostOnce).unwrap();
+    assert_eq!(broker.subscriptions(None),
+        vec![("tenant/t".to_string(), "embed-mounted".to_string(), QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(start(&broker), "embed-unmounted");
+    publish(&mut publisher, "tenant/t");
+    match sub.recv() {
+        Publish { ref topic_name, .. } => assert_eq!(topic_name, "t"),
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+    assert!(broker.push_message("embed-mounted", "direct", QosLv::AtMostOnce, b"now".to_vec())
+        .unwrap());
+    match sub.recv() {
+        Publish { ref topic_name, .. } => assert_eq!(topic_name, "direct"),
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+    assert!(broker.unsubscribe("embed-mounted", "t").unwrap());
+    assert!(broker.subscriptions(None).is_empty());
+}
@@ -0,0 +1,118 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::ctrlpkt::{CtrlPkt, CtrlPkt::*, QosLv};
+use mqtt_broker::{broker::Broker, config::Config, fault::*};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+fn start_with_faults(rules: Vec<FaultRule>) -> SocketAddr {
+    let mut broker = Broker::new();
+    broker.set_faults(Faults::new(rules));
+    start(&broker)
+}
+
+fn rule(client_id: &str, topic: Option<&str>) -> FaultRule {
+    FaultRule::new(Some(client_id.to_string()), topic.map(|topic| topic.to_string()))
+}
+
+fn publish(topic_name: &str, qos_lv: QosLv, pkt_id: Option<u16>) -> CtrlPkt {
+    Publish {
+        dup: false,
+        qos_lv,
+        retain: false,
+        topic_name: topic_name.to_string(),
+        pkt_id,
+        payload: b"hello".to_vec()
+    }
+}
+
+#[test]
+fn acks_are_dropped() {
+    let mut drop_acks = rule("flaky-pub", None);
+    drop_acks.drop_ack = 1.0;
+    let addr = start_with_faults(vec![drop_acks]);
+    let mut publisher = Client::connect_id(addr, "flaky-pub");
+    publisher.send(&publish("t", QosLv::AtLeastOnce, Some(1)));
+    publisher.send(&PingReq);
+    assert_pkt!(publisher.recv(), PingResp);
+    // Other clients are unaffected
+    let mut other = Client::connect_id(addr, "other-pub");
+    other.send(&publish("t", QosLv::AtLeastOnce, Some(1)));
+    assert_pkt!(other.recv(), PubAck(1));
+}
+
+#[test]
+fn qos1_deliveries_are_duplicated() {
+    let mut duplicate = rule("dup-sub", Some("t"));
+    duplicate.duplicate_qos1 = 1.0;
+    let addr = start_with_faults(vec![duplicate]);
+    let mut sub = Client::connect_id(addr, "dup-sub");
+    sub.subscribe(1, vec![("t", QosLv::AtLeastOnce)]);
+    let mut publisher = Client::connect_id(addr, "dup-pub");
+    publisher.send(&publish("t", QosLv::AtLeastOnce, Some(1)));
+    let pkt_id = match sub.recv() {
+        Publish { dup: false, pkt_id: Some(pkt_id), .. } => pkt_id,
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    };
+    match sub.recv() {
+        Publish { dup: true, pkt_id: Some(id), .. } => assert_eq!(id, pkt_id),
+        pkt => panic!("expected a DUP PUBLISH, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn deliveries_are_delayed() {
+    let mut delay = rule("slow-sub", None);
+    delay.delay_delivery = Some(Duration::from_millis(300));
+    let addr = start_with_faults(vec![delay]);
+    let mut sub = Client::connect_id(addr, "slow-sub");
+    sub.subscribe(1, vec![("t", QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr, "slow-pub");
+    let sent = Instant::now();
+    publisher.send(&publish("t", QosLv::AtMostOnce, None));
+    assert_pkt!(sub.recv(), Publish { .. });
+    assert!(sent.elapsed() >= Duration::from_millis(300));
+}
+
+#[test]
+fn connections_are_closed_on_matching_topic() {
+    let mut close = rule("victim", Some("boom"));
+    close.close_connection = 1.0;
+    let addr = start_with_faults(vec![close]);
+    let mut client = Client::connect_id(addr, "victim");
+    client.send(&publish("safe", QosLv::AtLeastOnce, Some(1)));
+    assert_pkt!(client.recv(), PubAck(1));
+    client.send(&publish("boom", QosLv::AtLeastOnce, Some(2)));
+    client.expect_closed();
+}
+
+#[test]
+fn fault_rules_are_parsed() {
+    let config = Config::parse("\
+listener 127.0.0.1:1883
+fault * sensors/temp
+drop_ack 0.5
+delay_delivery 250
+fault sensor-1 *
+duplicate_qos1 1
+close_connection 0.01
+").unwrap();
+    assert_eq!(config.listeners.len(), 1);
+    assert_eq!(config.faults.len(), 2);
+    let (any_client, sensor) = (&config.faults[0], &config.faults[1]);
+    assert_eq!(any_client.client_id, None);
+    assert_eq!(any_client.topic, Some("sensors/temp".to_string()));
+    assert_eq!(any_client.drop_ack, 0.5);
+    assert_eq!(any_client.delay_delivery, Some(Duration::from_millis(250)));
+    assert_eq!(sensor.client_id, Some("sensor-1".to_string()));
+    assert_eq!(sensor.topic, None);
+    assert_eq!(sensor.duplicate_qos1, 1.0);
+    assert_eq!(sensor.close_connection, 0.01);
+    assert!(Config::parse("listener 127.0.0.1:1883\nfault *\n").is_err());
+    assert!(Config::parse("listener 127.0.0.1:1883\nfault * *\ndrop_ack 2\n").is_err());
+}
@@ -0,0 +1,34 @@
+// Builds the crate with each combination of its features, so that the core doesn't come to
+// depend on an optional dependency. Each build takes a while, so these only run with
+// `cargo test --features feature-matrix`.
+#![cfg(feature = "feature-matrix")]
+
+use std::env;
+use std::process::Command;
+
+// The feature flags of every combination worth building: the minimal build, the default one,
+// and each optional subsystem on top of the minimal build
+const MATRIX: &[&[&str]] = &[
+    &["--no-default-features"],
+    &[],
+    &["--no-default-features", "--features", "json-schema"],
+    &["--all-features"]
+];
+
+#[test]
+fn every_feature_combination_builds() {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let manifest_dir = env!("CARGO_MANIFEST_DIR");
+    for flags in MATRIX {
+        // A separate target directory, as the one running this test is locked
+        let output = Command::new(&cargo)
+            .args(&["build", "--lib", "--bins"])
+            .args(*flags)
+            .current_dir(manifest_dir)
+            .env("CARGO_TARGET_DIR", format!("{}/target/feature-matrix", manifest_dir))
+            .output()
+            .expect("failed to run cargo");
+        assert!(output.status.success(), "cargo build {} failed:\n{}", flags.join(" "),
+            String::from_utf8_lossy(&output.stderr));
+    }
+}
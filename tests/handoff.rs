@@ -0,0 +1,116 @@
+#![cfg(unix)]
+
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{ConnAckRetCode, CtrlPkt::*, QosLv};
+use mqtt_broker::{broker::{self, Broker}, control};
+use std::env;
+use std::fs;
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+
+#[test]
+fn sessions_and_retained_messages_move_to_another_broker() {
+    let path = env::temp_dir().join(format!("mqtt-broker-handoff-{}", process::id()));
+    let path = path.to_str().unwrap();
+    let old = Broker::new();
+    let old_addr = start(&old);
+    let mut opts = ConnectOptions::new("handed-over".to_string());
+    opts.set_clean_session(false);
+    let (mut client, _) = Client::connect(old_addr, &opts);
+    client.subscribe(1, vec![("jobs/#", QosLv::AtLeastOnce)]);
+    let _clean = Client::connect_id(old_addr, "clean-client");
+    old.set_retained("config/rate", QosLv::AtLeastOnce, b"10".to_vec());
+
+    assert_eq!(control::execute(&old, &format!("handoff export {}", path)),
+        format!("closed 2 connections; wrote 1 sessions and 1 retained messages to {}\n", path));
+    client.expect_closed();
+    let mut refused = Client::open(old_addr);
+    refused.send(&opts.build().unwrap());
+    assert_pkt!(refused.recv(), ConnAck { return_code: ConnAckRetCode::ServerUnavailable, .. });
+
+    let new = Broker::new();
+    let new_addr = start(&new);
+    assert_eq!(control::execute(&new, &format!("handoff import {}", path)),
+        "imported 1 sessions (0 already here) and 1 retained messages\n");
+    assert_eq!(new.client_subscriptions("handed-over"),
+        Some(vec![("jobs/#".to_string(), QosLv::AtLeastOnce)]));
+    assert_eq!(new.retained("config/rate"), Some((QosLv::AtLeastOnce, b"10".to_vec())));
+    let (_, session_present) = Client::connect(new_addr, &opts);
+    assert!(session_present);
+    assert_eq!(control::execute(&new, &format!("handoff import {}", path)),
+        "imported 0 sessions (1 already here) and 1 retained messages\n");
+
+    assert_eq!(control::execute(&old, "handoff cancel"), "accepting connections again\n");
+    Client::connect_id(old_addr, "after-cancel");
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn messages_accepted_during_an_export_are_handed_over() {
+    let path = env::temp_dir().join(format!("mqtt-broker-handoff-busy-{}", process::id()));
+    let path = path.to_str().unwrap();
+    let old = Broker::new();
+    let old_addr = start(&old);
+    let mut opts = ConnectOptions::new("offline-sub".to_string());
+    opts.set_clean_session(false);
+    let (mut sub, _) = Client::connect(old_addr, &opts);
+    sub.subscribe(1, vec![("jobs/#", QosLv::AtLeastOnce)]);
+    sub.send(&Disconnect);
+    sub.expect_closed();
+
+    let mut publisher = Client::connect_id(old_addr, "busy-pub");
+    let (started_tx, started_rx) = mpsc::channel();
+    // Publishes until the export closes the connection, staying under the subscriber's queue limit
+    let publishing = thread::spawn(move || {
+        let mut acked = 0;
+        for pkt_id in 1..broker::DEFAULT_MAX_QUEUED_MESSAGES as u16 {
+            let sent = publisher.try_send(&Publish {
+                dup: false,
+                qos_lv: QosLv::AtLeastOnce,
+                retain: false,
+                topic_name: "jobs/1".to_string(),
+                pkt_id: Some(pkt_id),
+                payload: b"job".to_vec()
+            });
+            match publisher.try_recv() {
+                Some(PubAck(id)) if sent && id == pkt_id => acked += 1,
+                None => break,
+                pkt => panic!("expected PUBACK {}, got {:?}", pkt_id, pkt)
+            }
+            if acked == 1 {
+                started_tx.send(()).unwrap();
+            }
+        }
+        acked
+    });
+    started_rx.recv().unwrap();
+    control::execute(&old, &format!("handoff export {}", path));
+    let acked = publishing.join().unwrap();
+
+    let new = Broker::new();
+    control::execute(&new, &format!("handoff import {}", path));
+    let queued = new.export_session("offline-sub").unwrap().queued.len();
+    assert!(queued >= acked, "{} messages acknowledged but {} handed over", acked, queued);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn invalid_handoff_files_import_nothing() {
+    let path = env::temp_dir().join(format!("mqtt-broker-handoff-invalid-{}", process::id()));
+    fs::write(&path, "{\"retained\": {\"topic\": \"a\", \"qos\": 0, \"payload\": \"\"}}\nnot json\n")
+        .unwrap();
+    let broker = Broker::new();
+    assert_eq!(control::execute(&broker, &format!("handoff import {}", path.display())),
+        format!("failed to import {}: line 2: expected a session or retained message\n",
+            path.display()));
+    assert_eq!(broker.retained("a"), None);
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,47 @@
+extern crate mqtt_broker;
+
+use mqtt_broker::inflight::Inflight;
+
+#[test]
+fn acks_remove_deliveries_in_any_order() {
+    let mut inflight = Inflight::new();
+    for pkt_id in 1..6 {
+        inflight.insert(pkt_id, pkt_id * 10);
+    }
+    assert_eq!(inflight.remove(3), Some(30));
+    assert_eq!(inflight.remove(3), None);
+    assert_eq!(inflight.remove(1), Some(10));
+    assert!(!inflight.contains(1));
+    assert_eq!(inflight.len(), 3);
+    assert_eq!(inflight.iter().collect::<Vec<_>>(), vec![(2, &20), (4, &40), (5, &50)]);
+}
+
+#[test]
+fn deliveries_stay_in_send_order() {
+    let mut inflight = Inflight::new();
+    // Packet ids wrap around, so send order isn't packet id order
+    inflight.insert(65535, "a");
+    inflight.insert(1, "b");
+    inflight.insert(2, "c");
+    // A reused packet id goes to the back
+    inflight.insert(65535, "d");
+    assert_eq!(inflight.iter().collect::<Vec<_>>(), vec![(1, &"b"), (2, &"c"), (65535, &"d")]);
+    for pkt_id in &[1, 2, 65535] {
+        inflight.remove(*pkt_id);
+    }
+    assert!(inflight.is_empty());
+}
+
+#[test]
+fn deliveries_are_stamped_with_their_epoch() {
+    let mut inflight = Inflight::new();
+    inflight.insert(1, "a");
+    assert_eq!(inflight.next_epoch(), 2);
+    inflight.insert(2, "b");
+    assert_eq!(inflight.epoch_of(1), Some(1));
+    assert_eq!(inflight.epoch_of(2), Some(2));
+    assert_eq!(inflight.epoch_of(3), None);
+    // Resending a delivery moves it into the current epoch
+    inflight.insert(1, "a");
+    assert_eq!(inflight.epoch_of(1), Some(2));
+}
@@ -0,0 +1,147 @@
+// Drives the mosquitto command line clients against the broker. They have to be on the PATH, so
+// these only run with `cargo test --features interop`.
+#![cfg(feature = "interop")]
+
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::{ctrlpkt::*, ctrlpkt::CtrlPkt::*};
+use std::net::SocketAddr;
+use std::process::{Child, Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn mosquitto(program: &str, addr: SocketAddr, args: &[&str]) -> Command {
+    let mut cmd = Command::new(program);
+    cmd.arg("-h").arg(addr.ip().to_string()).arg("-p").arg(addr.port().to_string()).args(args);
+    cmd
+}
+
+fn mosquitto_pub(addr: SocketAddr, args: &[&str]) -> Output {
+    let output = mosquitto("mosquitto_pub", addr, args).output()
+        .expect("failed to run mosquitto_pub");
+    assert!(output.status.success(), "mosquitto_pub failed: {}",
+        String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+// Starts a mosquitto_sub that exits after receiving `count` messages or after the timeout
+fn spawn_mosquitto_sub(addr: SocketAddr, count: usize, args: &[&str]) -> Child {
+    mosquitto("mosquitto_sub", addr, args)
+        .arg("-C").arg(count.to_string())
+        .arg("-W").arg(TIMEOUT_SECS.to_string())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run mosquitto_sub")
+}
+
+// mosquitto_sub gives no sign of when its subscription is in place, so keep publishing until it
+// has received a message and exited
+fn publish_until_exit(addr: SocketAddr, sub: Child, topic_name: &str, qos_lv: QosLv,
+                      payload: &[u8]) -> Output {
+    let mut publisher = Client::connect_id(addr, "interop-pub");
+    let mut sub = sub;
+    let started = Instant::now();
+    let mut pkt_id = 1;
+    while sub.try_wait().unwrap().is_none() {
+        assert!(started.elapsed() < Duration::from_secs(TIMEOUT_SECS * 2),
+            "mosquitto_sub didn't receive anything");
+        publisher.send(&Publish {
+            dup: false,
+            qos_lv,
+            retain: false,
+            topic_name: topic_name.to_string(),
+            pkt_id: if qos_lv == QosLv::AtMostOnce { None } else { Some(pkt_id) },
+            payload: payload.to_vec()
+        });
+        match qos_lv {
+            QosLv::AtMostOnce => (),
+            QosLv::AtLeastOnce => assert_pkt!(publisher.recv(), PubAck(_)),
+            QosLv::ExactlyOnce => {
+                assert_pkt!(publisher.recv(), PubRec(_));
+                publisher.send(&PubRel(pkt_id));
+                assert_pkt!(publisher.recv(), PubComp(_));
+            }
+        }
+        pkt_id += 1;
+        thread::sleep(Duration::from_millis(100));
+    }
+    let output = sub.wait_with_output().unwrap();
+    assert!(output.status.success(), "mosquitto_sub failed: {}",
+        String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+fn check_mosquitto_pub(qos: &str, qos_lv: QosLv) {
+    let addr = start_broker();
+    let mut sub = Client::connect_id(addr, "interop-sub");
+    sub.subscribe(1, vec![("interop", qos_lv)]);
+    mosquitto_pub(addr, &["-t", "interop", "-m", "hello", "-q", qos]);
+    match sub.recv() {
+        Publish { ref topic_name, ref payload, .. } => {
+            assert_eq!(topic_name, "interop");
+            assert_eq!(payload, b"hello");
+        }
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+}
+
+fn check_mosquitto_sub(qos: &str, qos_lv: QosLv) {
+    let addr = start_broker();
+    let sub = spawn_mosquitto_sub(addr, 1, &["-t", "interop", "-q", qos]);
+    let output = publish_until_exit(addr, sub, "interop", qos_lv, b"hello");
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "hello");
+}
+
+#[test]
+fn mosquitto_pub_qos0() {
+    check_mosquitto_pub("0", QosLv::AtMostOnce);
+}
+
+#[test]
+fn mosquitto_pub_qos1() {
+    check_mosquitto_pub("1", QosLv::AtLeastOnce);
+}
+
+#[test]
+fn mosquitto_pub_qos2() {
+    check_mosquitto_pub("2", QosLv::ExactlyOnce);
+}
+
+#[test]
+fn mosquitto_sub_qos0() {
+    check_mosquitto_sub("0", QosLv::AtMostOnce);
+}
+
+#[test]
+fn mosquitto_sub_qos1() {
+    check_mosquitto_sub("1", QosLv::AtLeastOnce);
+}
+
+#[test]
+fn mosquitto_sub_qos2() {
+    check_mosquitto_sub("2", QosLv::ExactlyOnce);
+}
+
+#[test]
+fn mosquitto_retained() {
+    let addr = start_broker();
+    mosquitto_pub(addr, &["-t", "interop/retained", "-m", "last", "-r"]);
+    let output = spawn_mosquitto_sub(addr, 1, &["-t", "interop/retained"])
+        .wait_with_output().unwrap();
+    assert!(output.status.success(), "mosquitto_sub timed out");
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "last");
+}
+
+#[test]
+fn mosquitto_sub_wildcards() {
+    let addr = start_broker();
+    let sub = spawn_mosquitto_sub(addr, 1, &["-t", "interop/+/temp", "-t", "interop/#", "-v"]);
+    let output = publish_until_exit(addr, sub, "interop/kitchen/temp", QosLv::AtMostOnce, b"21");
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "interop/kitchen/temp 21");
+}
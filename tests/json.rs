@@ -0,0 +1,43 @@
+extern crate mqtt_broker;
+
+use mqtt_broker::json::{parse, parse_payload, Json};
+
+#[test]
+fn round_trips() {
+    let value = Json::Object(vec![
+        ("name".to_string(), Json::String("say \"hi\"\n\u{1}é😀".to_string())),
+        ("count".to_string(), Json::Number(18446744073709551615)),
+        ("flags".to_string(), Json::Array(vec![Json::Bool(true), Json::Bool(false), Json::Null])),
+        ("empty".to_string(), Json::Object(vec![]))
+    ]);
+    let encoded = value.to_string();
+    assert_eq!(encoded, "{\"name\":\"say \\\"hi\\\"\\n\\u0001é😀\",\
+        \"count\":18446744073709551615,\"flags\":[true,false,null],\"empty\":{}}");
+    assert_eq!(parse(&encoded), Some(value));
+}
+
+#[test]
+fn whitespace_and_escapes_are_accepted() {
+    let value = parse(" { \"a\" : [ 1 , \"\\u00e9\\ud83d\\ude00\\/\" ] } ").unwrap();
+    assert_eq!(value.get("a").and_then(|a| a.as_array()).map(|a| a.len()), Some(2));
+    assert_eq!(value.get("a").unwrap().as_array().unwrap()[1].as_str(), Some("é😀/"));
+}
+
+#[test]
+fn invalid_input_is_rejected() {
+    let cases = ["", "{", "[1,]", "{\"a\"}", "nul", "-1", "1.5", "18446744073709551616",
+        "\"\\ud83d\"", "\"a\nb\"", "[] []"];
+    for s in cases.iter() {
+        assert_eq!(parse(s), None, "{}", s);
+    }
+}
+
+#[test]
+fn payloads_may_hold_any_number() {
+    let value = parse_payload("[-1, 1.5, 2e3, -0.25E-2, 7]").unwrap();
+    assert_eq!(value, Json::Array(vec![Json::Real(-1.0), Json::Real(1.5), Json::Real(2000.0),
+        Json::Real(-0.0025), Json::Number(7)]));
+    for s in ["01", "1.", "-", "1e", "-01.5"].iter() {
+        assert_eq!(parse_payload(s), None, "{}", s);
+    }
+}
@@ -0,0 +1,77 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::CtrlPkt::*;
+use mqtt_broker::broker::Broker;
+use mqtt_broker::clock::VirtualClock;
+use mqtt_broker::keepalive::KeepAliveStats;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn connect_with_keep_alive(addr: SocketAddr, client_id: &str, secs: u16) -> Client {
+    let mut opts = ConnectOptions::new(client_id.to_string());
+    opts.set_keep_alive(secs);
+    Client::connect(addr, &opts).0
+}
+
+#[test]
+fn values_are_sorted_into_buckets() {
+    let stats = KeepAliveStats::new();
+    for &secs in [0, 10, 11, 60, 3600].iter() {
+        stats.record_keep_alive(secs);
+    }
+    stats.record_gap(Duration::from_secs(5), 60);
+    stats.record_gap(Duration::from_millis(10500), 10);
+    stats.record_gap(Duration::from_secs(20), 10);
+    // Clients without a keep-alive have no period to compare with
+    stats.record_gap(Duration::from_secs(20), 0);
+    let histogram = stats.histogram();
+    assert_eq!(histogram.keep_alives, vec![1, 1, 1, 1, 0, 0, 0, 0, 1]);
+    assert_eq!(histogram.gaps, vec![1, 0, 0, 0, 1, 0, 1]);
+    assert!(histogram.suggestions().is_empty());
+}
+
+#[test]
+fn late_packets_and_short_keep_alives_get_suggestions() {
+    let stats = KeepAliveStats::new();
+    for i in 0..100 {
+        stats.record_keep_alive(5);
+        stats.record_gap(Duration::from_secs(if i < 20 { 7 } else { 4 }), 5);
+    }
+    stats.record_timeout();
+    let suggestions = stats.histogram().suggestions();
+    assert_eq!(suggestions.len(), 2);
+    assert!(suggestions[0].starts_with("20% of packets arrived more than a keep-alive period"));
+    assert!(suggestions[0].contains("1 connections timed out"));
+    assert!(suggestions[1].starts_with("100% of clients connect with a keep-alive of 10 seconds"));
+}
+
+#[test]
+fn packets_from_clients_are_timed() {
+    let clock = Arc::new(VirtualClock::new());
+    let broker = Broker::with_clock(clock.clone());
+    let addr = start(&broker);
+    let mut client = connect_with_keep_alive(addr, "pinger", 10);
+    clock.advance(Duration::from_secs(12));
+    client.send(&PingReq);
+    assert_pkt!(client.recv(), PingResp);
+    let stats = broker.keep_alive_stats();
+    assert_eq!(stats.keep_alives, vec![0, 1, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(stats.gaps, vec![0, 0, 0, 0, 1, 0, 0]);
+    assert_eq!(stats.timeouts, 0);
+}
+
+#[test]
+fn keep_alive_timeouts_are_counted() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut client = connect_with_keep_alive(addr, "sleeper", 1);
+    client.expect_closed();
+    assert_eq!(broker.keep_alive_stats().timeouts, 1);
+}
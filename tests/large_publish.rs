@@ -0,0 +1,115 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::bufpool::BufPool;
+use libmqtt::client::send_pooled;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{CtrlPkt, CtrlPkt::*, PublishRef, QosLv, LARGE_PUBLISH_LEN};
+use libmqtt::pktstream::PacketStream;
+use mqtt_broker::broker::Broker;
+use std::io::Cursor;
+use std::sync::Arc;
+
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| i as u8).collect()
+}
+
+fn publish(qos_lv: QosLv, pkt_id: Option<u16>, payload: Vec<u8>) -> CtrlPkt {
+    Publish {
+        dup: false,
+        qos_lv,
+        retain: false,
+        topic_name: "firmware".to_string(),
+        pkt_id,
+        payload
+    }
+}
+
+#[test]
+fn large_payloads_are_decoded_outside_the_packet_buffer() {
+    let pool = Arc::new(BufPool::new(1, 1024));
+    let pkts = vec![
+        publish(QosLv::AtMostOnce, None, payload(LARGE_PUBLISH_LEN)),
+        publish(QosLv::AtLeastOnce, Some(1), payload(4 * LARGE_PUBLISH_LEN)),
+        PingReq
+    ];
+    let mut bytes = vec![];
+    for pkt in &pkts {
+        send_pooled(&mut bytes, pkt, &pool).unwrap();
+    }
+    let decoded: Vec<CtrlPkt> = PacketStream::with_pool(Cursor::new(bytes), Arc::clone(&pool))
+        .map(|pkt| pkt.unwrap())
+        .collect();
+    assert_eq!(format!("{:?}", decoded), format!("{:?}", pkts));
+    // The packet buffer only ever held a topic name and packet id, so it stays in the pool
+    assert_eq!(pool.stats().idle, 1);
+}
+
+#[test]
+fn borrowed_publishes_encode_like_owned_ones() {
+    let payload = payload(100);
+    let pkt = PublishRef {
+        dup: true,
+        qos_lv: QosLv::ExactlyOnce,
+        retain: true,
+        topic_name: "a/b",
+        pkt_id: Some(7),
+        payload: &payload
+    };
+    let mut bytes = vec![];
+    pkt.write_to(&mut bytes).unwrap();
+    assert_eq!(bytes, Publish {
+        dup: true,
+        qos_lv: QosLv::ExactlyOnce,
+        retain: true,
+        topic_name: "a/b".to_string(),
+        pkt_id: Some(7),
+        payload: payload.clone()
+    }.serialize().unwrap());
+}
+
+#[test]
+fn large_publish_with_topic_longer_than_packet_is_rejected() {
+    let mut bytes = vec![];
+    send_pooled(&mut bytes, &publish(QosLv::AtMostOnce, None, payload(LARGE_PUBLISH_LEN)),
+        &BufPool::new(1, 1024)).unwrap();
+    // Remaining length 0x010000 + 10, then a topic name length of 0xffff
+    assert_eq!(&bytes[..6], &[0x30, 0x8a, 0x80, 0x04, 0x00, 0x08]);
+    bytes[4] = 0xff;
+    bytes[5] = 0xff;
+    let mut pkts = PacketStream::new(Cursor::new(bytes));
+    assert!(pkts.next().unwrap().is_err());
+    assert!(pkts.next().is_none());
+}
+
+#[test]
+fn large_payloads_reach_every_subscriber() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut online = Client::connect_id(addr, "large-online");
+    online.subscribe(1, vec![("firmware", QosLv::AtLeastOnce)]);
+    let mut opts = ConnectOptions::new("large-offline".to_string());
+    opts.set_clean_session(false);
+    let mut offline = Client::connect(addr, &opts).0;
+    offline.subscribe(1, vec![("firmware", QosLv::AtLeastOnce)]);
+    offline.send(&Disconnect);
+    offline.expect_closed();
+
+    let firmware = payload(16 * LARGE_PUBLISH_LEN);
+    let mut publisher = Client::connect_id(addr, "large-publisher");
+    publisher.send(&publish(QosLv::AtLeastOnce, Some(1), firmware.clone()));
+    assert_pkt!(publisher.recv(), PubAck(1));
+    match online.recv() {
+        Publish { ref payload, .. } => assert!(payload == &firmware),
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+    let mut offline = Client::connect(addr, &opts).0;
+    match offline.recv() {
+        Publish { ref payload, .. } => assert!(payload == &firmware),
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+}
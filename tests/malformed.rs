@@ -0,0 +1,210 @@
+// Sends deliberately malformed packets after a valid CONNECT. The spec requires the broker to
+// close the connection, and a bad packet must never take the broker down with it.
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{CtrlPkt::*, QosLv};
+use mqtt_broker::broker::{Broker, UnknownPacketPolicy};
+use std::net::SocketAddr;
+
+const CORPUS: &[(&str, &[u8])] = &[
+    // Fixed header
+    ("reserved packet type 0", &[0x00, 0x00]),
+    ("reserved packet type 15", &[0xf0, 0x00]),
+    ("remaining length with 5 bytes", &[0x30, 0xff, 0xff, 0xff, 0xff, 0x01]),
+    ("remaining length too short for packet id", &[0x40, 0x01, 0x00]),
+    // Reserved fixed header flags
+    ("CONNECT with flags 1", &[0x11, 0x00]),
+    ("PUBACK with flags 2", &[0x42, 0x02, 0x00, 0x01]),
+    ("PUBREL with flags 0", &[0x60, 0x02, 0x00, 0x01]),
+    ("PUBCOMP with flags 8", &[0x78, 0x02, 0x00, 0x01]),
+    ("PINGREQ with flags 1", &[0xc1, 0x00]),
+    ("DISCONNECT with flags 2", &[0xe2, 0x00]),
+    // PUBLISH
+    ("topic length past end of packet", &[0x30, 0x05, 0x00, 0x0a, b'a', b'b', b'c']),
+    ("topic length past end of packet, 0xffff", &[0x30, 0x03, 0xff, 0xff, b'a']),
+    ("topic not UTF-8", &[0x30, 0x04, 0x00, 0x02, 0xff, 0xfe]),
+    ("QoS 3", &[0x36, 0x05, 0x00, 0x01, b'a', 0x00, 0x01]),
+    ("QoS 1 without packet id", &[0x32, 0x03, 0x00, 0x01, b'a']),
+    // SUBSCRIBE
+    ("SUBSCRIBE with reserved flags 0", &[0x80, 0x06, 0x00, 0x01, 0x00, 0x01, b'a', 0x00]),
+    ("SUBSCRIBE without topic filters", &[0x82, 0x02, 0x00, 0x01]),
+    ("SUBSCRIBE filter truncated", &[0x82, 0x05, 0x00, 0x01, 0x00, 0x05, b'a']),
+    ("SUBSCRIBE without requested QoS", &[0x82, 0x05, 0x00, 0x01, 0x00, 0x01, b'a']),
+    ("SUBSCRIBE requesting QoS 3", &[0x82, 0x06, 0x00, 0x01, 0x00, 0x01, b'a', 0x03]),
+    ("SUBSCRIBE QoS reserved bits", &[0x82, 0x06, 0x00, 0x01, 0x00, 0x01, b'a', 0x04]),
+    // UNSUBSCRIBE
+    ("UNSUBSCRIBE with reserved flags 0", &[0xa0, 0x05, 0x00, 0x01, 0x00, 0x01, b'a']),
+    ("UNSUBSCRIBE without topic filters", &[0xa2, 0x02, 0x00, 0x01]),
+    ("UNSUBSCRIBE filter truncated", &[0xa2, 0x05, 0x00, 0x01, 0x00, 0x05, b'a']),
+    // Packet id 0
+    ("PUBLISH QoS 1 with packet id 0", &[0x32, 0x05, 0x00, 0x01, b'a', 0x00, 0x00]),
+    ("PUBACK with packet id 0", &[0x40, 0x02, 0x00, 0x00]),
+    ("PUBREL with packet id 0", &[0x62, 0x02, 0x00, 0x00]),
+    ("SUBSCRIBE with packet id 0", &[0x82, 0x06, 0x00, 0x00, 0x00, 0x01, b'a', 0x00]),
+    ("UNSUBSCRIBE with packet id 0", &[0xa2, 0x05, 0x00, 0x00, 0x00, 0x01, b'a']),
+    // Only sent by servers, closed by default
+    ("UNSUBACK", &[0xb0, 0x02, 0x00, 0x01])
+];
+
+// CONNECT for "MQTT" 3.1.1 with client id "c" and the given flags
+fn connect_with_flags(flags: u8) -> Vec<u8> {
+    vec![0x10, 13, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, flags, 0x00, 0x3c, 0x00, 0x01, b'c']
+}
+
+const CONNECT_FLAGS: &[(&str, u8)] = &[
+    ("reserved bit set", 0b00000011),
+    ("will QoS 3", 0b00011110),
+    ("will retain without will flag", 0b00100010),
+    ("will QoS without will flag", 0b00001010),
+    ("password without username", 0b01000010)
+];
+
+// The broker is still healthy if a new client can connect and get a PINGRESP
+fn assert_broker_alive(addr: SocketAddr) {
+    let mut client = Client::connect_id(addr, "malformed-check");
+    client.send(&PingReq);
+    assert_pkt!(client.recv(), PingResp);
+}
+
+#[test]
+fn malformed_packets_close_connection() {
+    let addr = start_broker();
+    for &(name, bytes) in CORPUS {
+        println!("Sending {}", name);
+        let mut client = Client::connect_id(addr, "malformed");
+        client.send_raw(bytes);
+        client.expect_closed();
+        assert_broker_alive(addr);
+    }
+}
+
+#[test]
+fn invalid_connect_flags_close_connection() {
+    let addr = start_broker();
+    for &(name, flags) in CONNECT_FLAGS {
+        println!("Sending CONNECT with {}", name);
+        let mut client = Client::open(addr);
+        client.send_raw(&connect_with_flags(flags));
+        client.expect_closed();
+    }
+    assert_broker_alive(addr);
+}
+
+#[test]
+fn topic_of_maximum_length_is_accepted() {
+    // A 0xffff-byte topic name is valid and its encoded length doesn't fit in a u16
+    let addr = start_broker();
+    let topic_name = "t".repeat(0xffff);
+    let mut sub = Client::connect_id(addr, "long-topic-sub");
+    sub.subscribe(1, vec![(&topic_name, QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr, "long-topic-pub");
+    publisher.send(&Publish {
+        dup: false,
+        qos_lv: QosLv::AtMostOnce,
+        retain: false,
+        topic_name: topic_name.clone(),
+        pkt_id: None,
+        payload: b"hello".to_vec()
+    });
+    match sub.recv() {
+        Publish { topic_name: ref t, ref payload, .. } => {
+            assert_eq!(t.len(), 0xffff);
+            assert_eq!(payload, b"hello");
+        }
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn four_byte_remaining_length_is_accepted() {
+    // 2 MiB needs a 4-byte remaining length
+    let addr = start_broker();
+    let payload = vec![0x5a; 2 * 1024 * 1024];
+    let mut sub = Client::connect_id(addr, "large-sub");
+    sub.subscribe(1, vec![("large", QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr, "large-pub");
+    publisher.send(&Publish {
+        dup: false,
+        qos_lv: QosLv::AtMostOnce,
+        retain: false,
+        topic_name: "large".to_string(),
+        pkt_id: None,
+        payload: payload.clone()
+    });
+    match sub.recv() {
+        Publish { payload: ref received, .. } => assert!(*received == payload),
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn http_requests_close_connection() {
+    let addr = start_broker();
+    for request in ["GET /mqtt HTTP/1.1\r\n", "OPTIONS * HTTP/1.1\r\n"].iter() {
+        let mut client = Client::open(addr);
+        client.send_raw(format!("{}Host: broker\r\n\r\n", request).as_bytes());
+        client.expect_closed();
+    }
+    assert_broker_alive(addr);
+}
+
+#[test]
+fn unknown_packets_can_be_ignored() {
+    let mut broker = Broker::new();
+    broker.set_unknown_packets(UnknownPacketPolicy::Ignore);
+    let addr = start(&broker);
+    let mut client = Client::connect_id(addr, "unsupported");
+    client.send_raw(&[0xb0, 0x02, 0x00, 0x01]);
+    client.send(&PingReq);
+    assert_pkt!(client.recv(), PingResp);
+    let info = broker.clients().into_iter().find(|c| c.client_id == "unsupported").unwrap();
+    assert_eq!(info.unknown_packets, 1);
+
+    // Before CONNECT the connection is closed anyway
+    let mut client = Client::open(addr);
+    client.send_raw(&[0xb0, 0x02, 0x00, 0x01]);
+    client.expect_closed();
+}
+
+#[test]
+fn decode_errors_are_counted_per_connection() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut opts = ConnectOptions::new("broken-device".to_string());
+    opts.set_clean_session(false);
+    let stats = || broker.decode_stats("broken-device").unwrap();
+
+    let (mut client, _) = Client::connect(addr, &opts);
+    client.send(&PingReq);
+    assert_pkt!(client.recv(), PingResp);
+    client.send(&PingReq);
+    assert_pkt!(client.recv(), PingResp);
+    // PUBACK with flags 2
+    client.send_raw(&[0x42, 0x02, 0x00, 0x01]);
+    client.expect_closed();
+    let decode = stats();
+    assert_eq!(decode.packets.iter().map(|(name, count)| (*name, *count)).collect::<Vec<_>>(),
+        vec![("CONNECT", 1), ("PINGREQ", 2)]);
+    assert_eq!((decode.malformed, decode.oversized, decode.unknown_flags), (0, 0, 1));
+
+    // Counting starts over with each connection
+    let (mut client, _) = Client::connect(addr, &opts);
+    client.send_raw(&[0x30, 0xff, 0xff, 0xff, 0xff, 0x01]);
+    client.expect_closed();
+    let decode = stats();
+    assert_eq!(decode.packets.len(), 1);
+    assert_eq!((decode.malformed, decode.oversized, decode.unknown_flags), (0, 1, 0));
+
+    let (mut client, _) = Client::connect(addr, &opts);
+    // Topic not UTF-8
+    client.send_raw(&[0x30, 0x04, 0x00, 0x02, 0xff, 0xfe]);
+    client.expect_closed();
+    assert_eq!(stats().malformed, 1);
+    assert!(broker.decode_stats("unknown").is_none());
+}
@@ -0,0 +1,40 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::ctrlpkt::{QosLv, SubAckRetCode};
+use mqtt_broker::broker::Broker;
+
+#[test]
+fn granted_qos_is_capped() {
+    let mut broker = Broker::new();
+    broker.set_max_qos(QosLv::AtLeastOnce);
+    let addr = start(&broker);
+    let mut client = Client::connect_id(addr, "capped");
+    let ret_codes = client.subscribe(1, vec![
+        ("a", QosLv::AtMostOnce),
+        ("b", QosLv::AtLeastOnce),
+        ("c", QosLv::ExactlyOnce)
+    ]);
+    assert_pkt!(ret_codes.as_slice(), &[
+        SubAckRetCode::MaxQos0,
+        SubAckRetCode::MaxQos1,
+        SubAckRetCode::MaxQos1
+    ]);
+    assert_eq!(broker.subscriptions(Some("c")),
+        vec![("c".to_string(), "capped".to_string(), QosLv::AtLeastOnce)]);
+}
+
+#[test]
+fn subscriptions_made_for_clients_are_capped() {
+    let mut broker = Broker::new();
+    broker.set_max_qos(QosLv::AtMostOnce);
+    let addr = start(&broker);
+    let _client = Client::connect_id(addr, "embed-capped");
+    broker.subscribe("embed-capped", "t", QosLv::ExactlyOnce).unwrap();
+    assert_eq!(broker.client_subscriptions("embed-capped"),
+        Some(vec![("t".to_string(), QosLv::AtMostOnce)]));
+}
@@ -0,0 +1,136 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{CtrlPkt, CtrlPkt::*, QosLv};
+use mqtt_broker::broker::{Broker, ClientInfo, MemoryAction, MemoryLimits};
+use std::net::SocketAddr;
+
+const PAYLOAD_LEN: usize = 4096;
+
+fn publish(topic_name: &str, qos_lv: QosLv) -> CtrlPkt {
+    Publish {
+        dup: false,
+        qos_lv,
+        retain: false,
+        topic_name: topic_name.to_string(),
+        pkt_id: if qos_lv == QosLv::AtMostOnce { None } else { Some(1) },
+        payload: vec![0; PAYLOAD_LEN]
+    }
+}
+
+fn client(broker: &Broker, client_id: &str) -> ClientInfo {
+    broker.clients().into_iter().find(|c| c.client_id == client_id).unwrap()
+}
+
+// A ceiling that one undelivered payload takes the client over
+fn start_limited(action: MemoryAction) -> (Broker, SocketAddr) {
+    let mut broker = Broker::new();
+    broker.set_memory_limits(MemoryLimits { max_bytes: Some(PAYLOAD_LEN / 2), action });
+    let addr = start(&broker);
+    (broker, addr)
+}
+
+// Returns the packet id of the delivery, which is left unacknowledged
+fn recv_publish(sub: &mut Client) -> u16 {
+    match sub.recv() {
+        Publish { pkt_id: Some(pkt_id), .. } => pkt_id,
+        pkt => panic!("expected a QoS 1 PUBLISH, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn inflight_messages_are_accounted_until_acknowledged() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "accounted-sub");
+    sub.subscribe(1, vec![("t", QosLv::AtLeastOnce)]);
+    let idle = client(&broker, "accounted-sub").memory_bytes;
+    let mut publisher = Client::connect_id(addr, "accounted-pub");
+    publisher.send(&publish("t", QosLv::AtLeastOnce));
+    let pkt_id = recv_publish(&mut sub);
+    assert!(client(&broker, "accounted-sub").memory_bytes >= idle + PAYLOAD_LEN);
+    sub.send(&PubAck(pkt_id));
+    wait_until("the delivery is acknowledged",
+        || client(&broker, "accounted-sub").memory_bytes == idle);
+}
+
+#[test]
+fn qos0_messages_are_dropped_over_the_ceiling() {
+    let (broker, addr) = start_limited(MemoryAction::DropQos0);
+    let mut sub = Client::connect_id(addr, "drop-sub");
+    sub.subscribe(1, vec![("big", QosLv::AtLeastOnce), ("small", QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr, "drop-pub");
+    publisher.send(&publish("big", QosLv::AtLeastOnce));
+    let pkt_id = recv_publish(&mut sub);
+    publisher.send(&publish("small", QosLv::AtMostOnce));
+    wait_until("the QoS 0 message is dropped", || client(&broker, "drop-sub").dropped == 1);
+    // QoS 1 messages are still delivered
+    publisher.send(&publish("big", QosLv::AtLeastOnce));
+    let second = recv_publish(&mut sub);
+    sub.send(&PubAck(pkt_id));
+    sub.send(&PubAck(second));
+    wait_until("the deliveries are acknowledged",
+        || client(&broker, "drop-sub").inflight == 0);
+    publisher.send(&publish("small", QosLv::AtMostOnce));
+    assert_pkt!(sub.recv(), Publish { qos_lv: QosLv::AtMostOnce, .. });
+}
+
+#[test]
+fn clients_over_the_ceiling_are_disconnected() {
+    let (_broker, addr) = start_limited(MemoryAction::Disconnect);
+    let mut sub = Client::connect_id(addr, "disconnect-sub");
+    sub.subscribe(1, vec![("t", QosLv::AtLeastOnce)]);
+    let mut publisher = Client::connect_id(addr, "disconnect-pub");
+    publisher.send(&publish("t", QosLv::AtLeastOnce));
+    recv_publish(&mut sub);
+    publisher.send(&publish("t", QosLv::AtMostOnce));
+    sub.expect_closed();
+}
+
+#[test]
+fn offline_sessions_stop_queueing_over_the_ceiling() {
+    let (broker, addr) = start_limited(MemoryAction::Disconnect);
+    let mut opts = ConnectOptions::new("offline-sub".to_string());
+    opts.set_clean_session(false);
+    let mut sub = Client::connect(addr, &opts).0;
+    sub.subscribe(1, vec![("t", QosLv::AtLeastOnce)]);
+    sub.send(&Disconnect);
+    sub.expect_closed();
+    let mut publisher = Client::connect_id(addr, "offline-pub");
+    for _ in 0..3 {
+        publisher.send(&publish("t", QosLv::AtLeastOnce));
+        assert_pkt!(publisher.recv(), PubAck(1));
+    }
+    wait_until("the messages are dispatched", || {
+        let info = client(&broker, "offline-sub");
+        info.queued + info.dropped as usize == 3
+    });
+    let info = client(&broker, "offline-sub");
+    assert_eq!((info.queued, info.dropped), (1, 2));
+}
+
+#[test]
+fn top_consumers_are_published() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut small = Client::connect_id(addr, "top-small");
+    small.subscribe(1, vec![("a", QosLv::AtMostOnce)]);
+    let mut large = Client::connect_id(addr, "top-large");
+    large.subscribe(1, vec![("t", QosLv::AtLeastOnce)]);
+    let mut publisher = Client::connect_id(addr, "top-pub");
+    publisher.send(&publish("t", QosLv::AtLeastOnce));
+    recv_publish(&mut large);
+    broker.publish_sys_stats();
+    let top = broker.retained("$SYS/broker/memory/top")
+        .map(|(_, payload)| String::from_utf8(payload).unwrap())
+        .unwrap();
+    let client_ids: Vec<&str> = top.lines().map(|line| line.split(' ').next().unwrap()).collect();
+    assert_eq!(client_ids, vec!["top-large", "top-small", "top-pub"]);
+    let bytes = client(&broker, "top-large").memory_bytes;
+    assert_eq!(top.lines().next(), Some(format!("top-large {}", bytes).as_str()));
+}
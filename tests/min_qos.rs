@@ -0,0 +1,62 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+mod common;
+
+use common::*;
+use libmqtt::ctrlpkt::{CtrlPkt::*, QosLv};
+use mqtt_broker::broker::{Broker, MinQosAction, MinQosRule, MinQosStats};
+
+fn publish(client: &mut Client, topic_name: &str, qos_lv: QosLv, retain: bool, payload: &[u8]) {
+    client.send(&Publish {
+        dup: false,
+        qos_lv,
+        retain,
+        topic_name: topic_name.to_string(),
+        pkt_id: if qos_lv == QosLv::AtMostOnce { None } else { Some(1) },
+        payload: payload.to_vec()
+    });
+    if qos_lv == QosLv::AtLeastOnce {
+        match client.recv() {
+            PubAck(1) => (),
+            pkt => panic!("expected a PUBACK, got {:?}", pkt)
+        }
+    }
+}
+
+fn rule(filter: &str, action: MinQosAction) -> MinQosRule {
+    MinQosRule { filter: filter.to_string(), qos_lv: QosLv::AtLeastOnce, action }
+}
+
+#[test]
+fn qos_0_commands_are_rejected() {
+    let mut broker = Broker::new();
+    broker.set_min_qos(vec![rule("commands/#", MinQosAction::Reject)]);
+    let addr = start(&broker);
+    let mut device = Client::connect_id(addr, "device");
+    device.subscribe(1, vec![("commands/device", QosLv::AtMostOnce)]);
+    let mut controller = Client::connect_id(addr, "controller");
+    publish(&mut controller, "commands/device", QosLv::AtMostOnce, false, b"lost");
+    publish(&mut controller, "commands/device", QosLv::AtLeastOnce, false, b"kept");
+    // Messages on a topic are delivered in order, so the rejected one would have come first
+    match device.recv() {
+        Publish { ref payload, .. } => assert_eq!(payload, b"kept"),
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+    assert_eq!(broker.min_qos_stats(), MinQosStats { rejected: 1, upgraded: 0 });
+}
+
+#[test]
+fn upgraded_messages_are_retained_at_the_minimum() {
+    let mut broker = Broker::new();
+    broker.set_min_qos(vec![
+        rule("commands/#", MinQosAction::Upgrade),
+        rule("#", MinQosAction::Reject)
+    ]);
+    let addr = start(&broker);
+    let mut controller = Client::connect_id(addr, "controller");
+    publish(&mut controller, "commands/lamp", QosLv::AtMostOnce, true, b"on");
+    wait_until("the command is retained", || broker.retained("commands/lamp").is_some());
+    assert_eq!(broker.retained("commands/lamp"), Some((QosLv::AtLeastOnce, b"on".to_vec())));
+    assert_eq!(broker.min_qos_stats(), MinQosStats { rejected: 0, upgraded: 1 });
+}
@@ -0,0 +1,179 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{ConnAckRetCode, CtrlPkt, CtrlPkt::*, QosLv};
+use mqtt_broker::broker::Broker;
+use std::net::SocketAddr;
+
+fn publish(topic_name: &str, retain: bool) -> CtrlPkt {
+    Publish {
+        dup: false,
+        qos_lv: QosLv::AtMostOnce,
+        retain,
+        topic_name: topic_name.to_string(),
+        pkt_id: None,
+        payload: b"hello".to_vec()
+    }
+}
+
+fn start_mounted(broker: &Broker, mount_point: &str) -> SocketAddr {
+    let mut listener_config = local_listener();
+    listener_config.mount_point = Some(mount_point.to_string());
+    start_listener(broker, listener_config)
+}
+
+fn start_tenants(broker: &Broker) -> SocketAddr {
+    let mut listener_config = local_listener();
+    listener_config.tenant_isolation = true;
+    start_listener(broker, listener_config)
+}
+
+fn connect_tenant(addr: SocketAddr, tenant: &str, client_id: &str) -> Client {
+    let mut opts = ConnectOptions::new(client_id.to_string());
+    opts.set_username(tenant.to_string());
+    Client::connect(addr, &opts).0
+}
+
+fn expect_publish(client: &mut Client, expected_topic: &str) {
+    match client.recv() {
+        Publish { ref topic_name, .. } => assert_eq!(topic_name, expected_topic),
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn mount_points_isolate_listeners() {
+    let broker = Broker::new();
+    let (addr_a, addr_b, addr) =
+        (start_mounted(&broker, "tenant-a/"), start_mounted(&broker, "tenant-b/"), start(&broker));
+    let mut sub_a = Client::connect_id(addr_a, "mount-sub-a");
+    sub_a.subscribe(1, vec![("t", QosLv::AtMostOnce)]);
+    let mut sub_b = Client::connect_id(addr_b, "mount-sub-b");
+    sub_b.subscribe(1, vec![("t", QosLv::AtMostOnce)]);
+    let mut sub = Client::connect_id(addr, "mount-sub");
+    sub.subscribe(1, vec![("tenant-a/t", QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr_a, "mount-pub");
+    publisher.send(&publish("t", false));
+    expect_publish(&mut sub_a, "t");
+    // The unmounted listener sees the full topic
+    expect_publish(&mut sub, "tenant-a/t");
+    sub_b.send(&PingReq);
+    assert_pkt!(sub_b.recv(), PingResp);
+    assert_eq!(broker.subscriptions(Some("tenant-b/t")).len(), 1);
+}
+
+#[test]
+fn mount_points_are_whole_levels() {
+    let broker = Broker::new();
+    let addr_a = start_mounted(&broker, "tenant-a");
+    let addr_ab = start_mounted(&broker, "tenant-ab/");
+    let mut sub_a = Client::connect_id(addr_a, "prefix-sub-a");
+    // Would be tenant-ab/# if mount points were plain string prefixes
+    sub_a.subscribe(1, vec![("b/#", QosLv::AtMostOnce), ("t", QosLv::AtMostOnce)]);
+    assert_eq!(broker.subscriptions(Some("tenant-a/b/#")).len(), 1);
+    let mut publisher_ab = Client::connect_id(addr_ab, "prefix-pub-ab");
+    publisher_ab.send(&publish("secret", false));
+    let mut publisher_a = Client::connect_id(addr_a, "prefix-pub-a");
+    publisher_a.send(&publish("t", false));
+    expect_publish(&mut sub_a, "t");
+}
+
+#[test]
+fn retained_messages_are_mounted() {
+    let broker = Broker::new();
+    let addr = start_mounted(&broker, "tenant-a/");
+    let mut publisher = Client::connect_id(addr, "mount-retainer");
+    publisher.send(&publish("status", true));
+    publisher.send(&PingReq);
+    assert_pkt!(publisher.recv(), PingResp);
+    assert_eq!(broker.retained_topics(), vec!["tenant-a/status"]);
+    let mut sub = Client::connect_id(addr, "mount-retained-sub");
+    sub.subscribe(1, vec![("#", QosLv::AtMostOnce)]);
+    expect_publish(&mut sub, "status");
+}
+
+#[test]
+fn tenants_only_see_their_own_topics() {
+    let broker = Broker::new();
+    let (tenants, addr) = (start_tenants(&broker), start(&broker));
+    let mut sub_a = connect_tenant(tenants, "alice", "sensor");
+    sub_a.subscribe(1, vec![("t", QosLv::AtMostOnce)]);
+    // The same client id in another tenant is a different client
+    let mut sub_b = connect_tenant(tenants, "bob", "sensor");
+    sub_b.subscribe(1, vec![("t", QosLv::AtMostOnce), ("alice/t", QosLv::AtMostOnce)]);
+    let mut publisher = connect_tenant(tenants, "alice", "tenant-pub");
+    publisher.send(&publish("t", false));
+    expect_publish(&mut sub_a, "t");
+    sub_b.send(&PingReq);
+    assert_pkt!(sub_b.recv(), PingResp);
+    // Crafted topics stay inside the publisher's namespace
+    publisher.send(&publish("../bob/t", false));
+    let mut bob_pub = connect_tenant(tenants, "bob", "tenant-pub");
+    bob_pub.send(&publish("t", false));
+    expect_publish(&mut sub_b, "t");
+    assert_eq!(broker.subscriptions(Some("bob/t")), vec![
+        ("bob/t".to_string(), "bob/sensor".to_string(), QosLv::AtMostOnce)]);
+    // Clients outside tenant listeners can subscribe across tenants
+    let mut admin = Client::connect_id(addr, "tenant-admin");
+    admin.subscribe(1, vec![("alice/t", QosLv::AtMostOnce)]);
+    publisher.send(&publish("t", false));
+    expect_publish(&mut admin, "alice/t");
+}
+
+#[test]
+fn dispatch_enforces_tenant_namespaces() {
+    let broker = Broker::new();
+    let (tenants, addr) = (start_tenants(&broker), start(&broker));
+    let mut sub = connect_tenant(tenants, "alice", "escapee");
+    sub.subscribe(1, vec![("t", QosLv::AtMostOnce)]);
+    // A subscription outside the namespace, however it got there, delivers nothing
+    let mut opts = ConnectOptions::new("alice/escapee".to_string());
+    opts.set_clean_session(false);
+    let (mut other, _) = Client::connect(addr, &opts);
+    other.subscribe(1, vec![("bob/t", QosLv::AtMostOnce)]);
+    // Reconnecting on the tenant listener keeps the session and its subscriptions
+    let mut opts = ConnectOptions::new("escapee".to_string());
+    opts.set_username("alice".to_string()).set_clean_session(false);
+    let (mut sub, _) = Client::connect(tenants, &opts);
+    assert_eq!(broker.subscriptions(Some("bob/t")).len(), 1);
+    let mut bob = connect_tenant(tenants, "bob", "tenant-pub");
+    bob.send(&publish("t", false));
+    bob.send(&PingReq);
+    assert_pkt!(bob.recv(), PingResp);
+    sub.send(&PingReq);
+    assert_pkt!(sub.recv(), PingResp);
+}
+
+#[test]
+fn tenant_listeners_refuse_clients_without_a_tenant() {
+    let broker = Broker::new();
+    let addr = start_tenants(&broker);
+    for username in [None, Some("a/b"), Some("#")].iter() {
+        let mut opts = ConnectOptions::new("no-tenant".to_string());
+        if let Some(username) = *username {
+            opts.set_username(username.to_string());
+        }
+        let mut client = Client::open(addr);
+        client.send(&opts.build().unwrap());
+        assert_pkt!(client.recv(), ConnAck { return_code: ConnAckRetCode::NotAuthorized, .. });
+        client.expect_closed();
+    }
+}
+
+#[test]
+fn pre_authenticated_listeners_have_a_fixed_tenant() {
+    let broker = Broker::new();
+    let mut listener_config = local_listener();
+    listener_config.tenant_isolation = true;
+    listener_config.principal = Some("alice".to_string());
+    let addr = start_listener(&broker, listener_config);
+    // The username sent is ignored
+    let mut client = connect_tenant(addr, "bob", "tool");
+    client.subscribe(1, vec![("t", QosLv::AtMostOnce)]);
+    assert_eq!(broker.subscriptions(Some("alice/t")).len(), 1);
+}
@@ -0,0 +1,229 @@
+// Runs scripted publishers and subscribers against a broker over in-memory connections, dropping
+// their connections at random points of the QoS 1 and 2 flows and resuming their persistent
+// sessions, and checks the delivery guarantees: QoS 1 messages arrive at least once and QoS 2
+// messages exactly once. Each run is driven by a seed, so a failing one can be replayed.
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{ConnAckRetCode, CtrlPkt, CtrlPkt::*, QosLv};
+use libmqtt::decoder::PacketDecoder;
+use mqtt_broker::broker::Broker;
+use mqtt_broker::transport::{MemoryTransport, Transport};
+use std::collections::HashSet;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const MESSAGES: usize = 100;
+const SEEDS: &[u64] = &[1, 2, 3, 4, 5];
+// How long the subscriber waits for more deliveries before moving on, and at the end of a run
+const POLL_MILLIS: u64 = 5;
+const DRAIN_MILLIS: u64 = 500;
+
+// xorshift64, so that runs are reproducible
+struct Rng(u64);
+
+impl Rng {
+    // Percent chance of returning true
+    fn chance(&mut self, percent: u64) -> bool {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % 100 < percent
+    }
+}
+
+// A client on an in-memory connection. Dropping it drops the connection.
+struct MemClient {
+    transport: MemoryTransport,
+    decoder: PacketDecoder
+}
+
+impl MemClient {
+    // Connects with a persistent session
+    fn connect(broker: &Broker, client_id: &str) -> MemClient {
+        let (transport, broker_end) = MemoryTransport::pair();
+        let broker = broker.clone();
+        thread::spawn(move || {
+            let _ = broker.handle_client(Box::new(broker_end), Arc::new(local_listener()));
+        });
+        let mut client = MemClient { transport, decoder: PacketDecoder::new() };
+        let mut opts = ConnectOptions::new(client_id.to_string());
+        opts.set_clean_session(false);
+        client.send(&opts.build().unwrap());
+        assert_pkt!(client.recv(), ConnAck { return_code: ConnAckRetCode::Accepted, .. });
+        client
+    }
+
+    fn send(&mut self, pkt: &CtrlPkt) {
+        self.transport.write_all(&pkt.serialize().unwrap()).unwrap();
+    }
+
+    fn recv(&mut self) -> CtrlPkt {
+        self.recv_within(Duration::from_secs(TIMEOUT_SECS)).expect("timed out waiting for a packet")
+    }
+
+    // The next packet, or None if none arrives within `timeout`
+    fn recv_within(&mut self, timeout: Duration) -> Option<CtrlPkt> {
+        self.transport.set_read_timeout(Some(timeout)).unwrap();
+        let mut buf = [0; 4096];
+        loop {
+            if let Some(pkt) = self.decoder.next_pkt() {
+                return Some(pkt.unwrap());
+            }
+            match self.transport.read(&mut buf) {
+                Ok(0) => panic!("connection closed by the broker"),
+                Ok(len) => self.decoder.push(&buf[..len]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return None,
+                Err(e) => panic!("read failed: {:?}", e)
+            }
+        }
+    }
+}
+
+// Counts what it receives on "t", where payloads are message numbers. QoS 2 packet ids it has
+// received but not seen released are kept across connections, as the spec asks of clients.
+struct Subscriber {
+    client: MemClient,
+    qos_lv: QosLv,
+    unreleased: HashSet<u16>,
+    received: Vec<usize>
+}
+
+impl Subscriber {
+    fn connect(broker: &Broker, qos_lv: QosLv) -> Subscriber {
+        let mut client = MemClient::connect(broker, "sub");
+        client.send(&Subscribe { pkt_id: 1, subs: vec![("t".to_string(), qos_lv)] });
+        assert_pkt!(client.recv(), SubAck { .. });
+        Subscriber { client, qos_lv, unreleased: HashSet::new(), received: vec![0; MESSAGES] }
+    }
+
+    fn reconnect(&mut self, broker: &Broker) {
+        self.client = MemClient::connect(broker, "sub");
+    }
+
+    // Handles deliveries until none arrives for `wait`, dropping the connection at random before
+    // acknowledging one. Returns false if it did.
+    fn poll(&mut self, broker: &Broker, rng: &mut Rng, percent: u64, wait: Duration) -> bool {
+        while let Some(pkt) = self.client.recv_within(wait) {
+            if rng.chance(percent) {
+                self.reconnect(broker);
+                return false;
+            }
+            match pkt {
+                Publish { qos_lv: QosLv::AtLeastOnce, pkt_id: Some(pkt_id), ref payload, .. } => {
+                    self.count(payload);
+                    self.client.send(&PubAck(pkt_id));
+                }
+                Publish { qos_lv: QosLv::ExactlyOnce, pkt_id: Some(pkt_id), ref payload, .. } => {
+                    if self.unreleased.insert(pkt_id) {
+                        self.count(payload);
+                    }
+                    self.client.send(&PubRec(pkt_id));
+                }
+                PubRel(pkt_id) => {
+                    self.unreleased.remove(&pkt_id);
+                    self.client.send(&PubComp(pkt_id));
+                }
+                pkt => panic!("unexpected {:?} for a QoS {} subscriber", pkt, self.qos_lv as u8)
+            }
+        }
+        true
+    }
+
+    fn count(&mut self, payload: &[u8]) {
+        let n: usize = String::from_utf8_lossy(payload).parse().unwrap();
+        self.received[n] += 1;
+    }
+}
+
+// Publishes message `n` until the broker has taken responsibility for it, resending it with DUP (or
+// resending the PUBREL, for QoS 2) after losing the connection at random
+fn publish(broker: &Broker, publisher: &mut MemClient, rng: &mut Rng, percent: u64, n: usize,
+           qos_lv: QosLv) {
+    let pkt_id = n as u16 + 1;
+    let mut dup = false;
+    let mut received = false;
+    loop {
+        if received {
+            publisher.send(&PubRel(pkt_id));
+        } else {
+            publisher.send(&Publish {
+                dup,
+                qos_lv,
+                retain: false,
+                topic_name: "t".to_string(),
+                pkt_id: Some(pkt_id),
+                payload: n.to_string().into_bytes()
+            });
+        }
+        if rng.chance(percent) {
+            *publisher = MemClient::connect(broker, "pub");
+            dup = true;
+            continue;
+        }
+        match publisher.recv() {
+            PubAck(id) | PubComp(id) if id == pkt_id => return,
+            PubRec(id) if id == pkt_id => received = true,
+            pkt => panic!("unexpected {:?} for packet id {}", pkt, pkt_id)
+        }
+    }
+}
+
+// Returns how many times each message reached the subscriber
+fn run(seed: u64, qos_lv: QosLv, percent: u64) -> Vec<usize> {
+    let mut rng = Rng(seed);
+    let broker = Broker::new();
+    let mut sub = Subscriber::connect(&broker, qos_lv);
+    let mut publisher = MemClient::connect(&broker, "pub");
+    for n in 0..MESSAGES {
+        publish(&broker, &mut publisher, &mut rng, percent, n, qos_lv);
+        if rng.chance(percent) {
+            sub.reconnect(&broker);
+        }
+        sub.poll(&broker, &mut rng, percent, Duration::from_millis(POLL_MILLIS));
+    }
+    // Whatever is still queued or in flight, without dropping the connection again
+    sub.reconnect(&broker);
+    while !sub.poll(&broker, &mut rng, 0, Duration::from_millis(DRAIN_MILLIS)) {}
+    sub.received
+}
+
+fn check(qos_lv: QosLv, percent: u64, ok: fn(usize) -> bool) {
+    for &seed in SEEDS {
+        let received = run(seed, qos_lv, percent);
+        let wrong: Vec<(usize, usize)> = received.iter().cloned().enumerate()
+            .filter(|&(_, count)| !ok(count))
+            .collect();
+        assert!(wrong.is_empty(), "seed {}: (message, deliveries) {:?}", seed, wrong);
+    }
+}
+
+fn at_least_once(count: usize) -> bool {
+    count >= 1
+}
+
+fn exactly_once(count: usize) -> bool {
+    count == 1
+}
+
+#[test]
+fn qos1_messages_are_delivered_without_disconnects() {
+    check(QosLv::AtLeastOnce, 0, at_least_once);
+}
+
+#[test]
+fn qos1_messages_are_delivered_at_least_once_across_disconnects() {
+    check(QosLv::AtLeastOnce, 10, at_least_once);
+}
+
+#[test]
+fn qos2_messages_are_delivered_exactly_once_across_disconnects() {
+    check(QosLv::ExactlyOnce, 10, exactly_once);
+}
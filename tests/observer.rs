@@ -0,0 +1,144 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{CtrlPkt, CtrlPkt::*, ProtocolLv, QosLv};
+use mqtt_broker::broker::{Broker, ConnectionParams, QueueLimits};
+use mqtt_broker::observer::{DispatchObserver, DropReason, ObservedMessage, Observer};
+use std::sync::{Arc, Mutex};
+
+// Records every event as a line, with the message id
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<String>>>);
+
+impl Recorder {
+    fn push(&self, msg: &ObservedMessage, event: String) {
+        self.0.lock().unwrap().push(format!("{} {}", msg.id, event));
+    }
+
+    fn events(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl DispatchObserver for Recorder {
+    fn received(&self, msg: &ObservedMessage) {
+        self.push(msg, format!("received from {} on {} qos={} payload={}", msg.sender_id,
+            msg.topic_name, msg.qos_lv as u8, String::from_utf8_lossy(&msg.payload)));
+    }
+
+    fn matched(&self, msg: &ObservedMessage, subscribers: usize) {
+        self.push(msg, format!("matched {}", subscribers));
+    }
+
+    fn enqueued(&self, msg: &ObservedMessage, client_id: &str) {
+        self.push(msg, format!("enqueued for {}", client_id));
+    }
+
+    fn delivered(&self, msg: &ObservedMessage, client_id: &str, qos_lv: QosLv) {
+        self.push(msg, format!("delivered to {} qos={}", client_id, qos_lv as u8));
+    }
+
+    fn dropped(&self, msg: &ObservedMessage, client_id: Option<&str>, reason: DropReason) {
+        self.push(msg, format!("dropped for {:?}: {:?}", client_id, reason));
+    }
+}
+
+// Records the parameters of every connection
+#[derive(Clone, Default)]
+struct Connections(Arc<Mutex<Vec<(String, ConnectionParams)>>>);
+
+impl DispatchObserver for Connections {
+    fn connected(&self, client_id: &str, params: &ConnectionParams) {
+        self.0.lock().unwrap().push((client_id.to_string(), params.clone()));
+    }
+}
+
+fn publish(qos_lv: QosLv, pkt_id: Option<u16>) -> CtrlPkt {
+    Publish {
+        dup: false,
+        qos_lv,
+        retain: false,
+        topic_name: "t".to_string(),
+        pkt_id,
+        payload: b"hello".to_vec()
+    }
+}
+
+fn wait_for_events(recorder: &Recorder, n: usize) -> Vec<String> {
+    wait_until("observer events", || recorder.events().len() >= n);
+    let mut events = recorder.events();
+    // Subscribers are sent to in no particular order
+    events.sort();
+    events
+}
+
+#[test]
+fn messages_are_followed_through_dispatch() {
+    let recorder = Recorder::default();
+    let mut broker = Broker::new();
+    broker.set_dispatch_observer(Some(Observer::new(recorder.clone())))
+        .set_queue_limits(QueueLimits { max_messages: 1, max_age: None });
+    let addr = start(&broker);
+    let mut online = Client::connect_id(addr, "online");
+    online.subscribe(1, vec![("t", QosLv::AtLeastOnce)]);
+    let mut opts = ConnectOptions::new("offline".to_string());
+    opts.set_clean_session(false);
+    let mut offline = Client::connect(addr, &opts).0;
+    offline.subscribe(1, vec![("t", QosLv::AtLeastOnce)]);
+    offline.send(&Disconnect);
+    offline.expect_closed();
+
+    let mut publisher = Client::connect_id(addr, "publisher");
+    publisher.send(&publish(QosLv::AtLeastOnce, Some(1)));
+    assert_pkt!(publisher.recv(), PubAck(1));
+    assert_pkt!(online.recv(), Publish { .. });
+    assert_eq!(wait_for_events(&recorder, 4), vec![
+        "1 delivered to online qos=1",
+        "1 enqueued for offline",
+        "1 matched 2",
+        "1 received from publisher on t qos=1 payload=hello"
+    ]);
+
+    // The offline session's queue only has room for one message
+    publisher.send(&publish(QosLv::AtLeastOnce, Some(2)));
+    assert_pkt!(publisher.recv(), PubAck(2));
+    assert_pkt!(online.recv(), Publish { .. });
+    assert_eq!(wait_for_events(&recorder, 8)[4..].to_vec(), vec![
+        "2 delivered to online qos=1",
+        "2 dropped for Some(\"offline\"): QueueFull",
+        "2 matched 2",
+        "2 received from publisher on t qos=1 payload=hello"
+    ]);
+
+    let _offline = Client::connect(addr, &opts).0;
+    wait_for_events(&recorder, 9);
+    assert_eq!(recorder.events()[8], "1 delivered to offline qos=1");
+}
+
+#[test]
+fn connections_are_reported_with_their_parameters() {
+    let connections = Connections::default();
+    let mut broker = Broker::new();
+    broker.set_dispatch_observer(Some(Observer::new(connections.clone())))
+        .set_max_qos(QosLv::AtLeastOnce);
+    let addr = start(&broker);
+    let mut opts = ConnectOptions::new("params".to_string());
+    opts.set_clean_session(false).set_keep_alive(30).set_username("fleet".to_string());
+    let _client = Client::connect(addr, &opts).0;
+    let params = ConnectionParams {
+        protocol_lv: ProtocolLv::V311,
+        keep_alive: 30,
+        clean_session: false,
+        session_present: false,
+        username: Some("fleet".to_string()),
+        max_qos: QosLv::AtLeastOnce
+    };
+    wait_until("the connection is reported", || !connections.0.lock().unwrap().is_empty());
+    assert_eq!(*connections.0.lock().unwrap(), vec![("params".to_string(), params.clone())]);
+    assert_eq!(broker.clients()[0].params, Some(params));
+}
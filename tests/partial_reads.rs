@@ -0,0 +1,146 @@
+// Feeds packets to the decoders in pieces, down to one byte at a time, as a non-blocking transport
+// would hand them over. Every split must decode to the same packets as the whole buffer.
+extern crate libmqtt;
+
+use libmqtt::connopts::{ConnectOptions, Will};
+use libmqtt::ctrlpkt::{ConnAckRetCode, CtrlPkt, CtrlPkt::*, QosLv, SubAckRetCode,
+                       LARGE_PUBLISH_LEN};
+use libmqtt::decoder::PacketDecoder;
+use libmqtt::error::Error;
+use libmqtt::pktstream::PacketStream;
+use std::io::{self, ErrorKind, Read};
+
+fn publish(qos_lv: QosLv, pkt_id: Option<u16>, payload: Vec<u8>) -> CtrlPkt {
+    Publish { dup: false, qos_lv, retain: true, topic_name: "a/b".to_string(), pkt_id, payload }
+}
+
+// One of every packet type, with every optional field
+fn corpus() -> Vec<CtrlPkt> {
+    let mut opts = ConnectOptions::new("partial".to_string());
+    let mut will = Will::new("last/will".to_string(), b"gone".to_vec());
+    will.set_qos_lv(QosLv::AtLeastOnce);
+    opts.set_will(will).set_username("user".to_string()).set_password(b"secret".to_vec());
+    vec![
+        opts.build().unwrap(),
+        ConnAck { session_present: true, return_code: ConnAckRetCode::Accepted },
+        publish(QosLv::AtMostOnce, None, b"hello".to_vec()),
+        publish(QosLv::ExactlyOnce, Some(2), vec![]),
+        // A remaining length of 3 bytes
+        publish(QosLv::AtLeastOnce, Some(1), vec![7; 20000]),
+        // Read past the packet buffer
+        publish(QosLv::AtLeastOnce, Some(3), vec![8; LARGE_PUBLISH_LEN]),
+        PubAck(1),
+        PubRec(2),
+        PubRel(2),
+        PubComp(2),
+        Subscribe { pkt_id: 4, subs: vec![("a/#".to_string(), QosLv::AtLeastOnce),
+            ("b".to_string(), QosLv::ExactlyOnce)] },
+        SubAck { pkt_id: 4, sub_ack_ret_codes: vec![SubAckRetCode::MaxQos1,
+            SubAckRetCode::Failure] },
+        Unsubscribe { pkt_id: 5, topic_filters: vec!["a/#".to_string()] },
+        UnsubAck(5),
+        PingReq,
+        PingResp,
+        Disconnect
+    ]
+}
+
+fn corpus_bytes() -> Vec<u8> {
+    corpus().iter().flat_map(|pkt| pkt.serialize().unwrap()).collect()
+}
+
+// Hands out at most `chunk` bytes per read, with an interruption before each one
+struct Trickle {
+    bytes: Vec<u8>,
+    pos: usize,
+    chunk: usize,
+    interrupted: bool
+}
+
+impl Trickle {
+    fn new(bytes: Vec<u8>, chunk: usize) -> Trickle {
+        Trickle { bytes, pos: 0, chunk, interrupted: false }
+    }
+}
+
+impl Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.interrupted = !self.interrupted;
+        if self.interrupted {
+            return Err(io::Error::new(ErrorKind::Interrupted, "interrupted"));
+        }
+        let len = buf.len().min(self.chunk).min(self.bytes.len() - self.pos);
+        buf[..len].copy_from_slice(&self.bytes[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+fn decode_in_chunks(bytes: &[u8], chunk: usize) -> Vec<CtrlPkt> {
+    let mut decoder = PacketDecoder::new();
+    let mut pkts = vec![];
+    for piece in bytes.chunks(chunk) {
+        decoder.push(piece);
+        while let Some(pkt) = decoder.next_pkt() {
+            pkts.push(pkt.unwrap());
+        }
+    }
+    assert_eq!(decoder.buffered(), 0);
+    pkts
+}
+
+#[test]
+fn packet_stream_tolerates_short_and_interrupted_reads() {
+    let expected = format!("{:?}", corpus());
+    for &chunk in [1, 2, 3, 7].iter() {
+        let pkts: Vec<CtrlPkt> = PacketStream::new(Trickle::new(corpus_bytes(), chunk))
+            .map(|pkt| pkt.unwrap())
+            .collect();
+        assert_eq!(format!("{:?}", pkts), expected, "{} bytes per read", chunk);
+    }
+}
+
+#[test]
+fn decoder_accepts_any_split() {
+    let (bytes, expected) = (corpus_bytes(), format!("{:?}", corpus()));
+    for &chunk in [1, 2, 3, 5, 64, bytes.len()].iter() {
+        assert_eq!(format!("{:?}", decode_in_chunks(&bytes, chunk)), expected,
+            "{} bytes per push", chunk);
+    }
+}
+
+#[test]
+fn decoder_waits_for_every_byte_of_the_header() {
+    // PUBACK 1, then the first byte of a PINGREQ
+    let mut decoder = PacketDecoder::new();
+    for &byte in [0x40, 0x02, 0x00].iter() {
+        decoder.push(&[byte]);
+        assert!(decoder.next_pkt().is_none());
+    }
+    decoder.push(&[0x01, 0xc0]);
+    match decoder.next_pkt() {
+        Some(Ok(PubAck(1))) => (),
+        pkt => panic!("expected PUBACK 1, got {:?}", pkt)
+    }
+    assert!(decoder.next_pkt().is_none());
+    assert_eq!(decoder.buffered(), 1);
+    decoder.push(&[0x00]);
+    match decoder.next_pkt() {
+        Some(Ok(PingReq)) => (),
+        pkt => panic!("expected PINGREQ, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn decoder_rejects_a_remaining_length_over_4_bytes() {
+    let mut decoder = PacketDecoder::new();
+    for &byte in [0x30, 0xff, 0xff, 0xff].iter() {
+        decoder.push(&[byte]);
+        assert!(decoder.next_pkt().is_none());
+    }
+    decoder.push(&[0xff]);
+    match decoder.next_pkt() {
+        Some(Err(Error::MalformedRemainingLen)) => (),
+        pkt => panic!("expected MalformedRemainingLen, got {:?}", pkt)
+    }
+}
@@ -0,0 +1,49 @@
+#![cfg(unix)]
+extern crate mqtt_broker;
+
+use mqtt_broker::error::Error;
+use mqtt_broker::passwd;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::PathBuf;
+use std::{env, process};
+
+fn passwd_file(name: &str, contents: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("mqtt-broker-passwd-{}-{}", name, process::id()));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn read(path: &PathBuf) -> String {
+    let mut contents = String::new();
+    File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+    contents
+}
+
+#[test]
+fn users_are_added_and_updated_in_place() {
+    let path = passwd_file("edit", "# broker users\nalice:old\n\nbob:old\n");
+    assert!(!passwd::set(&path, "alice", "new").unwrap());
+    assert!(passwd::set(&path, "carol", "first").unwrap());
+    assert_eq!(read(&path), "# broker users\nalice:new\n\nbob:old\ncarol:first\n");
+}
+
+#[test]
+fn missing_files_are_created() {
+    let path = passwd_file("create", "");
+    fs::remove_file(&path).unwrap();
+    assert!(passwd::set(&path, "alice", "first").unwrap());
+    assert_eq!(read(&path), "alice:first\n");
+}
+
+#[test]
+fn usernames_that_break_the_format_are_rejected() {
+    let path = passwd_file("invalid", "alice:old\n");
+    for username in &["", "a:b", "a b"] {
+        match passwd::set(&path, username, "new") {
+            Err(Error::Passwd(_)) => (),
+            res => panic!("expected {:?} to be rejected, got {:?}", username, res)
+        }
+    }
+    assert_eq!(read(&path), "alice:old\n");
+}
@@ -0,0 +1,82 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::CtrlPkt::*;
+use libmqtt::ctrlpkt::QosLv;
+use mqtt_broker::broker::{Broker, PersistentSessions};
+use std::net::SocketAddr;
+
+// Connects without a clean session, disconnects, and returns whether the session was kept
+fn session_kept(addr: SocketAddr, opts: &mut ConnectOptions) -> bool {
+    opts.set_clean_session(false);
+    let (mut client, _) = Client::connect(addr, opts);
+    client.send(&Disconnect);
+    client.expect_closed();
+    let (_, session_present) = Client::connect(addr, opts);
+    session_present
+}
+
+#[test]
+fn only_allowed_clients_keep_sessions() {
+    let mut broker = Broker::new();
+    broker.set_persistent_sessions(Some(PersistentSessions {
+        client_ids: vec!["gateway-*".to_string()],
+        usernames: vec!["fleet".to_string()]
+    }));
+    let addr = start(&broker);
+    assert!(session_kept(addr, &mut ConnectOptions::new("gateway-1".to_string())));
+    assert!(!session_kept(addr, &mut ConnectOptions::new("sensor-1".to_string())));
+    let mut opts = ConnectOptions::new("truck-7".to_string());
+    opts.set_username("fleet".to_string());
+    assert!(session_kept(addr, &mut opts));
+}
+
+// The client id's inflight count and stale ack count
+fn acks(broker: &Broker, client_id: &str) -> (usize, u64) {
+    let client = broker.clients().into_iter().find(|c| c.client_id == client_id).unwrap();
+    (client.inflight, client.stale_acks)
+}
+
+fn recv_pkt_id(client: &mut Client) -> u16 {
+    match client.recv() {
+        Publish { pkt_id: Some(pkt_id), .. } => pkt_id,
+        pkt => panic!("expected a QoS 1 PUBLISH, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn unacknowledged_deliveries_are_resent_on_resume() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut opts = ConnectOptions::new("meter-3".to_string());
+    opts.set_clean_session(false);
+    let (mut meter, _) = Client::connect(addr, &opts);
+    assert!(broker.push_message("meter-3", "meters/3", QosLv::AtLeastOnce, b"a".to_vec()).unwrap());
+    let old_pkt_id = recv_pkt_id(&mut meter);
+    meter.send(&Disconnect);
+    meter.expect_closed();
+
+    let (mut meter, session_present) = Client::connect(addr, &opts);
+    assert!(session_present);
+    // Resent with its packet id, ahead of anything new
+    match meter.recv() {
+        Publish { dup: true, pkt_id: Some(pkt_id), ref payload, .. } => {
+            assert_eq!((pkt_id, payload.as_slice()), (old_pkt_id, &b"a"[..]));
+        }
+        pkt => panic!("expected the resent PUBLISH, got {:?}", pkt)
+    }
+    assert!(broker.push_message("meter-3", "meters/3", QosLv::AtLeastOnce, b"b".to_vec()).unwrap());
+    let new_pkt_id = recv_pkt_id(&mut meter);
+    assert_eq!(broker.clients()[0].epoch, 2);
+    let unknown_pkt_id = (1..).find(|&id| id != old_pkt_id && id != new_pkt_id).unwrap();
+    meter.send(&PubAck(unknown_pkt_id));
+    wait_until("an unknown ack is counted", || acks(&broker, "meter-3") == (2, 1));
+    meter.send(&PubAck(old_pkt_id));
+    wait_until("the resent delivery is acked", || acks(&broker, "meter-3") == (1, 1));
+    meter.send(&PubAck(new_pkt_id));
+    wait_until("the new delivery is acked", || acks(&broker, "meter-3") == (0, 1));
+}
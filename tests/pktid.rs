@@ -0,0 +1,16 @@
+extern crate libmqtt;
+
+use libmqtt::pktid::PktIdGen;
+use std::collections::HashSet;
+use std::u16;
+
+#[test]
+fn every_packet_id_but_zero_is_handed_out() {
+    let mut pkt_id_gen = PktIdGen::new();
+    let pkt_ids: HashSet<u16> = (0..u16::MAX).map(|_| pkt_id_gen.gen().unwrap()).collect();
+    assert_eq!(pkt_ids.len(), u16::MAX as usize);
+    assert!(!pkt_ids.contains(&0));
+    assert_eq!(pkt_id_gen.gen(), None);
+    pkt_id_gen.rm(7);
+    assert_eq!(pkt_id_gen.gen(), Some(7));
+}
@@ -0,0 +1,29 @@
+#![cfg(target_os = "linux")]
+extern crate mqtt_broker;
+
+use mqtt_broker::broker::Broker;
+use mqtt_broker::process;
+use std::fs::File;
+use std::sync::mpsc;
+use std::thread;
+
+#[test]
+fn process_stats_are_read_from_proc_and_published() {
+    let before = process::stats();
+    assert!(before.rss_bytes.unwrap() > 0);
+    let (tx, rx) = mpsc::channel::<()>();
+    let waiter = thread::spawn(move || { let _ = rx.recv(); });
+    let file = File::open("/proc/self/status").unwrap();
+    let during = process::stats();
+    assert!(during.threads.unwrap() > before.threads.unwrap());
+    assert!(during.open_fds.unwrap() > before.open_fds.unwrap());
+    drop((tx, file));
+    waiter.join().unwrap();
+    // Checked in the same test, as the broker's threads would throw off the counts above
+    let broker = Broker::new();
+    broker.publish_sys_stats();
+    for name in ["rss_bytes", "open_fds", "threads"].iter() {
+        let (_, value) = broker.retained(&format!("$SYS/broker/process/{}", name)).unwrap();
+        assert!(String::from_utf8(value).unwrap().parse::<u64>().unwrap() > 0);
+    }
+}
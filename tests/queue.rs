@@ -0,0 +1,137 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{CtrlPkt, CtrlPkt::*, QosLv};
+use mqtt_broker::broker::{Broker, ClientInfo, QueueLimits};
+use mqtt_broker::clock::VirtualClock;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn publish(pkt_id: u16, payload: &[u8]) -> CtrlPkt {
+    Publish {
+        dup: false,
+        qos_lv: QosLv::AtLeastOnce,
+        retain: false,
+        topic_name: "cmd".to_string(),
+        pkt_id: Some(pkt_id),
+        payload: payload.to_vec()
+    }
+}
+
+fn client(broker: &Broker, client_id: &str) -> ClientInfo {
+    broker.clients().into_iter().find(|c| c.client_id == client_id).unwrap()
+}
+
+fn connect_persistent(addr: SocketAddr, client_id: &str) -> (Client, bool) {
+    let mut opts = ConnectOptions::new(client_id.to_string());
+    opts.set_clean_session(false);
+    Client::connect(addr, &opts)
+}
+
+// Subscribes a persistent session to `cmd` and takes it offline
+fn go_offline(broker: &Broker, addr: SocketAddr, client_id: &str) {
+    let (mut sub, _) = connect_persistent(addr, client_id);
+    sub.subscribe(1, vec![("cmd", QosLv::AtLeastOnce)]);
+    assert!(broker.kick(client_id).unwrap());
+    sub.expect_closed();
+}
+
+// Asserts that nothing was sent ahead of the PINGRESP
+fn expect_nothing_queued(client: &mut Client) {
+    client.send(&PingReq);
+    assert_pkt!(client.recv(), PingResp);
+}
+
+#[test]
+fn queued_messages_are_delivered_on_reconnect() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    go_offline(&broker, addr, "queue-sub");
+    let mut publisher = Client::connect_id(addr, "queue-pub");
+    publisher.send(&publish(1, b"first"));
+    assert_pkt!(publisher.recv(), PubAck(1));
+    publisher.send(&publish(2, b"second"));
+    assert_pkt!(publisher.recv(), PubAck(2));
+    wait_until("both messages are queued", || client(&broker, "queue-sub").queued == 2);
+    let (mut sub, session_present) = connect_persistent(addr, "queue-sub");
+    assert!(session_present);
+    for expected in [&b"first"[..], &b"second"[..]].iter() {
+        match sub.recv() {
+            Publish { qos_lv: QosLv::AtLeastOnce, ref payload, .. } =>
+                assert_eq!(&payload[..], *expected),
+            pkt => panic!("expected a queued PUBLISH, got {:?}", pkt)
+        }
+    }
+    let info = client(&broker, "queue-sub");
+    assert_eq!((info.queued, info.delivered, info.inflight), (0, 2, 2));
+}
+
+#[test]
+fn messages_older_than_max_age_are_dropped() {
+    let clock = Arc::new(VirtualClock::new());
+    let mut broker = Broker::with_clock(clock.clone());
+    broker.set_queue_limits(QueueLimits {
+        max_messages: 10,
+        max_age: Some(Duration::from_secs(10 * 60))
+    });
+    let addr = start(&broker);
+    go_offline(&broker, addr, "stale-sub");
+    let mut publisher = Client::connect_id(addr, "stale-pub");
+    publisher.send(&publish(1, b"stale"));
+    assert_pkt!(publisher.recv(), PubAck(1));
+    wait_until("the message is queued", || client(&broker, "stale-sub").queued == 1);
+    clock.advance(Duration::from_secs(5 * 60));
+    publisher.send(&publish(2, b"fresh"));
+    assert_pkt!(publisher.recv(), PubAck(2));
+    wait_until("the message is queued", || client(&broker, "stale-sub").queued == 2);
+    clock.advance(Duration::from_secs(6 * 60));
+    let (mut sub, _) = connect_persistent(addr, "stale-sub");
+    assert_pkt!(sub.recv(), Publish { .. });
+    expect_nothing_queued(&mut sub);
+    let info = client(&broker, "stale-sub");
+    assert_eq!((info.queued, info.delivered, info.dropped), (0, 1, 1));
+}
+
+#[test]
+fn messages_beyond_the_queue_limit_are_dropped() {
+    let mut broker = Broker::new();
+    broker.set_queue_limits(QueueLimits { max_messages: 1, max_age: None });
+    let addr = start(&broker);
+    go_offline(&broker, addr, "full-sub");
+    let mut publisher = Client::connect_id(addr, "full-pub");
+    publisher.send(&publish(1, b"kept"));
+    assert_pkt!(publisher.recv(), PubAck(1));
+    publisher.send(&publish(2, b"dropped"));
+    assert_pkt!(publisher.recv(), PubAck(2));
+    wait_until("the second message is dropped", || client(&broker, "full-sub").dropped == 1);
+    let (mut sub, _) = connect_persistent(addr, "full-sub");
+    match sub.recv() {
+        Publish { ref payload, .. } => assert_eq!(payload, b"kept"),
+        pkt => panic!("expected the queued PUBLISH, got {:?}", pkt)
+    }
+    expect_nothing_queued(&mut sub);
+}
+
+#[test]
+fn clean_sessions_are_not_queued_for() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "clean-sub");
+    sub.subscribe(1, vec![("cmd", QosLv::AtLeastOnce)]);
+    assert!(broker.kick("clean-sub").unwrap());
+    sub.expect_closed();
+    wait_until("the session is discarded",
+        || broker.clients().iter().all(|c| c.client_id != "clean-sub"));
+    let mut publisher = Client::connect_id(addr, "clean-pub");
+    publisher.send(&publish(1, b"lost"));
+    assert_pkt!(publisher.recv(), PubAck(1));
+    let (mut sub, session_present) = connect_persistent(addr, "clean-sub");
+    assert!(!session_present);
+    expect_nothing_queued(&mut sub);
+}
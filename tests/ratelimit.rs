@@ -0,0 +1,67 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::ctrlpkt::CtrlPkt::*;
+use mqtt_broker::broker::Broker;
+use mqtt_broker::clock::VirtualClock;
+use mqtt_broker::ratelimit::RateLimiter;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[test]
+fn bursts_then_the_rate() {
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new(2, 3, start);
+    assert!(limiter.try_acquire(start));
+    assert!(!limiter.is_overloaded());
+    assert!(limiter.try_acquire(start));
+    assert!(limiter.is_overloaded());
+    assert!(limiter.try_acquire(start));
+    assert!(!limiter.try_acquire(start));
+    let later = start + Duration::from_millis(500);
+    assert!(limiter.try_acquire(later));
+    assert!(!limiter.try_acquire(later));
+    // Idle time doesn't build up more than a burst
+    let much_later = later + Duration::from_secs(60);
+    for _ in 0..3 {
+        assert!(limiter.try_acquire(much_later));
+    }
+    assert!(!limiter.try_acquire(much_later));
+}
+
+#[test]
+fn connections_over_the_rate_are_closed() {
+    let clock = Arc::new(VirtualClock::new());
+    let broker = Broker::with_clock(clock.clone());
+    let mut listener_config = local_listener();
+    listener_config.connection_rate = Some(1);
+    listener_config.overload_connack_delay = Some(Duration::from_millis(100));
+    let addr = start_listener(&broker, listener_config);
+    Client::connect_id(addr, "first");
+    Client::open(addr).expect_closed();
+    clock.advance(Duration::from_secs(1));
+    Client::connect_id(addr, "after-a-second");
+}
+
+#[test]
+fn ping_floods_are_disconnected() {
+    let clock = Arc::new(VirtualClock::new());
+    let mut broker = Broker::with_clock(clock.clone());
+    broker.set_max_ping_rate(Some(2));
+    let addr = start(&broker);
+    let mut client = Client::connect_id(addr, "pinger");
+    for _ in 0..2 {
+        client.send(&PingReq);
+        assert_pkt!(client.recv(), PingResp);
+    }
+    clock.advance(Duration::from_millis(500));
+    client.send(&PingReq);
+    assert_pkt!(client.recv(), PingResp);
+    client.send(&PingReq);
+    client.expect_closed();
+    wait_until("the flood to be counted", || broker.ping_floods() == 1);
+}
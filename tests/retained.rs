@@ -0,0 +1,128 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::ctrlpkt::{CtrlPkt::*, QosLv};
+use mqtt_broker::broker::{Broker, RetainedQos};
+use std::time::SystemTime;
+
+// Retains a QoS 1 message, subscribes at QoS `granted`, and returns the QoS it's delivered at
+fn retained_delivery_qos(retained_qos: RetainedQos, msg_qos_lv: QosLv, granted: QosLv) -> QosLv {
+    let mut broker = Broker::new();
+    broker.set_retained_qos(retained_qos);
+    let addr = start(&broker);
+    let mut publisher = Client::connect_id(addr, "retainer");
+    publisher.send(&Publish {
+        dup: false,
+        qos_lv: msg_qos_lv,
+        retain: true,
+        topic_name: "state".to_string(),
+        pkt_id: if msg_qos_lv == QosLv::AtMostOnce { None } else { Some(1) },
+        payload: b"on".to_vec()
+    });
+    wait_until("the message is retained", || broker.retained("state").is_some());
+    let mut sub = Client::connect_id(addr, "retained-sub");
+    sub.subscribe(1, vec![("state", granted)]);
+    match sub.recv() {
+        Publish { retain: true, qos_lv, .. } => qos_lv,
+        pkt => panic!("expected the retained PUBLISH, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn retained_qos_defaults_to_the_minimum() {
+    let (qos1, qos0) = (QosLv::AtLeastOnce, QosLv::AtMostOnce);
+    assert_eq!(retained_delivery_qos(RetainedQos::Minimum, qos1, qos0), qos0);
+    assert_eq!(retained_delivery_qos(RetainedQos::Minimum, qos0, qos1), qos0);
+}
+
+#[test]
+fn retained_qos_can_follow_the_message_or_the_subscription() {
+    let (qos1, qos0) = (QosLv::AtLeastOnce, QosLv::AtMostOnce);
+    assert_eq!(retained_delivery_qos(RetainedQos::Original, qos1, qos0), qos1);
+    assert_eq!(retained_delivery_qos(RetainedQos::Granted, qos0, qos1), qos1);
+    assert_eq!(retained_delivery_qos(RetainedQos::Granted, qos1, qos0), qos0);
+}
+
+#[test]
+fn retained_messages_are_sent_in_batches() {
+    let mut broker = Broker::new();
+    broker.set_retained_batch_size(2);
+    for i in 0..5 {
+        broker.set_retained(&format!("lights/{}", i), QosLv::AtMostOnce, b"off".to_vec());
+    }
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "batched-sub");
+    sub.subscribe(1, vec![("lights/#", QosLv::AtMostOnce)]);
+    // The last message is in a partial batch, which is flushed too
+    let mut topics: Vec<String> = (0..5).map(|_| match sub.recv() {
+        Publish { retain: true, topic_name, .. } => topic_name,
+        pkt => panic!("expected a retained PUBLISH, got {:?}", pkt)
+    }).collect();
+    topics.sort();
+    assert_eq!(topics, (0..5).map(|i| format!("lights/{}", i)).collect::<Vec<_>>());
+}
+
+#[test]
+fn identical_payloads_are_stored_once() {
+    let broker = Broker::new();
+    let config = vec![7; 1024];
+    for i in 0..100 {
+        broker.set_retained(&format!("devices/{}/config", i), QosLv::AtLeastOnce, config.clone());
+    }
+    broker.set_retained("devices/0/status", QosLv::AtMostOnce, b"on".to_vec());
+    let stats = broker.retained_stats();
+    assert_eq!((stats.messages, stats.payloads, stats.deduplicated), (101, 2, 99));
+    assert_eq!(stats.payload_bytes, 1024 + 2);
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "dedup-sub");
+    sub.subscribe(1, vec![("devices/7/config", QosLv::AtMostOnce)]);
+    match sub.recv() {
+        Publish { retain: true, ref payload, .. } => assert!(payload == &config),
+        pkt => panic!("expected the retained PUBLISH, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn replaced_payloads_are_forgotten_on_compaction() {
+    let broker = Broker::new();
+    broker.set_retained("a", QosLv::AtMostOnce, b"v1".to_vec());
+    broker.set_retained("b", QosLv::AtMostOnce, b"v1".to_vec());
+    broker.set_retained("a", QosLv::AtMostOnce, b"v2".to_vec());
+    // b still holds v1
+    assert_eq!(broker.compact_retained(), 0);
+    broker.set_retained("b", QosLv::AtMostOnce, b"v2".to_vec());
+    assert!(broker.delete_retained("a"));
+    wait_until("v1 is freed", || broker.retained_stats().payloads == 1);
+    assert_eq!(broker.compact_retained(), 1);
+    assert_eq!(broker.compact_retained(), 0);
+    let stats = broker.retained_stats();
+    assert_eq!((stats.messages, stats.payloads, stats.deduplicated), (1, 1, 2));
+}
+
+#[test]
+fn past_retained_values_are_looked_up_by_time() {
+    let mut broker = Broker::new();
+    broker.set_retained_history(2);
+    let before = SystemTime::now();
+    broker.set_retained("devices/x/status", QosLv::AtMostOnce, b"online".to_vec());
+    broker.set_retained("devices/x/status", QosLv::AtLeastOnce, b"offline".to_vec());
+    assert!(broker.delete_retained("devices/x/status"));
+    broker.set_retained("$SYS/broker/uptime", QosLv::AtMostOnce, b"1".to_vec());
+    // Only the last two changes are kept
+    let history = broker.retained_history("devices/x/status");
+    assert_eq!(history.iter().map(|&(_, ref msg)| msg.clone()).collect::<Vec<_>>(),
+        vec![Some((QosLv::AtLeastOnce, b"offline".to_vec())), None]);
+    let (offline_at, deleted_at) = (history[0].0, history[1].0);
+    let retained = broker.retained_at("devices/x/status", offline_at).unwrap();
+    assert_eq!((retained.qos_lv, retained.payload, retained.retained_at),
+        (QosLv::AtLeastOnce, b"offline".to_vec(), offline_at));
+    assert!(broker.retained_at("devices/x/status", deleted_at).is_none());
+    assert!(broker.retained_at("devices/x/status", before).is_none());
+    assert!(broker.retained_history("$SYS/broker/uptime").is_empty());
+    broker.set_retained_history(0);
+    assert!(broker.retained_history("devices/x/status").is_empty());
+}
@@ -0,0 +1,226 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{CtrlPkt, CtrlPkt::*, QosLv};
+use mqtt_broker::broker::{Broker, ClientInfo, Qos2Limits};
+use mqtt_broker::clock::VirtualClock;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn publish(topic_name: &str) -> CtrlPkt {
+    Publish {
+        dup: false,
+        qos_lv: QosLv::AtMostOnce,
+        retain: false,
+        topic_name: topic_name.to_string(),
+        pkt_id: None,
+        payload: b"hello".to_vec()
+    }
+}
+
+fn client(broker: &Broker, client_id: &str) -> ClientInfo {
+    broker.clients().into_iter().find(|c| c.client_id == client_id).unwrap()
+}
+
+// Clean sessions are discarded on disconnect, so offline stats need a persistent one
+fn connect_persistent(addr: SocketAddr, client_id: &str) -> Client {
+    let mut opts = ConnectOptions::new(client_id.to_string());
+    opts.set_clean_session(false);
+    Client::connect(addr, &opts).0
+}
+
+#[test]
+fn deliveries_are_counted() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "counted-sub");
+    sub.subscribe(1, vec![("t", QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr, "counted-pub");
+    publisher.send(&publish("t"));
+    publisher.send(&publish("t"));
+    assert_pkt!(sub.recv(), Publish { .. });
+    assert_pkt!(sub.recv(), Publish { .. });
+    let info = client(&broker, "counted-sub");
+    assert_eq!((info.delivered, info.dropped), (2, 0));
+}
+
+#[test]
+fn deliveries_to_disconnected_sessions_are_dropped() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut sub = connect_persistent(addr, "offline-sub");
+    sub.subscribe(1, vec![("t", QosLv::AtMostOnce)]);
+    assert!(broker.kick("offline-sub").unwrap());
+    sub.expect_closed();
+    let mut publisher = Client::connect_id(addr, "offline-pub");
+    publisher.send(&publish("t"));
+    wait_until("the message is dropped", || client(&broker, "offline-sub").dropped == 1);
+    let info = client(&broker, "offline-sub");
+    assert_eq!((info.connected, info.delivered), (false, 0));
+}
+
+#[test]
+fn closed_connections_are_not_written_to() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut sub = connect_persistent(addr, "gone-sub");
+    sub.subscribe(1, vec![("t", QosLv::AtMostOnce)]);
+    sub.send(&Disconnect);
+    sub.expect_closed();
+    wait_until("gone-sub is unregistered", || !client(&broker, "gone-sub").connected);
+    // The publisher is unaffected by the subscriber having gone away
+    let mut publisher = Client::connect_id(addr, "gone-pub");
+    publisher.send(&Publish {
+        dup: false,
+        qos_lv: QosLv::AtLeastOnce,
+        retain: false,
+        topic_name: "t".to_string(),
+        pkt_id: Some(1),
+        payload: b"hello".to_vec()
+    });
+    assert_pkt!(publisher.recv(), PubAck(1));
+    wait_until("the message is dropped", || client(&broker, "gone-sub").dropped == 1);
+    assert_eq!(client(&broker, "gone-sub").delivered, 0);
+}
+
+#[test]
+fn stats_are_published_on_sys_topics() {
+    let clock = Arc::new(VirtualClock::new());
+    let broker = Broker::with_clock(clock.clone());
+    let addr = start(&broker);
+    broker.start_sys(Duration::from_secs(10));
+    let mut sub = Client::connect_id(addr, "sys-sub");
+    sub.subscribe(1, vec![
+        ("t", QosLv::AtMostOnce),
+        ("$SYS/broker/clients/sys-sub/delivered", QosLv::AtMostOnce)
+    ]);
+    let mut publisher = Client::connect_id(addr, "sys-pub");
+    publisher.send(&publish("t"));
+    publisher.send(&publish("t"));
+    assert_pkt!(sub.recv(), Publish { .. });
+    assert_pkt!(sub.recv(), Publish { .. });
+    clock.wait_for_sleepers(1);
+    clock.advance(Duration::from_secs(10));
+    match sub.recv() {
+        Publish { ref topic_name, ref payload, .. } => {
+            assert_eq!(topic_name, "$SYS/broker/clients/sys-sub/delivered");
+            assert_eq!(payload, b"2");
+        }
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+    let retained = broker.retained("$SYS/broker/clients/sys-sub/delivered");
+    assert_eq!(retained.map(|(_, payload)| payload), Some(b"2".to_vec()));
+}
+
+#[test]
+fn client_stats_topics_are_escaped_and_deleted_with_the_session() {
+    let clock = Arc::new(VirtualClock::new());
+    let broker = Broker::with_clock(clock.clone());
+    let addr = start(&broker);
+    broker.start_sys(Duration::from_secs(10));
+    let topic_name = "$SYS/broker/clients/a%2Fb%2B%23%25/delivered";
+    let mut client = Client::connect_id(addr, "a/b+#%");
+    clock.wait_for_sleepers(1);
+    clock.advance(Duration::from_secs(10));
+    wait_until("the client's stats are retained", || broker.retained(topic_name).is_some());
+    client.send(&Disconnect);
+    client.expect_closed();
+    wait_until("the session has ended", || broker.clients().is_empty());
+    clock.wait_for_sleepers(1);
+    clock.advance(Duration::from_secs(10));
+    wait_until("the client's stats are deleted", || broker.retained(topic_name).is_none());
+}
+
+#[test]
+fn node_stats_are_published_under_the_node_id() {
+    let mut broker = Broker::new();
+    broker.set_node_id("node-a");
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "node-sub");
+    sub.subscribe(1, vec![("a/#", QosLv::AtMostOnce), ("b", QosLv::AtMostOnce)]);
+    let offline = connect_persistent(addr, "node-offline");
+    drop(offline);
+    wait_until("the client is offline", || !client(&broker, "node-offline").connected);
+    broker.publish_sys_stats();
+    let stat = |name: &str| broker.retained(&format!("$SYS/broker/nodes/node-a/{}", name))
+        .map(|(_, payload)| String::from_utf8(payload).unwrap());
+    assert_eq!(stat("clients"), Some("1".to_string()));
+    assert_eq!(stat("routes"), Some("2".to_string()));
+    assert_eq!(stat("peers"), Some("0".to_string()));
+}
+
+#[test]
+fn qos2_duplicates_are_counted_and_not_delivered() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "qos2-sub");
+    sub.subscribe(1, vec![("t", QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr, "qos2-pub");
+    let qos2 = |dup: bool, payload: &[u8]| Publish {
+        dup,
+        qos_lv: QosLv::ExactlyOnce,
+        retain: false,
+        topic_name: "t".to_string(),
+        pkt_id: Some(7),
+        payload: payload.to_vec()
+    };
+    publisher.send(&qos2(false, b"first"));
+    assert_pkt!(publisher.recv(), PubRec(7));
+    publisher.send(&qos2(true, b"first"));
+    assert_pkt!(publisher.recv(), PubRec(7));
+    publisher.send(&PubRel(7));
+    assert_pkt!(publisher.recv(), PubComp(7));
+    // Once released, the packet id can be reused for a new message
+    publisher.send(&qos2(false, b"second"));
+    assert_pkt!(publisher.recv(), PubRec(7));
+    for expected in &[b"first".to_vec(), b"second".to_vec()] {
+        match sub.recv() {
+            Publish { ref payload, .. } => assert_eq!(payload, expected),
+            pkt => panic!("expected a publish, got {:?}", pkt)
+        }
+    }
+    assert_eq!(client(&broker, "qos2-pub").qos2_duplicates, 1);
+}
+
+#[test]
+fn abandoned_qos2_exchanges_are_forgotten() {
+    let clock = Arc::new(VirtualClock::new());
+    let mut broker = Broker::with_clock(clock.clone());
+    broker.set_qos2_limits(Qos2Limits {
+        release_timeout: Some(Duration::from_secs(10)),
+        max_awaiting_rel: Some(2)
+    });
+    let addr = start(&broker);
+    let mut publisher = Client::connect_id(addr, "qos2-abandoner");
+    let mut qos2 = |pkt_id: u16| {
+        publisher.send(&Publish {
+            dup: false,
+            qos_lv: QosLv::ExactlyOnce,
+            retain: false,
+            topic_name: "t".to_string(),
+            pkt_id: Some(pkt_id),
+            payload: b"never released".to_vec()
+        });
+        assert_pkt!(publisher.recv(), PubRec(_));
+    };
+    qos2(1);
+    qos2(2);
+    // Over the cap: the oldest is forgotten
+    qos2(3);
+    assert_eq!(client(&broker, "qos2-abandoner").qos2_abandoned, 1);
+    // So it is a new message again rather than a duplicate
+    qos2(1);
+    let info = client(&broker, "qos2-abandoner");
+    assert_eq!((info.qos2_duplicates, info.qos2_abandoned), (0, 2));
+    // Both still waiting time out
+    clock.advance(Duration::from_secs(11));
+    qos2(4);
+    let info = client(&broker, "qos2-abandoner");
+    assert_eq!((info.qos2_abandoned, info.awaiting_rel), (4, 1));
+}
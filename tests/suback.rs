@@ -0,0 +1,96 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::ctrlpkt::{CtrlPkt::*, QosLv, SubAckRetCode};
+use mqtt_broker::broker::Broker;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn return_codes_follow_the_filter_order() {
+    let mut broker = Broker::new();
+    broker.set_anonymous_topics(Some("public/#".to_string())).set_max_qos(QosLv::AtLeastOnce);
+    let addr = start(&broker);
+    let mut client = Client::connect_id(addr, "mixed-sub");
+    let ret_codes = client.subscribe(1, vec![
+        ("public/a", QosLv::ExactlyOnce),
+        // Outside the anonymous topics
+        ("private/a", QosLv::AtMostOnce),
+        // Not a valid filter
+        ("public/a#", QosLv::AtLeastOnce),
+        ("public/b", QosLv::AtMostOnce),
+        ("#", QosLv::AtLeastOnce)
+    ]);
+    assert_pkt!(ret_codes.as_slice(), &[
+        SubAckRetCode::MaxQos1,
+        SubAckRetCode::Failure,
+        SubAckRetCode::Failure,
+        SubAckRetCode::MaxQos0,
+        SubAckRetCode::Failure
+    ]);
+    // Only the granted filters, at the granted QoS
+    assert_eq!(broker.client_subscriptions("mixed-sub"), Some(vec![
+        ("public/a".to_string(), QosLv::AtLeastOnce),
+        ("public/b".to_string(), QosLv::AtMostOnce)
+    ]));
+    assert_eq!(broker.subscriptions(None).len(), 2);
+}
+
+#[test]
+fn listeners_can_refuse_wildcards() {
+    let broker = Broker::new();
+    let mut listener_config = local_listener();
+    listener_config.wildcard_subscriptions = false;
+    let public = start_listener(&broker, listener_config);
+    let mut client = Client::connect_id(public, "public-sub");
+    let ret_codes = client.subscribe(1, vec![
+        ("#", QosLv::AtMostOnce),
+        ("sensors/+/temp", QosLv::AtMostOnce),
+        ("sensors/1/temp", QosLv::AtMostOnce)
+    ]);
+    assert_pkt!(ret_codes.as_slice(),
+        &[SubAckRetCode::Failure, SubAckRetCode::Failure, SubAckRetCode::MaxQos0]);
+    // Other listeners still allow them
+    let mut client = Client::connect_id(start(&broker), "internal-sub");
+    assert_pkt!(client.subscribe(1, vec![("#", QosLv::AtMostOnce)]).as_slice(),
+        &[SubAckRetCode::MaxQos0]);
+}
+
+#[test]
+fn deliveries_on_new_subscriptions_follow_the_suback() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let done = Arc::new(AtomicBool::new(false));
+    let publisher = {
+        let done = Arc::clone(&done);
+        let mut publisher = Client::connect_id(addr, "race-pub");
+        thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                publisher.send(&Publish {
+                    dup: false,
+                    qos_lv: QosLv::AtMostOnce,
+                    retain: false,
+                    topic_name: "race".to_string(),
+                    pkt_id: None,
+                    payload: b"hello".to_vec()
+                });
+                thread::sleep(Duration::from_millis(1));
+            }
+        })
+    };
+    for i in 0..20 {
+        let mut sub = Client::connect_id(addr, &format!("race-sub-{}", i));
+        // Client::subscribe fails on anything but the SUBACK as the next packet
+        let ret_codes = sub.subscribe(1, vec![("race", QosLv::AtMostOnce)]);
+        assert_pkt!(ret_codes.as_slice(), &[SubAckRetCode::MaxQos0]);
+        assert_pkt!(sub.recv(), Publish { .. });
+    }
+    done.store(true, Ordering::SeqCst);
+    publisher.join().unwrap();
+}
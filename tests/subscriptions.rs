@@ -0,0 +1,190 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+use libmqtt::ctrlpkt::QosLv;
+use mqtt_broker::subscriptions::SubscriptionTable;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+fn sub(filter: &str, client_id: &str) -> (String, String, QosLv) {
+    (filter.to_string(), client_id.to_string(), QosLv::AtLeastOnce)
+}
+
+#[test]
+fn snapshots_are_unaffected_by_later_updates() {
+    let table = SubscriptionTable::new();
+    table.subscribe(&[sub("a", "one")]);
+    let before = table.snapshot();
+    table.subscribe(&[sub("a", "two"), sub("b", "one")]);
+    assert_eq!(before.len(), 1);
+    assert_eq!(before["a"].len(), 1);
+    let after = table.snapshot();
+    assert_eq!(after.len(), 2);
+    assert_eq!(after["a"].get("two"), Some(&QosLv::AtLeastOnce));
+}
+
+#[test]
+fn unsubscribing_the_last_client_drops_the_filter() {
+    let table = SubscriptionTable::new();
+    table.subscribe(&[sub("a", "one"), sub("a", "two")]);
+    table.unsubscribe(&[("a".to_string(), "one".to_string())]);
+    assert_eq!(table.snapshot()["a"].len(), 1);
+    table.unsubscribe(&[
+        ("a".to_string(), "two".to_string()),
+        ("b".to_string(), "one".to_string())
+    ]);
+    assert!(table.snapshot().is_empty());
+}
+
+#[test]
+fn publishes_follow_wildcards_level_by_level() {
+    let table = SubscriptionTable::new();
+    table.subscribe(&[sub("a/#", "hash"), sub("a/+", "plus"), sub("a/b", "exact"),
+        sub("#", "all"), sub("+/b", "first")]);
+    let mut matched: Vec<_> = table.subscribers("a").into_iter().map(|(id, _)| id).collect();
+    matched.sort();
+    assert_eq!(matched, ["all", "hash"]);
+    let mut matched: Vec<_> = table.subscribers("a/b").into_iter().map(|(id, _)| id).collect();
+    matched.sort();
+    assert_eq!(matched, ["all", "exact", "first", "hash", "plus"]);
+    // `+` matches an empty level
+    assert!(table.subscribers("a/").contains_key("plus"));
+    assert!(!table.subscribers("a/b/c").contains_key("plus"));
+    // Filters starting with a wildcard don't match topics starting with `$`
+    assert!(table.subscribers("$SYS/b").is_empty());
+}
+
+#[test]
+fn concurrent_updates_are_not_lost() {
+    let table = Arc::new(SubscriptionTable::new());
+    let threads: Vec<_> = (0..8).map(|t| {
+        let table = Arc::clone(&table);
+        thread::spawn(move || {
+            for i in 0..100 {
+                table.subscribe(&[sub("shared", &format!("client-{}-{}", t, i))]);
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(table.snapshot()["shared"].len(), 800);
+}
+
+#[test]
+fn stats_describe_the_table() {
+    let table = SubscriptionTable::new();
+    table.subscribe(&[sub("a/+/c", "one"), sub("a/+/c", "two"), sub("b", "one")]);
+    let stats = table.stats();
+    assert_eq!((stats.filters, stats.subscriptions, stats.max_depth), (2, 3, 3));
+    assert!(stats.estimated_bytes > 0);
+}
+
+#[test]
+fn compaction_shrinks_maps_left_sparse_by_unsubscribes() {
+    let table = SubscriptionTable::new();
+    let subs: Vec<_> = (0..1000).map(|i| sub("crowded", &format!("client-{}", i))).collect();
+    table.subscribe(&subs);
+    let unsubs: Vec<_> = subs[1..].iter()
+        .map(|&(ref filter, ref client_id, _)| (filter.clone(), client_id.clone()))
+        .collect();
+    table.unsubscribe(&unsubs);
+    let before = table.stats();
+    assert!(table.compact() > 0);
+    let after = table.stats();
+    assert_eq!((after.filters, after.subscriptions), (1, 1));
+    assert!(after.estimated_bytes < before.estimated_bytes / 10);
+    // Nothing left to reclaim
+    assert_eq!(table.compact(), 0);
+}
+
+#[test]
+fn updates_apply_promptly_under_heavy_reading() {
+    let table = Arc::new(SubscriptionTable::new());
+    let stop = Arc::new(AtomicBool::new(false));
+    // Dispatch threads taking a snapshot for every message
+    let readers: Vec<_> = (0..4).map(|_| {
+        let (table, stop) = (Arc::clone(&table), Arc::clone(&stop));
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                let _ = table.snapshot().get("busy");
+            }
+        })
+    }).collect();
+    for i in 0..200 {
+        table.subscribe(&[sub("busy", &format!("client-{}", i))]);
+    }
+    stop.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    let stats = table.stats();
+    assert_eq!(stats.updates, 200);
+    // Generous, so that a loaded test machine doesn't fail it; a starved writer takes far longer
+    assert!(stats.max_update_micros < 1_000_000, "an update took {} us", stats.max_update_micros);
+}
+
+#[test]
+fn filter_stats_put_the_busiest_filters_first() {
+    let table = SubscriptionTable::new();
+    table.subscribe(&[sub("a", "one"), sub("b", "one"), sub("b", "two")]);
+    table.subscribers("a");
+    table.subscribers("b");
+    table.subscribers("c");
+    let stats = table.filter_stats(None);
+    let summary: Vec<_> = stats.iter()
+        .map(|s| (s.filter.as_str(), s.subscribers, s.matched, s.deliveries))
+        .collect();
+    assert_eq!(summary, vec![("b", 2, 1, 2), ("a", 1, 1, 1)]);
+    assert!(stats[0].match_rate > 0.0);
+    assert_eq!(table.filter_stats(Some("a")).len(), 1);
+    // A filter's counts go with its last subscriber
+    table.unsubscribe(&[("a".to_string(), "one".to_string())]);
+    table.subscribe(&[sub("a", "one")]);
+    assert_eq!(table.filter_stats(Some("a"))[0].matched, 0);
+}
+
+#[test]
+fn wildcard_filters_match_topics() {
+    let table = SubscriptionTable::new();
+    table.subscribe(&[sub("sensors/+/temp", "one"), sub("sensors/#", "two"),
+        sub("sensors/1/temp", "three"), sub("#", "four")]);
+    table.subscribe(&[("sensors/#".to_string(), "one".to_string(), QosLv::ExactlyOnce)]);
+    let subscribers = table.subscribers("sensors/1/temp");
+    let mut clients: Vec<_> = subscribers.iter().map(|(id, qos_lv)| (id.as_str(), *qos_lv))
+        .collect();
+    clients.sort();
+    // `one` matches twice and gets the higher QoS of the two
+    assert_eq!(clients, vec![("four", QosLv::AtLeastOnce), ("one", QosLv::ExactlyOnce),
+        ("three", QosLv::AtLeastOnce), ("two", QosLv::AtLeastOnce)]);
+    assert_eq!(table.subscribers("sensors/1/humidity").len(), 3);
+    assert_eq!(table.subscribers("$SYS/broker/uptime").len(), 0);
+    assert_eq!(table.filter_stats(Some("sensors/+/temp"))[0].matched, 1);
+    assert_eq!(table.filter_stats(Some("#"))[0].matched, 2);
+    // Once unsubscribed, a wildcard no longer matches
+    table.unsubscribe(&[("#".to_string(), "four".to_string())]);
+    assert!(!table.subscribers("sensors/1/temp").contains_key("four"));
+}
+
+#[test]
+fn match_counts_are_kept_across_concurrent_updates() {
+    let table = Arc::new(SubscriptionTable::new());
+    table.subscribe(&[sub("counted/+", "one")]);
+    let counters: Vec<_> = (0..4).map(|_| {
+        let table = Arc::clone(&table);
+        thread::spawn(move || {
+            for _ in 0..1000 {
+                table.subscribers("counted/topic");
+            }
+        })
+    }).collect();
+    for i in 0..50 {
+        table.subscribe(&[sub("other", &format!("client-{}", i))]);
+    }
+    for counter in counters {
+        counter.join().unwrap();
+    }
+    let stats = &table.filter_stats(Some("counted/+"))[0];
+    assert_eq!((stats.matched, stats.deliveries), (4000, 4000));
+}
@@ -0,0 +1,72 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::ConnectOptions;
+use libmqtt::ctrlpkt::{ConnAckRetCode, CtrlPkt::*};
+use mqtt_broker::broker::{Broker, TakeoverPolicy};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn start_with(policy: TakeoverPolicy) -> SocketAddr {
+    let mut broker = Broker::new();
+    broker.set_takeover(policy);
+    start(&broker)
+}
+
+// Sends a CONNECT without waiting for the CONNACK
+fn open_connect(addr: SocketAddr, client_id: &str) -> Client {
+    let mut client = Client::open(addr);
+    client.send(&ConnectOptions::new(client_id.to_string()).build().unwrap());
+    client
+}
+
+#[test]
+fn takeover_policies_are_parsed() {
+    assert_eq!(TakeoverPolicy::from_str("reject"), Some(TakeoverPolicy::Reject));
+    assert_eq!(TakeoverPolicy::from_str("grace"),
+        Some(TakeoverPolicy::Grace(Duration::from_millis(1000))));
+    assert_eq!(TakeoverPolicy::from_str("grace 250"),
+        Some(TakeoverPolicy::Grace(Duration::from_millis(250))));
+    assert_eq!(TakeoverPolicy::from_str("grace soon"), None);
+    assert_eq!(TakeoverPolicy::Grace(Duration::from_millis(250)).name(), "grace 250");
+}
+
+#[test]
+fn second_connection_can_be_rejected() {
+    let addr = start_with(TakeoverPolicy::Reject);
+    let mut first = Client::connect_id(addr, "twice");
+    let mut second = open_connect(addr, "twice");
+    assert_pkt!(second.recv(), ConnAck { return_code: ConnAckRetCode::IdRejected, .. });
+    second.expect_closed();
+    first.send(&PingReq);
+    assert_pkt!(first.recv(), PingResp);
+}
+
+#[test]
+fn silent_connection_is_taken_over_after_the_grace_period() {
+    let addr = start_with(TakeoverPolicy::Grace(Duration::from_millis(200)));
+    let mut first = Client::connect_id(addr, "nat");
+    let started = Instant::now();
+    let mut second = open_connect(addr, "nat");
+    assert_pkt!(second.recv(), ConnAck { return_code: ConnAckRetCode::Accepted, .. });
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    first.expect_closed();
+}
+
+#[test]
+fn live_connection_keeps_its_client_id_during_the_grace_period() {
+    let addr = start_with(TakeoverPolicy::Grace(Duration::from_millis(1000)));
+    let mut first = Client::connect_id(addr, "alive");
+    let mut second = open_connect(addr, "alive");
+    thread::sleep(Duration::from_millis(100));
+    first.send(&PingReq);
+    assert_pkt!(first.recv(), PingResp);
+    assert_pkt!(second.recv(), ConnAck { return_code: ConnAckRetCode::IdRejected, .. });
+    first.send(&PingReq);
+    assert_pkt!(first.recv(), PingResp);
+}
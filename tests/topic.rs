@@ -0,0 +1,104 @@
+extern crate mqtt_broker;
+
+use mqtt_broker::topic::{is_mounted, is_valid_filter, matches, mount, unmount, within, TopicTree};
+
+#[test]
+fn filters_match_topics() {
+    let cases = [
+        ("a/b", "a/b", true),
+        ("a/b", "a/c", false),
+        ("a/+", "a/b", true),
+        ("a/+", "a/b/c", false),
+        ("a/+", "a", false),
+        ("+/+", "/b", true),
+        ("a/#", "a", true),
+        ("a/#", "a/b/c", true),
+        ("#", "a/b", true),
+        ("a/#/c", "a/b/c", false),
+        ("#", "$SYS/broker", false),
+        ("+/broker", "$SYS/broker", false),
+        ("$SYS/#", "$SYS/broker", true)
+    ];
+    for &(filter, topic_name, expected) in cases.iter() {
+        assert_eq!(matches(filter, topic_name), expected, "{} against {}", filter, topic_name);
+    }
+}
+
+#[test]
+fn filter_syntax_is_checked() {
+    let cases = [
+        ("sensors/+/temp", true),
+        ("sensors/#", true),
+        ("#", true),
+        ("+", true),
+        ("+/+/#", true),
+        ("/", true),
+        ("sensors/*", true),
+        ("", false),
+        ("sensors/#/temp", false),
+        ("sensors/t#", false),
+        ("sensors/+temp", false),
+        ("sensors/te+mp/#", false),
+        ("a\0b", false)
+    ];
+    for &(filter, expected) in cases.iter() {
+        assert_eq!(is_valid_filter(filter), expected, "{:?}", filter);
+    }
+}
+
+#[test]
+fn mount_points_prefix_topics() {
+    assert_eq!(mount(Some("tenant/"), "a/b"), "tenant/a/b");
+    assert_eq!(mount(None, "a/b"), "a/b");
+    assert_eq!(unmount(Some("tenant/"), "tenant/a/b"), "a/b");
+    assert_eq!(unmount(Some("tenant/"), "other/a/b"), "other/a/b");
+    assert_eq!(unmount(None, "tenant/a/b"), "tenant/a/b");
+    assert_eq!(mount(Some("tenant"), "a/b"), "tenant/a/b");
+    assert_eq!(unmount(Some("tenant"), "tenant/a/b"), "a/b");
+    assert_eq!(unmount(Some("tenant"), "tenants/a/b"), "tenants/a/b");
+    assert!(is_mounted("tenant", "tenant/a") && is_mounted("tenant/", "tenant/a"));
+    assert!(!is_mounted("tenant-a", "tenant-ab/a") && !is_mounted("tenant-a/", "tenant-ab/a"));
+}
+
+#[test]
+fn filters_within_subtrees() {
+    let cases = [
+        ("public/a", "public/#", true),
+        ("public/+/b", "public/#", true),
+        ("public", "public/#", true),
+        ("public/#", "public/#", true),
+        ("#", "public/#", false),
+        ("+/a", "public/#", false),
+        ("private/a", "public/#", false),
+        ("public/a", "public/+", true),
+        ("public/#", "public/+", false),
+        ("public/a/b", "public/+", false)
+    ];
+    for &(filter, subtree, expected) in cases.iter() {
+        assert_eq!(within(filter, subtree), expected, "{} within {}", filter, subtree);
+    }
+}
+
+#[test]
+fn topic_tree_finds_the_topics_a_filter_matches() {
+    let topics = ["a", "a/b", "a/b/c", "a/c", "a/", "b/b", "$SYS/uptime", "/x"];
+    let mut tree = TopicTree::new();
+    for topic in topics.iter() {
+        tree.insert(topic);
+    }
+    let filters = ["#", "+", "a/#", "a/+", "+/b", "+/+/c", "$SYS/#", "/+", "a/b/c/#", "c"];
+    for filter in filters.iter() {
+        let mut found = tree.matching(filter);
+        found.sort();
+        let mut expected: Vec<String> = topics.iter().filter(|topic| matches(filter, topic))
+            .map(|topic| topic.to_string())
+            .collect();
+        expected.sort();
+        assert_eq!(found, expected, "{}", filter);
+    }
+    assert!(tree.remove("a/b"));
+    assert!(!tree.remove("a/b"));
+    assert_eq!(tree.matching("a/b/#"), vec!["a/b/c".to_string()]);
+    assert!(tree.remove("a/b/c"));
+    assert!(tree.matching("a/b/#").is_empty());
+}
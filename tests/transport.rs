@@ -0,0 +1,90 @@
+extern crate mqtt_broker;
+
+use mqtt_broker::transport::{RetryWrites, Transport, WRITE_RETRIES};
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Fails writes with the queued errors, then accepts everything
+#[derive(Clone)]
+struct FlakyTransport {
+    errors: Arc<Mutex<Vec<ErrorKind>>>,
+    written: Arc<Mutex<Vec<u8>>>
+}
+
+impl FlakyTransport {
+    fn new(errors: Vec<ErrorKind>) -> FlakyTransport {
+        FlakyTransport {
+            errors: Arc::new(Mutex::new(errors)),
+            written: Arc::new(Mutex::new(vec![]))
+        }
+    }
+}
+
+impl Read for FlakyTransport {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for FlakyTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut errors = self.errors.lock().unwrap();
+        if !errors.is_empty() {
+            return Err(io::Error::new(errors.remove(0), "flaky"));
+        }
+        self.written.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for FlakyTransport {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn peer_addr(&self) -> String {
+        "flaky://".to_string()
+    }
+
+    fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn write(errors: Vec<ErrorKind>) -> (io::Result<()>, Vec<u8>) {
+    let flaky = FlakyTransport::new(errors);
+    let mut transport = RetryWrites::new(Box::new(flaky.clone()));
+    let res = transport.write_all(b"hello");
+    let written = flaky.written.lock().unwrap().clone();
+    (res, written)
+}
+
+#[test]
+fn transient_write_errors_are_retried() {
+    let (res, written) = write(vec![ErrorKind::WouldBlock, ErrorKind::Interrupted]);
+    assert!(res.is_ok());
+    assert_eq!(written, b"hello");
+}
+
+#[test]
+fn fatal_write_errors_are_not_retried() {
+    let (res, written) = write(vec![ErrorKind::BrokenPipe]);
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::BrokenPipe);
+    assert!(written.is_empty());
+}
+
+#[test]
+fn write_retries_run_out() {
+    let (res, written) = write(vec![ErrorKind::WouldBlock; WRITE_RETRIES as usize + 1]);
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::WouldBlock);
+    assert!(written.is_empty());
+}
@@ -0,0 +1,82 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::ctrlpkt::{CtrlPkt, CtrlPkt::*, QosLv};
+use mqtt_broker::broker::Broker;
+
+fn publish(topic_name: &str) -> CtrlPkt {
+    Publish {
+        dup: false,
+        qos_lv: QosLv::AtMostOnce,
+        retain: false,
+        topic_name: topic_name.to_string(),
+        pkt_id: None,
+        payload: b"hello".to_vec()
+    }
+}
+
+fn filters(broker: &Broker, client_id: &str) -> Vec<String> {
+    broker.subscriptions(None).into_iter()
+        .filter(|&(_, ref id, _)| id == client_id)
+        .map(|(filter, _, _)| filter)
+        .collect()
+}
+
+#[test]
+fn unsubscribing_a_wildcard_leaves_topics_it_matches() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "wildcard-unsub");
+    sub.subscribe(1, vec![("a/#", QosLv::AtMostOnce), ("a/b", QosLv::AtMostOnce)]);
+    sub.unsubscribe(2, vec!["a/#"]);
+    assert_eq!(filters(&broker, "wildcard-unsub"), vec!["a/b"]);
+    let mut publisher = Client::connect_id(addr, "wildcard-unsub-pub");
+    publisher.send(&publish("a/b"));
+    assert_pkt!(sub.recv(), Publish { .. });
+}
+
+#[test]
+fn unsubscribing_a_topic_leaves_wildcards_matching_it() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "topic-unsub");
+    sub.subscribe(1, vec![("a/#", QosLv::AtMostOnce), ("a/+", QosLv::AtMostOnce),
+        ("a/b", QosLv::AtMostOnce)]);
+    sub.unsubscribe(2, vec!["a/b"]);
+    assert_eq!(filters(&broker, "topic-unsub"), vec!["a/#", "a/+"]);
+    let info = broker.clients().into_iter().find(|c| c.client_id == "topic-unsub").unwrap();
+    assert_eq!(info.subscriptions, 2);
+}
+
+#[test]
+fn unknown_filters_are_acknowledged() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "unknown-unsub");
+    sub.subscribe(1, vec![("a/b", QosLv::AtMostOnce)]);
+    // Matches a/b as a topic, but isn't the filter the client subscribed with
+    sub.unsubscribe(2, vec!["a/+", "c"]);
+    assert_eq!(filters(&broker, "unknown-unsub"), vec!["a/b"]);
+}
+
+#[test]
+fn unsubscribed_topics_are_no_longer_delivered() {
+    let broker = Broker::new();
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "delivery-unsub");
+    sub.subscribe(1, vec![("a", QosLv::AtMostOnce), ("b", QosLv::AtMostOnce)]);
+    sub.unsubscribe(2, vec!["a"]);
+    let mut publisher = Client::connect_id(addr, "delivery-unsub-pub");
+    publisher.send(&publish("a"));
+    publisher.send(&publish("b"));
+    match sub.recv() {
+        Publish { ref topic_name, .. } => assert_eq!(topic_name, "b"),
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+    // The table drops filters nobody is subscribed to any more
+    assert!(broker.subscriptions(Some("a")).is_empty());
+}
@@ -0,0 +1,129 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::ctrlpkt::{CtrlPkt, CtrlPkt::*, QosLv};
+use mqtt_broker::broker::{Broker, ValidationStats};
+use mqtt_broker::validation::{PayloadValidator, ValidationAction, Validators};
+#[cfg(feature = "json-schema")]
+use mqtt_broker::validation::JsonSchema;
+
+struct NotEmpty;
+
+impl PayloadValidator for NotEmpty {
+    fn validate(&self, _topic_name: &str, payload: &[u8]) -> Result<(), String> {
+        if payload.is_empty() { Err("empty payload".to_string()) } else { Ok(()) }
+    }
+}
+
+fn publish(topic_name: &str, payload: &[u8]) -> CtrlPkt {
+    Publish {
+        dup: false,
+        qos_lv: QosLv::AtLeastOnce,
+        retain: true,
+        topic_name: topic_name.to_string(),
+        pkt_id: Some(1),
+        payload: payload.to_vec()
+    }
+}
+
+fn expect_payload(client: &mut Client, expected: &[u8]) {
+    match client.recv() {
+        Publish { ref payload, .. } => assert_eq!(payload.as_slice(), expected),
+        pkt => panic!("expected a PUBLISH, got {:?}", pkt)
+    }
+}
+
+#[test]
+fn rejected_payloads_are_neither_delivered_nor_retained() {
+    let mut broker = Broker::new();
+    broker.add_validator("sensors/#", NotEmpty, ValidationAction::Reject);
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "reject-sub");
+    sub.subscribe(1, vec![("sensors/a", QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr, "reject-pub");
+    publisher.send(&publish("sensors/a", b""));
+    // Acknowledged all the same
+    assert_pkt!(publisher.recv(), PubAck(1));
+    publisher.send(&publish("sensors/a", b"21"));
+    assert_pkt!(publisher.recv(), PubAck(1));
+    expect_payload(&mut sub, b"21");
+    assert_eq!(broker.retained("sensors/a").map(|(_, payload)| payload), Some(b"21".to_vec()));
+    assert_eq!(broker.validation_stats(), ValidationStats { rejected: 1, flagged: 0 });
+}
+
+#[test]
+fn flagged_payloads_are_delivered_and_counted() {
+    let mut broker = Broker::new();
+    broker.add_validator("sensors/#", NotEmpty, ValidationAction::Flag);
+    let addr = start(&broker);
+    let mut sub = Client::connect_id(addr, "flag-sub");
+    sub.subscribe(1, vec![("sensors/a", QosLv::AtMostOnce), ("other", QosLv::AtMostOnce)]);
+    let mut publisher = Client::connect_id(addr, "flag-pub");
+    publisher.send(&publish("sensors/a", b""));
+    expect_payload(&mut sub, b"");
+    // Outside the filter, nothing is checked
+    publisher.send(&publish("other", b""));
+    expect_payload(&mut sub, b"");
+    assert_eq!(broker.validation_stats(), ValidationStats { rejected: 0, flagged: 1 });
+}
+
+#[test]
+fn a_rejecting_validator_wins_over_a_flagging_one() {
+    let mut validators = Validators::new();
+    validators.add("a/#", NotEmpty, ValidationAction::Flag)
+        .add("a/+", NotEmpty, ValidationAction::Reject);
+    assert_eq!(validators.check("a/b", b"x"), None);
+    assert_eq!(validators.check("a/b", b""),
+        Some((ValidationAction::Reject, "empty payload (validated for a/+)".to_string())));
+    assert_eq!(validators.check("a/b/c", b""),
+        Some((ValidationAction::Flag, "empty payload (validated for a/#)".to_string())));
+}
+
+#[cfg(feature = "json-schema")]
+#[test]
+fn payloads_are_checked_against_json_schemas() {
+    let schema = JsonSchema::parse(r#"{
+        "type": "object",
+        "required": ["device", "temp"],
+        "additionalProperties": false,
+        "properties": {
+            "device": {"type": "string", "minLength": 1},
+            "temp": {"type": "number", "minimum": -40, "maximum": 85},
+            "unit": {"enum": ["C", "F"]},
+            "samples": {"type": "array", "maxItems": 2, "items": {"type": "integer"}}
+        }
+    }"#).unwrap();
+    let validate = |payload: &str| schema.validate("t", payload.as_bytes());
+    assert_eq!(validate(r#"{"device": "lamp-7", "temp": -3.5, "unit": "C", "samples": [1, 2]}"#),
+        Ok(()));
+    let cases = [
+        ("not json", "payload is not JSON"),
+        ("[]", "/: expected object, got array"),
+        (r#"{"device": "lamp-7"}"#, "/: missing temp"),
+        (r#"{"device": "", "temp": 1}"#, "/device: expected at least 1 characters, got 0"),
+        (r#"{"device": "a", "temp": 90}"#, "/temp: 90 is more than 85"),
+        (r#"{"device": "a", "temp": "hot"}"#, "/temp: expected number, got string"),
+        (r#"{"device": "a", "temp": 1, "unit": "K"}"#,
+            "/unit: \"K\" is not one of the allowed values"),
+        (r#"{"device": "a", "temp": 1, "samples": [1, 2.5]}"#,
+            "/samples/1: expected integer, got number"),
+        (r#"{"device": "a", "temp": 1, "samples": [1, 2, 3]}"#,
+            "/samples: expected at most 2 items, got 3"),
+        (r#"{"device": "a", "temp": 1, "extra": true}"#, "/: unexpected extra")
+    ];
+    for &(payload, reason) in cases.iter() {
+        assert_eq!(validate(payload), Err(reason.to_string()), "{}", payload);
+    }
+}
+
+#[cfg(feature = "json-schema")]
+#[test]
+fn schemas_must_be_json_objects() {
+    assert!(JsonSchema::parse("[]").is_err());
+    assert!(JsonSchema::parse("{").is_err());
+    assert!(JsonSchema::parse("{}").is_ok());
+}
@@ -0,0 +1,36 @@
+extern crate libmqtt;
+extern crate mqtt_broker;
+
+#[macro_use]
+mod common;
+
+use common::*;
+use libmqtt::connopts::{ConnectOptions, Will};
+use libmqtt::ctrlpkt::{ConnAckRetCode, CtrlPkt::*};
+use mqtt_broker::broker::{Broker, WillLimits};
+use std::net::SocketAddr;
+
+fn connect_with_will(addr: SocketAddr, topic_name: &str, payload: &[u8]) -> Client {
+    let mut opts = ConnectOptions::new("will-limited".to_string());
+    opts.set_will(Will::new(topic_name.to_string(), payload.to_vec()));
+    let mut client = Client::open(addr);
+    client.send(&opts.build().unwrap());
+    client
+}
+
+#[test]
+fn oversized_wills_are_refused() {
+    let mut broker = Broker::new();
+    broker.set_will_limits(WillLimits { max_topic_len: Some(8), max_payload_len: Some(4) });
+    // The limits apply to the topic the client sent, not to the mounted one
+    let mut listener_config = local_listener();
+    listener_config.mount_point = Some("a-long-mount-point/".to_string());
+    let addr = start_listener(&broker, listener_config);
+    for &(topic_name, payload) in &[("status/9", &b"gone!"[..]), ("status/10", &b"gone"[..])] {
+        let mut client = connect_with_will(addr, topic_name, payload);
+        assert_pkt!(client.recv(), ConnAck { return_code: ConnAckRetCode::NotAuthorized, .. });
+        client.expect_closed();
+    }
+    let mut client = connect_with_will(addr, "status/9", b"gone");
+    assert_pkt!(client.recv(), ConnAck { return_code: ConnAckRetCode::Accepted, .. });
+}